@@ -1,17 +1,156 @@
-use super::{DataResult, Datastore, DatastoreError, DatastoreErrorType};
-use crate::logic::domain;
-use std::{collections::HashMap, sync::Mutex};
+use super::{
+    user_event, DataResult, Datastore, DatastoreError, DatastoreErrorType, UserListParams,
+};
+use crate::{
+    logic::{
+        domain,
+        dto::{Filter, FilterField, FilterOp, SortKey},
+    },
+    worker::{Job, JobState},
+};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Mutex,
+};
+use time::{Duration, OffsetDateTime};
 
 pub struct InMemDatastore {
-    // HashMap<String, String> and Vec<String> are Send+Sync
-    // so Mutex is also Send+Sync => no Arc needed
-    users: Mutex<HashMap<String, String>>, // <id, json>
+    // Tables is Send+Sync so Mutex is also Send+Sync => no Arc needed.
+    // One lock covers the primary map and its indexes, so they never drift apart.
+    tables: Mutex<Tables>,
+}
+
+struct Tables {
+    // Tenant data can only be reached through its partition
+    partitions: HashMap<domain::TenantId, Partition>,
+
+    // Pending events, the key plays the part of the AUTO_INCREMENT column
+    outbox: BTreeMap<u64, String>, // <seq, json>
+    outbox_seq: u64,
+
+    jobs: BTreeMap<String, String>, // <id, json>
+}
+
+// The rows of one tenant
+struct Partition {
+    // Ordered by id so listings are stable for keyset pagination.
+    users: BTreeMap<String, String>, // <id, json>
+
+    // Secondary indexes, mirroring the unique keys in MySQL
+    users_by_email: UniqueIndex, // <canonical email, id>
+
+    // Append-only, in insertion order per user
+    audit: HashMap<String, Vec<String>>, // <user id, [json]>
+    audit_ids: HashSet<String>,          // primary key of the entries
+
+    idempotency: HashMap<String, String>, // <key, json>
+
+    verification_tokens: HashMap<String, String>, // <user id, json>
+}
+
+impl Tables {
+    // `event` is serialized up front, so this can't fail halfway through a write
+    fn push_event(&mut self, event: String) {
+        self.outbox_seq += 1;
+        self.outbox
+            .insert(self.outbox_seq, event);
+    }
+
+    // None until the tenant's first write
+    fn partition(&self, tenant: &domain::TenantId) -> Option<&Partition> {
+        self.partitions.get(tenant)
+    }
+
+    fn partition_mut(&mut self, tenant: &domain::TenantId) -> &mut Partition {
+        self.partitions
+            .entry(tenant.clone())
+            .or_insert_with(Partition::new)
+    }
+}
+
+impl Partition {
+    fn new() -> Self {
+        Partition {
+            users: BTreeMap::new(),
+            users_by_email: UniqueIndex::new("email"),
+            audit: HashMap::new(),
+            audit_ids: HashSet::new(),
+            idempotency: HashMap::new(),
+            verification_tokens: HashMap::new(),
+        }
+    }
+
+    fn check_audit(&self, entry: &domain::AuditEntry) -> DataResult<()> {
+        match self
+            .audit_ids
+            .contains(&entry.id.to_string())
+        {
+            true => Err(DatastoreError::new(
+                format!("audit entry: {} (already exists)", entry.id),
+                DatastoreErrorType::Conflict,
+            )),
+            false => Ok(()),
+        }
+    }
+
+    // `data` is the serialized entry, call `check_audit` first
+    fn push_audit(&mut self, entry: &domain::AuditEntry, data: String) {
+        self.audit_ids
+            .insert(entry.id.to_string());
+        self.audit
+            .entry(entry.user_id.to_string())
+            .or_default()
+            .push(data);
+    }
+}
+
+// Maps a unique key to the id of the row that owns it
+struct UniqueIndex {
+    name: &'static str,
+    entries: HashMap<String, String>,
+}
+
+impl UniqueIndex {
+    fn new(name: &'static str) -> Self {
+        UniqueIndex {
+            name,
+            entries: HashMap::new(),
+        }
+    }
+
+    // Fails if `key` already belongs to a row other than `id`
+    fn check(&self, key: &str, id: &str) -> DataResult<()> {
+        match self.entries.get(key) {
+            Some(owner) if owner != id => Err(DatastoreError::new(
+                format!("{}: {} (taken by {})", self.name, key, owner),
+                DatastoreErrorType::Conflict,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Moves `id` from `old_key` (if any) to `new_key`, call `check` first
+    fn set(&mut self, old_key: Option<&str>, new_key: &str, id: &str) {
+        if let Some(old_key) = old_key {
+            if old_key != new_key {
+                self.entries.remove(old_key);
+            }
+        }
+        self.entries
+            .insert(new_key.to_string(), id.to_string());
+    }
 }
 
 impl InMemDatastore {
     pub fn new() -> Self {
         InMemDatastore {
-            users: Mutex::new(HashMap::new()),
+            tables: Mutex::new(Tables {
+                partitions: HashMap::new(),
+                outbox: BTreeMap::new(),
+                outbox_seq: 0,
+                jobs: BTreeMap::new(),
+            }),
         }
     }
 
@@ -42,6 +181,58 @@ impl InMemDatastore {
     }
 }
 
+// Stored form of a user, the domain serialization leaves out the canonical
+// email and when it was verified
+#[derive(serde::Serialize)]
+struct UserRecordRef<'a> {
+    #[serde(flatten)]
+    user: &'a domain::User,
+    email_canonical: &'a str,
+    #[serde(with = "time::serde::rfc3339::option")]
+    email_verified_at: Option<OffsetDateTime>,
+}
+
+#[derive(serde::Deserialize)]
+struct UserRecord {
+    #[serde(flatten)]
+    user: domain::User,
+    email_canonical: String,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    email_verified_at: Option<OffsetDateTime>,
+}
+
+fn user_to_json(usr: &domain::User) -> DataResult<String> {
+    InMemDatastore::to_json(UserRecordRef {
+        user: usr,
+        email_canonical: usr.email().canonical(),
+        email_verified_at: usr.email_verified_at(),
+    })
+}
+
+fn user_from_json(js: &str) -> DataResult<domain::User> {
+    let record = InMemDatastore::from_json::<UserRecord>(js)?;
+    let mut usr = record.user;
+    let email = domain::Email::from_stored(usr.email().to_string(), record.email_canonical);
+    usr.set_email(email);
+    usr.set_email_verified_at(record.email_verified_at);
+    Ok(usr)
+}
+
+fn check_stored_version(usr: &domain::User, expected: u64) -> DataResult<()> {
+    match usr.version() == expected {
+        true => Ok(()),
+        false => Err(DatastoreError::new(
+            format!(
+                "id: {} (version {} is stored, expected {})",
+                usr.id(),
+                usr.version(),
+                expected
+            ),
+            DatastoreErrorType::VersionMismatch,
+        )),
+    }
+}
+
 impl Default for InMemDatastore {
     fn default() -> Self {
         Self::new()
@@ -50,18 +241,270 @@ impl Default for InMemDatastore {
 
 #[tonic::async_trait]
 impl Datastore for InMemDatastore {
-    async fn store_user(&self, obj: &domain::User) -> DataResult<()> {
-        let data = InMemDatastore::to_json(obj)?;
-        let mut db = self.users.lock().unwrap();
-        db.insert(obj.id().to_string(), data);
+    async fn store_user(
+        &self, tenant: &domain::TenantId, obj: &domain::User, audit: &domain::AuditEntry,
+    ) -> DataResult<()> {
+        let data = user_to_json(obj)?;
+        let event = Self::to_json(user_event(
+            tenant,
+            domain::UserEventType::UserCreated,
+            obj,
+        )?)?;
+        let entry = Self::to_json(audit)?;
+        let id = obj.id().to_string();
+        let email = obj.email().canonical();
+
+        // Check every constraint before touching anything
+        let mut tables = self.tables.lock().unwrap();
+        let part = tables.partition_mut(tenant);
+        if part.users.contains_key(&id) {
+            return Err(DatastoreError::new(
+                format!("id: {} (already exists)", id),
+                DatastoreErrorType::Conflict,
+            ));
+        }
+        part.users_by_email.check(email, &id)?;
+        part.check_audit(audit)?;
+
+        part.users_by_email
+            .set(None, email, &id);
+        part.users.insert(id, data);
+        part.push_audit(audit, entry);
+        tables.push_event(event);
         Ok(())
     }
 
-    async fn get_user(&self, id: &domain::ID) -> DataResult<domain::User> {
-        let db = self.users.lock().unwrap();
-        match db.get(&id.to_string()) {
+    async fn store_users(
+        &self, tenant: &domain::TenantId, users: &[domain::User], audit: &[domain::AuditEntry],
+        atomic: bool,
+    ) -> DataResult<Vec<DataResult<()>>> {
+        if users.len() != audit.len() {
+            return Err(DatastoreError::new(
+                format!(
+                    "{} users but {} audit entries",
+                    users.len(),
+                    audit.len()
+                ),
+                DatastoreErrorType::Other,
+            ));
+        }
+
+        let mut rows: Vec<(String, String, String, String)> = Vec::with_capacity(users.len());
+        for (usr, entry) in users.iter().zip(audit) {
+            let event = Self::to_json(user_event(
+                tenant,
+                domain::UserEventType::UserCreated,
+                usr,
+            )?)?;
+            rows.push((
+                usr.id().to_string(),
+                user_to_json(usr)?,
+                event,
+                Self::to_json(entry)?,
+            ));
+        }
+
+        let mut tables = self.tables.lock().unwrap();
+        let part = tables.partition_mut(tenant);
+
+        // Rows earlier in the batch count as stored for the checks of later ones
+        let mut ids: HashSet<&str> = HashSet::new();
+        let mut audit_ids: HashSet<&domain::ID> = HashSet::new();
+        let mut emails = UniqueIndex::new("email");
+        let mut results: Vec<DataResult<()>> = Vec::with_capacity(users.len());
+        for ((usr, entry), (id, _, _, _)) in users.iter().zip(audit).zip(rows.iter()) {
+            let email = usr.email().canonical();
+            let result = if part.users.contains_key(id) || ids.contains(id.as_str()) {
+                Err(DatastoreError::new(
+                    format!("id: {} (already exists)", id),
+                    DatastoreErrorType::Conflict,
+                ))
+            } else if audit_ids.contains(&entry.id) {
+                Err(DatastoreError::new(
+                    format!("audit entry: {} (already exists)", entry.id),
+                    DatastoreErrorType::Conflict,
+                ))
+            } else {
+                part.users_by_email
+                    .check(email, id)
+                    .and_then(|_| emails.check(email, id))
+                    .and_then(|_| part.check_audit(entry))
+            };
+
+            if result.is_ok() {
+                ids.insert(id);
+                audit_ids.insert(&entry.id);
+                emails.set(None, email, id);
+            }
+            results.push(result);
+        }
+
+        if atomic && results.iter().any(|r| r.is_err()) {
+            return Ok(results);
+        }
+
+        let mut events = Vec::with_capacity(rows.len());
+        for (((usr, entry), (id, data, event, entry_data)), result) in users
+            .iter()
+            .zip(audit)
+            .zip(rows)
+            .zip(results.iter())
+        {
+            if result.is_err() {
+                continue;
+            }
+            part.users_by_email
+                .set(None, usr.email().canonical(), &id);
+            part.users.insert(id, data);
+            part.push_audit(entry, entry_data);
+            events.push(event);
+        }
+        for event in events {
+            tables.push_event(event);
+        }
+
+        Ok(results)
+    }
+
+    async fn update_user(
+        &self, tenant: &domain::TenantId, obj: &domain::User, audit: &domain::AuditEntry,
+    ) -> DataResult<()> {
+        let data = user_to_json(obj)?;
+        let event = Self::to_json(user_event(
+            tenant,
+            domain::UserEventType::UserUpdated,
+            obj,
+        )?)?;
+        let entry = Self::to_json(audit)?;
+        let id = obj.id().to_string();
+        let email = obj.email().canonical();
+
+        let mut tables = self.tables.lock().unwrap();
+        let part = tables.partition_mut(tenant);
+        let existing = match part.users.get(&id) {
+            Some(existing) => user_from_json(existing)?,
+            None => {
+                return Err(DatastoreError::new(
+                    format!("id: {}", obj.id()),
+                    DatastoreErrorType::NotFound,
+                ))
+            },
+        };
+        if existing.version() + 1 != obj.version() {
+            return Err(DatastoreError::new(
+                format!(
+                    "id: {} (version {} is stored, got {})",
+                    id,
+                    existing.version(),
+                    obj.version()
+                ),
+                DatastoreErrorType::VersionMismatch,
+            ));
+        }
+        let old_email = existing.email().canonical().to_string();
+        part.users_by_email.check(email, &id)?;
+        part.check_audit(audit)?;
+
+        part.users_by_email
+            .set(Some(&old_email), email, &id);
+        part.users.insert(id, data);
+        part.push_audit(audit, entry);
+        tables.push_event(event);
+        Ok(())
+    }
+
+    async fn delete_user(
+        &self, tenant: &domain::TenantId, id: &domain::ID, version: u64,
+        deleted_at: OffsetDateTime, audit: &domain::AuditEntry,
+    ) -> DataResult<()> {
+        let entry = Self::to_json(audit)?;
+        let mut tables = self.tables.lock().unwrap();
+        let part = tables.partition_mut(tenant);
+        let mut item = match part.users.get(&id.to_string()) {
+            Some(data) => user_from_json(data)?,
+            None => {
+                return Err(DatastoreError::new(
+                    format!("id: {}", id),
+                    DatastoreErrorType::NotFound,
+                ))
+            },
+        };
+
+        if item.is_deleted() {
+            return Err(DatastoreError::new(
+                format!("id: {} (already deleted)", id),
+                DatastoreErrorType::NotFound,
+            ));
+        }
+        check_stored_version(&item, version)?;
+        part.check_audit(audit)?;
+
+        item.set_deleted_at(Some(deleted_at));
+        item.set_updated_at(deleted_at);
+        item.bump_version();
+        let event = Self::to_json(user_event(
+            tenant,
+            domain::UserEventType::UserDeleted,
+            &item,
+        )?)?;
+        part.users
+            .insert(id.to_string(), user_to_json(&item)?);
+        part.push_audit(audit, entry);
+        tables.push_event(event);
+        Ok(())
+    }
+
+    async fn restore_user(
+        &self, tenant: &domain::TenantId, id: &domain::ID, version: u64,
+        restored_at: OffsetDateTime, audit: &domain::AuditEntry,
+    ) -> DataResult<()> {
+        let entry = Self::to_json(audit)?;
+        let mut tables = self.tables.lock().unwrap();
+        let part = tables.partition_mut(tenant);
+        let mut item = match part.users.get(&id.to_string()) {
+            Some(data) => user_from_json(data)?,
+            None => {
+                return Err(DatastoreError::new(
+                    format!("id: {}", id),
+                    DatastoreErrorType::NotFound,
+                ))
+            },
+        };
+
+        if !item.is_deleted() {
+            return Err(DatastoreError::new(
+                format!("id: {} (not deleted)", id),
+                DatastoreErrorType::NotFound,
+            ));
+        }
+        check_stored_version(&item, version)?;
+        part.check_audit(audit)?;
+
+        item.set_deleted_at(None);
+        item.set_updated_at(restored_at);
+        item.bump_version();
+        let event = Self::to_json(user_event(
+            tenant,
+            domain::UserEventType::UserRestored,
+            &item,
+        )?)?;
+        part.users
+            .insert(id.to_string(), user_to_json(&item)?);
+        part.push_audit(audit, entry);
+        tables.push_event(event);
+        Ok(())
+    }
+
+    async fn get_user(
+        &self, tenant: &domain::TenantId, id: &domain::ID,
+    ) -> DataResult<domain::User> {
+        let tables = self.tables.lock().unwrap();
+        match tables
+            .partition(tenant)
+            .and_then(|p| p.users.get(&id.to_string()))
+        {
             Some(data) => {
-                let item = InMemDatastore::from_json::<domain::User>(data)?;
+                let item = user_from_json(data)?;
                 Ok(item)
             },
 
@@ -72,23 +515,366 @@ impl Datastore for InMemDatastore {
         }
     }
 
-    async fn list_users(&self) -> DataResult<Vec<domain::User>> {
-        let db = self.users.lock().unwrap();
+    async fn list_users(
+        &self, tenant: &domain::TenantId, params: &UserListParams,
+    ) -> DataResult<Vec<domain::User>> {
+        let tables = self.tables.lock().unwrap();
+        let users = match tables.partition(tenant) {
+            Some(p) => &p.users,
+            None => return Ok(vec![]),
+        };
 
-        // This would normally come from the query limit
-        let limit = db.len();
+        // (sort values, user)
+        let mut rows: Vec<(Vec<String>, domain::User)> = Vec::new();
 
-        let mut items: Vec<domain::User> = Vec::with_capacity(limit);
+        for (_, data) in users.iter() {
+            let u = user_from_json(data)?;
+            if !params.include_deleted && u.is_deleted() {
+                continue;
+            }
 
-        for (_, data) in db.iter() {
-            let u = InMemDatastore::from_json::<domain::User>(data)?;
-            items.push(u);
+            if let Some(ref filter) = params.filter {
+                if !matches_filter(filter, &u) {
+                    continue;
+                }
+            }
+
+            let values = params
+                .sort
+                .iter()
+                .map(|k| k.field.value_of(&u))
+                .collect();
+            rows.push((values, u));
         }
 
-        items.shrink_to_fit();
+        rows.sort_by(|a, b| compare_sort_values(&params.sort, &a.0, &b.0));
+
+        let items = rows
+            .into_iter()
+            .filter(|(values, _)| match params.after {
+                Some(ref after) => {
+                    compare_sort_values(&params.sort, values, after) == Ordering::Greater
+                },
+                None => true,
+            })
+            .take(params.limit)
+            .map(|(_, u)| u)
+            .collect();
 
         Ok(items)
     }
+
+    async fn list_audit_entries(
+        &self, tenant: &domain::TenantId, user_id: &domain::ID,
+    ) -> DataResult<Vec<domain::AuditEntry>> {
+        let tables = self.tables.lock().unwrap();
+        match tables
+            .partition(tenant)
+            .and_then(|p| p.audit.get(&user_id.to_string()))
+        {
+            Some(entries) => entries
+                .iter()
+                .map(|data| Self::from_json::<domain::AuditEntry>(data))
+                .collect(),
+            None => Ok(vec![]),
+        }
+    }
+
+    async fn list_outbox(&self, limit: usize) -> DataResult<Vec<domain::UserEvent>> {
+        let tables = self.tables.lock().unwrap();
+        tables
+            .outbox
+            .iter()
+            .take(limit)
+            .map(|(seq, data)| {
+                let mut event = Self::from_json::<domain::UserEvent>(data)?;
+                event.seq = *seq;
+                Ok(event)
+            })
+            .collect()
+    }
+
+    async fn ack_outbox(&self, seqs: &[u64]) -> DataResult<()> {
+        let mut tables = self.tables.lock().unwrap();
+        for seq in seqs.iter() {
+            tables.outbox.remove(seq);
+        }
+        Ok(())
+    }
+
+    async fn enqueue_job(&self, job: &Job) -> DataResult<()> {
+        let data = Self::to_json(job)?;
+        let id = job.id.to_string();
+
+        let mut tables = self.tables.lock().unwrap();
+        if tables.jobs.contains_key(&id) {
+            return Err(DatastoreError::new(
+                format!("job id: {} (already exists)", id),
+                DatastoreErrorType::Conflict,
+            ));
+        }
+        tables.jobs.insert(id, data);
+        Ok(())
+    }
+
+    async fn claim_jobs(
+        &self, now: OffsetDateTime, limit: usize, lease: Duration,
+    ) -> DataResult<Vec<Job>> {
+        let mut tables = self.tables.lock().unwrap();
+
+        let mut due: Vec<Job> = Vec::new();
+        for data in tables.jobs.values() {
+            let job = Self::from_json::<Job>(data)?;
+            let claimable = matches!(job.state, JobState::Pending | JobState::Running);
+            if claimable && job.run_at <= now {
+                due.push(job);
+            }
+        }
+        due.sort_by_key(|j| j.run_at);
+        due.truncate(limit);
+
+        for job in due.iter_mut() {
+            job.state = JobState::Running;
+            job.attempts += 1;
+            job.run_at = now + lease;
+            let data = Self::to_json(&*job)?;
+            tables
+                .jobs
+                .insert(job.id.to_string(), data);
+        }
+
+        Ok(due)
+    }
+
+    async fn update_job(&self, job: &Job) -> DataResult<()> {
+        let data = Self::to_json(job)?;
+        let id = job.id.to_string();
+
+        let mut tables = self.tables.lock().unwrap();
+        let stored = match tables.jobs.get(&id) {
+            Some(stored) => Self::from_json::<Job>(stored)?,
+            None => {
+                return Err(DatastoreError::new(
+                    format!("job id: {}", id),
+                    DatastoreErrorType::NotFound,
+                ))
+            },
+        };
+        if stored.attempts != job.attempts {
+            return Err(DatastoreError::new(
+                format!(
+                    "job id: {} (attempt {} is stored, got {})",
+                    id, stored.attempts, job.attempts
+                ),
+                DatastoreErrorType::VersionMismatch,
+            ));
+        }
+
+        tables.jobs.insert(id, data);
+        Ok(())
+    }
+
+    async fn get_job(&self, id: &domain::ID) -> DataResult<Job> {
+        let tables = self.tables.lock().unwrap();
+        match tables.jobs.get(&id.to_string()) {
+            Some(data) => Self::from_json::<Job>(data),
+            None => Err(DatastoreError::new(
+                format!("job id: {}", id),
+                DatastoreErrorType::NotFound,
+            )),
+        }
+    }
+
+    async fn reserve_idempotency_key(
+        &self, tenant: &domain::TenantId, record: &domain::IdempotencyRecord, now: OffsetDateTime,
+    ) -> DataResult<()> {
+        let data = Self::to_json(record)?;
+
+        let mut tables = self.tables.lock().unwrap();
+        let part = tables.partition_mut(tenant);
+        if let Some(stored) = part.idempotency.get(&record.key) {
+            let stored = Self::from_json::<domain::IdempotencyRecord>(stored)?;
+            if stored.expires_at > now {
+                return Err(DatastoreError::new(
+                    format!("idempotency key: {} (already exists)", record.key),
+                    DatastoreErrorType::Conflict,
+                ));
+            }
+        }
+        part.idempotency
+            .insert(record.key.clone(), data);
+        Ok(())
+    }
+
+    async fn get_idempotency_record(
+        &self, tenant: &domain::TenantId, key: &str, now: OffsetDateTime,
+    ) -> DataResult<domain::IdempotencyRecord> {
+        let tables = self.tables.lock().unwrap();
+        let stored = match tables
+            .partition(tenant)
+            .and_then(|p| p.idempotency.get(key))
+        {
+            Some(data) => Self::from_json::<domain::IdempotencyRecord>(data)?,
+            None => {
+                return Err(DatastoreError::new(
+                    format!("idempotency key: {}", key),
+                    DatastoreErrorType::NotFound,
+                ))
+            },
+        };
+        if stored.expires_at <= now {
+            return Err(DatastoreError::new(
+                format!("idempotency key: {} (expired)", key),
+                DatastoreErrorType::NotFound,
+            ));
+        }
+        Ok(stored)
+    }
+
+    async fn complete_idempotency_key(
+        &self, tenant: &domain::TenantId, key: &str, response: &str, expires_at: OffsetDateTime,
+    ) -> DataResult<()> {
+        let mut tables = self.tables.lock().unwrap();
+        let part = tables.partition_mut(tenant);
+        let mut stored = match part.idempotency.get(key) {
+            Some(data) => Self::from_json::<domain::IdempotencyRecord>(data)?,
+            None => {
+                return Err(DatastoreError::new(
+                    format!("idempotency key: {}", key),
+                    DatastoreErrorType::NotFound,
+                ))
+            },
+        };
+        stored.response = Some(response.to_string());
+        stored.expires_at = expires_at;
+
+        let data = Self::to_json(&stored)?;
+        part.idempotency
+            .insert(key.to_string(), data);
+        Ok(())
+    }
+
+    async fn release_idempotency_key(
+        &self, tenant: &domain::TenantId, key: &str,
+    ) -> DataResult<()> {
+        let mut tables = self.tables.lock().unwrap();
+        let part = tables.partition_mut(tenant);
+        if let Some(data) = part.idempotency.get(key) {
+            let stored = Self::from_json::<domain::IdempotencyRecord>(data)?;
+            if stored.response.is_none() {
+                part.idempotency.remove(key);
+            }
+        }
+        Ok(())
+    }
+
+    async fn store_verification_token(
+        &self, tenant: &domain::TenantId, token: &domain::VerificationToken,
+    ) -> DataResult<()> {
+        let data = Self::to_json(token)?;
+
+        let mut tables = self.tables.lock().unwrap();
+        tables
+            .partition_mut(tenant)
+            .verification_tokens
+            .insert(token.user_id.to_string(), data);
+        Ok(())
+    }
+
+    async fn get_verification_token(
+        &self, tenant: &domain::TenantId, user_id: &domain::ID,
+    ) -> DataResult<domain::VerificationToken> {
+        let tables = self.tables.lock().unwrap();
+        match tables.partition(tenant).and_then(|p| {
+            p.verification_tokens
+                .get(&user_id.to_string())
+        }) {
+            Some(data) => Self::from_json::<domain::VerificationToken>(data),
+            None => Err(DatastoreError::new(
+                format!("verification token of user: {}", user_id),
+                DatastoreErrorType::NotFound,
+            )),
+        }
+    }
+
+    async fn delete_verification_token(
+        &self, tenant: &domain::TenantId, user_id: &domain::ID, id: &domain::ID,
+    ) -> DataResult<()> {
+        let user_id = user_id.to_string();
+
+        let mut tables = self.tables.lock().unwrap();
+        let part = tables.partition_mut(tenant);
+        if let Some(data) = part.verification_tokens.get(&user_id) {
+            let stored = Self::from_json::<domain::VerificationToken>(data)?;
+            if stored.id == *id {
+                part.verification_tokens
+                    .remove(&user_id);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Byte-wise, like the binary collation used by `SqlDatastore`
+fn compare_sort_values(keys: &[SortKey], a: &[String], b: &[String]) -> Ordering {
+    for (i, key) in keys.iter().enumerate() {
+        let ord = match key.descending {
+            true => b[i].cmp(&a[i]),
+            false => a[i].cmp(&b[i]),
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+    Ordering::Equal
+}
+
+// Must stay in line with the SQL translation in `sql.rs` (see datastore::conformance)
+fn matches_filter(filter: &Filter, usr: &domain::User) -> bool {
+    match filter {
+        Filter::And(left, right) => matches_filter(left, usr) && matches_filter(right, usr),
+        Filter::Or(left, right) => matches_filter(left, usr) || matches_filter(right, usr),
+        Filter::Not(inner) => !matches_filter(inner, usr),
+        Filter::Compare {
+            field,
+            op,
+            value,
+        } => {
+            let actual = filter_field_value(field, usr);
+            match op {
+                FilterOp::Eq => actual == *value,
+                FilterOp::Ne => actual != *value,
+                FilterOp::Prefix => actual.starts_with(value.as_str()),
+                FilterOp::Suffix => actual.ends_with(value.as_str()),
+                FilterOp::Contains => actual.contains(value.as_str()),
+            }
+        },
+        Filter::In {
+            field,
+            values,
+        } => {
+            let actual = filter_field_value(field, usr);
+            values.contains(&actual)
+        },
+    }
+}
+
+fn filter_field_value(field: &FilterField, usr: &domain::User) -> String {
+    match field {
+        FilterField::Id => usr.id().to_string(),
+        FilterField::Email => usr.email().to_string(),
+        FilterField::EmailDomain => match usr.email().canonical().rsplit_once('@') {
+            Some((_, domain)) => domain.to_string(),
+            None => String::new(),
+        },
+        FilterField::Name => usr.name().to_string(),
+        FilterField::Status => usr.status().to_string(),
+        FilterField::Metadata(key) => usr
+            .metadata()
+            .get(key)
+            .map(|v| v.to_string())
+            .unwrap_or_default(),
+    }
 }
 
 impl std::fmt::Debug for InMemDatastore {
@@ -100,11 +886,15 @@ impl std::fmt::Debug for InMemDatastore {
 #[cfg(test)]
 mod tests {
     use crate::{
-        datastore::{Datastore, DatastoreErrorType},
-        logic::domain::{Email, User, UserName, ID},
+        datastore::{conformance, Datastore, DatastoreErrorType, UserListParams},
+        logic::{
+            domain::{AuditOperation, Email, TenantId, User, UserName, ID},
+            dto::SortKey,
+        },
     };
 
     use super::InMemDatastore;
+    use time::OffsetDateTime;
 
     #[test]
     fn datastore_is_send_sync() {
@@ -114,36 +904,259 @@ mod tests {
     #[tokio::test]
     async fn add_user_get_user() {
         let ds = InMemDatastore::new();
+        let t = tenant();
         let usr = User::new(
             ID::new(),
             Email::try_from("test@test.com".to_owned()).unwrap(),
             UserName::try_from("Jeff Jeffries".to_owned()).unwrap(),
+            OffsetDateTime::now_utc(),
         );
 
-        ds.store_user(&usr).await.unwrap();
+        ds.store_user(
+            &t,
+            &usr,
+            &conformance::audit(&usr, AuditOperation::Create),
+        )
+        .await
+        .unwrap();
 
-        let res = ds.get_user(usr.id()).await.unwrap();
+        let res = ds.get_user(&t, usr.id()).await.unwrap();
         assert_eq!(res.id().to_string(), usr.id().to_string());
         assert_eq!(res.email().to_string(), usr.email().to_string());
         assert_eq!(res.name().to_string(), usr.name().to_string());
     }
 
+    #[tokio::test]
+    async fn update_user_get_user() {
+        let ds = InMemDatastore::new();
+        let t = tenant();
+        let mut usr = User::new(
+            ID::new(),
+            Email::try_from("test@test.com".to_owned()).unwrap(),
+            UserName::try_from("Jeff Jeffries".to_owned()).unwrap(),
+            OffsetDateTime::now_utc(),
+        );
+
+        ds.store_user(
+            &t,
+            &usr,
+            &conformance::audit(&usr, AuditOperation::Create),
+        )
+        .await
+        .unwrap();
+
+        usr.set_email(Email::try_from("changed@test.com".to_owned()).unwrap());
+        usr.set_name(UserName::try_from("Geoff Jeffries".to_owned()).unwrap());
+        usr.bump_version();
+        ds.update_user(
+            &t,
+            &usr,
+            &conformance::audit(&usr, AuditOperation::Update),
+        )
+        .await
+        .unwrap();
+
+        let res = ds.get_user(&t, usr.id()).await.unwrap();
+        assert_eq!(res, usr);
+    }
+
+    #[tokio::test]
+    async fn update_user_conflict_keeps_indexes() {
+        let ds = InMemDatastore::new();
+        let t = tenant();
+        let first = User::new(
+            ID::new(),
+            Email::try_from("first@test.com").unwrap(),
+            UserName::try_from("Jeff Jeffries".to_owned()).unwrap(),
+            OffsetDateTime::now_utc(),
+        );
+        let mut second = User::new(
+            ID::new(),
+            Email::try_from("second@test.com").unwrap(),
+            UserName::try_from("Geoff Jeffries".to_owned()).unwrap(),
+            OffsetDateTime::now_utc(),
+        );
+        ds.store_user(
+            &t,
+            &first,
+            &conformance::audit(&first, AuditOperation::Create),
+        )
+        .await
+        .unwrap();
+        ds.store_user(
+            &t,
+            &second,
+            &conformance::audit(&second, AuditOperation::Create),
+        )
+        .await
+        .unwrap();
+
+        second.set_email(Email::try_from("First@test.com").unwrap());
+        second.bump_version();
+        let err = ds
+            .update_user(
+                &t,
+                &second,
+                &conformance::audit(&second, AuditOperation::Update),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.error_type,
+            DatastoreErrorType::Conflict
+        ));
+
+        // The failed update changed neither the row nor the index
+        let stored = ds
+            .get_user(&t, second.id())
+            .await
+            .unwrap();
+        assert_eq!("second@test.com", stored.email().to_string());
+
+        let third = User::new(
+            ID::new(),
+            Email::try_from("second@test.com").unwrap(),
+            UserName::try_from("Jeff Geoffries".to_owned()).unwrap(),
+            OffsetDateTime::now_utc(),
+        );
+        let err = ds
+            .store_user(
+                &t,
+                &third,
+                &conformance::audit(&third, AuditOperation::Create),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.error_type,
+            DatastoreErrorType::Conflict
+        ));
+    }
+
+    #[tokio::test]
+    async fn update_user_not_found() {
+        let ds = InMemDatastore::new();
+        let t = tenant();
+        let usr = User::new(
+            ID::new(),
+            Email::try_from("test@test.com".to_owned()).unwrap(),
+            UserName::try_from("Jeff Jeffries".to_owned()).unwrap(),
+            OffsetDateTime::now_utc(),
+        );
+
+        let res = ds
+            .update_user(
+                &t,
+                &usr,
+                &conformance::audit(&usr, AuditOperation::Update),
+            )
+            .await
+            .expect_err("should be error");
+
+        assert!(matches!(
+            res.error_type,
+            DatastoreErrorType::NotFound
+        ));
+    }
+
+    #[tokio::test]
+    async fn delete_user_restore_user() {
+        let ds = InMemDatastore::new();
+        let t = tenant();
+        let usr = User::new(
+            ID::new(),
+            Email::try_from("test@test.com".to_owned()).unwrap(),
+            UserName::try_from("Jeff Jeffries".to_owned()).unwrap(),
+            OffsetDateTime::now_utc(),
+        );
+
+        ds.store_user(
+            &t,
+            &usr,
+            &conformance::audit(&usr, AuditOperation::Create),
+        )
+        .await
+        .unwrap();
+        ds.delete_user(
+            &t,
+            usr.id(),
+            1,
+            OffsetDateTime::now_utc(),
+            &conformance::audit(&usr, AuditOperation::Delete),
+        )
+        .await
+        .unwrap();
+
+        // Tombstone is kept, but hidden from listings by default
+        let res = ds.get_user(&t, usr.id()).await.unwrap();
+        assert!(res.is_deleted());
+        assert!(ds
+            .list_users(&t, &all(false))
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            ds.list_users(&t, &all(true))
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Deleting twice is an error
+        let res = ds
+            .delete_user(
+                &t,
+                usr.id(),
+                2,
+                OffsetDateTime::now_utc(),
+                &conformance::audit(&usr, AuditOperation::Delete),
+            )
+            .await
+            .expect_err("should be error");
+        assert!(matches!(
+            res.error_type,
+            DatastoreErrorType::NotFound
+        ));
+
+        ds.restore_user(
+            &t,
+            usr.id(),
+            2,
+            OffsetDateTime::now_utc(),
+            &conformance::audit(&usr, AuditOperation::Restore),
+        )
+        .await
+        .unwrap();
+
+        let res = ds.get_user(&t, usr.id()).await.unwrap();
+        assert!(!res.is_deleted());
+        assert_eq!(
+            ds.list_users(&t, &all(false))
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
     #[tokio::test]
     async fn get_user_corrupt_data() {
         let ds = InMemDatastore::new();
+        let t = tenant();
         let user_id = ID::new();
 
         // Add invalid json
         {
-            let mut lock = ds.users.lock().unwrap();
-            lock.insert(
+            let mut lock = ds.tables.lock().unwrap();
+            lock.partition_mut(&t).users.insert(
                 user_id.to_string(),
                 "{\"hello\": \"world\"}".to_owned(),
             );
         }
 
         let res = ds
-            .get_user(&user_id)
+            .get_user(&t, &user_id)
             .await
             .expect_err("should be error");
 
@@ -156,6 +1169,7 @@ mod tests {
     #[tokio::test]
     async fn add_users_list_users() {
         let ds = InMemDatastore::new();
+        let t = tenant();
 
         let id1 = ID::new();
         let id2 = ID::new();
@@ -175,20 +1189,53 @@ mod tests {
         let name4 = UserName::try_from("Person Four".to_string()).unwrap();
         let name5 = UserName::try_from("Person Five".to_string()).unwrap();
 
-        let user1 = User::new(id1, email1, name1);
-        let user2 = User::new(id2, email2, name2);
-        let user3 = User::new(id3, email3, name3);
-        let user4 = User::new(id4, email4, name4);
-        let user5 = User::new(id5, email5, name5);
+        let user1 = User::new(id1, email1, name1, OffsetDateTime::now_utc());
+        let user2 = User::new(id2, email2, name2, OffsetDateTime::now_utc());
+        let user3 = User::new(id3, email3, name3, OffsetDateTime::now_utc());
+        let user4 = User::new(id4, email4, name4, OffsetDateTime::now_utc());
+        let user5 = User::new(id5, email5, name5, OffsetDateTime::now_utc());
 
-        ds.store_user(&user1).await.unwrap();
-        ds.store_user(&user2).await.unwrap();
-        ds.store_user(&user3).await.unwrap();
-        ds.store_user(&user4).await.unwrap();
-        ds.store_user(&user5).await.unwrap();
+        ds.store_user(
+            &t,
+            &user1,
+            &conformance::audit(&user1, AuditOperation::Create),
+        )
+        .await
+        .unwrap();
+        ds.store_user(
+            &t,
+            &user2,
+            &conformance::audit(&user2, AuditOperation::Create),
+        )
+        .await
+        .unwrap();
+        ds.store_user(
+            &t,
+            &user3,
+            &conformance::audit(&user3, AuditOperation::Create),
+        )
+        .await
+        .unwrap();
+        ds.store_user(
+            &t,
+            &user4,
+            &conformance::audit(&user4, AuditOperation::Create),
+        )
+        .await
+        .unwrap();
+        ds.store_user(
+            &t,
+            &user5,
+            &conformance::audit(&user5, AuditOperation::Create),
+        )
+        .await
+        .unwrap();
 
         {
-            let res = ds.list_users().await.unwrap();
+            let res = ds
+                .list_users(&t, &all(false))
+                .await
+                .unwrap();
 
             assert!(res.len() == 5);
             assert!(res.contains(&user1));
@@ -198,4 +1245,173 @@ mod tests {
             assert!(res.contains(&user5));
         }
     }
+
+    #[tokio::test]
+    async fn list_users_paginated() {
+        let ds = InMemDatastore::new();
+        let t = tenant();
+
+        let mut ids: Vec<ID> = Vec::new();
+        for i in 0..5 {
+            let usr = User::new(
+                ID::new(),
+                Email::try_from(format!("user{i}@test.com")).unwrap(),
+                UserName::try_from(format!("Person {i}")).unwrap(),
+                OffsetDateTime::now_utc(),
+            );
+            ds.store_user(
+                &t,
+                &usr,
+                &conformance::audit(&usr, AuditOperation::Create),
+            )
+            .await
+            .unwrap();
+            ids.push(*usr.id());
+        }
+        ids.sort_by_key(|id| id.to_string());
+
+        let page1 = ds
+            .list_users(
+                &t,
+                &UserListParams {
+                    limit: 2,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            page1
+                .iter()
+                .map(|u| *u.id())
+                .collect::<Vec<_>>(),
+            ids[0..2]
+        );
+
+        // A row inserted before the cursor must not shift the next page
+        let early = User::new(
+            ID::try_from("00000000-0000-4000-8000-000000000000").unwrap(),
+            Email::try_from("early@test.com").unwrap(),
+            UserName::try_from("Early Bird".to_string()).unwrap(),
+            OffsetDateTime::now_utc(),
+        );
+        ds.store_user(
+            &t,
+            &early,
+            &conformance::audit(&early, AuditOperation::Create),
+        )
+        .await
+        .unwrap();
+
+        let page2 = ds
+            .list_users(
+                &t,
+                &UserListParams {
+                    limit: 2,
+                    after: Some(vec![page1[1].id().to_string()]),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            page2
+                .iter()
+                .map(|u| *u.id())
+                .collect::<Vec<_>>(),
+            ids[2..4]
+        );
+    }
+
+    conformance::conformance_tests! {
+        ds: InMemDatastore::new(), tag: "inmem";
+        list_users_filter_conformance => check_filter_cases,
+        list_users_sort_conformance => check_sort_cases,
+        email_conflict_conformance => check_email_conflicts,
+        version_conformance => check_versions,
+        timestamps_conformance => check_timestamps,
+        audit_conformance => check_audit_entries,
+        audit_atomicity_conformance => check_audit_atomicity,
+        outbox_conformance => check_outbox,
+        store_users_conformance => check_store_users,
+        jobs_conformance => check_jobs,
+        idempotency_conformance => check_idempotency_keys,
+        verification_tokens_conformance => check_verification_tokens,
+        tenant_isolation_conformance => check_tenant_isolation,
+        metadata_conformance => check_metadata,
+    }
+
+    #[tokio::test]
+    async fn list_users_sorted_paginated() {
+        let ds = InMemDatastore::new();
+        let t = tenant();
+
+        for (email, name) in [
+            ("a@test.com", "Bravo"),
+            ("b@test.com", "Alpha"),
+            ("c@test.com", "Bravo"),
+            ("d@test.com", "Charlie"),
+        ] {
+            let usr = User::new(
+                ID::new(),
+                Email::try_from(email).unwrap(),
+                UserName::try_from(name.to_string()).unwrap(),
+                OffsetDateTime::now_utc(),
+            );
+            ds.store_user(
+                &t,
+                &usr,
+                &conformance::audit(&usr, AuditOperation::Create),
+            )
+            .await
+            .unwrap();
+        }
+
+        let sort = SortKey::parse_list("-name,email").unwrap();
+        let mut seen: Vec<String> = Vec::new();
+        let mut after: Option<Vec<String>> = None;
+
+        loop {
+            let page = ds
+                .list_users(
+                    &t,
+                    &UserListParams {
+                        sort: sort.clone(),
+                        after: after.clone(),
+                        limit: 1,
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+
+            let last = match page.last() {
+                Some(v) => v,
+                None => break,
+            };
+
+            seen.push(last.email().to_string());
+            after = Some(
+                sort.iter()
+                    .map(|k| k.field.value_of(last))
+                    .collect(),
+            );
+        }
+
+        assert_eq!(
+            seen,
+            vec!["d@test.com", "a@test.com", "c@test.com", "b@test.com"]
+        );
+    }
+
+    fn tenant() -> TenantId {
+        TenantId::try_from("acme").unwrap()
+    }
+
+    fn all(include_deleted: bool) -> UserListParams {
+        UserListParams {
+            include_deleted,
+            ..Default::default()
+        }
+    }
 }
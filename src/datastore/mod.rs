@@ -1,5 +1,9 @@
-use crate::logic::domain;
+use crate::{
+    logic::{domain, dto},
+    worker,
+};
 use std::{error::Error, fmt::Display};
+use time::{Duration, OffsetDateTime};
 
 pub mod inmem;
 pub mod sql;
@@ -8,11 +12,133 @@ pub type DataResult<T> = std::result::Result<T, DatastoreError>;
 
 // INTERFACE --------------
 
+// Every user write stores the given AuditEntry and appends a UserEvent to the
+// outbox, atomically with the write itself: if any of the three fails, none
+// of them is stored.
+//
+// Users, audit entries, idempotency keys and verification tokens live in a
+// tenant: their methods take the tenant and never read or write another one's
+// rows (an id of another tenant is NotFound, emails are unique per tenant).
+// The outbox and the job queue are shared by the whole deployment.
 #[tonic::async_trait]
 pub trait Datastore {
-    async fn store_user(&self, usr: &domain::User) -> DataResult<()>;
-    async fn get_user(&self, id: &domain::ID) -> DataResult<domain::User>;
-    async fn list_users(&self) -> DataResult<Vec<domain::User>>;
+    async fn store_user(
+        &self, tenant: &domain::TenantId, usr: &domain::User, audit: &domain::AuditEntry,
+    ) -> DataResult<()>;
+
+    // Bulk insert, one result per user in the same order, `audit` holding the
+    // entry of each user. With `atomic` nothing is stored unless every user can
+    // be; otherwise each user stands on its own.
+    async fn store_users(
+        &self, tenant: &domain::TenantId, users: &[domain::User], audit: &[domain::AuditEntry],
+        atomic: bool,
+    ) -> DataResult<Vec<DataResult<()>>>;
+
+    // Compare-and-swap: only writes if the stored version is `usr.version() - 1`,
+    // otherwise fails with VersionMismatch
+    async fn update_user(
+        &self, tenant: &domain::TenantId, usr: &domain::User, audit: &domain::AuditEntry,
+    ) -> DataResult<()>;
+
+    // Both bump the stored version and set `updated_at` to the given time.
+    // Compare-and-swap as well: `version` is the one expected to be stored.
+    async fn delete_user(
+        &self, tenant: &domain::TenantId, id: &domain::ID, version: u64,
+        deleted_at: OffsetDateTime, audit: &domain::AuditEntry,
+    ) -> DataResult<()>;
+    async fn restore_user(
+        &self, tenant: &domain::TenantId, id: &domain::ID, version: u64,
+        restored_at: OffsetDateTime, audit: &domain::AuditEntry,
+    ) -> DataResult<()>;
+
+    // Returns tombstoned users as well, callers decide whether to hide them
+    async fn get_user(
+        &self, tenant: &domain::TenantId, id: &domain::ID,
+    ) -> DataResult<domain::User>;
+    async fn list_users(
+        &self, tenant: &domain::TenantId, params: &UserListParams,
+    ) -> DataResult<Vec<domain::User>>;
+
+    // Audit entries are append-only and listed oldest first. Their ids are
+    // unique, storing an entry under a taken id is a Conflict.
+    async fn list_audit_entries(
+        &self, tenant: &domain::TenantId, user_id: &domain::ID,
+    ) -> DataResult<Vec<domain::AuditEntry>>;
+
+    // Pending outbox events, lowest seq first. Acked events are removed.
+    async fn list_outbox(&self, limit: usize) -> DataResult<Vec<domain::UserEvent>>;
+    async fn ack_outbox(&self, seqs: &[u64]) -> DataResult<()>;
+
+    // Job queue: claiming leases up to `limit` due jobs (pending, or running with
+    // an expired lease) until `now + lease`, marking them running and counting
+    // the attempt. `update_job` only writes if `attempts` still matches the
+    // stored value (VersionMismatch otherwise), so a worker that lost its lease
+    // can't overwrite the outcome of a newer claim.
+    async fn enqueue_job(&self, job: &worker::Job) -> DataResult<()>;
+    async fn claim_jobs(
+        &self, now: OffsetDateTime, limit: usize, lease: Duration,
+    ) -> DataResult<Vec<worker::Job>>;
+    async fn update_job(&self, job: &worker::Job) -> DataResult<()>;
+    async fn get_job(&self, id: &domain::ID) -> DataResult<worker::Job>;
+
+    // Idempotency keys: reserving fails with Conflict while an unexpired record
+    // holds the key, expired records are replaced. Getting an expired record is
+    // NotFound. Only records without a response yet can be released.
+    async fn reserve_idempotency_key(
+        &self, tenant: &domain::TenantId, record: &domain::IdempotencyRecord, now: OffsetDateTime,
+    ) -> DataResult<()>;
+    async fn get_idempotency_record(
+        &self, tenant: &domain::TenantId, key: &str, now: OffsetDateTime,
+    ) -> DataResult<domain::IdempotencyRecord>;
+    async fn complete_idempotency_key(
+        &self, tenant: &domain::TenantId, key: &str, response: &str, expires_at: OffsetDateTime,
+    ) -> DataResult<()>;
+    async fn release_idempotency_key(&self, tenant: &domain::TenantId, key: &str)
+        -> DataResult<()>;
+
+    // Verification tokens, at most one per user: storing a token replaces the
+    // user's earlier one. Deleting only removes the token if it is still the
+    // stored one, and is a no-op otherwise.
+    async fn store_verification_token(
+        &self, tenant: &domain::TenantId, token: &domain::VerificationToken,
+    ) -> DataResult<()>;
+    async fn get_verification_token(
+        &self, tenant: &domain::TenantId, user_id: &domain::ID,
+    ) -> DataResult<domain::VerificationToken>;
+    async fn delete_verification_token(
+        &self, tenant: &domain::TenantId, user_id: &domain::ID, id: &domain::ID,
+    ) -> DataResult<()>;
+}
+
+// Keyset pagination: rows are ordered by `sort` (which must end with a unique key)
+// and start strictly after the row whose sort values are `after`
+#[derive(Debug)]
+pub struct UserListParams {
+    pub include_deleted: bool,
+    pub sort: Vec<dto::SortKey>,
+    pub after: Option<Vec<String>>,
+    pub limit: usize,
+    pub filter: Option<dto::Filter>,
+}
+
+impl Default for UserListParams {
+    fn default() -> Self {
+        UserListParams {
+            include_deleted: false,
+            sort: vec![dto::SortKey::id()],
+            after: None,
+            limit: usize::MAX,
+            filter: None,
+        }
+    }
+}
+
+// Builds the outbox event that goes with a user write
+fn user_event(
+    tenant: &domain::TenantId, event_type: domain::UserEventType, usr: &domain::User,
+) -> DataResult<domain::UserEvent> {
+    domain::UserEvent::new(tenant, event_type, usr)
+        .map_err(|e| DatastoreError::new(e, DatastoreErrorType::Other))
 }
 
 // ERRORS -----------------
@@ -28,6 +154,7 @@ pub enum DatastoreErrorType {
     NotFound,
     DataCorruption,
     Conflict,
+    VersionMismatch,
     Other,
 }
 
@@ -57,3 +184,1509 @@ impl Display for DatastoreErrorType {
 }
 
 impl Error for DatastoreError {}
+
+// SHARED TESTS -----------
+
+// Cases every Datastore implementation must agree on.
+// `tag` keeps fixtures unique so the cases can run against a shared database.
+#[cfg(test)]
+pub(crate) mod conformance {
+    use super::{Datastore, DatastoreErrorType, UserListParams};
+    use crate::logic::{
+        domain::{
+            AuditEntry, AuditOperation, Email, EmailPolicy, FieldChange, IdempotencyRecord,
+            Metadata, TenantId, User, UserEventType, UserName, UserStatus, VerificationToken, ID,
+        },
+        dto::{Filter, SortKey},
+    };
+    use crate::{
+        toolbox::clock::{Clock, SystemClock},
+        worker::{Job, JobState},
+    };
+    use time::{Duration, OffsetDateTime};
+
+    // One `#[tokio::test]` per check. `$ds` builds the backend and `$tag` the
+    // fixture tag, both are evaluated in the test body.
+    macro_rules! conformance_tests {
+        (
+            $(#[$attr:meta])*
+            ds: $ds:expr, tag: $tag:expr;
+            $($name:ident => $check:ident),+ $(,)?
+        ) => {
+            $crate::datastore::conformance::conformance_tests!(
+                @each [$(#[$attr])*] $ds, $tag; $($name => $check),+
+            );
+        };
+        (@each $attrs:tt $ds:expr, $tag:expr; $($name:ident => $check:ident),+) => {
+            $(
+                $crate::datastore::conformance::conformance_tests!(
+                    @test $attrs $ds, $tag, $name, $check
+                );
+            )+
+        };
+        (@test [$(#[$attr:meta])*] $ds:expr, $tag:expr, $name:ident, $check:ident) => {
+            #[tokio::test]
+            $(#[$attr])*
+            async fn $name() {
+                let ds = $ds;
+                let tag = $tag;
+                $crate::datastore::conformance::$check(&ds, &tag[..]).await;
+            }
+        };
+    }
+    pub(crate) use conformance_tests;
+
+    const FIXTURES: &[(&str, &str, UserStatus)] = &[
+        (
+            "john.{tag}@acme.com",
+            "John Smith",
+            UserStatus::Active,
+        ),
+        (
+            "jane.{tag}@acme.com",
+            "Jane Doe",
+            UserStatus::Active,
+        ),
+        (
+            "bob.{tag}@example.org",
+            "Bob Jones",
+            UserStatus::Suspended,
+        ),
+        (
+            "alice.{tag}@sub.acme.com",
+            "Alice Johnson",
+            UserStatus::Active,
+        ),
+        (
+            "jonas.{tag}@acme.org",
+            "jonas 100%_sure",
+            UserStatus::PendingVerification,
+        ),
+        (
+            "zed.{tag}@example.org",
+            "Bob Jones",
+            UserStatus::Suspended,
+        ),
+        (
+            "Mixed.{tag}@ACME.com",
+            "Mixed Case",
+            UserStatus::Active,
+        ),
+        (
+            "idn.{tag}@Bücher.example",
+            "Idn User",
+            UserStatus::Active,
+        ),
+    ];
+
+    // (filter, indexes into FIXTURES that must match)
+    const FILTER_CASES: &[(&str, &[usize])] = &[
+        (r#"email.domain == "acme.com""#, &[0, 1, 6]),
+        (r#"email.domain == "Acme.COM""#, &[0, 1, 6]),
+        (r#"email.domain != "acme.com""#, &[2, 3, 4, 5, 7]),
+        (r#"email.domain $= "acme.com""#, &[0, 1, 3, 6]),
+        (r#"email.domain == "bücher.example""#, &[7]),
+        (r#"email.domain == "xn--bcher-kva.example""#, &[7]),
+        (r#"email ^= "mixed.""#, &[]),
+        (r#"email ^= "bob.""#, &[2]),
+        (r#"name ^= "Jo""#, &[0]),
+        (r#"name ^= "jo""#, &[4]),
+        (r#"name *= "Jo""#, &[0, 2, 3, 5]),
+        (r#"name $= "son""#, &[3]),
+        (r#"name == "Jane Doe""#, &[1]),
+        (r#"name == "jane doe""#, &[]),
+        (r#"name *= "%""#, &[4]),
+        (r#"name *= "_""#, &[4]),
+        (r#"name ^= "J_hn""#, &[]),
+        (
+            r#"email.domain == "acme.com" and name ^= "Jo""#,
+            &[0],
+        ),
+        (
+            r#"email.domain == "example.org" or name ^= "Ja""#,
+            &[1, 2, 5],
+        ),
+        (r#"not (name *= "J")"#, &[4, 6, 7]),
+        (r#"id in ("{id1}", "{id3}")"#, &[1, 3]),
+        (r#"id == "{ID3}""#, &[3]),
+        (r#"id $= "{id2_tail}""#, &[2]),
+        (r#"id *= "{ID2_tail}""#, &[2]),
+        (
+            r#"id != "{id0}" and email.domain == "acme.com""#,
+            &[1, 6],
+        ),
+        (r#"status == "suspended""#, &[2, 5]),
+        (
+            r#"status in ("pending_verification", "deactivated")"#,
+            &[4],
+        ),
+        (
+            r#"status != "active" and name == "Bob Jones""#,
+            &[2, 5],
+        ),
+    ];
+
+    // (sort, indexes into FIXTURES in the expected order)
+    const SORT_CASES: &[(&str, &[usize])] = &[
+        ("name,email", &[3, 2, 5, 7, 1, 0, 6, 4]),
+        ("-name,email", &[4, 6, 0, 1, 7, 2, 5, 3]),
+        ("name,-email", &[3, 5, 2, 7, 1, 0, 6, 4]),
+        ("-email", &[5, 4, 0, 1, 7, 2, 3, 6]),
+    ];
+
+    pub(crate) async fn check_filter_cases(ds: &dyn Datastore, tag: &str) {
+        let t = tenant(tag);
+        let ids = store_fixtures(ds, tag).await;
+        let scope = scope_filter(&ids);
+
+        for (case, expected) in FILTER_CASES.iter() {
+            // {idN}, uppercased as {IDN}, or only its random last 12 digits
+            let mut expr = case.to_string();
+            for (i, id) in ids.iter().enumerate() {
+                let tail = &id[id.len() - 12..];
+                expr = expr
+                    .replace(&format!("{{id{i}}}"), id)
+                    .replace(&format!("{{ID{i}}}"), &id.to_uppercase())
+                    .replace(&format!("{{id{i}_tail}}"), tail)
+                    .replace(&format!("{{ID{i}_tail}}"), &tail.to_uppercase());
+            }
+
+            let filter = Filter::parse(&format!("({expr}) and {scope}")).unwrap();
+            let params = UserListParams {
+                include_deleted: true,
+                limit: 1000,
+                filter: Some(filter),
+                ..Default::default()
+            };
+
+            let mut actual: Vec<String> = ds
+                .list_users(&t, &params)
+                .await
+                .unwrap()
+                .iter()
+                .map(|u| u.id().to_string())
+                .collect();
+            actual.sort();
+
+            let mut expected: Vec<String> = expected
+                .iter()
+                .map(|i| ids[*i].clone())
+                .collect();
+            expected.sort();
+
+            assert_eq!(actual, expected, "filter: {case}");
+        }
+    }
+
+    // Walks every sort order two rows at a time to exercise the keyset cursor
+    pub(crate) async fn check_sort_cases(ds: &dyn Datastore, tag: &str) {
+        let t = tenant(tag);
+        let ids = store_fixtures(ds, tag).await;
+        let scope = Filter::parse(&scope_filter(&ids)).unwrap();
+
+        for (case, expected) in SORT_CASES.iter() {
+            let sort = SortKey::parse_list(case).unwrap();
+            let mut actual: Vec<String> = Vec::new();
+            let mut after: Option<Vec<String>> = None;
+
+            loop {
+                let params = UserListParams {
+                    include_deleted: true,
+                    sort: sort.clone(),
+                    after: after.clone(),
+                    limit: 2,
+                    filter: Some(scope.clone()),
+                };
+
+                let page = ds
+                    .list_users(&t, &params)
+                    .await
+                    .unwrap();
+                let last = match page.last() {
+                    Some(v) => v,
+                    None => break,
+                };
+
+                after = Some(
+                    sort.iter()
+                        .map(|k| k.field.value_of(last))
+                        .collect(),
+                );
+                actual.extend(page.iter().map(|u| u.id().to_string()));
+            }
+
+            let expected: Vec<String> = expected
+                .iter()
+                .map(|i| ids[*i].clone())
+                .collect();
+
+            assert_eq!(actual, expected, "sort: {case}");
+        }
+    }
+
+    // The canonical email is the uniqueness key, not the address as typed
+    pub(crate) async fn check_email_conflicts(ds: &dyn Datastore, tag: &str) {
+        let t = tenant(tag);
+        let policy = EmailPolicy {
+            strip_plus_domains: vec![format!("{tag}.com")],
+        };
+        let email = |v: &str| Email::parse(&v.replace("{tag}", tag), &policy).unwrap();
+        let name = || UserName::try_from("Jeff Jefferson".to_string()).unwrap();
+
+        let first = User::new(
+            ID::new(),
+            email("jeff@{tag}.com"),
+            name(),
+            OffsetDateTime::now_utc(),
+        );
+        ds.store_user(&t, &first, &audit(&first, AuditOperation::Create))
+            .await
+            .unwrap();
+
+        for dupe in ["Jeff@{TAG}.COM", "jeff+news@{tag}.com", " JEFF@{tag}.com "] {
+            let usr = User::new(
+                ID::new(),
+                email(&dupe.replace("{TAG}", &tag.to_uppercase())),
+                name(),
+                OffsetDateTime::now_utc(),
+            );
+            let err = ds
+                .store_user(&t, &usr, &audit(&usr, AuditOperation::Create))
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(err.error_type, DatastoreErrorType::Conflict),
+                "store: {dupe}"
+            );
+        }
+
+        let mut second = User::new(
+            ID::new(),
+            email("geoff@{tag}.com"),
+            name(),
+            OffsetDateTime::now_utc(),
+        );
+        ds.store_user(
+            &t,
+            &second,
+            &audit(&second, AuditOperation::Create),
+        )
+        .await
+        .unwrap();
+
+        second.set_email(email("JEFF+other@{tag}.com"));
+        second.bump_version();
+        let err = ds
+            .update_user(
+                &t,
+                &second,
+                &audit(&second, AuditOperation::Update),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.error_type,
+            DatastoreErrorType::Conflict
+        ));
+
+        // Changing only the case of your own email is not a conflict
+        let mut first = ds
+            .get_user(&t, first.id())
+            .await
+            .unwrap();
+        assert_eq!(
+            first.email().canonical(),
+            format!("jeff@{tag}.com")
+        );
+        first.set_email(email("JEFF@{tag}.com"));
+        first.bump_version();
+        ds.update_user(&t, &first, &audit(&first, AuditOperation::Update))
+            .await
+            .unwrap();
+
+        let stored = ds
+            .get_user(&t, first.id())
+            .await
+            .unwrap();
+        assert_eq!(
+            stored.email().to_string(),
+            format!("JEFF@{tag}.com")
+        );
+        assert_eq!(
+            stored.email().canonical(),
+            format!("jeff@{tag}.com")
+        );
+
+        // Moving to another email releases the old one
+        first.set_email(email("jeffrey@{tag}.com"));
+        first.bump_version();
+        ds.update_user(&t, &first, &audit(&first, AuditOperation::Update))
+            .await
+            .unwrap();
+
+        let third = User::new(
+            ID::new(),
+            email("jeff@{tag}.com"),
+            name(),
+            OffsetDateTime::now_utc(),
+        );
+        ds.store_user(&t, &third, &audit(&third, AuditOperation::Create))
+            .await
+            .unwrap();
+
+        // Ids are unique too
+        let err = ds
+            .store_user(&t, &third, &audit(&third, AuditOperation::Create))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.error_type,
+            DatastoreErrorType::Conflict
+        ));
+    }
+
+    // Every write bumps the version, stale updates are rejected
+    pub(crate) async fn check_versions(ds: &dyn Datastore, tag: &str) {
+        let t = tenant(tag);
+        let mut usr = User::new(
+            ID::new(),
+            Email::try_from(format!("versions.{tag}@acme.com")).unwrap(),
+            UserName::try_from("Jeff Jefferson".to_string()).unwrap(),
+            OffsetDateTime::now_utc(),
+        );
+        ds.store_user(&t, &usr, &audit(&usr, AuditOperation::Create))
+            .await
+            .unwrap();
+        assert_eq!(
+            ds.get_user(&t, usr.id())
+                .await
+                .unwrap()
+                .version(),
+            1
+        );
+
+        let stale = ds.get_user(&t, usr.id()).await.unwrap();
+
+        usr.set_name(UserName::try_from("Geoff Jefferson".to_string()).unwrap());
+        usr.set_status(UserStatus::Suspended);
+        usr.bump_version();
+        ds.update_user(&t, &usr, &audit(&usr, AuditOperation::Update))
+            .await
+            .unwrap();
+        assert_eq!(
+            ds.get_user(&t, usr.id())
+                .await
+                .unwrap()
+                .version(),
+            2
+        );
+
+        // Replaying the same write, or writing from a stale read, both fail
+        let err = ds
+            .update_user(&t, &usr, &audit(&usr, AuditOperation::Update))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.error_type,
+            DatastoreErrorType::VersionMismatch
+        ));
+
+        let mut stale = stale;
+        stale.bump_version();
+        let err = ds
+            .update_user(&t, &stale, &audit(&stale, AuditOperation::Update))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.error_type,
+            DatastoreErrorType::VersionMismatch
+        ));
+
+        let stored = ds.get_user(&t, usr.id()).await.unwrap();
+        assert_eq!(stored.name().to_string(), "Geoff Jefferson");
+        assert_eq!(stored.status(), UserStatus::Suspended);
+
+        // Deletes and restores are compare-and-swap too
+        for stale in [1, 3] {
+            let err = ds
+                .delete_user(
+                    &t,
+                    usr.id(),
+                    stale,
+                    OffsetDateTime::now_utc(),
+                    &audit(&usr, AuditOperation::Delete),
+                )
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                err.error_type,
+                DatastoreErrorType::VersionMismatch
+            ));
+        }
+
+        ds.delete_user(
+            &t,
+            usr.id(),
+            2,
+            OffsetDateTime::now_utc(),
+            &audit(&usr, AuditOperation::Delete),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            ds.get_user(&t, usr.id())
+                .await
+                .unwrap()
+                .version(),
+            3
+        );
+
+        let err = ds
+            .restore_user(
+                &t,
+                usr.id(),
+                2,
+                OffsetDateTime::now_utc(),
+                &audit(&usr, AuditOperation::Restore),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.error_type,
+            DatastoreErrorType::VersionMismatch
+        ));
+        assert!(ds
+            .get_user(&t, usr.id())
+            .await
+            .unwrap()
+            .is_deleted());
+
+        ds.restore_user(
+            &t,
+            usr.id(),
+            3,
+            OffsetDateTime::now_utc(),
+            &audit(&usr, AuditOperation::Restore),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            ds.get_user(&t, usr.id())
+                .await
+                .unwrap()
+                .version(),
+            4
+        );
+    }
+
+    // Timestamps survive a round trip through the datastore unchanged
+    pub(crate) async fn check_timestamps(ds: &dyn Datastore, tag: &str) {
+        let t = tenant(tag);
+        let created_at = SystemClock.now() - Duration::days(1);
+        let mut usr = User::new(
+            ID::new(),
+            Email::try_from(format!("timestamps.{tag}@acme.com")).unwrap(),
+            UserName::try_from("Jeff Jefferson".to_string()).unwrap(),
+            created_at,
+        );
+        ds.store_user(&t, &usr, &audit(&usr, AuditOperation::Create))
+            .await
+            .unwrap();
+
+        let stored = ds.get_user(&t, usr.id()).await.unwrap();
+        assert_eq!(stored.created_at(), created_at);
+        assert_eq!(stored.updated_at(), created_at);
+
+        let updated_at = SystemClock.now();
+        usr.set_updated_at(updated_at);
+        usr.bump_version();
+        ds.update_user(&t, &usr, &audit(&usr, AuditOperation::Update))
+            .await
+            .unwrap();
+
+        let stored = ds.get_user(&t, usr.id()).await.unwrap();
+        assert_eq!(stored.created_at(), created_at);
+        assert_eq!(stored.updated_at(), updated_at);
+
+        let deleted_at = updated_at + Duration::minutes(1);
+        ds.delete_user(
+            &t,
+            usr.id(),
+            2,
+            deleted_at,
+            &audit(&usr, AuditOperation::Delete),
+        )
+        .await
+        .unwrap();
+
+        let stored = ds.get_user(&t, usr.id()).await.unwrap();
+        assert_eq!(stored.deleted_at(), Some(deleted_at));
+        assert_eq!(stored.updated_at(), deleted_at);
+
+        let restored_at = deleted_at + Duration::minutes(1);
+        ds.restore_user(
+            &t,
+            usr.id(),
+            3,
+            restored_at,
+            &audit(&usr, AuditOperation::Restore),
+        )
+        .await
+        .unwrap();
+
+        let stored = ds.get_user(&t, usr.id()).await.unwrap();
+        assert_eq!(stored.deleted_at(), None);
+        assert_eq!(stored.updated_at(), restored_at);
+    }
+
+    pub(crate) async fn check_audit_entries(ds: &dyn Datastore, tag: &str) {
+        let t = tenant(tag);
+        let at = SystemClock.now();
+        let mut usr = User::new(
+            ID::new(),
+            Email::try_from(format!("audit.{tag}@acme.com")).unwrap(),
+            UserName::try_from("name 0".to_string()).unwrap(),
+            at,
+        );
+        let entry = |usr: &User, i: usize, operation: AuditOperation| AuditEntry {
+            id: ID::new(),
+            user_id: *usr.id(),
+            actor: format!("actor.{tag}"),
+            trace_id: format!("trace-{i}"),
+            at: at + Duration::seconds(i as i64),
+            operation,
+            changes: vec![FieldChange {
+                field: "name".to_string(),
+                before: (i > 0).then(|| format!("name {}", i - 1)),
+                after: Some(format!("name {i}")),
+            }],
+        };
+
+        ds.store_user(&t, &usr, &entry(&usr, 0, AuditOperation::Create))
+            .await
+            .unwrap();
+        usr.set_name(UserName::try_from("name 1".to_string()).unwrap());
+        usr.bump_version();
+        ds.update_user(&t, &usr, &entry(&usr, 1, AuditOperation::Update))
+            .await
+            .unwrap();
+        ds.delete_user(
+            &t,
+            usr.id(),
+            2,
+            at + Duration::seconds(2),
+            &entry(&usr, 2, AuditOperation::Delete),
+        )
+        .await
+        .unwrap();
+
+        // Entries of other users stay out of the history
+        let other = User::new(
+            ID::new(),
+            Email::try_from(format!("audit.other.{tag}@acme.com")).unwrap(),
+            UserName::try_from("Other".to_string()).unwrap(),
+            at,
+        );
+        ds.store_user(&t, &other, &audit(&other, AuditOperation::Create))
+            .await
+            .unwrap();
+
+        let history = ds
+            .list_audit_entries(&t, usr.id())
+            .await
+            .unwrap();
+        let actual: Vec<_> = history
+            .iter()
+            .map(|e| e.operation)
+            .collect();
+        assert_eq!(
+            actual,
+            [
+                AuditOperation::Create,
+                AuditOperation::Update,
+                AuditOperation::Delete
+            ]
+        );
+        assert_eq!(history[0].changes[0].before, None);
+        assert_eq!(
+            history[2].changes[0].before.as_deref(),
+            Some("name 1")
+        );
+        assert_eq!(history[2].trace_id, "trace-2");
+        assert_eq!(history[2].at, at + Duration::seconds(2));
+
+        assert!(ds
+            .list_audit_entries(&t, &ID::new())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    // A user write and its audit entry are stored together or not at all
+    pub(crate) async fn check_audit_atomicity(ds: &dyn Datastore, tag: &str) {
+        let t = tenant(tag);
+        let user = |name: &str| {
+            User::new(
+                ID::new(),
+                Email::try_from(format!("{name}.{tag}@acme.com")).unwrap(),
+                UserName::try_from("Atomic".to_string()).unwrap(),
+                SystemClock.now(),
+            )
+        };
+        let history = |id: ID| {
+            let t = &t;
+            async move {
+                ds.list_audit_entries(t, &id)
+                    .await
+                    .unwrap()
+                    .len()
+            }
+        };
+        let conflict = |err: super::DatastoreError| {
+            assert!(
+                matches!(err.error_type, DatastoreErrorType::Conflict),
+                "{err}"
+            )
+        };
+
+        let mut first = user("first");
+        let taken = audit(&first, AuditOperation::Create);
+        ds.store_user(&t, &first, &taken)
+            .await
+            .unwrap();
+
+        // Failed user writes leave no entry
+        let dupe = user("first");
+        conflict(
+            ds.store_user(&t, &dupe, &audit(&dupe, AuditOperation::Create))
+                .await
+                .unwrap_err(),
+        );
+        assert_eq!(history(*dupe.id()).await, 0);
+
+        first.bump_version();
+        first.bump_version();
+        let err = ds
+            .update_user(&t, &first, &audit(&first, AuditOperation::Update))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.error_type,
+            DatastoreErrorType::VersionMismatch
+        ));
+        assert_eq!(history(*first.id()).await, 1);
+
+        // Failed entries leave no user write, nor an event
+        let outbox_len = ds
+            .list_outbox(10_000)
+            .await
+            .unwrap()
+            .len();
+        let mut reused = taken.clone();
+
+        let second = user("second");
+        reused.user_id = *second.id();
+        conflict(
+            ds.store_user(&t, &second, &reused)
+                .await
+                .unwrap_err(),
+        );
+        let err = ds
+            .get_user(&t, second.id())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.error_type,
+            DatastoreErrorType::NotFound
+        ));
+
+        let mut first = ds
+            .get_user(&t, first.id())
+            .await
+            .unwrap();
+        first.set_name(UserName::try_from("Changed".to_string()).unwrap());
+        first.bump_version();
+        reused.user_id = *first.id();
+        conflict(
+            ds.update_user(&t, &first, &reused)
+                .await
+                .unwrap_err(),
+        );
+        conflict(
+            ds.delete_user(&t, first.id(), 1, SystemClock.now(), &reused)
+                .await
+                .unwrap_err(),
+        );
+        let stored = ds
+            .get_user(&t, first.id())
+            .await
+            .unwrap();
+        assert_eq!(stored.name().to_string(), "Atomic");
+        assert_eq!(stored.version(), 1);
+        assert!(!stored.is_deleted());
+
+        // In a batch only the user with the failing entry is left out
+        let batch = [user("third"), user("fourth")];
+        let entries = [
+            AuditEntry {
+                user_id: *batch[0].id(),
+                ..taken.clone()
+            },
+            audit(&batch[1], AuditOperation::Create),
+        ];
+        let results = ds
+            .store_users(&t, &batch, &entries, false)
+            .await
+            .unwrap();
+        assert!(results[0].is_err());
+        assert!(results[1].is_ok());
+        assert!(ds
+            .get_user(&t, batch[0].id())
+            .await
+            .is_err());
+        assert_eq!(history(*batch[0].id()).await, 0);
+        assert_eq!(history(*batch[1].id()).await, 1);
+
+        assert_eq!(
+            ds.list_outbox(10_000)
+                .await
+                .unwrap()
+                .len(),
+            outbox_len + 1
+        );
+        assert_eq!(history(*first.id()).await, 1);
+    }
+
+    // Every write leaves one event, in order, until it is acked
+    pub(crate) async fn check_outbox(ds: &dyn Datastore, tag: &str) {
+        let t = tenant(tag);
+        let mut usr = User::new(
+            ID::new(),
+            Email::try_from(format!("outbox.{tag}@acme.com")).unwrap(),
+            UserName::try_from("Outbox".to_string()).unwrap(),
+            SystemClock.now(),
+        );
+        ds.store_user(&t, &usr, &audit(&usr, AuditOperation::Create))
+            .await
+            .unwrap();
+
+        usr.set_name(UserName::try_from("Outbox Two".to_string()).unwrap());
+        usr.bump_version();
+        ds.update_user(&t, &usr, &audit(&usr, AuditOperation::Update))
+            .await
+            .unwrap();
+
+        let now = SystemClock.now();
+        ds.delete_user(
+            &t,
+            usr.id(),
+            2,
+            now,
+            &audit(&usr, AuditOperation::Delete),
+        )
+        .await
+        .unwrap();
+        ds.restore_user(
+            &t,
+            usr.id(),
+            3,
+            now,
+            &audit(&usr, AuditOperation::Restore),
+        )
+        .await
+        .unwrap();
+
+        // A failed write leaves nothing behind
+        ds.restore_user(
+            &t,
+            usr.id(),
+            4,
+            now,
+            &audit(&usr, AuditOperation::Restore),
+        )
+        .await
+        .unwrap_err();
+
+        let events: Vec<_> = ds
+            .list_outbox(10_000)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|e| e.user_id == *usr.id())
+            .collect();
+
+        let types: Vec<_> = events
+            .iter()
+            .map(|e| e.event_type)
+            .collect();
+        assert_eq!(
+            types,
+            [
+                UserEventType::UserCreated,
+                UserEventType::UserUpdated,
+                UserEventType::UserDeleted,
+                UserEventType::UserRestored,
+            ]
+        );
+        let versions: Vec<_> = events
+            .iter()
+            .map(|e| e.version)
+            .collect();
+        assert_eq!(versions, [1, 2, 3, 4]);
+        assert!(events
+            .windows(2)
+            .all(|w| w[0].seq < w[1].seq));
+
+        let payload: serde_json::Value = serde_json::from_str(&events[1].payload).unwrap();
+        assert_eq!(payload["name"], "Outbox Two");
+        assert_eq!(payload["version"], 2);
+        let payload: serde_json::Value = serde_json::from_str(&events[2].payload).unwrap();
+        assert!(payload["deleted_at"].is_string());
+
+        let seqs: Vec<_> = events.iter().map(|e| e.seq).collect();
+        ds.ack_outbox(&seqs[..2]).await.unwrap();
+
+        let pending: Vec<_> = ds
+            .list_outbox(10_000)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|e| e.user_id == *usr.id())
+            .map(|e| e.seq)
+            .collect();
+        assert_eq!(pending, seqs[2..]);
+
+        ds.ack_outbox(&seqs).await.unwrap();
+    }
+
+    pub(crate) async fn check_store_users(ds: &dyn Datastore, tag: &str) {
+        let t = tenant(tag);
+        let now = SystemClock.now();
+        let user = |email: &str| {
+            User::new(
+                ID::new(),
+                Email::try_from(email.replace("{tag}", tag)).unwrap(),
+                UserName::try_from("Batch".to_string()).unwrap(),
+                now,
+            )
+        };
+
+        let existing = user("taken.{tag}@acme.com");
+        ds.store_user(
+            &t,
+            &existing,
+            &audit(&existing, AuditOperation::Create),
+        )
+        .await
+        .unwrap();
+
+        // Conflicts with a stored user and within the batch
+        let batch = [
+            user("one.{tag}@acme.com"),
+            user("Taken.{tag}@acme.com"),
+            user("two.{tag}@acme.com"),
+            user("ONE.{tag}@acme.com"),
+        ];
+        let outcome = |results: &[super::DataResult<()>]| {
+            results
+                .iter()
+                .map(|r| match r {
+                    Ok(_) => "ok",
+                    Err(e) if matches!(e.error_type, DatastoreErrorType::Conflict) => "conflict",
+                    Err(_) => "other",
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let results = ds
+            .store_users(&t, &batch, &audit_batch(&batch), true)
+            .await
+            .unwrap();
+        assert_eq!(
+            outcome(&results),
+            ["ok", "conflict", "ok", "conflict"]
+        );
+        for usr in batch.iter() {
+            let err = ds
+                .get_user(&t, usr.id())
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                err.error_type,
+                DatastoreErrorType::NotFound
+            ));
+        }
+
+        let results = ds
+            .store_users(&t, &batch, &audit_batch(&batch), false)
+            .await
+            .unwrap();
+        assert_eq!(
+            outcome(&results),
+            ["ok", "conflict", "ok", "conflict"]
+        );
+        assert_eq!(
+            ds.get_user(&t, batch[0].id())
+                .await
+                .unwrap(),
+            batch[0]
+        );
+        assert_eq!(
+            ds.get_user(&t, batch[2].id())
+                .await
+                .unwrap(),
+            batch[2]
+        );
+        ds.get_user(&t, batch[1].id())
+            .await
+            .unwrap_err();
+
+        // Everything fits: stored in one go
+        let batch = [user("three.{tag}@acme.com"), user("four.{tag}@acme.com")];
+        let results = ds
+            .store_users(&t, &batch, &audit_batch(&batch), true)
+            .await
+            .unwrap();
+        assert_eq!(outcome(&results), ["ok", "ok"]);
+        for usr in batch.iter() {
+            assert_eq!(&ds.get_user(&t, usr.id()).await.unwrap(), usr);
+        }
+
+        assert!(ds
+            .store_users(&t, &[], &[], true)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    pub(crate) async fn check_jobs(ds: &dyn Datastore, tag: &str) {
+        let lease = Duration::minutes(5);
+        let now = SystemClock.now();
+        let job = Job::new(
+            ID::new(),
+            &format!("test.{tag}"),
+            r#"{"n":1}"#.to_string(),
+            now,
+        );
+        ds.enqueue_job(&job).await.unwrap();
+
+        // Not due yet
+        let later = Job::new(
+            ID::new(),
+            &format!("test.{tag}"),
+            "{}".to_string(),
+            now + lease,
+        );
+        ds.enqueue_job(&later).await.unwrap();
+
+        let claim = |at: OffsetDateTime| async move {
+            ds.claim_jobs(at, 1000, lease)
+                .await
+                .unwrap()
+                .into_iter()
+                .filter(|j| j.id == job.id || j.id == later.id)
+                .collect::<Vec<_>>()
+        };
+
+        let claimed = claim(now).await;
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(claimed[0].id, job.id);
+        assert_eq!(claimed[0].state, JobState::Running);
+        assert_eq!(claimed[0].attempts, 1);
+        assert_eq!(claimed[0].run_at, now + lease);
+        assert_eq!(claimed[0].payload, r#"{"n":1}"#);
+
+        let stored = ds.get_job(&job.id).await.unwrap();
+        assert_eq!(stored, claimed[0]);
+
+        // Leased, nobody else gets it until the visibility timeout ran out
+        assert!(claim(now + Duration::minutes(1))
+            .await
+            .is_empty());
+        let reclaimed = claim(now + lease).await;
+        let ids: Vec<_> = reclaimed.iter().map(|j| j.id).collect();
+        assert_eq!(ids.len(), 2);
+        assert!(ids.contains(&job.id));
+        let reclaimed = reclaimed
+            .into_iter()
+            .find(|j| j.id == job.id)
+            .unwrap();
+        assert_eq!(reclaimed.attempts, 2);
+
+        // The first claim's lease is gone
+        let mut stale = claimed[0].clone();
+        stale.state = JobState::Done;
+        let err = ds.update_job(&stale).await.unwrap_err();
+        assert!(matches!(
+            err.error_type,
+            DatastoreErrorType::VersionMismatch
+        ));
+
+        let mut done = reclaimed.clone();
+        done.state = JobState::Done;
+        done.last_error = Some("flaky".to_string());
+        ds.update_job(&done).await.unwrap();
+        assert_eq!(ds.get_job(&job.id).await.unwrap(), done);
+
+        assert!(claim(now + Duration::days(1))
+            .await
+            .iter()
+            .all(|j| j.id != job.id));
+
+        let err = ds
+            .get_job(&ID::new())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.error_type,
+            DatastoreErrorType::NotFound
+        ));
+    }
+
+    pub(crate) async fn check_idempotency_keys(ds: &dyn Datastore, tag: &str) {
+        let t = tenant(tag);
+        let now = SystemClock.now();
+        let record = IdempotencyRecord {
+            key: format!("key-{tag}"),
+            fingerprint: "f1".to_string(),
+            response: None,
+            created_at: now,
+            expires_at: now + Duration::minutes(1),
+        };
+        ds.reserve_idempotency_key(&t, &record, now)
+            .await
+            .unwrap();
+        assert_eq!(
+            ds.get_idempotency_record(&t, &record.key, now)
+                .await
+                .unwrap(),
+            record
+        );
+
+        // Held until it expires
+        let err = ds
+            .reserve_idempotency_key(&t, &record, now)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.error_type,
+            DatastoreErrorType::Conflict
+        ));
+
+        let expires_at = now + Duration::days(1);
+        ds.complete_idempotency_key(&t, &record.key, r#"{"id":1}"#, expires_at)
+            .await
+            .unwrap();
+        let completed = ds
+            .get_idempotency_record(&t, &record.key, now + Duration::hours(1))
+            .await
+            .unwrap();
+        assert_eq!(completed.response.as_deref(), Some(r#"{"id":1}"#));
+        assert_eq!(completed.expires_at, expires_at);
+
+        // Completed records stay
+        ds.release_idempotency_key(&t, &record.key)
+            .await
+            .unwrap();
+        assert!(ds
+            .get_idempotency_record(&t, &record.key, now)
+            .await
+            .is_ok());
+
+        // Expired ones are gone and can be reserved again
+        let err = ds
+            .get_idempotency_record(&t, &record.key, expires_at)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.error_type,
+            DatastoreErrorType::NotFound
+        ));
+        let renewed = IdempotencyRecord {
+            fingerprint: "f2".to_string(),
+            created_at: expires_at,
+            expires_at: expires_at + Duration::minutes(1),
+            ..record.clone()
+        };
+        ds.reserve_idempotency_key(&t, &renewed, expires_at)
+            .await
+            .unwrap();
+
+        ds.release_idempotency_key(&t, &renewed.key)
+            .await
+            .unwrap();
+        let err = ds
+            .get_idempotency_record(&t, &renewed.key, expires_at)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.error_type,
+            DatastoreErrorType::NotFound
+        ));
+
+        let err = ds
+            .complete_idempotency_key(&t, &format!("missing-{tag}"), "{}", expires_at)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.error_type,
+            DatastoreErrorType::NotFound
+        ));
+    }
+
+    pub(crate) async fn check_verification_tokens(ds: &dyn Datastore, tag: &str) {
+        let t = tenant(tag);
+        let now = SystemClock.now();
+        let user_id = ID::new();
+        let first = VerificationToken {
+            id: ID::new(),
+            user_id,
+            email: format!("verify-{tag}@acme.com"),
+            email_canonical: format!("verify-{tag}@acme.com"),
+            created_at: now,
+            expires_at: now + Duration::days(1),
+        };
+        ds.store_verification_token(&t, &first)
+            .await
+            .unwrap();
+        assert_eq!(
+            ds.get_verification_token(&t, &user_id)
+                .await
+                .unwrap(),
+            first
+        );
+
+        // A new token replaces the old one
+        let second = VerificationToken {
+            id: ID::new(),
+            email: format!("Verify2-{tag}@acme.com"),
+            email_canonical: format!("verify2-{tag}@acme.com"),
+            ..first.clone()
+        };
+        ds.store_verification_token(&t, &second)
+            .await
+            .unwrap();
+        assert_eq!(
+            ds.get_verification_token(&t, &user_id)
+                .await
+                .unwrap(),
+            second
+        );
+
+        // Deleting the replaced token leaves the current one alone
+        ds.delete_verification_token(&t, &user_id, &first.id)
+            .await
+            .unwrap();
+        assert!(ds
+            .get_verification_token(&t, &user_id)
+            .await
+            .is_ok());
+
+        ds.delete_verification_token(&t, &user_id, &second.id)
+            .await
+            .unwrap();
+        let err = ds
+            .get_verification_token(&t, &user_id)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.error_type,
+            DatastoreErrorType::NotFound
+        ));
+
+        ds.delete_verification_token(&t, &user_id, &second.id)
+            .await
+            .unwrap();
+    }
+
+    // Nothing of one tenant is visible to, or changeable by, another
+    pub(crate) async fn check_tenant_isolation(ds: &dyn Datastore, tag: &str) {
+        let (t1, t2) = (tenant(tag), tenant(&format!("{tag}-2")));
+        let now = SystemClock.now();
+        let user = || {
+            User::new(
+                ID::new(),
+                Email::try_from(format!("iso.{tag}@acme.com")).unwrap(),
+                UserName::try_from("Isolated".to_string()).unwrap(),
+                now,
+            )
+        };
+
+        // Emails are unique per tenant only
+        let mut first = user();
+        let second = user();
+        ds.store_user(
+            &t1,
+            &first,
+            &audit(&first, AuditOperation::Create),
+        )
+        .await
+        .unwrap();
+        ds.store_user(
+            &t2,
+            &second,
+            &audit(&second, AuditOperation::Create),
+        )
+        .await
+        .unwrap();
+
+        let not_found = |err: super::DatastoreError| {
+            assert!(
+                matches!(err.error_type, DatastoreErrorType::NotFound),
+                "{err}"
+            )
+        };
+        not_found(
+            ds.get_user(&t2, first.id())
+                .await
+                .unwrap_err(),
+        );
+        not_found(
+            ds.delete_user(
+                &t2,
+                first.id(),
+                1,
+                now,
+                &audit(&first, AuditOperation::Delete),
+            )
+            .await
+            .unwrap_err(),
+        );
+        first.bump_version();
+        not_found(
+            ds.update_user(
+                &t2,
+                &first,
+                &audit(&first, AuditOperation::Update),
+            )
+            .await
+            .unwrap_err(),
+        );
+        assert_eq!(
+            ds.get_user(&t1, first.id())
+                .await
+                .unwrap()
+                .version(),
+            1
+        );
+
+        let listed = ds
+            .list_users(&t2, &UserListParams::default())
+            .await
+            .unwrap();
+        let ids: Vec<_> = listed.iter().map(|u| u.id()).collect();
+        assert_eq!(ids, [second.id()]);
+
+        assert_eq!(
+            ds.list_audit_entries(&t1, first.id())
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(ds
+            .list_audit_entries(&t2, first.id())
+            .await
+            .unwrap()
+            .is_empty());
+
+        // The same idempotency key can be held in both
+        let record = IdempotencyRecord {
+            key: format!("iso-{tag}"),
+            fingerprint: "f1".to_string(),
+            response: None,
+            created_at: now,
+            expires_at: now + Duration::minutes(1),
+        };
+        ds.reserve_idempotency_key(&t1, &record, now)
+            .await
+            .unwrap();
+        not_found(
+            ds.get_idempotency_record(&t2, &record.key, now)
+                .await
+                .unwrap_err(),
+        );
+        ds.reserve_idempotency_key(&t2, &record, now)
+            .await
+            .unwrap();
+
+        let token = VerificationToken {
+            id: ID::new(),
+            user_id: *first.id(),
+            email: first.email().to_string(),
+            email_canonical: first.email().canonical().to_string(),
+            created_at: now,
+            expires_at: now + Duration::days(1),
+        };
+        ds.store_verification_token(&t1, &token)
+            .await
+            .unwrap();
+        not_found(
+            ds.get_verification_token(&t2, first.id())
+                .await
+                .unwrap_err(),
+        );
+        ds.delete_verification_token(&t2, first.id(), &token.id)
+            .await
+            .unwrap();
+        assert!(ds
+            .get_verification_token(&t1, first.id())
+            .await
+            .is_ok());
+
+        // Events say which tenant they belong to
+        let events = ds.list_outbox(10_000).await.unwrap();
+        for (usr, t) in [(&first, &t1), (&second, &t2)] {
+            assert!(events
+                .iter()
+                .filter(|e| e.user_id == *usr.id())
+                .all(|e| e.tenant_id == *t));
+        }
+    }
+
+    // Metadata round-trips, is replaced as a whole and can be filtered on
+    pub(crate) async fn check_metadata(ds: &dyn Datastore, tag: &str) {
+        let t = tenant(tag);
+        let metadata =
+            |value: serde_json::Value| Metadata::parse(value.as_object().unwrap()).unwrap();
+
+        let mut users = Vec::new();
+        for (i, value) in [
+            serde_json::json!({"department": "sales", "cost_center": 4711, "remote": true}),
+            serde_json::json!({"department": "sales ops"}),
+            serde_json::json!({}),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let mut usr = User::new(
+                ID::new(),
+                Email::try_from(format!("meta{i}.{tag}@acme.com")).unwrap(),
+                UserName::try_from("Meta".to_string()).unwrap(),
+                OffsetDateTime::now_utc(),
+            );
+            usr.set_metadata(metadata(value));
+            ds.store_user(&t, &usr, &audit(&usr, AuditOperation::Create))
+                .await
+                .unwrap();
+            users.push(usr);
+        }
+
+        for usr in users.iter() {
+            let stored = ds.get_user(&t, usr.id()).await.unwrap();
+            assert_eq!(stored.metadata(), usr.metadata());
+        }
+
+        // Values compare in their text form, missing keys as ""
+        let ids: Vec<String> = users
+            .iter()
+            .map(|u| u.id().to_string())
+            .collect();
+        let scope = scope_filter(&ids);
+        let cases: &[(&str, &[usize])] = &[
+            (r#"metadata.department == "sales""#, &[0]),
+            (r#"metadata.cost_center == "4711""#, &[0]),
+            (r#"metadata.remote == "true""#, &[0]),
+            (r#"metadata.department ^= "sales""#, &[0, 1]),
+            (r#"not (metadata.department == "sales")"#, &[1, 2]),
+            (r#"metadata.missing == """#, &[0, 1, 2]),
+        ];
+        for (case, expected) in cases.iter() {
+            let params = UserListParams {
+                limit: 1000,
+                filter: Some(Filter::parse(&format!("({case}) and {scope}")).unwrap()),
+                ..Default::default()
+            };
+
+            let mut actual: Vec<String> = ds
+                .list_users(&t, &params)
+                .await
+                .unwrap()
+                .iter()
+                .map(|u| u.id().to_string())
+                .collect();
+            actual.sort();
+
+            let mut expected: Vec<String> = expected
+                .iter()
+                .map(|i| ids[*i].clone())
+                .collect();
+            expected.sort();
+
+            assert_eq!(actual, expected, "filter: {case}");
+        }
+
+        // Updates replace the whole map
+        let mut usr = users.remove(0);
+        usr.set_metadata(metadata(
+            serde_json::json!({"department": "support"}),
+        ));
+        usr.bump_version();
+        ds.update_user(&t, &usr, &audit(&usr, AuditOperation::Update))
+            .await
+            .unwrap();
+        let stored = ds.get_user(&t, usr.id()).await.unwrap();
+        assert_eq!(stored.metadata(), usr.metadata());
+        assert!(stored
+            .metadata()
+            .get("cost_center")
+            .is_none());
+    }
+
+    async fn store_fixtures(ds: &dyn Datastore, tag: &str) -> Vec<String> {
+        let t = tenant(tag);
+        let mut ids: Vec<String> = Vec::with_capacity(FIXTURES.len());
+
+        for (email, name, status) in FIXTURES.iter() {
+            let mut usr = User::new(
+                ID::new(),
+                Email::try_from(email.replace("{tag}", tag)).unwrap(),
+                UserName::try_from(name.to_string()).unwrap(),
+                OffsetDateTime::now_utc(),
+            );
+            usr.set_status(*status);
+            ds.store_user(&t, &usr, &audit(&usr, AuditOperation::Create))
+                .await
+                .unwrap();
+            ids.push(usr.id().to_string());
+        }
+
+        ids
+    }
+
+    // The entry stored with a write of `usr`, the checks don't look at its content
+    pub(crate) fn audit(usr: &User, operation: AuditOperation) -> AuditEntry {
+        AuditEntry {
+            id: ID::new(),
+            user_id: *usr.id(),
+            actor: "conformance".to_string(),
+            trace_id: String::new(),
+            at: usr.updated_at(),
+            operation,
+            changes: vec![],
+        }
+    }
+
+    fn audit_batch(users: &[User]) -> Vec<AuditEntry> {
+        users
+            .iter()
+            .map(|usr| audit(usr, AuditOperation::Create))
+            .collect()
+    }
+
+    // Every check works in a tenant of its own
+    fn tenant(tag: &str) -> TenantId {
+        TenantId::try_from(format!("t-{tag}")).unwrap()
+    }
+
+    // Scopes a case to this run's fixtures
+    fn scope_filter(ids: &[String]) -> String {
+        let list = ids
+            .iter()
+            .map(|id| format!("\"{id}\""))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        format!("id in ({list})")
+    }
+}
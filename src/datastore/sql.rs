@@ -1,6 +1,15 @@
-use super::{DataResult, Datastore, DatastoreError, DatastoreErrorType};
-use crate::logic::domain;
-use sqlx::{Executor, MySql};
+use super::{
+    user_event, DataResult, Datastore, DatastoreError, DatastoreErrorType, UserListParams,
+};
+use crate::{
+    logic::{
+        domain,
+        dto::{Filter, FilterField, FilterOp, SortField, SortKey},
+    },
+    worker::{Job, JobState},
+};
+use sqlx::{Connection, Executor, MySql, MySqlConnection, QueryBuilder};
+use time::{Duration, OffsetDateTime};
 
 static DB_NAME: &str = "blueprint_db";
 static MAX_CONN: u32 = 5;
@@ -27,33 +36,312 @@ impl SqlDatastore {
 }
 
 /*
-CREATE TABLE IF NOT EXISTS `users` (`id` VARCHAR(36) PRIMARY KEY,`email` VARCHAR(255) UNIQUE NOT NULL, `name` VARCHAR(255) NOT NULL);
+CREATE TABLE IF NOT EXISTS `users` (`tenant_id` VARCHAR(64) CHARACTER SET ascii COLLATE ascii_bin NOT NULL, `id` BINARY(16) NOT NULL,`email` VARCHAR(255) NOT NULL, `email_canonical` VARCHAR(255) NOT NULL, `name` VARCHAR(255) NOT NULL, `created_at` DATETIME(6) NOT NULL, `updated_at` DATETIME(6) NOT NULL, `deleted_at` DATETIME(6) NULL, `version` BIGINT UNSIGNED NOT NULL DEFAULT 1, `status` VARCHAR(32) CHARACTER SET ascii COLLATE ascii_bin NOT NULL DEFAULT 'active', `metadata` JSON NOT NULL, `email_verified_at` DATETIME(6) NULL, PRIMARY KEY (`tenant_id`, `id`), UNIQUE INDEX `users_tenant_email` (`tenant_id`, `email_canonical`), INDEX `users_status` (`status`));
+
+Existing tables:
+ALTER TABLE `users` ADD COLUMN `email_canonical` VARCHAR(255) NULL AFTER `email`;
+UPDATE `users` SET `email_canonical` = LOWER(`email`);
+ALTER TABLE `users` MODIFY `email_canonical` VARCHAR(255) UNIQUE NOT NULL, DROP INDEX `email`;
+ALTER TABLE `users` ADD COLUMN `version` BIGINT UNSIGNED NOT NULL DEFAULT 1;
+ALTER TABLE `users` ADD COLUMN `id_bin` BINARY(16) NULL FIRST;
+UPDATE `users` SET `id_bin` = UUID_TO_BIN(`id`);
+ALTER TABLE `users` DROP PRIMARY KEY, DROP COLUMN `id`, RENAME COLUMN `id_bin` TO `id`, MODIFY `id` BINARY(16) NOT NULL, ADD PRIMARY KEY (`id`);
+ALTER TABLE `users` ADD COLUMN `created_at` DATETIME(6) NOT NULL DEFAULT CURRENT_TIMESTAMP(6) AFTER `name`, ADD COLUMN `updated_at` DATETIME(6) NOT NULL DEFAULT CURRENT_TIMESTAMP(6) AFTER `created_at`;
+ALTER TABLE `users` ADD COLUMN `status` VARCHAR(32) CHARACTER SET ascii COLLATE ascii_bin NOT NULL DEFAULT 'active', ADD INDEX `users_status` (`status`);
+ALTER TABLE `users` ADD COLUMN `tenant_id` VARCHAR(64) CHARACTER SET ascii COLLATE ascii_bin NOT NULL DEFAULT 'default' FIRST, DROP PRIMARY KEY, ADD PRIMARY KEY (`tenant_id`, `id`), DROP INDEX `email_canonical`, ADD UNIQUE INDEX `users_tenant_email` (`tenant_id`, `email_canonical`);
+ALTER TABLE `users` ALTER `tenant_id` DROP DEFAULT;
+ALTER TABLE `users` ADD COLUMN `metadata` JSON NULL;
+UPDATE `users` SET `metadata` = JSON_OBJECT();
+ALTER TABLE `users` MODIFY `metadata` JSON NOT NULL;
+ALTER TABLE `users` ADD COLUMN `email_verified_at` DATETIME(6) NULL;
+UPDATE `users` SET `email_verified_at` = `updated_at` WHERE `status` <> 'pending_verification';
+
+CREATE TABLE IF NOT EXISTS `user_audit` (`id` BINARY(16) PRIMARY KEY, `tenant_id` VARCHAR(64) CHARACTER SET ascii COLLATE ascii_bin NOT NULL, `user_id` BINARY(16) NOT NULL, `actor` VARCHAR(255) NOT NULL, `trace_id` VARCHAR(64) NOT NULL, `at` DATETIME(6) NOT NULL, `operation` VARCHAR(16) NOT NULL, `changes` JSON NOT NULL, INDEX `user_audit_user_at` (`tenant_id`, `user_id`, `at`));
+
+Existing tables:
+ALTER TABLE `user_audit` ADD COLUMN `tenant_id` VARCHAR(64) CHARACTER SET ascii COLLATE ascii_bin NOT NULL DEFAULT 'default' AFTER `id`, DROP INDEX `user_audit_user_at`, ADD INDEX `user_audit_user_at` (`tenant_id`, `user_id`, `at`);
+ALTER TABLE `user_audit` ALTER `tenant_id` DROP DEFAULT;
+
+CREATE TABLE IF NOT EXISTS `user_outbox` (`seq` BIGINT UNSIGNED AUTO_INCREMENT PRIMARY KEY, `tenant_id` VARCHAR(64) CHARACTER SET ascii COLLATE ascii_bin NOT NULL, `event_type` VARCHAR(32) NOT NULL, `user_id` BINARY(16) NOT NULL, `version` BIGINT UNSIGNED NOT NULL, `occurred_at` DATETIME(6) NOT NULL, `payload` JSON NOT NULL);
+
+Existing tables:
+ALTER TABLE `user_outbox` ADD COLUMN `tenant_id` VARCHAR(64) CHARACTER SET ascii COLLATE ascii_bin NOT NULL DEFAULT 'default' AFTER `seq`;
+ALTER TABLE `user_outbox` ALTER `tenant_id` DROP DEFAULT;
+
+CREATE TABLE IF NOT EXISTS `jobs` (`id` BINARY(16) PRIMARY KEY, `kind` VARCHAR(64) NOT NULL, `payload` JSON NOT NULL, `state` VARCHAR(16) NOT NULL, `attempts` INT UNSIGNED NOT NULL, `max_attempts` INT UNSIGNED NOT NULL, `run_at` DATETIME(6) NOT NULL, `last_error` TEXT NULL, `created_at` DATETIME(6) NOT NULL, INDEX `jobs_state_run_at` (`state`, `run_at`));
+
+CREATE TABLE IF NOT EXISTS `idempotency_keys` (`tenant_id` VARCHAR(64) CHARACTER SET ascii COLLATE ascii_bin NOT NULL, `key` VARCHAR(255) CHARACTER SET ascii COLLATE ascii_bin NOT NULL, `fingerprint` CHAR(64) NOT NULL, `response` TEXT NULL, `created_at` DATETIME(6) NOT NULL, `expires_at` DATETIME(6) NOT NULL, PRIMARY KEY (`tenant_id`, `key`));
+
+CREATE TABLE IF NOT EXISTS `verification_tokens` (`tenant_id` VARCHAR(64) CHARACTER SET ascii COLLATE ascii_bin NOT NULL, `user_id` BINARY(16) NOT NULL, `id` BINARY(16) NOT NULL, `email` VARCHAR(255) NOT NULL, `email_canonical` VARCHAR(255) NOT NULL, `created_at` DATETIME(6) NOT NULL, `expires_at` DATETIME(6) NOT NULL, PRIMARY KEY (`tenant_id`, `user_id`));
+
+Existing tables (keys and tokens are short-lived, so they are simply dropped):
+DROP TABLE `idempotency_keys`, `verification_tokens`;
+
+Tokens stored before `email_canonical` (a token whose address is no longer the user's is stale anyway):
+ALTER TABLE `verification_tokens` ADD COLUMN `email_canonical` VARCHAR(255) NOT NULL DEFAULT '' AFTER `email`;
+UPDATE `verification_tokens` t JOIN `users` u ON u.`tenant_id` = t.`tenant_id` AND u.`id` = t.`user_id` AND u.`email` = t.`email` SET t.`email_canonical` = u.`email_canonical`;
+ALTER TABLE `verification_tokens` ALTER `email_canonical` DROP DEFAULT;
 */
 
 #[tonic::async_trait]
 impl Datastore for SqlDatastore {
-    async fn store_user(&self, usr: &domain::User) -> DataResult<()> {
-        let q = sqlx::query("INSERT INTO `users` (`id`, `email`,`name` ) VALUES (?, ?, ?)")
-            .bind(usr.id().to_string())
-            .bind(usr.email().to_string())
-            .bind(usr.name().to_string());
+    async fn store_user(
+        &self, tenant: &domain::TenantId, usr: &domain::User, audit: &domain::AuditEntry,
+    ) -> DataResult<()> {
+        let event = user_event(tenant, domain::UserEventType::UserCreated, usr)?;
+        let mut tx = self.pool.begin().await?;
 
-        self.pool.execute(q).await?;
+        let q = sqlx::query(
+            "INSERT INTO `users` \
+             (`tenant_id`, `id`, `email`, `email_canonical`, `name`, `created_at`, `updated_at`, \
+             `version`, `status`, `metadata`, `email_verified_at`) \
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(tenant.as_str())
+        .bind(usr.id().as_bytes().as_slice())
+        .bind(usr.email().to_string())
+        .bind(usr.email().canonical())
+        .bind(usr.name().to_string())
+        .bind(usr.created_at())
+        .bind(usr.updated_at())
+        .bind(usr.version())
+        .bind(usr.status().as_str())
+        .bind(usr.metadata().to_json())
+        .bind(usr.email_verified_at());
+
+        tx.execute(q).await?;
+        push_audit(&mut tx, tenant, audit).await?;
+        push_event(&mut tx, &event).await?;
+        tx.commit().await?;
 
         Ok(())
     }
 
-    async fn get_user(&self, id: &domain::ID) -> DataResult<domain::User> {
-        let row = sqlx::query_as::<_, UserRow>("SELECT * FROM `users` WHERE `id` = ? LIMIT 1")
-            .bind(id.to_string())
-            .fetch_one(&self.pool)
+    // One multi-row INSERT per chunk. MySQL only rolls back the failing statement,
+    // so on a conflict the batch is replayed row by row in a fresh transaction to
+    // find out which users conflict, each row behind a savepoint so a user whose
+    // audit entry fails doesn't stay behind.
+    async fn store_users(
+        &self, tenant: &domain::TenantId, users: &[domain::User], audit: &[domain::AuditEntry],
+        atomic: bool,
+    ) -> DataResult<Vec<DataResult<()>>> {
+        if users.len() != audit.len() {
+            return Err(DatastoreError::new(
+                format!(
+                    "{} users but {} audit entries",
+                    users.len(),
+                    audit.len()
+                ),
+                DatastoreErrorType::Other,
+            ));
+        }
+        if users.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut events = Vec::with_capacity(users.len());
+        for usr in users.iter() {
+            events.push(user_event(
+                tenant,
+                domain::UserEventType::UserCreated,
+                usr,
+            )?);
+        }
+
+        let mut tx = self.pool.begin().await?;
+        match insert_users(&mut tx, tenant, users, audit, &events).await {
+            Ok(_) => {
+                tx.commit().await?;
+                return Ok(users.iter().map(|_| Ok(())).collect());
+            },
+            Err(e) if matches!(e.error_type, DatastoreErrorType::Conflict) => {
+                tx.rollback().await?;
+            },
+            Err(e) => return Err(e),
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let mut results: Vec<DataResult<()>> = Vec::with_capacity(users.len());
+        for ((usr, entry), event) in users
+            .iter()
+            .zip(audit)
+            .zip(events.iter())
+        {
+            let mut row = tx.begin().await?;
+            match insert_users(
+                &mut row,
+                tenant,
+                std::slice::from_ref(usr),
+                std::slice::from_ref(entry),
+                std::slice::from_ref(event),
+            )
+            .await
+            {
+                Ok(_) => {
+                    row.commit().await?;
+                    results.push(Ok(()));
+                },
+                Err(e) if matches!(e.error_type, DatastoreErrorType::Conflict) => {
+                    row.rollback().await?;
+                    results.push(Err(e));
+                },
+                Err(e) => return Err(e),
+            }
+        }
+
+        match atomic && results.iter().any(|r| r.is_err()) {
+            true => tx.rollback().await?,
+            false => tx.commit().await?,
+        }
+
+        Ok(results)
+    }
+
+    async fn update_user(
+        &self, tenant: &domain::TenantId, usr: &domain::User, audit: &domain::AuditEntry,
+    ) -> DataResult<()> {
+        let event = user_event(tenant, domain::UserEventType::UserUpdated, usr)?;
+        let mut tx = self.pool.begin().await?;
+
+        let q = sqlx::query(
+            "UPDATE `users` SET `email` = ?, `email_canonical` = ?, `name` = ?, `updated_at` = ?, \
+             `version` = ?, `status` = ?, `metadata` = ?, `email_verified_at` = ? \
+             WHERE `tenant_id` = ? AND `id` = ? AND `version` = ?",
+        )
+        .bind(usr.email().to_string())
+        .bind(usr.email().canonical())
+        .bind(usr.name().to_string())
+        .bind(usr.updated_at())
+        .bind(usr.version())
+        .bind(usr.status().as_str())
+        .bind(usr.metadata().to_json())
+        .bind(usr.email_verified_at())
+        .bind(tenant.as_str())
+        .bind(usr.id().as_bytes().as_slice())
+        .bind(usr.version() - 1);
+
+        let res = tx.execute(q).await?;
+
+        // CLIENT_FOUND_ROWS is set by sqlx, so this counts matched (not changed) rows
+        if res.rows_affected() == 0 {
+            let stored = sqlx::query_scalar::<_, u64>(
+                "SELECT `version` FROM `users` WHERE `tenant_id` = ? AND `id` = ? LIMIT 1",
+            )
+            .bind(tenant.as_str())
+            .bind(usr.id().as_bytes().as_slice())
+            .fetch_optional(&mut *tx)
             .await?;
 
+            return Err(match stored {
+                Some(version) => DatastoreError::new(
+                    format!(
+                        "id: {} (version {} is stored, got {})",
+                        usr.id(),
+                        version,
+                        usr.version()
+                    ),
+                    DatastoreErrorType::VersionMismatch,
+                ),
+                None => DatastoreError::new(
+                    format!("id: {}", usr.id()),
+                    DatastoreErrorType::NotFound,
+                ),
+            });
+        }
+
+        push_audit(&mut tx, tenant, audit).await?;
+        push_event(&mut tx, &event).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn delete_user(
+        &self, tenant: &domain::TenantId, id: &domain::ID, version: u64,
+        deleted_at: OffsetDateTime, audit: &domain::AuditEntry,
+    ) -> DataResult<()> {
+        let q = sqlx::query(
+            "UPDATE `users` SET `deleted_at` = ?, `updated_at` = ?, `version` = `version` + 1 \
+             WHERE `tenant_id` = ? AND `id` = ? AND `deleted_at` IS NULL AND `version` = ?",
+        )
+        .bind(deleted_at)
+        .bind(deleted_at)
+        .bind(tenant.as_str())
+        .bind(id.as_bytes().as_slice())
+        .bind(version);
+
+        let mut tx = self.pool.begin().await?;
+        let res = tx.execute(q).await?;
+
+        if res.rows_affected() == 0 {
+            return Err(tombstone_cas_error(&mut tx, tenant, id, version, false).await);
+        }
+
+        let usr = fetch_user(&mut tx, tenant, id).await?;
+        push_audit(&mut tx, tenant, audit).await?;
+        push_event(
+            &mut tx,
+            &user_event(tenant, domain::UserEventType::UserDeleted, &usr)?,
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn restore_user(
+        &self, tenant: &domain::TenantId, id: &domain::ID, version: u64,
+        restored_at: OffsetDateTime, audit: &domain::AuditEntry,
+    ) -> DataResult<()> {
+        let q = sqlx::query(
+            "UPDATE `users` SET `deleted_at` = NULL, `updated_at` = ?, `version` = `version` + 1 \
+             WHERE `tenant_id` = ? AND `id` = ? AND `deleted_at` IS NOT NULL AND `version` = ?",
+        )
+        .bind(restored_at)
+        .bind(tenant.as_str())
+        .bind(id.as_bytes().as_slice())
+        .bind(version);
+
+        let mut tx = self.pool.begin().await?;
+        let res = tx.execute(q).await?;
+
+        if res.rows_affected() == 0 {
+            return Err(tombstone_cas_error(&mut tx, tenant, id, version, true).await);
+        }
+
+        let usr = fetch_user(&mut tx, tenant, id).await?;
+        push_audit(&mut tx, tenant, audit).await?;
+        push_event(
+            &mut tx,
+            &user_event(tenant, domain::UserEventType::UserRestored, &usr)?,
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn get_user(
+        &self, tenant: &domain::TenantId, id: &domain::ID,
+    ) -> DataResult<domain::User> {
+        let row = sqlx::query_as::<_, UserRow>(&format!(
+            "{USER_COLUMNS} WHERE `tenant_id` = ? AND `id` = ? LIMIT 1"
+        ))
+        .bind(tenant.as_str())
+        .bind(id.as_bytes().as_slice())
+        .fetch_one(&self.pool)
+        .await?;
+
         convert_from_row(row)
     }
 
-    async fn list_users(&self) -> DataResult<Vec<domain::User>> {
-        let rows = sqlx::query_as::<_, UserRow>("SELECT * FROM `users`")
+    async fn list_users(
+        &self, tenant: &domain::TenantId, params: &UserListParams,
+    ) -> DataResult<Vec<domain::User>> {
+        let mut qb = list_users_query(tenant, params);
+
+        let rows = qb
+            .build_query_as::<UserRow>()
             .fetch_all(&self.pool)
             .await?;
 
@@ -65,6 +353,715 @@ impl Datastore for SqlDatastore {
 
         Ok(results)
     }
+
+    async fn list_audit_entries(
+        &self, tenant: &domain::TenantId, user_id: &domain::ID,
+    ) -> DataResult<Vec<domain::AuditEntry>> {
+        // JSON columns don't decode into String, hence the cast
+        let rows = sqlx::query_as::<_, AuditRow>(
+            "SELECT `id`, `user_id`, `actor`, `trace_id`, `at`, `operation`, \
+             CAST(`changes` AS CHAR) AS `changes` FROM `user_audit` \
+             WHERE `tenant_id` = ? AND `user_id` = ? ORDER BY `at` ASC, `id` ASC",
+        )
+        .bind(tenant.as_str())
+        .bind(user_id.as_bytes().as_slice())
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter()
+            .map(convert_from_row)
+            .collect()
+    }
+
+    async fn list_outbox(&self, limit: usize) -> DataResult<Vec<domain::UserEvent>> {
+        let rows = sqlx::query_as::<_, OutboxRow>(
+            "SELECT `seq`, `tenant_id`, `event_type`, `user_id`, `version`, `occurred_at`, \
+             CAST(`payload` AS CHAR) AS `payload` FROM `user_outbox` ORDER BY `seq` ASC LIMIT ?",
+        )
+        .bind(limit as u64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter()
+            .map(convert_from_row)
+            .collect()
+    }
+
+    async fn ack_outbox(&self, seqs: &[u64]) -> DataResult<()> {
+        if seqs.is_empty() {
+            return Ok(());
+        }
+
+        let mut qb = QueryBuilder::<MySql>::new("DELETE FROM `user_outbox` WHERE `seq` IN (");
+        let mut list = qb.separated(", ");
+        for seq in seqs.iter() {
+            list.push_bind(*seq);
+        }
+        list.push_unseparated(")");
+
+        qb.build().execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn enqueue_job(&self, job: &Job) -> DataResult<()> {
+        let q = sqlx::query(
+            "INSERT INTO `jobs` (`id`, `kind`, `payload`, `state`, `attempts`, `max_attempts`, \
+             `run_at`, `last_error`, `created_at`) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(job.id.as_bytes().as_slice())
+        .bind(job.kind.as_str())
+        .bind(job.payload.as_str())
+        .bind(job.state.as_str())
+        .bind(job.attempts)
+        .bind(job.max_attempts)
+        .bind(job.run_at)
+        .bind(job.last_error.as_deref())
+        .bind(job.created_at);
+
+        self.pool.execute(q).await?;
+
+        Ok(())
+    }
+
+    // SKIP LOCKED lets concurrent workers claim disjoint batches
+    async fn claim_jobs(
+        &self, now: OffsetDateTime, limit: usize, lease: Duration,
+    ) -> DataResult<Vec<Job>> {
+        let mut tx = self.pool.begin().await?;
+
+        let rows = sqlx::query_as::<_, JobRow>(&format!(
+            "{JOB_COLUMNS} WHERE `state` IN ('pending', 'running') AND `run_at` <= ? \
+             ORDER BY `run_at` ASC LIMIT ? FOR UPDATE SKIP LOCKED"
+        ))
+        .bind(now)
+        .bind(limit as u64)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        if rows.is_empty() {
+            tx.commit().await?;
+            return Ok(vec![]);
+        }
+
+        let lease_end = now + lease;
+        let mut qb = QueryBuilder::<MySql>::new(
+            "UPDATE `jobs` SET `state` = 'running', `attempts` = `attempts` + 1, `run_at` = ",
+        );
+        qb.push_bind(lease_end)
+            .push(" WHERE `id` IN (");
+        let mut list = qb.separated(", ");
+        for row in rows.iter() {
+            list.push_bind(row.id.clone());
+        }
+        list.push_unseparated(")");
+        qb.build().execute(&mut *tx).await?;
+
+        tx.commit().await?;
+
+        let mut jobs: Vec<Job> = Vec::with_capacity(rows.len());
+        for row in rows.into_iter() {
+            let mut job: Job = convert_from_row(row)?;
+            job.state = JobState::Running;
+            job.attempts += 1;
+            job.run_at = lease_end;
+            jobs.push(job);
+        }
+
+        Ok(jobs)
+    }
+
+    async fn update_job(&self, job: &Job) -> DataResult<()> {
+        let q = sqlx::query(
+            "UPDATE `jobs` SET `state` = ?, `run_at` = ?, `last_error` = ? \
+             WHERE `id` = ? AND `attempts` = ?",
+        )
+        .bind(job.state.as_str())
+        .bind(job.run_at)
+        .bind(job.last_error.as_deref())
+        .bind(job.id.as_bytes().as_slice())
+        .bind(job.attempts);
+
+        let res = self.pool.execute(q).await?;
+
+        if res.rows_affected() == 0 {
+            let stored = sqlx::query_scalar::<_, u32>(
+                "SELECT `attempts` FROM `jobs` WHERE `id` = ? LIMIT 1",
+            )
+            .bind(job.id.as_bytes().as_slice())
+            .fetch_optional(&self.pool)
+            .await?;
+
+            return Err(match stored {
+                Some(attempts) => DatastoreError::new(
+                    format!(
+                        "job id: {} (attempt {} is stored, got {})",
+                        job.id, attempts, job.attempts
+                    ),
+                    DatastoreErrorType::VersionMismatch,
+                ),
+                None => DatastoreError::new(
+                    format!("job id: {}", job.id),
+                    DatastoreErrorType::NotFound,
+                ),
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn get_job(&self, id: &domain::ID) -> DataResult<Job> {
+        let row = sqlx::query_as::<_, JobRow>(&format!("{JOB_COLUMNS} WHERE `id` = ? LIMIT 1"))
+            .bind(id.as_bytes().as_slice())
+            .fetch_one(&self.pool)
+            .await?;
+
+        convert_from_row(row)
+    }
+
+    // Expired records are only cleared out when their key is reused
+    async fn reserve_idempotency_key(
+        &self, tenant: &domain::TenantId, record: &domain::IdempotencyRecord, now: OffsetDateTime,
+    ) -> DataResult<()> {
+        let mut tx = self.pool.begin().await?;
+
+        let q = sqlx::query(
+            "DELETE FROM `idempotency_keys` \
+             WHERE `tenant_id` = ? AND `key` = ? AND `expires_at` <= ?",
+        )
+        .bind(tenant.as_str())
+        .bind(record.key.as_str())
+        .bind(now);
+        tx.execute(q).await?;
+
+        let q = sqlx::query(
+            "INSERT INTO `idempotency_keys` \
+             (`tenant_id`, `key`, `fingerprint`, `response`, `created_at`, `expires_at`) \
+             VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(tenant.as_str())
+        .bind(record.key.as_str())
+        .bind(record.fingerprint.as_str())
+        .bind(record.response.as_deref())
+        .bind(record.created_at)
+        .bind(record.expires_at);
+        tx.execute(q).await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_idempotency_record(
+        &self, tenant: &domain::TenantId, key: &str, now: OffsetDateTime,
+    ) -> DataResult<domain::IdempotencyRecord> {
+        let row = sqlx::query_as::<_, IdempotencyRow>(
+            "SELECT * FROM `idempotency_keys` \
+             WHERE `tenant_id` = ? AND `key` = ? AND `expires_at` > ? LIMIT 1",
+        )
+        .bind(tenant.as_str())
+        .bind(key)
+        .bind(now)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.into())
+    }
+
+    async fn complete_idempotency_key(
+        &self, tenant: &domain::TenantId, key: &str, response: &str, expires_at: OffsetDateTime,
+    ) -> DataResult<()> {
+        let q = sqlx::query(
+            "UPDATE `idempotency_keys` SET `response` = ?, `expires_at` = ? \
+             WHERE `tenant_id` = ? AND `key` = ?",
+        )
+        .bind(response)
+        .bind(expires_at)
+        .bind(tenant.as_str())
+        .bind(key);
+
+        let res = self.pool.execute(q).await?;
+        if res.rows_affected() == 0 {
+            return Err(DatastoreError::new(
+                format!("idempotency key: {}", key),
+                DatastoreErrorType::NotFound,
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn release_idempotency_key(
+        &self, tenant: &domain::TenantId, key: &str,
+    ) -> DataResult<()> {
+        let q = sqlx::query(
+            "DELETE FROM `idempotency_keys` \
+             WHERE `tenant_id` = ? AND `key` = ? AND `response` IS NULL",
+        )
+        .bind(tenant.as_str())
+        .bind(key);
+        self.pool.execute(q).await?;
+
+        Ok(())
+    }
+
+    async fn store_verification_token(
+        &self, tenant: &domain::TenantId, token: &domain::VerificationToken,
+    ) -> DataResult<()> {
+        let q = sqlx::query(
+            "REPLACE INTO `verification_tokens` \
+             (`tenant_id`, `user_id`, `id`, `email`, `email_canonical`, `created_at`, \
+             `expires_at`) VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(tenant.as_str())
+        .bind(token.user_id.as_bytes().as_slice())
+        .bind(token.id.as_bytes().as_slice())
+        .bind(token.email.as_str())
+        .bind(token.email_canonical.as_str())
+        .bind(token.created_at)
+        .bind(token.expires_at);
+        self.pool.execute(q).await?;
+
+        Ok(())
+    }
+
+    async fn get_verification_token(
+        &self, tenant: &domain::TenantId, user_id: &domain::ID,
+    ) -> DataResult<domain::VerificationToken> {
+        let row = sqlx::query_as::<_, VerificationTokenRow>(
+            "SELECT * FROM `verification_tokens` WHERE `tenant_id` = ? AND `user_id` = ? LIMIT 1",
+        )
+        .bind(tenant.as_str())
+        .bind(user_id.as_bytes().as_slice())
+        .fetch_one(&self.pool)
+        .await?;
+
+        convert_from_row(row)
+    }
+
+    async fn delete_verification_token(
+        &self, tenant: &domain::TenantId, user_id: &domain::ID, id: &domain::ID,
+    ) -> DataResult<()> {
+        let q = sqlx::query(
+            "DELETE FROM `verification_tokens` \
+             WHERE `tenant_id` = ? AND `user_id` = ? AND `id` = ?",
+        )
+        .bind(tenant.as_str())
+        .bind(user_id.as_bytes().as_slice())
+        .bind(id.as_bytes().as_slice());
+        self.pool.execute(q).await?;
+
+        Ok(())
+    }
+}
+
+// Why a delete/restore matched no row: the user is missing or already in the
+// target state (NotFound), or another write got there first (VersionMismatch)
+async fn tombstone_cas_error(
+    conn: &mut MySqlConnection, tenant: &domain::TenantId, id: &domain::ID, expected: u64,
+    deleted: bool,
+) -> DatastoreError {
+    let stored = sqlx::query_scalar::<_, u64>(
+        "SELECT `version` FROM `users` \
+         WHERE `tenant_id` = ? AND `id` = ? AND (`deleted_at` IS NOT NULL) = ? LIMIT 1",
+    )
+    .bind(tenant.as_str())
+    .bind(id.as_bytes().as_slice())
+    .bind(deleted)
+    .fetch_optional(conn)
+    .await;
+
+    match stored {
+        Ok(Some(version)) => DatastoreError::new(
+            format!(
+                "id: {} (version {} is stored, expected {})",
+                id, version, expected
+            ),
+            DatastoreErrorType::VersionMismatch,
+        ),
+        Ok(None) => DatastoreError::new(
+            format!("id: {}", id),
+            DatastoreErrorType::NotFound,
+        ),
+        Err(e) => e.into(),
+    }
+}
+
+// JSON columns don't decode into String, hence the cast
+const USER_COLUMNS: &str = "SELECT `id`, `email`, `email_canonical`, `name`, `created_at`, \
+                            `updated_at`, `deleted_at`, `version`, `status`, \
+                            CAST(`metadata` AS CHAR) AS `metadata`, `email_verified_at` \
+                            FROM `users`";
+
+const JOB_COLUMNS: &str = "SELECT `id`, `kind`, CAST(`payload` AS CHAR) AS `payload`, `state`, \
+                           `attempts`, `max_attempts`, `run_at`, `last_error`, `created_at` \
+                           FROM `jobs`";
+
+// Reads inside the caller's transaction, so it sees its own uncommitted write
+async fn fetch_user(
+    conn: &mut MySqlConnection, tenant: &domain::TenantId, id: &domain::ID,
+) -> DataResult<domain::User> {
+    let row = sqlx::query_as::<_, UserRow>(&format!(
+        "{USER_COLUMNS} WHERE `tenant_id` = ? AND `id` = ? LIMIT 1"
+    ))
+    .bind(tenant.as_str())
+    .bind(id.as_bytes().as_slice())
+    .fetch_one(conn)
+    .await?;
+
+    convert_from_row(row)
+}
+
+async fn push_event(conn: &mut MySqlConnection, event: &domain::UserEvent) -> DataResult<()> {
+    let q = sqlx::query(
+        "INSERT INTO `user_outbox` \
+         (`tenant_id`, `event_type`, `user_id`, `version`, `occurred_at`, `payload`) \
+         VALUES (?, ?, ?, ?, ?, ?)",
+    )
+    .bind(event.tenant_id.as_str())
+    .bind(event.event_type.as_str())
+    .bind(event.user_id.as_bytes().as_slice())
+    .bind(event.version)
+    .bind(event.occurred_at)
+    .bind(event.payload.as_str());
+
+    conn.execute(q).await?;
+
+    Ok(())
+}
+
+async fn push_audit(
+    conn: &mut MySqlConnection, tenant: &domain::TenantId, entry: &domain::AuditEntry,
+) -> DataResult<()> {
+    insert_audit_query(tenant, std::slice::from_ref(entry))?
+        .build()
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+// Keeps statements well below the placeholder limit
+const INSERT_CHUNK_SIZE: usize = 500;
+
+async fn insert_users(
+    conn: &mut MySqlConnection, tenant: &domain::TenantId, users: &[domain::User],
+    audit: &[domain::AuditEntry], events: &[domain::UserEvent],
+) -> DataResult<()> {
+    for chunk in users.chunks(INSERT_CHUNK_SIZE) {
+        insert_users_query(tenant, chunk)
+            .build()
+            .execute(&mut *conn)
+            .await?;
+    }
+    for chunk in audit.chunks(INSERT_CHUNK_SIZE) {
+        insert_audit_query(tenant, chunk)?
+            .build()
+            .execute(&mut *conn)
+            .await?;
+    }
+    for chunk in events.chunks(INSERT_CHUNK_SIZE) {
+        insert_events_query(chunk)
+            .build()
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+fn insert_users_query<'a>(
+    tenant: &'a domain::TenantId, users: &'a [domain::User],
+) -> QueryBuilder<'a, MySql> {
+    let mut qb = QueryBuilder::<MySql>::new(
+        "INSERT INTO `users` \
+         (`tenant_id`, `id`, `email`, `email_canonical`, `name`, `created_at`, `updated_at`, \
+         `version`, `status`, `metadata`, `email_verified_at`) ",
+    );
+    qb.push_values(users, |mut row, usr| {
+        row.push_bind(tenant.as_str())
+            .push_bind(usr.id().as_bytes().as_slice())
+            .push_bind(usr.email().to_string())
+            .push_bind(usr.email().canonical())
+            .push_bind(usr.name().to_string())
+            .push_bind(usr.created_at())
+            .push_bind(usr.updated_at())
+            .push_bind(usr.version())
+            .push_bind(usr.status().as_str())
+            .push_bind(usr.metadata().to_json())
+            .push_bind(usr.email_verified_at());
+    });
+    qb
+}
+
+fn insert_audit_query<'a>(
+    tenant: &'a domain::TenantId, entries: &'a [domain::AuditEntry],
+) -> DataResult<QueryBuilder<'a, MySql>> {
+    let mut changes = Vec::with_capacity(entries.len());
+    for entry in entries.iter() {
+        changes.push(
+            serde_json::to_string(&entry.changes).map_err(|e| {
+                DatastoreError::new(
+                    format!("SqlDatastore json error: {}", e),
+                    DatastoreErrorType::Other,
+                )
+            })?,
+        );
+    }
+
+    let mut qb = QueryBuilder::<MySql>::new(
+        "INSERT INTO `user_audit` \
+         (`id`, `tenant_id`, `user_id`, `actor`, `trace_id`, `at`, `operation`, `changes`) ",
+    );
+    qb.push_values(
+        entries.iter().zip(changes),
+        |mut row, (entry, changes)| {
+            row.push_bind(entry.id.as_bytes().as_slice())
+                .push_bind(tenant.as_str())
+                .push_bind(entry.user_id.as_bytes().as_slice())
+                .push_bind(entry.actor.as_str())
+                .push_bind(entry.trace_id.as_str())
+                .push_bind(entry.at)
+                .push_bind(entry.operation.as_str())
+                .push_bind(changes);
+        },
+    );
+    Ok(qb)
+}
+
+fn insert_events_query(events: &[domain::UserEvent]) -> QueryBuilder<'_, MySql> {
+    let mut qb = QueryBuilder::<MySql>::new(
+        "INSERT INTO `user_outbox` \
+         (`tenant_id`, `event_type`, `user_id`, `version`, `occurred_at`, `payload`) ",
+    );
+    qb.push_values(events, |mut row, event| {
+        row.push_bind(event.tenant_id.as_str())
+            .push_bind(event.event_type.as_str())
+            .push_bind(event.user_id.as_bytes().as_slice())
+            .push_bind(event.version)
+            .push_bind(event.occurred_at)
+            .push_bind(event.payload.as_str());
+    });
+    qb
+}
+
+// Keyset pagination on the sort columns (the last one being the primary key),
+// so concurrent inserts can't shift pages
+fn list_users_query<'a>(
+    tenant: &'a domain::TenantId, params: &'a UserListParams,
+) -> QueryBuilder<'a, MySql> {
+    let mut qb = QueryBuilder::<MySql>::new(format!("{USER_COLUMNS} WHERE `tenant_id` = "));
+    qb.push_bind(tenant.as_str());
+
+    if !params.include_deleted {
+        qb.push(" AND `deleted_at` IS NULL");
+    }
+
+    if let Some(ref filter) = params.filter {
+        qb.push(" AND ");
+        push_filter(&mut qb, filter);
+    }
+
+    let default_sort = [SortKey::id()];
+    let sort = match params.sort.is_empty() {
+        true => &default_sort[..],
+        false => &params.sort[..],
+    };
+
+    // (k1 > v1) OR (k1 = v1 AND k2 > v2) OR ... with '<' for descending keys
+    if let Some(ref after) = params.after {
+        qb.push(" AND (");
+        for (i, key) in sort.iter().enumerate() {
+            if i > 0 {
+                qb.push(" OR ");
+            }
+            qb.push("(");
+            for (prev, value) in sort.iter().zip(after.iter()).take(i) {
+                qb.push(sort_column(prev.field))
+                    .push(" = ");
+                push_sort_value(&mut qb, prev.field, value);
+                qb.push(" AND ");
+            }
+            let op = match key.descending {
+                true => " < ",
+                false => " > ",
+            };
+            qb.push(sort_column(key.field)).push(op);
+            push_sort_value(
+                &mut qb,
+                key.field,
+                after
+                    .get(i)
+                    .map(String::as_str)
+                    .unwrap_or_default(),
+            );
+            qb.push(")");
+        }
+        qb.push(")");
+    }
+
+    qb.push(" ORDER BY ");
+    for (i, key) in sort.iter().enumerate() {
+        if i > 0 {
+            qb.push(", ");
+        }
+        qb.push(sort_column(key.field));
+        qb.push(match key.descending {
+            true => " DESC",
+            false => " ASC",
+        });
+    }
+
+    qb.push(" LIMIT ")
+        .push_bind(params.limit as u64);
+
+    qb
+}
+
+// Binary collation so the order matches the in-memory (byte-wise) order.
+// Ids are BINARY(16): their byte order is the order of the lowercase hex text.
+fn sort_column(field: SortField) -> &'static str {
+    match field {
+        SortField::Id => "`id`",
+        SortField::Email => "`email` COLLATE utf8mb4_0900_bin",
+        SortField::Name => "`name` COLLATE utf8mb4_0900_bin",
+    }
+}
+
+// Translates the filter AST into parameterized SQL.
+// Must stay in line with the in-memory evaluation (see datastore::conformance):
+// comparisons use a binary, no-pad collation so they are exact and case-sensitive.
+fn push_filter(qb: &mut QueryBuilder<'_, MySql>, filter: &Filter) {
+    match filter {
+        Filter::And(left, right) => {
+            qb.push("(");
+            push_filter(qb, left);
+            qb.push(" AND ");
+            push_filter(qb, right);
+            qb.push(")");
+        },
+        Filter::Or(left, right) => {
+            qb.push("(");
+            push_filter(qb, left);
+            qb.push(" OR ");
+            push_filter(qb, right);
+            qb.push(")");
+        },
+        Filter::Not(inner) => {
+            qb.push("NOT (");
+            push_filter(qb, inner);
+            qb.push(")");
+        },
+        Filter::Compare {
+            field,
+            op,
+            value,
+        } => {
+            match op {
+                FilterOp::Eq => {
+                    push_filter_column(qb, field);
+                    qb.push(" = ");
+                    push_filter_value(qb, field, value);
+                },
+                FilterOp::Ne => {
+                    push_filter_column(qb, field);
+                    qb.push(" <> ");
+                    push_filter_value(qb, field, value);
+                },
+                FilterOp::Prefix => {
+                    push_filter_text_column(qb, field);
+                    qb.push(" LIKE ")
+                        .push_bind(format!("{}%", escape_like(value)));
+                },
+                FilterOp::Suffix => {
+                    push_filter_text_column(qb, field);
+                    qb.push(" LIKE ")
+                        .push_bind(format!("%{}", escape_like(value)));
+                },
+                FilterOp::Contains => {
+                    push_filter_text_column(qb, field);
+                    qb.push(" LIKE ")
+                        .push_bind(format!("%{}%", escape_like(value)));
+                },
+            };
+        },
+        Filter::In {
+            field,
+            values,
+        } => {
+            push_filter_column(qb, field);
+            qb.push(" IN (");
+            let mut list = qb.separated(", ");
+            for value in values.iter() {
+                match field {
+                    FilterField::Id => list.push_bind(id_bytes(value)),
+                    _ => list.push_bind(value.clone()),
+                };
+            }
+            list.push_unseparated(")");
+        },
+    }
+}
+
+// Page tokens carry ids as text
+fn push_sort_value(qb: &mut QueryBuilder<'_, MySql>, field: SortField, value: &str) {
+    match field {
+        SortField::Id => qb.push_bind(id_bytes(value)),
+        _ => qb.push_bind(value.to_string()),
+    };
+}
+
+// Equality and IN compare against the binary id, LIKE needs its text form
+fn push_filter_value(qb: &mut QueryBuilder<'_, MySql>, field: &FilterField, value: &str) {
+    match field {
+        FilterField::Id => qb.push_bind(id_bytes(value)),
+        _ => qb.push_bind(value.to_string()),
+    };
+}
+
+// Unparsable ids can't match anything, the empty value makes sure they don't
+fn id_bytes(value: &str) -> Vec<u8> {
+    match domain::ID::try_from(value) {
+        Ok(id) => id.as_bytes().to_vec(),
+        Err(_) => Vec::new(),
+    }
+}
+
+// Metadata values are unquoted to their text form, missing keys read as ''
+fn push_filter_column(qb: &mut QueryBuilder<'_, MySql>, field: &FilterField) {
+    match field {
+        FilterField::Id => qb.push("`id`"),
+        FilterField::Email => qb.push("`email` COLLATE utf8mb4_0900_bin"),
+        FilterField::EmailDomain => {
+            qb.push("SUBSTRING_INDEX(`email_canonical`, '@', -1) COLLATE utf8mb4_0900_bin")
+        },
+        FilterField::Name => qb.push("`name` COLLATE utf8mb4_0900_bin"),
+        FilterField::Status => qb.push("`status`"),
+        FilterField::Metadata(key) => qb
+            .push("IFNULL(JSON_UNQUOTE(JSON_EXTRACT(`metadata`, ")
+            .push_bind(format!("$.{key}"))
+            .push(")), '') COLLATE utf8mb4_0900_bin"),
+    };
+}
+
+fn push_filter_text_column(qb: &mut QueryBuilder<'_, MySql>, field: &FilterField) {
+    match field {
+        FilterField::Id => {
+            qb.push("BIN_TO_UUID(`id`) COLLATE utf8mb4_0900_bin");
+        },
+        _ => push_filter_column(qb, field),
+    }
+}
+
+// LIKE wildcards in user input must match literally
+fn escape_like(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
 }
 
 impl From<sqlx::Error> for DatastoreError {
@@ -106,19 +1103,399 @@ where
 
 #[derive(sqlx::FromRow)]
 struct UserRow {
-    id: String,
+    id: Vec<u8>,
     email: String,
+    email_canonical: String,
     name: String,
+    created_at: OffsetDateTime,
+    updated_at: OffsetDateTime,
+    deleted_at: Option<OffsetDateTime>,
+    version: u64,
+    status: String,
+    metadata: String,
+    email_verified_at: Option<OffsetDateTime>,
 }
 
 impl TryFrom<UserRow> for domain::User {
     type Error = String;
 
     fn try_from(value: UserRow) -> Result<Self, Self::Error> {
-        let id = domain::ID::try_from(value.id)?;
-        let email = domain::Email::try_from(value.email)?;
-        let name = domain::UserName::try_from(value.name)?;
+        let id = domain::ID::try_from(value.id.as_slice())?;
+        let email = domain::Email::from_stored(value.email, value.email_canonical);
+        // Names stored before validation existed must stay readable
+        let name = domain::UserName::new_unchecked(value.name);
+
+        let mut usr = domain::User::new(id, email, name, value.created_at);
+        usr.set_updated_at(value.updated_at);
+        usr.set_deleted_at(value.deleted_at);
+        usr.set_version(value.version);
+        usr.set_status(domain::UserStatus::try_from(
+            value.status.as_str(),
+        )?);
+        usr.set_metadata(
+            serde_json::from_str(&value.metadata).map_err(|e| format!("invalid metadata: {e}"))?,
+        );
+        usr.set_email_verified_at(value.email_verified_at);
+
+        Ok(usr)
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct AuditRow {
+    id: Vec<u8>,
+    user_id: Vec<u8>,
+    actor: String,
+    trace_id: String,
+    at: OffsetDateTime,
+    operation: String,
+    changes: String,
+}
+
+impl TryFrom<AuditRow> for domain::AuditEntry {
+    type Error = String;
+
+    fn try_from(value: AuditRow) -> Result<Self, Self::Error> {
+        Ok(domain::AuditEntry {
+            id: domain::ID::try_from(value.id.as_slice())?,
+            user_id: domain::ID::try_from(value.user_id.as_slice())?,
+            actor: value.actor,
+            trace_id: value.trace_id,
+            at: value.at,
+            operation: domain::AuditOperation::try_from(value.operation.as_str())?,
+            changes: serde_json::from_str(&value.changes)
+                .map_err(|e| format!("invalid audit changes: {e}"))?,
+        })
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct OutboxRow {
+    seq: u64,
+    tenant_id: String,
+    event_type: String,
+    user_id: Vec<u8>,
+    version: u64,
+    occurred_at: OffsetDateTime,
+    payload: String,
+}
 
-        Ok(domain::User::new(id, email, name))
+impl TryFrom<OutboxRow> for domain::UserEvent {
+    type Error = String;
+
+    fn try_from(value: OutboxRow) -> Result<Self, Self::Error> {
+        Ok(domain::UserEvent {
+            seq: value.seq,
+            tenant_id: domain::TenantId::try_from(value.tenant_id)?,
+            event_type: domain::UserEventType::try_from(value.event_type.as_str())?,
+            user_id: domain::ID::try_from(value.user_id.as_slice())?,
+            version: value.version,
+            occurred_at: value.occurred_at,
+            payload: value.payload,
+        })
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct JobRow {
+    id: Vec<u8>,
+    kind: String,
+    payload: String,
+    state: String,
+    attempts: u32,
+    max_attempts: u32,
+    run_at: OffsetDateTime,
+    last_error: Option<String>,
+    created_at: OffsetDateTime,
+}
+
+impl TryFrom<JobRow> for Job {
+    type Error = String;
+
+    fn try_from(value: JobRow) -> Result<Self, Self::Error> {
+        Ok(Job {
+            id: domain::ID::try_from(value.id.as_slice())?,
+            kind: value.kind,
+            payload: value.payload,
+            state: JobState::try_from(value.state.as_str())?,
+            attempts: value.attempts,
+            max_attempts: value.max_attempts,
+            run_at: value.run_at,
+            last_error: value.last_error,
+            created_at: value.created_at,
+        })
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct IdempotencyRow {
+    key: String,
+    fingerprint: String,
+    response: Option<String>,
+    created_at: OffsetDateTime,
+    expires_at: OffsetDateTime,
+}
+
+impl From<IdempotencyRow> for domain::IdempotencyRecord {
+    fn from(value: IdempotencyRow) -> Self {
+        domain::IdempotencyRecord {
+            key: value.key,
+            fingerprint: value.fingerprint,
+            response: value.response,
+            created_at: value.created_at,
+            expires_at: value.expires_at,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct VerificationTokenRow {
+    user_id: Vec<u8>,
+    id: Vec<u8>,
+    email: String,
+    email_canonical: String,
+    created_at: OffsetDateTime,
+    expires_at: OffsetDateTime,
+}
+
+impl TryFrom<VerificationTokenRow> for domain::VerificationToken {
+    type Error = String;
+
+    fn try_from(value: VerificationTokenRow) -> Result<Self, Self::Error> {
+        Ok(domain::VerificationToken {
+            id: domain::ID::try_from(value.id.as_slice())?,
+            user_id: domain::ID::try_from(value.user_id.as_slice())?,
+            email: value.email,
+            email_canonical: value.email_canonical,
+            created_at: value.created_at,
+            expires_at: value.expires_at,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_like, insert_users_query, list_users_query, SqlDatastore, USER_COLUMNS};
+    use crate::{
+        datastore::{conformance, UserListParams},
+        logic::{
+            domain::{Email, TenantId, User, UserName, ID},
+            dto::{Filter, SortKey},
+        },
+        Config, ConfigDbType,
+    };
+
+    #[test]
+    fn insert_users_query_multi_row() {
+        let users: Vec<User> = ["a@acme.com", "b@acme.com"]
+            .iter()
+            .map(|email| {
+                User::new(
+                    ID::new(),
+                    Email::try_from(*email).unwrap(),
+                    UserName::try_from("Batch".to_string()).unwrap(),
+                    time::OffsetDateTime::now_utc(),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            insert_users_query(&tenant(), &users).sql(),
+            "INSERT INTO `users` \
+             (`tenant_id`, `id`, `email`, `email_canonical`, `name`, `created_at`, `updated_at`, \
+             `version`, `status`, `metadata`, `email_verified_at`) \
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?), (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+        );
+    }
+
+    #[test]
+    fn list_users_query_first_page() {
+        let params = UserListParams {
+            limit: 10,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            list_users_query(&tenant(), &params).sql(),
+            format!(
+                "{USER_COLUMNS} WHERE `tenant_id` = ? AND `deleted_at` IS NULL \
+                 ORDER BY `id` ASC LIMIT ?"
+            )
+        );
+    }
+
+    #[test]
+    fn list_users_query_after_cursor() {
+        let params = UserListParams {
+            include_deleted: true,
+            after: Some(vec![ID::new().to_string()]),
+            limit: 10,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            list_users_query(&tenant(), &params).sql(),
+            format!(
+                "{USER_COLUMNS} WHERE `tenant_id` = ? AND ((`id` > ?)) \
+                 ORDER BY `id` ASC LIMIT ?"
+            )
+        );
+    }
+
+    #[test]
+    fn list_users_query_sorted_after_cursor() {
+        let params = UserListParams {
+            include_deleted: true,
+            sort: SortKey::parse_list("-name").unwrap(),
+            after: Some(vec!["Jeff".to_string(), ID::new().to_string()]),
+            limit: 10,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            list_users_query(&tenant(), &params).sql(),
+            format!(
+                "{USER_COLUMNS} WHERE `tenant_id` = ? AND (\
+                 (`name` COLLATE utf8mb4_0900_bin < ?) OR \
+                 (`name` COLLATE utf8mb4_0900_bin = ? AND `id` > ?)) \
+                 ORDER BY `name` COLLATE utf8mb4_0900_bin DESC, `id` ASC LIMIT ?"
+            )
+        );
+    }
+
+    #[test]
+    fn list_users_query_with_filter() {
+        let filter =
+            Filter::parse(r#"email.domain == "acme.com" and not (name ^= "Jo" or name *= "50%")"#)
+                .unwrap();
+
+        let params = UserListParams {
+            include_deleted: true,
+            limit: 10,
+            filter: Some(filter),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            list_users_query(&tenant(), &params).sql(),
+            format!(
+                "{USER_COLUMNS} WHERE `tenant_id` = ? AND \
+                 (SUBSTRING_INDEX(`email_canonical`, '@', -1) COLLATE utf8mb4_0900_bin = ? AND \
+                 NOT ((`name` COLLATE utf8mb4_0900_bin LIKE ? OR `name` COLLATE utf8mb4_0900_bin LIKE ?))) \
+                 ORDER BY `id` ASC LIMIT ?"
+            )
+        );
+    }
+
+    #[test]
+    fn list_users_query_with_in_filter() {
+        let id1 = ID::new();
+        let id2 = ID::new();
+        let filter = Filter::parse(&format!(r#"id in ("{id1}", "{id2}")"#)).unwrap();
+
+        let params = UserListParams {
+            include_deleted: true,
+            limit: 10,
+            filter: Some(filter),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            list_users_query(&tenant(), &params).sql(),
+            format!(
+                "{USER_COLUMNS} WHERE `tenant_id` = ? AND `id` IN (?, ?) \
+                 ORDER BY `id` ASC LIMIT ?"
+            )
+        );
+    }
+
+    #[test]
+    fn list_users_query_id_text_filter() {
+        let id = ID::new();
+        let filter = Filter::parse(&format!(r#"id == "{id}" or id ^= "{id}""#)).unwrap();
+
+        let params = UserListParams {
+            include_deleted: true,
+            limit: 10,
+            filter: Some(filter),
+            ..Default::default()
+        };
+
+        // The binary column for equality, its text form for LIKE
+        assert_eq!(
+            list_users_query(&tenant(), &params).sql(),
+            format!(
+                "{USER_COLUMNS} WHERE `tenant_id` = ? AND \
+                 (`id` = ? OR BIN_TO_UUID(`id`) COLLATE utf8mb4_0900_bin LIKE ?) \
+                 ORDER BY `id` ASC LIMIT ?"
+            )
+        );
+    }
+
+    #[test]
+    fn list_users_query_with_metadata_filter() {
+        let filter = Filter::parse(r#"metadata.cost_center == "4711""#).unwrap();
+
+        let params = UserListParams {
+            include_deleted: true,
+            limit: 10,
+            filter: Some(filter),
+            ..Default::default()
+        };
+
+        // The key's JSON path is bound, not spliced in
+        assert_eq!(
+            list_users_query(&tenant(), &params).sql(),
+            format!(
+                "{USER_COLUMNS} WHERE `tenant_id` = ? AND \
+                 IFNULL(JSON_UNQUOTE(JSON_EXTRACT(`metadata`, ?)), '') COLLATE utf8mb4_0900_bin = ? \
+                 ORDER BY `id` ASC LIMIT ?"
+            )
+        );
+    }
+
+    fn tenant() -> TenantId {
+        TenantId::try_from("acme").unwrap()
+    }
+
+    #[test]
+    fn escape_like_wildcards() {
+        assert_eq!(escape_like("100%_sure\\"), "100\\%\\_sure\\\\");
+        assert_eq!(escape_like("plain"), "plain");
+    }
+
+    conformance::conformance_tests! {
+        #[ignore = "needs a MySQL instance, see `make create-local-db`"]
+        ds: connect_test_db().await,
+        tag: uuid::Uuid::new_v4().simple().to_string()[..8].to_owned();
+        list_users_filter_conformance => check_filter_cases,
+        list_users_sort_conformance => check_sort_cases,
+        email_conflict_conformance => check_email_conflicts,
+        version_conformance => check_versions,
+        timestamps_conformance => check_timestamps,
+        audit_conformance => check_audit_entries,
+        audit_atomicity_conformance => check_audit_atomicity,
+        outbox_conformance => check_outbox,
+        store_users_conformance => check_store_users,
+        jobs_conformance => check_jobs,
+        idempotency_conformance => check_idempotency_keys,
+        verification_tokens_conformance => check_verification_tokens,
+        tenant_isolation_conformance => check_tenant_isolation,
+        metadata_conformance => check_metadata,
+    }
+
+    async fn connect_test_db() -> SqlDatastore {
+        let config = Config::new_from_file("config.yaml").unwrap();
+        match config.datastore {
+            ConfigDbType::MySql {
+                addr,
+                port,
+                user,
+                password,
+            } => SqlDatastore::new(&addr, port, &user, &password)
+                .await
+                .unwrap(),
+            ConfigDbType::InMem => panic!("config.yaml does not point to MySQL"),
+        }
     }
 }
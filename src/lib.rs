@@ -3,8 +3,11 @@
 
 pub mod datastore;
 pub mod logic;
+pub mod mailer;
+pub mod outbox;
 pub mod server;
 pub mod toolbox;
+pub mod worker;
 
 // Import proto generated files
 pub mod proto {
@@ -18,6 +21,49 @@ pub struct Config {
     pub http_port: u16,
     pub grpc_port: u16,
     pub datastore: ConfigDbType,
+
+    #[serde(default)]
+    pub user_name: logic::domain::UserNamePolicy,
+
+    #[serde(default)]
+    pub email: logic::domain::EmailPolicy,
+
+    #[serde(default)]
+    pub id_format: ConfigIdFormat,
+
+    #[serde(default)]
+    pub outbox: outbox::OutboxConfig,
+
+    #[serde(default)]
+    pub worker: worker::WorkerConfig,
+
+    #[serde(default)]
+    pub idempotency: logic::IdempotencyConfig,
+
+    #[serde(default)]
+    pub verification: logic::verification::VerificationConfig,
+
+    #[serde(default)]
+    pub mailer: mailer::MailerConfig,
+
+    #[serde(default)]
+    pub auth: server::auth::AuthConfig,
+
+    #[serde(default)]
+    pub authz: logic::authz::AuthzConfig,
+
+    #[serde(default)]
+    pub tenancy: server::tenancy::TenancyConfig,
+}
+
+// How new user ids are generated, the wire format is the same for both
+#[derive(serde::Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum ConfigIdFormat {
+    #[default]
+    #[serde(rename = "uuid_v4")]
+    UuidV4,
+    #[serde(rename = "uuid_v7")]
+    UuidV7,
 }
 
 #[derive(serde::Deserialize)]
@@ -54,6 +100,17 @@ impl Config {
             http_port,
             grpc_port,
             datastore,
+            user_name: logic::domain::UserNamePolicy::default(),
+            email: logic::domain::EmailPolicy::default(),
+            id_format: ConfigIdFormat::default(),
+            outbox: outbox::OutboxConfig::default(),
+            worker: worker::WorkerConfig::default(),
+            idempotency: logic::IdempotencyConfig::default(),
+            verification: logic::verification::VerificationConfig::default(),
+            mailer: mailer::MailerConfig::default(),
+            auth: server::auth::AuthConfig::default(),
+            authz: logic::authz::AuthzConfig::default(),
+            tenancy: server::tenancy::TenancyConfig::default(),
         }
     }
 
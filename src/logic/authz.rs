@@ -0,0 +1,230 @@
+// Role-based access to the use cases. Principals carry role names (from a JWT
+// claim or the API key's config), the roles map to permissions here. Without
+// a principal in the Context authentication is off and everything is allowed.
+
+use super::{
+    domain::{Principal, PrincipalKind, ID},
+    error::{LogicError, LogicErrorCode},
+};
+use crate::toolbox::context::Context;
+use std::collections::{HashMap, HashSet};
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Permission {
+    #[serde(rename = "users:create")]
+    CreateUsers,
+    // Any user
+    #[serde(rename = "users:read")]
+    ReadUsers,
+    // Only the user whose id is the principal's subject
+    #[serde(rename = "users:read_self")]
+    ReadSelf,
+    #[serde(rename = "users:list")]
+    ListUsers,
+    // Edit, or replace, any user
+    #[serde(rename = "users:update")]
+    UpdateUsers,
+    // Delete and restore any user
+    #[serde(rename = "users:delete")]
+    DeleteUsers,
+    // Suspend, reactivate, deactivate and verify any user
+    #[serde(rename = "users:manage")]
+    ManageUsers,
+    // Verify the principal's own user, with the token mailed to it
+    #[serde(rename = "users:verify_self")]
+    VerifySelf,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct AuthzConfig {
+    // <role, permissions>, roles not listed here grant nothing
+    pub roles: HashMap<String, HashSet<Permission>>,
+}
+
+impl Default for AuthzConfig {
+    fn default() -> Self {
+        AuthzConfig {
+            roles: HashMap::from([
+                (
+                    "admin".to_string(),
+                    HashSet::from([
+                        Permission::CreateUsers,
+                        Permission::ReadUsers,
+                        Permission::ListUsers,
+                        Permission::UpdateUsers,
+                        Permission::DeleteUsers,
+                        Permission::ManageUsers,
+                    ]),
+                ),
+                (
+                    "user".to_string(),
+                    HashSet::from([Permission::ReadSelf, Permission::VerifySelf]),
+                ),
+            ]),
+        }
+    }
+}
+
+impl AuthzConfig {
+    fn allows(&self, principal: &Principal, permission: Permission) -> bool {
+        principal
+            .roles
+            .iter()
+            .filter_map(|role| self.roles.get(role))
+            .any(|perms| perms.contains(&permission))
+    }
+
+    pub(super) fn require(&self, ctx: &Context, permission: Permission) -> Result<(), LogicError> {
+        match ctx.get_clone::<Principal>("principal") {
+            Some(p) if !self.allows(&p, permission) => Err(forbidden(&p, permission)),
+            _ => Ok(()),
+        }
+    }
+
+    // ReadUsers, or ReadSelf for the principal's own user
+    pub(super) fn require_read(&self, ctx: &Context, id: &ID) -> Result<(), LogicError> {
+        self.require_on(
+            ctx,
+            id,
+            Permission::ReadUsers,
+            Permission::ReadSelf,
+        )
+    }
+
+    // `any` for every user, `own` only for the principal's own one
+    pub(super) fn require_on(
+        &self, ctx: &Context, id: &ID, any: Permission, own: Permission,
+    ) -> Result<(), LogicError> {
+        let p = match ctx.get_clone::<Principal>("principal") {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+
+        let is_self = p.kind == PrincipalKind::User && p.subject == id.to_string();
+        if self.allows(&p, any) || (is_self && self.allows(&p, own)) {
+            return Ok(());
+        }
+
+        Err(forbidden(&p, any))
+    }
+}
+
+fn forbidden(principal: &Principal, permission: Permission) -> LogicError {
+    LogicError::new(LogicErrorCode::Forbidden).with_internal_msg(format!(
+        "{} ({:?}) lacks {:?}",
+        principal, principal.roles, permission
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuthzConfig, Permission};
+    use crate::{
+        logic::{
+            domain::{Principal, PrincipalKind, ID},
+            error::LogicErrorCode,
+        },
+        toolbox::context::Context,
+    };
+
+    fn ctx(kind: PrincipalKind, subject: &str, roles: &[&str]) -> Context {
+        let ctx = Context::new();
+        ctx.store(
+            "principal",
+            Principal {
+                kind,
+                subject: subject.to_string(),
+                roles: roles
+                    .iter()
+                    .map(|r| r.to_string())
+                    .collect(),
+                tenant: None,
+            },
+        );
+        ctx
+    }
+
+    #[test]
+    fn roles_grant_permissions() {
+        let authz = AuthzConfig::default();
+        let admin = ctx(PrincipalKind::ApiKey, "ci", &["admin"]);
+        let user = ctx(PrincipalKind::User, "alice", &["user"]);
+        let nobody = ctx(PrincipalKind::User, "bob", &["unknown"]);
+
+        assert!(authz
+            .require(&admin, Permission::ListUsers)
+            .is_ok());
+        assert!(authz
+            .require(&Context::new(), Permission::ListUsers)
+            .is_ok());
+
+        for ctx in [&user, &nobody] {
+            let err = authz
+                .require(ctx, Permission::ListUsers)
+                .unwrap_err();
+            assert!(matches!(err.code(), LogicErrorCode::Forbidden));
+        }
+    }
+
+    #[test]
+    fn read_self() {
+        let authz = AuthzConfig::default();
+        let id = ID::new();
+        let own = ctx(PrincipalKind::User, &id.to_string(), &["user"]);
+        let other = ctx(
+            PrincipalKind::User,
+            &ID::new().to_string(),
+            &["user"],
+        );
+        // An API key named like the user is still not that user
+        let key = ctx(PrincipalKind::ApiKey, &id.to_string(), &["user"]);
+        let admin = ctx(PrincipalKind::ApiKey, "ci", &["admin"]);
+
+        assert!(authz.require_read(&own, &id).is_ok());
+        assert!(authz.require_read(&admin, &id).is_ok());
+        assert!(authz.require_read(&other, &id).is_err());
+        assert!(authz.require_read(&key, &id).is_err());
+
+        // The own user only gets what the role grants for it
+        assert!(authz
+            .require_on(
+                &own,
+                &id,
+                Permission::ManageUsers,
+                Permission::VerifySelf
+            )
+            .is_ok());
+        assert!(authz
+            .require_on(
+                &other,
+                &id,
+                Permission::ManageUsers,
+                Permission::VerifySelf
+            )
+            .is_err());
+        for permission in [
+            Permission::UpdateUsers,
+            Permission::DeleteUsers,
+            Permission::ManageUsers,
+        ] {
+            assert!(authz.require(&own, permission).is_err());
+            assert!(authz
+                .require(&admin, permission)
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn config() {
+        let config: AuthzConfig =
+            serde_json::from_str(r#"{"roles": {"support": ["users:read", "users:list"]}}"#)
+                .unwrap();
+        assert_eq!(config.roles.len(), 1);
+        assert!(config.roles["support"].contains(&Permission::ReadUsers));
+
+        assert!(
+            serde_json::from_str::<AuthzConfig>(r#"{"roles": {"x": ["users:nuke"]}}"#).is_err()
+        );
+    }
+}
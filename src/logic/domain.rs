@@ -1,14 +1,25 @@
-use super::{error::LogicError, LogicErrorCode};
+use super::{
+    error::{FieldViolation, LogicError},
+    LogicErrorCode,
+};
 use crate::proto;
-use std::{fmt::Display, str::FromStr};
+use std::{collections::BTreeMap, fmt::Display, str::FromStr};
+use time::OffsetDateTime;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
 use uuid::Uuid as uuid_bytes;
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
-pub struct ID(String);
+// 16-byte UUID, rendered as the usual 36-character hyphenated string
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ID(uuid_bytes);
 
 impl ID {
     pub fn new() -> Self {
-        ID(uuid_bytes::new_v4().to_string())
+        ID(uuid_bytes::new_v4())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        self.0.as_bytes()
     }
 }
 
@@ -17,12 +28,23 @@ impl TryFrom<&str> for ID {
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         match uuid_bytes::parse_str(value) {
-            Ok(raw) => Ok(ID(raw.to_string())),
+            Ok(raw) => Ok(ID(raw)),
             Err(_) => Err(format!("invalid id: {value}")),
         }
     }
 }
 
+impl TryFrom<&[u8]> for ID {
+    type Error = String;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        match uuid_bytes::from_slice(value) {
+            Ok(raw) => Ok(ID(raw)),
+            Err(_) => Err(format!("invalid id: {} bytes", value.len())),
+        }
+    }
+}
+
 impl TryFrom<String> for ID {
     type Error = String;
 
@@ -31,6 +53,25 @@ impl TryFrom<String> for ID {
     }
 }
 
+impl From<uuid_bytes> for ID {
+    fn from(value: uuid_bytes) -> Self {
+        ID(value)
+    }
+}
+
+impl serde::Serialize for ID {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&self.0)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ID {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        ID::try_from(value).map_err(serde::de::Error::custom)
+    }
+}
+
 impl Default for ID {
     fn default() -> Self {
         ID::new()
@@ -39,51 +80,158 @@ impl Default for ID {
 
 impl Display for ID {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(self.0.as_str())
+        self.0.hyphenated().fmt(f)
+    }
+}
+
+// A customer of the deployment. Users and everything hanging off them belong
+// to exactly one tenant, datastores never look across tenants.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TenantId(String);
+
+impl TenantId {
+    const MAX_LEN: usize = 64;
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+// ASCII letters, digits, '-' and '_' so it is safe in headers, logs and keys
+impl TryFrom<&str> for TenantId {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let valid = !value.is_empty()
+            && value.len() <= Self::MAX_LEN
+            && value
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+
+        match valid {
+            true => Ok(TenantId(value.to_string())),
+            false => Err(format!("invalid tenant id: {value}")),
+        }
+    }
+}
+
+impl TryFrom<String> for TenantId {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        TenantId::try_from(value.as_str())
+    }
+}
+
+impl serde::Serialize for TenantId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for TenantId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        TenantId::try_from(value).map_err(serde::de::Error::custom)
     }
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+impl Display for TenantId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
 pub struct User {
     id: ID,
     email: Email,
     name: UserName,
+
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+
+    #[serde(with = "time::serde::rfc3339")]
+    updated_at: OffsetDateTime,
+
+    // Tombstone: set when the user is soft-deleted
+    #[serde(
+        default,
+        with = "time::serde::rfc3339::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    deleted_at: Option<OffsetDateTime>,
+
+    // Starts at 1 and grows by one with every write
+    #[serde(default)]
+    version: u64,
+
+    // Users stored before statuses existed are active
+    #[serde(default)]
+    status: UserStatus,
+
+    #[serde(default)]
+    metadata: Metadata,
+
+    // When the current address was verified. Tracked apart from the status,
+    // which suspending or deactivating overwrites. Not part of the
+    // serialization, datastores store it themselves.
+    #[serde(skip)]
+    email_verified_at: Option<OffsetDateTime>,
 }
 
 impl User {
-    pub fn new(id: ID, email: Email, name: UserName) -> Self {
+    // `now` becomes both created_at and updated_at. New users start out
+    // unverified.
+    pub fn new(id: ID, email: Email, name: UserName, now: OffsetDateTime) -> Self {
         User {
             id,
             email,
             name,
+            created_at: now,
+            updated_at: now,
+            deleted_at: None,
+            version: 1,
+            status: UserStatus::PendingVerification,
+            metadata: Metadata::default(),
+            email_verified_at: None,
         }
     }
 
-    pub fn try_new(id: &str, email: &str, name: &str) -> Result<Self, LogicError> {
+    // Validates every field and reports all violations at once
+    pub fn try_new(
+        id: &str, email: &str, name: &str, metadata: &RawMetadata, now: OffsetDateTime,
+        email_policy: &EmailPolicy, name_policy: &UserNamePolicy,
+    ) -> Result<Self, LogicError> {
         let parsed_id = match ID::try_from(id) {
             Ok(v) => v,
             Err(e) => return Err(LogicError::new(LogicErrorCode::InvalidID).with_internal_msg(e)),
         };
 
-        let parsed_email = match Email::try_from(email.to_string()) {
-            Ok(v) => v,
-            Err(e) => {
-                return Err(LogicError::new(LogicErrorCode::UserInvalidData).with_internal_msg(e))
-            },
-        };
+        let parsed_email = Email::parse(email, email_policy);
+        let parsed_name = UserName::parse(name, name_policy);
+        let parsed_metadata = Metadata::parse(metadata);
 
-        let parsed_name = match UserName::try_from(name.to_string()) {
-            Ok(v) => v,
-            Err(e) => {
-                return Err(LogicError::new(LogicErrorCode::UserInvalidData).with_internal_msg(e))
+        match (parsed_email, parsed_name, parsed_metadata) {
+            (Ok(email), Ok(name), Ok(metadata)) => {
+                let mut usr = User::new(parsed_id, email, name, now);
+                usr.set_metadata(metadata);
+                Ok(usr)
             },
-        };
-
-        Ok(User {
-            id: parsed_id,
-            email: parsed_email,
-            name: parsed_name,
-        })
+            (email, name, metadata) => {
+                let mut violations = Vec::new();
+                if let Err(e) = email {
+                    violations.push(e.into_violation("email"));
+                }
+                if let Err(e) = name {
+                    violations.push(e.into_violation("name"));
+                }
+                if let Err(e) = metadata {
+                    violations.push(e.into_violation("metadata"));
+                }
+                Err(LogicError::new(LogicErrorCode::UserInvalidData).with_violations(violations))
+            },
+        }
     }
 
     pub fn id(&self) -> &ID {
@@ -97,6 +245,97 @@ impl User {
     pub fn name(&self) -> &UserName {
         &self.name
     }
+
+    pub fn set_email(&mut self, email: Email) {
+        self.email = email;
+    }
+
+    // `set_email` for edits: a different address has to be verified again, so
+    // an active user goes back to pending verification
+    pub fn change_email(&mut self, email: Email) {
+        if email.canonical() != self.email.canonical() {
+            self.email_verified_at = None;
+            if self.status == UserStatus::Active {
+                self.status = UserStatus::PendingVerification;
+            }
+        }
+        self.email = email;
+    }
+
+    pub fn email_verified_at(&self) -> Option<OffsetDateTime> {
+        self.email_verified_at
+    }
+
+    pub fn is_email_verified(&self) -> bool {
+        self.email_verified_at.is_some()
+    }
+
+    pub fn set_email_verified_at(&mut self, email_verified_at: Option<OffsetDateTime>) {
+        self.email_verified_at = email_verified_at;
+    }
+
+    pub fn set_name(&mut self, name: UserName) {
+        self.name = name;
+    }
+
+    pub fn created_at(&self) -> OffsetDateTime {
+        self.created_at
+    }
+
+    pub fn updated_at(&self) -> OffsetDateTime {
+        self.updated_at
+    }
+
+    pub fn set_updated_at(&mut self, updated_at: OffsetDateTime) {
+        self.updated_at = updated_at;
+    }
+
+    pub fn deleted_at(&self) -> Option<OffsetDateTime> {
+        self.deleted_at
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    pub fn set_deleted_at(&mut self, deleted_at: Option<OffsetDateTime>) {
+        self.deleted_at = deleted_at;
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn set_version(&mut self, version: u64) {
+        self.version = version;
+    }
+
+    pub fn bump_version(&mut self) {
+        self.version += 1;
+    }
+
+    pub fn status(&self) -> UserStatus {
+        self.status
+    }
+
+    // Unchecked, `UserStatus::apply` decides which changes are allowed
+    pub fn set_status(&mut self, status: UserStatus) {
+        self.status = status;
+    }
+
+    // The status after `change`, None if the transition isn't allowed
+    pub fn status_after(&self, change: StatusChange) -> Option<UserStatus> {
+        self.status
+            .apply(change, self.is_email_verified())
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    pub fn set_metadata(&mut self, metadata: Metadata) {
+        self.metadata = metadata;
+    }
 }
 
 impl TryFrom<proto::User> for User {
@@ -106,11 +345,40 @@ impl TryFrom<proto::User> for User {
         let id = ID::try_from(value.id)?;
         let email = Email::try_from(value.email)?;
         let name = UserName::try_from(value.name)?;
+        let created_at = match value.created_at {
+            Some(ts) => timestamp_from_proto(ts)?,
+            None => return Err("missing created_at".to_string()),
+        };
+        let updated_at = match value.updated_at {
+            Some(ts) => timestamp_from_proto(ts)?,
+            None => return Err("missing updated_at".to_string()),
+        };
+        let deleted_at = match value.deleted_at {
+            Some(ts) => Some(timestamp_from_proto(ts)?),
+            None => None,
+        };
+        let status = match value.status.as_str() {
+            "" => UserStatus::default(),
+            v => UserStatus::try_from(v)?,
+        };
+        let metadata = match value.metadata {
+            Some(v) => serde_json::from_value(serde_json::Value::Object(metadata_from_proto(v)))
+                .map_err(|e| format!("invalid metadata: {e}"))?,
+            None => Metadata::default(),
+        };
 
         Ok(User {
             id,
             email,
             name,
+            created_at,
+            updated_at,
+            deleted_at,
+            version: value.version,
+            status,
+            metadata,
+            // Not part of the proto
+            email_verified_at: None,
         })
     }
 }
@@ -118,95 +386,908 @@ impl TryFrom<proto::User> for User {
 impl From<User> for proto::User {
     fn from(val: User) -> Self {
         proto::User {
-            id: val.id.0,
+            id: val.id.to_string(),
             name: val.name.0,
-            email: val.email.0,
+            email: val.email.address,
+            deleted_at: val.deleted_at.map(timestamp_to_proto),
+            version: val.version,
+            created_at: Some(timestamp_to_proto(val.created_at)),
+            updated_at: Some(timestamp_to_proto(val.updated_at)),
+            status: val.status.to_string(),
+            metadata: Some(val.metadata.into()),
         }
     }
 }
 
-impl From<Vec<User>> for proto::UserList {
-    fn from(val: Vec<User>) -> Self {
-        let converted: Vec<proto::User> = val
-            .into_iter()
-            .map(|element| element.into())
-            .collect();
+// Account status, independent of soft deletion
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UserStatus {
+    #[default]
+    Active,
+    PendingVerification,
+    Suspended,
+    Deactivated,
+}
+
+// The ways a status can be changed on purpose
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatusChange {
+    Suspend,
+    Reactivate,
+    Deactivate,
+    // Confirms the email address, see Logic::verify_user
+    Verify,
+}
 
-        proto::UserList {
-            items: converted,
+impl UserStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserStatus::Active => "active",
+            UserStatus::PendingVerification => "pending_verification",
+            UserStatus::Suspended => "suspended",
+            UserStatus::Deactivated => "deactivated",
         }
     }
-}
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
-pub struct Email(String);
+    // The status after `change`, None if the transition isn't allowed.
+    // `email_verified` is whether the user's current address is verified.
+    pub fn apply(self, change: StatusChange, email_verified: bool) -> Option<UserStatus> {
+        let allowed = match change {
+            StatusChange::Suspend => {
+                matches!(
+                    self,
+                    UserStatus::Active | UserStatus::PendingVerification
+                )
+            },
+            StatusChange::Reactivate => {
+                matches!(
+                    self,
+                    UserStatus::Suspended | UserStatus::Deactivated
+                )
+            },
+            StatusChange::Deactivate => matches!(
+                self,
+                UserStatus::Active | UserStatus::PendingVerification | UserStatus::Suspended
+            ),
+            StatusChange::Verify => self == UserStatus::PendingVerification,
+        };
 
-impl Email {}
+        allowed.then(|| change.target(email_verified))
+    }
+}
 
-impl TryFrom<&str> for Email {
+impl StatusChange {
+    // Reactivating can't skip verification: without a verified address the
+    // user is back to pending
+    pub fn target(&self, email_verified: bool) -> UserStatus {
+        match self {
+            StatusChange::Suspend => UserStatus::Suspended,
+            StatusChange::Reactivate if !email_verified => UserStatus::PendingVerification,
+            StatusChange::Reactivate => UserStatus::Active,
+            StatusChange::Deactivate => UserStatus::Deactivated,
+            StatusChange::Verify => UserStatus::Active,
+        }
+    }
+}
+
+impl TryFrom<&str> for UserStatus {
     type Error = String;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "active" => Ok(UserStatus::Active),
+            "pending_verification" => Ok(UserStatus::PendingVerification),
+            "suspended" => Ok(UserStatus::Suspended),
+            "deactivated" => Ok(UserStatus::Deactivated),
+            _ => Err(format!("invalid user status: {value}")),
+        }
+    }
+}
+
+impl Display for UserStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Why a single value was rejected, e.g. rule "format"
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    pub rule: &'static str,
+    pub message: String,
+}
+
+impl ValidationError {
+    pub fn new(rule: &'static str, message: String) -> Self {
+        ValidationError {
+            rule,
+            message,
+        }
+    }
+
+    pub fn into_violation(self, field: &str) -> FieldViolation {
+        FieldViolation::new(field, self.rule, self.message)
+    }
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.message.as_str())
+    }
+}
+
+impl From<ValidationError> for String {
+    fn from(value: ValidationError) -> Self {
+        value.message
+    }
+}
+
+// Serialized as the address only; the canonical form is the uniqueness key
+#[derive(Debug, Clone, PartialEq)]
+pub struct Email {
+    address: String,
+    canonical: String,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct EmailPolicy {
+    // Domains where "user+tag@domain" is the same mailbox as "user@domain"
+    pub strip_plus_domains: Vec<String>,
+}
+
+impl EmailPolicy {
+    fn strips_plus(&self, domain: &str) -> bool {
+        self.strip_plus_domains
+            .iter()
+            .any(|d| d.eq_ignore_ascii_case(domain))
+    }
+}
+
+impl Email {
+    // Keeps the trimmed address for display and derives the canonical form:
+    // lowercase, punycode domain, plus-addressing stripped if the policy says so
+    pub fn parse(value: &str, policy: &EmailPolicy) -> Result<Self, ValidationError> {
         let value = value.trim();
         if let Err(e) = email_address::EmailAddress::from_str(value) {
-            return Err(format!("invalid email: {e}"));
+            return Err(ValidationError::new(
+                "format",
+                format!("invalid email: {e}"),
+            ));
+        }
+
+        // Checked above, the domain can't contain '@'
+        let (local, domain) = value
+            .rsplit_once('@')
+            .unwrap_or((value, ""));
+
+        let domain = match idna::domain_to_ascii(domain) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(ValidationError::new(
+                    "format",
+                    format!("invalid email domain: {e}"),
+                ))
+            },
+        };
+
+        let mut local = local.to_lowercase();
+        if policy.strips_plus(&domain) {
+            if let Some((base, _)) = local.split_once('+') {
+                local = base.to_string();
+            }
         }
-        Ok(Email(value.to_string()))
+
+        Ok(Email {
+            address: value.to_string(),
+            canonical: format!("{local}@{domain}"),
+        })
+    }
+
+    // For values already persisted together with their canonical form
+    pub(crate) fn from_stored(address: String, canonical: String) -> Self {
+        Email {
+            address,
+            canonical,
+        }
+    }
+
+    pub fn canonical(&self) -> &str {
+        self.canonical.as_str()
+    }
+}
+
+impl TryFrom<&str> for Email {
+    type Error = ValidationError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Email::parse(value, &EmailPolicy::default())
     }
 }
 
 impl TryFrom<String> for Email {
-    type Error = String;
+    type Error = ValidationError;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
         Email::try_from(value.as_str())
     }
 }
 
+impl serde::Serialize for Email {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.address.as_str())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Email {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        Email::try_from(value).map_err(serde::de::Error::custom)
+    }
+}
+
 impl Display for Email {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(self.0.as_str())
+        f.write_str(self.address.as_str())
     }
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
 pub struct UserName(String);
 
-impl UserName {}
+// Anything longer than this per allowed grapheme is rejected before normalizing
+const MAX_BYTES_PER_GRAPHEME: usize = 32;
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct UserNamePolicy {
+    pub min_graphemes: usize,
+    pub max_graphemes: usize,
+}
+
+impl Default for UserNamePolicy {
+    fn default() -> Self {
+        UserNamePolicy {
+            min_graphemes: 1,
+            max_graphemes: 100,
+        }
+    }
+}
+
+impl UserName {
+    // NFC-normalizes, trims and collapses whitespace, then checks the policy
+    pub fn parse(value: &str, policy: &UserNamePolicy) -> Result<Self, ValidationError> {
+        if value.len()
+            > policy
+                .max_graphemes
+                .saturating_mul(MAX_BYTES_PER_GRAPHEME)
+        {
+            return Err(ValidationError::new(
+                "too_long",
+                format!(
+                    "name must be at most {} characters",
+                    policy.max_graphemes
+                ),
+            ));
+        }
+
+        let normalized: String = value.nfc().collect();
+        let collapsed = normalized
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        if let Some(c) = collapsed
+            .chars()
+            .find(|c| is_bidi_control(*c))
+        {
+            return Err(ValidationError::new(
+                "bidi_control",
+                format!(
+                    "name must not contain bidi control character U+{:04X}",
+                    c as u32
+                ),
+            ));
+        }
+
+        if let Some(c) = collapsed
+            .chars()
+            .find(|c| c.is_control())
+        {
+            return Err(ValidationError::new(
+                "control_char",
+                format!(
+                    "name must not contain control character U+{:04X}",
+                    c as u32
+                ),
+            ));
+        }
+
+        let length = collapsed.graphemes(true).count();
+        if length < policy.min_graphemes {
+            return Err(ValidationError::new(
+                "too_short",
+                format!(
+                    "name must be at least {} characters",
+                    policy.min_graphemes
+                ),
+            ));
+        }
+        if length > policy.max_graphemes {
+            return Err(ValidationError::new(
+                "too_long",
+                format!(
+                    "name must be at most {} characters",
+                    policy.max_graphemes
+                ),
+            ));
+        }
+
+        Ok(UserName(collapsed))
+    }
+
+    // For values already persisted, which may predate the current rules
+    pub(crate) fn new_unchecked(value: String) -> Self {
+        UserName(value)
+    }
+}
 
 impl TryFrom<String> for UserName {
-    type Error = String;
+    type Error = ValidationError;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
-        // todo
-        Ok(UserName(value))
+        UserName::parse(&value, &UserNamePolicy::default())
     }
 }
 
+// Embeddings, overrides and isolates (LRE..RLO, LRI..PDI)
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
 impl Display for UserName {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(self.0.as_str())
     }
 }
 
+// Metadata as received, checked by `Metadata::parse`
+pub type RawMetadata = serde_json::Map<String, serde_json::Value>;
+
+const METADATA_MAX_KEYS: usize = 32;
+const METADATA_MAX_KEY_LEN: usize = 64;
+const METADATA_MAX_VALUE_LEN: usize = 512;
+// Of the whole map, serialized as JSON
+const METADATA_MAX_BYTES: usize = 8192;
+
+// Custom attributes, e.g. {"department": "sales", "cost_center": 4711}.
+// Keys are snake_case, values strings, numbers or booleans.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct Metadata(BTreeMap<String, MetadataValue>);
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum MetadataValue {
+    String(String),
+    Number(serde_json::Number),
+    Bool(bool),
+}
+
+impl Metadata {
+    pub fn parse(value: &RawMetadata) -> Result<Self, ValidationError> {
+        if value.len() > METADATA_MAX_KEYS {
+            return Err(ValidationError::new(
+                "too_many_keys",
+                format!("metadata must have at most {METADATA_MAX_KEYS} keys"),
+            ));
+        }
+
+        let mut map = BTreeMap::new();
+        for (key, value) in value.iter() {
+            if !is_metadata_key(key) {
+                return Err(ValidationError::new(
+                    "key_format",
+                    format!(
+                        "metadata key '{key}' must be 1 to {METADATA_MAX_KEY_LEN} lowercase \
+                         letters, digits or underscores, starting with a letter"
+                    ),
+                ));
+            }
+
+            let value = match value {
+                serde_json::Value::String(v) if v.chars().count() > METADATA_MAX_VALUE_LEN => {
+                    return Err(ValidationError::new(
+                        "too_long",
+                        format!(
+                            "metadata value of '{key}' must be at most {METADATA_MAX_VALUE_LEN} \
+                             characters"
+                        ),
+                    ))
+                },
+                serde_json::Value::String(v) => MetadataValue::String(v.clone()),
+                serde_json::Value::Number(v) => MetadataValue::Number(v.clone()),
+                serde_json::Value::Bool(v) => MetadataValue::Bool(*v),
+                _ => {
+                    return Err(ValidationError::new(
+                        "value_type",
+                        format!("metadata value of '{key}' must be a string, number or boolean"),
+                    ))
+                },
+            };
+            map.insert(key.clone(), value);
+        }
+
+        let metadata = Metadata(map);
+        if metadata.to_json().len() > METADATA_MAX_BYTES {
+            return Err(ValidationError::new(
+                "too_large",
+                format!("metadata must be at most {METADATA_MAX_BYTES} bytes as JSON"),
+            ));
+        }
+
+        Ok(metadata)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&MetadataValue> {
+        self.0.get(key)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Can't fail, keys are strings and values scalars
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.0).unwrap_or_default()
+    }
+}
+
+// Also what filters may name as `metadata.<key>`
+pub fn is_metadata_key(key: &str) -> bool {
+    key.len() <= METADATA_MAX_KEY_LEN
+        && key.starts_with(|c: char| c.is_ascii_lowercase())
+        && key
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+}
+
+// The text filters compare against: 3, true, or the string itself
+impl Display for MetadataValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetadataValue::String(v) => f.write_str(v),
+            MetadataValue::Number(v) => v.fmt(f),
+            MetadataValue::Bool(v) => v.fmt(f),
+        }
+    }
+}
+
+impl From<Metadata> for prost_types::Struct {
+    fn from(val: Metadata) -> Self {
+        let fields = val
+            .0
+            .into_iter()
+            .map(|(key, value)| {
+                let kind = match value {
+                    MetadataValue::String(v) => prost_types::value::Kind::StringValue(v),
+                    MetadataValue::Number(v) => {
+                        prost_types::value::Kind::NumberValue(v.as_f64().unwrap_or_default())
+                    },
+                    MetadataValue::Bool(v) => prost_types::value::Kind::BoolValue(v),
+                };
+                (
+                    key,
+                    prost_types::Value {
+                        kind: Some(kind),
+                    },
+                )
+            })
+            .collect();
+
+        prost_types::Struct {
+            fields,
+        }
+    }
+}
+
+// Unchecked, nested values are kept for `Metadata::parse` to reject. Whole
+// numbers come back as integers, Struct only knows doubles.
+pub fn metadata_from_proto(value: prost_types::Struct) -> RawMetadata {
+    value
+        .fields
+        .into_iter()
+        .map(|(key, value)| (key, value_from_proto(value)))
+        .collect()
+}
+
+fn value_from_proto(value: prost_types::Value) -> serde_json::Value {
+    use prost_types::value::Kind;
+
+    match value.kind {
+        Some(Kind::StringValue(v)) => serde_json::Value::String(v),
+        Some(Kind::BoolValue(v)) => serde_json::Value::Bool(v),
+        Some(Kind::NumberValue(v)) if v.fract() == 0.0 && v.abs() < 2f64.powi(53) => {
+            serde_json::Value::from(v as i64)
+        },
+        // NaN and infinities become null
+        Some(Kind::NumberValue(v)) => serde_json::Value::from(v),
+        Some(Kind::StructValue(v)) => serde_json::Value::Object(metadata_from_proto(v)),
+        Some(Kind::ListValue(v)) => v
+            .values
+            .into_iter()
+            .map(value_from_proto)
+            .collect(),
+        Some(Kind::NullValue(_)) | None => serde_json::Value::Null,
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOperation {
+    Create,
+    Update,
+    Delete,
+    Restore,
+    Suspend,
+    Reactivate,
+    Deactivate,
+    Verify,
+}
+
+impl AuditOperation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditOperation::Create => "create",
+            AuditOperation::Update => "update",
+            AuditOperation::Delete => "delete",
+            AuditOperation::Restore => "restore",
+            AuditOperation::Suspend => "suspend",
+            AuditOperation::Reactivate => "reactivate",
+            AuditOperation::Deactivate => "deactivate",
+            AuditOperation::Verify => "verify",
+        }
+    }
+}
+
+impl From<StatusChange> for AuditOperation {
+    fn from(value: StatusChange) -> Self {
+        match value {
+            StatusChange::Suspend => AuditOperation::Suspend,
+            StatusChange::Reactivate => AuditOperation::Reactivate,
+            StatusChange::Deactivate => AuditOperation::Deactivate,
+            StatusChange::Verify => AuditOperation::Verify,
+        }
+    }
+}
+
+impl TryFrom<&str> for AuditOperation {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "create" => Ok(AuditOperation::Create),
+            "update" => Ok(AuditOperation::Update),
+            "delete" => Ok(AuditOperation::Delete),
+            "restore" => Ok(AuditOperation::Restore),
+            "suspend" => Ok(AuditOperation::Suspend),
+            "reactivate" => Ok(AuditOperation::Reactivate),
+            "deactivate" => Ok(AuditOperation::Deactivate),
+            "verify" => Ok(AuditOperation::Verify),
+            _ => Err(format!("invalid audit operation: {value}")),
+        }
+    }
+}
+
+impl Display for AuditOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+// One mutation of a user, as recorded in its history
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub id: ID,
+    pub user_id: ID,
+    pub actor: String,
+    pub trace_id: String,
+
+    #[serde(with = "time::serde::rfc3339")]
+    pub at: OffsetDateTime,
+
+    pub operation: AuditOperation,
+    pub changes: Vec<FieldChange>,
+}
+
+impl AuditEntry {
+    // Fields whose value differs between the two sides, None standing for "no user"
+    pub fn diff(before: Option<&User>, after: Option<&User>) -> Vec<FieldChange> {
+        let before = audited_fields(before);
+        let after = audited_fields(after);
+
+        before
+            .into_iter()
+            .zip(after)
+            .filter(|((_, old), (_, new))| old != new)
+            .map(|((field, before), (_, after))| FieldChange {
+                field: field.to_string(),
+                before,
+                after,
+            })
+            .collect()
+    }
+}
+
+// Versions and updated_at change with every write and are left out of diffs
+fn audited_fields(usr: Option<&User>) -> [(&'static str, Option<String>); 5] {
+    [
+        ("email", usr.map(|u| u.email.to_string())),
+        ("name", usr.map(|u| u.name.to_string())),
+        ("status", usr.map(|u| u.status.to_string())),
+        (
+            "deleted_at",
+            usr.and_then(|u| u.deleted_at).map(|v| {
+                v.format(&time::format_description::well_known::Rfc3339)
+                    .unwrap_or_default()
+            }),
+        ),
+        (
+            "metadata",
+            usr.filter(|u| !u.metadata.is_empty())
+                .map(|u| u.metadata.to_json()),
+        ),
+    ]
+}
+
+impl From<AuditEntry> for proto::AuditEntry {
+    fn from(val: AuditEntry) -> Self {
+        proto::AuditEntry {
+            id: val.id.to_string(),
+            user_id: val.user_id.to_string(),
+            actor: val.actor,
+            trace_id: val.trace_id,
+            at: Some(timestamp_to_proto(val.at)),
+            operation: val.operation.to_string(),
+            changes: val
+                .changes
+                .into_iter()
+                .map(|c| proto::FieldChange {
+                    field: c.field,
+                    before: c.before,
+                    after: c.after,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum UserEventType {
+    UserCreated,
+    UserUpdated,
+    UserDeleted,
+    UserRestored,
+}
+
+impl UserEventType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserEventType::UserCreated => "UserCreated",
+            UserEventType::UserUpdated => "UserUpdated",
+            UserEventType::UserDeleted => "UserDeleted",
+            UserEventType::UserRestored => "UserRestored",
+        }
+    }
+}
+
+impl TryFrom<&str> for UserEventType {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "UserCreated" => Ok(UserEventType::UserCreated),
+            "UserUpdated" => Ok(UserEventType::UserUpdated),
+            "UserDeleted" => Ok(UserEventType::UserDeleted),
+            "UserRestored" => Ok(UserEventType::UserRestored),
+            _ => Err(format!("invalid event type: {value}")),
+        }
+    }
+}
+
+impl Display for UserEventType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// A user write as seen by other services. `payload` is the user (JSON) right
+// after the write; (user_id, version) identifies the event for deduplication.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct UserEvent {
+    // Assigned by the outbox, 0 until stored
+    pub seq: u64,
+    pub tenant_id: TenantId,
+    pub event_type: UserEventType,
+    pub user_id: ID,
+    pub version: u64,
+
+    #[serde(with = "time::serde::rfc3339")]
+    pub occurred_at: OffsetDateTime,
+
+    pub payload: String,
+}
+
+impl UserEvent {
+    pub fn new(
+        tenant_id: &TenantId, event_type: UserEventType, usr: &User,
+    ) -> Result<Self, String> {
+        let payload =
+            serde_json::to_string(usr).map_err(|e| format!("invalid event payload: {e}"))?;
+
+        Ok(UserEvent {
+            seq: 0,
+            tenant_id: tenant_id.clone(),
+            event_type,
+            user_id: usr.id,
+            version: usr.version,
+            occurred_at: usr.updated_at,
+            payload,
+        })
+    }
+}
+
+// A create made under an idempotency key. `response` (the created user, JSON)
+// stays None while the first request is in flight.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct IdempotencyRecord {
+    pub key: String,
+    // Hash of the operation and its request
+    pub fingerprint: String,
+    pub response: Option<String>,
+
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+
+    #[serde(with = "time::serde::rfc3339")]
+    pub expires_at: OffsetDateTime,
+}
+
+// Who a request is made by. The transports authenticate requests and put
+// this in the Context as "principal".
+#[derive(Debug, Clone, PartialEq)]
+pub struct Principal {
+    pub kind: PrincipalKind,
+    // The JWT's `sub` claim, or the name the API key is configured under
+    pub subject: String,
+    // The JWT's `roles` claim, or the roles the API key is configured with
+    pub roles: Vec<String>,
+    // The JWT's `tenant` claim, or the API key's tenant. None if the
+    // credentials aren't bound to a tenant.
+    pub tenant: Option<TenantId>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PrincipalKind {
+    User,
+    ApiKey,
+}
+
+impl Display for Principal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            PrincipalKind::User => write!(f, "user:{}", self.subject),
+            PrincipalKind::ApiKey => write!(f, "api_key:{}", self.subject),
+        }
+    }
+}
+
+// The outstanding email verification of a user, at most one per user. Only
+// the token id is stored, the token itself is signed (see logic::verification).
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct VerificationToken {
+    pub id: ID,
+    pub user_id: ID,
+    // The address the token was sent to, and its canonical form, which
+    // must still be the user's when the token is used
+    pub email: String,
+    pub email_canonical: String,
+
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+
+    #[serde(with = "time::serde::rfc3339")]
+    pub expires_at: OffsetDateTime,
+}
+
+fn timestamp_to_proto(value: OffsetDateTime) -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: value.unix_timestamp(),
+        nanos: value.nanosecond() as i32,
+    }
+}
+
+fn timestamp_from_proto(value: prost_types::Timestamp) -> Result<OffsetDateTime, String> {
+    let nanos = value.seconds as i128 * 1_000_000_000 + value.nanos as i128;
+    OffsetDateTime::from_unix_timestamp_nanos(nanos).map_err(|e| format!("invalid timestamp: {e}"))
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Email;
+    use super::{
+        metadata_from_proto, AuditEntry, Email, EmailPolicy, Metadata, RawMetadata, StatusChange,
+        TenantId, User, UserName, UserNamePolicy, UserStatus, ID,
+    };
+    use crate::logic::error::LogicErrorCode;
+
+    #[test]
+    fn id_text_round_trip() {
+        let v = "094c6c65-fa4c-4324-bb7e-c0dda9595e54";
+        let id = ID::try_from(v).unwrap();
+        assert_eq!(v, id.to_string());
+
+        // Other spellings are accepted but always rendered the same way
+        let id = ID::try_from("094C6C65FA4C4324BB7EC0DDA9595E54").unwrap();
+        assert_eq!(v, id.to_string());
+        assert_eq!(
+            serde_json::to_value(id).unwrap(),
+            serde_json::json!(v)
+        );
+    }
+
+    #[test]
+    fn tenant_id() {
+        for v in ["acme", "ACME-corp_2", &"a".repeat(64)] {
+            assert_eq!(TenantId::try_from(v).unwrap().as_str(), v);
+        }
+        for v in ["", "acme corp", "acme/1", "ünicode", &"a".repeat(65)] {
+            assert!(TenantId::try_from(v).is_err(), "{v}");
+        }
+        assert!(serde_json::from_str::<TenantId>(r#""a.b""#).is_err());
+    }
+
+    #[test]
+    fn id_bytes_round_trip() {
+        let id = ID::try_from("094c6c65-fa4c-4324-bb7e-c0dda9595e54").unwrap();
+        assert_eq!(id.as_bytes()[0], 0x09);
+        assert_eq!(ID::try_from(&id.as_bytes()[..]).unwrap(), id);
+        assert!(ID::try_from(&id.as_bytes()[..15]).is_err());
+    }
+
+    #[test]
+    fn id_invalid() {
+        assert_eq!(
+            ID::try_from("123").unwrap_err(),
+            "invalid id: 123"
+        );
+        assert!(serde_json::from_value::<ID>(serde_json::json!("123")).is_err());
+    }
 
     #[test]
     fn email_valid() {
         let v = "test@foo.com";
         let res = Email::try_from(v);
         assert!(res.is_ok());
-        assert_eq!(v, res.unwrap().0);
+        assert_eq!(v, res.unwrap().address);
     }
 
     #[test]
     fn email_invalid() {
         let res = Email::try_from("not_an_email.com");
         assert!(res.is_err());
-        assert!(res
-            .unwrap_err()
+
+        let err = res.unwrap_err();
+        assert_eq!(err.rule, "format");
+        assert!(err
+            .message
             .contains("invalid email: Missing separator character"));
     }
 
@@ -214,6 +1295,386 @@ mod tests {
     fn email_valid_trim() {
         let res = Email::try_from("           test@foo.com                     ");
         assert!(res.is_ok());
-        assert_eq!("test@foo.com", res.unwrap().0);
+        assert_eq!("test@foo.com", res.unwrap().address);
+    }
+
+    #[test]
+    fn email_canonical_case() {
+        let res = Email::try_from("Foo.Bar@Example.COM").unwrap();
+        assert_eq!("Foo.Bar@Example.COM", res.to_string());
+        assert_eq!("foo.bar@example.com", res.canonical());
+    }
+
+    #[test]
+    fn email_canonical_idn() {
+        let res = Email::try_from("jeff@Bücher.example").unwrap();
+        assert_eq!("jeff@Bücher.example", res.to_string());
+        assert_eq!("jeff@xn--bcher-kva.example", res.canonical());
+    }
+
+    #[test]
+    fn email_canonical_plus_addressing() {
+        let policy = EmailPolicy {
+            strip_plus_domains: vec!["gmail.com".to_string()],
+        };
+
+        let res = Email::parse("Jeff+news@GMail.com", &policy).unwrap();
+        assert_eq!("jeff@gmail.com", res.canonical());
+
+        // Other domains keep the tag
+        let res = Email::parse("jeff+news@foo.com", &policy).unwrap();
+        assert_eq!("jeff+news@foo.com", res.canonical());
+    }
+
+    #[test]
+    fn email_serializes_as_address() {
+        let res = Email::try_from("Jeff@Foo.com").unwrap();
+        assert_eq!(
+            serde_json::to_value(&res).unwrap(),
+            serde_json::json!("Jeff@Foo.com")
+        );
+    }
+
+    #[test]
+    fn user_try_new_collects_violations() {
+        let id = "094c6c65-fa4c-4324-bb7e-c0dda9595e54";
+        let metadata = serde_json::json!({"Dept": "sales"});
+        let err = User::try_new(
+            id,
+            "not_an_email.com",
+            "",
+            metadata.as_object().unwrap(),
+            time::OffsetDateTime::now_utc(),
+            &EmailPolicy::default(),
+            &UserNamePolicy::default(),
+        )
+        .unwrap_err();
+
+        assert!(matches!(
+            err.code(),
+            LogicErrorCode::UserInvalidData
+        ));
+        assert_eq!(err.violations().len(), 3);
+        assert_eq!(err.violations()[0].field, "email");
+        assert_eq!(err.violations()[0].rule, "format");
+        assert_eq!(err.violations()[1].field, "name");
+        assert_eq!(err.violations()[1].rule, "too_short");
+        assert_eq!(err.violations()[2].field, "metadata");
+        assert_eq!(err.violations()[2].rule, "key_format");
+    }
+
+    #[test]
+    fn user_try_new_invalid_id() {
+        let err = User::try_new(
+            "123",
+            "test@foo.com",
+            "Jeff",
+            &RawMetadata::new(),
+            time::OffsetDateTime::now_utc(),
+            &EmailPolicy::default(),
+            &UserNamePolicy::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err.code(), LogicErrorCode::InvalidID));
+        assert!(err.violations().is_empty());
+    }
+
+    #[test]
+    fn metadata_parse() {
+        let parse = |v: serde_json::Value| Metadata::parse(v.as_object().unwrap());
+
+        let metadata =
+            parse(serde_json::json!({"department": "sales", "cost_center": 4711, "remote": true}))
+                .unwrap();
+        assert_eq!(
+            metadata.to_json(),
+            r#"{"cost_center":4711,"department":"sales","remote":true}"#
+        );
+        assert_eq!(
+            metadata
+                .get("cost_center")
+                .unwrap()
+                .to_string(),
+            "4711"
+        );
+
+        let many: RawMetadata = (0..33)
+            .map(|i| (format!("k{i}"), serde_json::json!(i)))
+            .collect();
+        let cases = [
+            (serde_json::json!({"": 1}), "key_format"),
+            (serde_json::json!({"1st": 1}), "key_format"),
+            (
+                serde_json::json!({"cost-center": 1}),
+                "key_format",
+            ),
+            (
+                serde_json::json!({"k".repeat(65): 1}),
+                "key_format",
+            ),
+            (serde_json::json!({"tags": ["a"]}), "value_type"),
+            (
+                serde_json::json!({"manager": {"id": 1}}),
+                "value_type",
+            ),
+            (serde_json::json!({"note": null}), "value_type"),
+            (
+                serde_json::json!({"note": "x".repeat(513)}),
+                "too_long",
+            ),
+            (serde_json::Value::Object(many), "too_many_keys"),
+        ];
+        for (value, rule) in cases {
+            assert_eq!(
+                parse(value.clone()).unwrap_err().rule,
+                rule,
+                "{value}"
+            );
+        }
+
+        let large: RawMetadata = (0..20)
+            .map(|i| {
+                (
+                    format!("k{i}"),
+                    serde_json::json!("x".repeat(500)),
+                )
+            })
+            .collect();
+        assert_eq!(
+            Metadata::parse(&large)
+                .unwrap_err()
+                .rule,
+            "too_large"
+        );
+    }
+
+    #[test]
+    fn metadata_proto_round_trip() {
+        let metadata = Metadata::parse(
+            serde_json::json!({"department": "sales", "level": 3, "ratio": 0.5, "remote": false})
+                .as_object()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let raw = metadata_from_proto(metadata.clone().into());
+        assert_eq!(Metadata::parse(&raw).unwrap(), metadata);
+
+        // Values Struct can hold but metadata can't
+        let nested = prost_types::Struct {
+            fields: [(
+                "tags".to_string(),
+                prost_types::Value {
+                    kind: Some(prost_types::value::Kind::ListValue(
+                        prost_types::ListValue {
+                            values: vec![],
+                        },
+                    )),
+                },
+            )]
+            .into(),
+        };
+        assert_eq!(
+            Metadata::parse(&metadata_from_proto(nested))
+                .unwrap_err()
+                .rule,
+            "value_type"
+        );
+    }
+
+    #[test]
+    fn user_name_trim_and_collapse() {
+        let res = UserName::try_from("  Jeff \t\n  Jefferson  ".to_string());
+        assert_eq!("Jeff Jefferson", res.unwrap().0);
+    }
+
+    #[test]
+    fn user_name_nfc() {
+        // "e" + combining acute accent becomes a single "é"
+        let res = UserName::try_from("Ame\u{301}lie".to_string());
+        assert_eq!("Am\u{e9}lie", res.unwrap().0);
+    }
+
+    #[test]
+    fn user_name_empty() {
+        let err = UserName::try_from("   ".to_string()).unwrap_err();
+        assert_eq!(err.rule, "too_short");
+    }
+
+    #[test]
+    fn user_name_control_char() {
+        let err = UserName::try_from("Jeff\u{0}".to_string()).unwrap_err();
+        assert_eq!(err.rule, "control_char");
+        assert!(err.message.contains("U+0000"));
+    }
+
+    #[test]
+    fn user_name_bidi_override() {
+        let err = UserName::try_from("Jeff\u{202E}nosreffeJ".to_string()).unwrap_err();
+        assert_eq!(err.rule, "bidi_control");
+    }
+
+    #[test]
+    fn user_name_counts_graphemes() {
+        let policy = UserNamePolicy {
+            min_graphemes: 2,
+            max_graphemes: 3,
+        };
+
+        // Flags and accented letters count as one grapheme each
+        assert!(UserName::parse("\u{1F1EC}\u{1F1E7}e\u{301}o", &policy).is_ok());
+        assert_eq!(
+            UserName::parse("abcd", &policy)
+                .unwrap_err()
+                .rule,
+            "too_long"
+        );
+        assert_eq!(
+            UserName::parse("a", &policy)
+                .unwrap_err()
+                .rule,
+            "too_short"
+        );
+    }
+
+    #[test]
+    fn user_name_huge_input() {
+        let err = UserName::try_from("a".repeat(1024 * 1024)).unwrap_err();
+        assert_eq!(err.rule, "too_long");
+    }
+
+    #[test]
+    fn audit_diff() {
+        let now = time::OffsetDateTime::UNIX_EPOCH;
+        let before = User::new(
+            ID::new(),
+            Email::try_from("jo@example.com").unwrap(),
+            UserName::try_from("Jo".to_string()).unwrap(),
+            now,
+        );
+
+        let created = AuditEntry::diff(None, Some(&before));
+        let fields: Vec<_> = created
+            .iter()
+            .map(|c| c.field.as_str())
+            .collect();
+        assert_eq!(fields, ["email", "name", "status"]);
+        assert_eq!(created[0].before, None);
+        assert_eq!(
+            created[0].after.as_deref(),
+            Some("jo@example.com")
+        );
+
+        let mut after = before.clone();
+        after.set_name(UserName::try_from("Joe".to_string()).unwrap());
+        after.set_deleted_at(Some(now));
+        after.bump_version();
+
+        let changes = AuditEntry::diff(Some(&before), Some(&after));
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].field, "name");
+        assert_eq!(changes[0].before.as_deref(), Some("Jo"));
+        assert_eq!(changes[0].after.as_deref(), Some("Joe"));
+        assert_eq!(changes[1].field, "deleted_at");
+        assert_eq!(
+            changes[1].after.as_deref(),
+            Some("1970-01-01T00:00:00Z")
+        );
+
+        assert!(AuditEntry::diff(Some(&before), Some(&before)).is_empty());
+    }
+
+    #[test]
+    fn status_transitions() {
+        use StatusChange::*;
+        use UserStatus::*;
+
+        // (from, change, whether the address is verified, expected)
+        let cases = [
+            (Active, Suspend, true, Some(Suspended)),
+            (Active, Reactivate, true, None),
+            (Active, Deactivate, true, Some(Deactivated)),
+            (Active, Verify, true, None),
+            (
+                PendingVerification,
+                Suspend,
+                false,
+                Some(Suspended),
+            ),
+            (PendingVerification, Reactivate, false, None),
+            (
+                PendingVerification,
+                Deactivate,
+                false,
+                Some(Deactivated),
+            ),
+            (PendingVerification, Verify, false, Some(Active)),
+            (Suspended, Suspend, true, None),
+            (Suspended, Reactivate, true, Some(Active)),
+            (
+                Suspended,
+                Reactivate,
+                false,
+                Some(PendingVerification),
+            ),
+            (Suspended, Deactivate, true, Some(Deactivated)),
+            (Suspended, Verify, false, None),
+            (Deactivated, Suspend, true, None),
+            (Deactivated, Reactivate, true, Some(Active)),
+            (
+                Deactivated,
+                Reactivate,
+                false,
+                Some(PendingVerification),
+            ),
+            (Deactivated, Deactivate, true, None),
+            (Deactivated, Verify, false, None),
+        ];
+
+        for (from, change, verified, expected) in cases {
+            assert_eq!(
+                from.apply(change, verified),
+                expected,
+                "{from} + {change:?} (verified: {verified})"
+            );
+        }
+
+        let now = time::OffsetDateTime::UNIX_EPOCH;
+        let mut usr = User::new(
+            ID::new(),
+            Email::try_from("jo@acme.com").unwrap(),
+            UserName::try_from("Jo".to_string()).unwrap(),
+            now,
+        );
+
+        // Suspending an unverified user and reactivating it skips nothing
+        usr.set_status(usr.status_after(Suspend).unwrap());
+        assert_eq!(
+            usr.status_after(Reactivate),
+            Some(PendingVerification)
+        );
+
+        // A new address while suspended has to be verified again
+        usr.set_email_verified_at(Some(now));
+        assert_eq!(usr.status_after(Reactivate), Some(Active));
+        usr.change_email(Email::try_from("JO@acme.com").unwrap());
+        assert_eq!(usr.status_after(Reactivate), Some(Active));
+        usr.change_email(Email::try_from("joe@acme.com").unwrap());
+        assert_eq!(usr.status(), Suspended);
+        assert_eq!(
+            usr.status_after(Reactivate),
+            Some(PendingVerification)
+        );
+
+        // Active users go back to pending right away
+        usr.set_status(Active);
+        usr.set_email_verified_at(Some(now));
+        usr.change_email(Email::try_from("jo@acme.com").unwrap());
+        assert_eq!(usr.status(), PendingVerification);
+        assert!(!usr.is_email_verified());
+
+        for status in [Active, PendingVerification, Suspended, Deactivated] {
+            assert_eq!(UserStatus::try_from(status.as_str()), Ok(status));
+        }
     }
 }
@@ -1,9 +1,1013 @@
+use super::{domain, error::LogicError};
+use crate::proto;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
 #[derive(serde::Deserialize, Debug)]
 pub struct CreateUserRequest {
     pub email: String,
     pub name: String,
+    #[serde(default)]
+    pub metadata: domain::RawMetadata,
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+pub struct BatchCreateUsersRequest {
+    pub items: Vec<CreateUserRequest>,
+    #[serde(default)]
+    pub mode: BatchMode,
+}
+
+#[derive(serde::Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchMode {
+    // Either every item is created or none is
+    #[default]
+    AllOrNothing,
+    // Valid items are created regardless of the others
+    BestEffort,
+}
+
+// One result per request item, in the same order
+#[derive(serde::Serialize, Debug)]
+pub struct BatchCreateUsersResponse {
+    pub items: Vec<BatchCreateUserResult>,
+}
+
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchCreateUserResult {
+    User(domain::User),
+    Error(LogicError),
+}
+
+impl From<BatchCreateUsersResponse> for proto::BatchCreateUsersResponse {
+    fn from(val: BatchCreateUsersResponse) -> Self {
+        let items = val
+            .items
+            .into_iter()
+            .map(|item| proto::BatchCreateUserResult {
+                result: Some(match item {
+                    BatchCreateUserResult::User(usr) => {
+                        proto::batch_create_user_result::Result::User(usr.into())
+                    },
+                    BatchCreateUserResult::Error(err) => {
+                        proto::batch_create_user_result::Result::Error(proto::ItemError {
+                            code: err.code().into(),
+                            violations: err
+                                .violations()
+                                .iter()
+                                .map(|v| proto::FieldViolation {
+                                    field: v.field.clone(),
+                                    rule: v.rule.clone(),
+                                    message: v.message.clone(),
+                                })
+                                .collect(),
+                        })
+                    },
+                }),
+            })
+            .collect();
+
+        proto::BatchCreateUsersResponse {
+            items,
+        }
+    }
+}
+
+// Outcome of an import, rejected rows are in line order
+#[derive(serde::Serialize, Debug, Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub rejected: Vec<RejectedRow>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct RejectedRow {
+    // 1-based line of the input the row starts on
+    pub line: u64,
+    pub error: LogicError,
+}
+
+// Partial update (PATCH): only the provided fields are changed
+#[derive(serde::Deserialize, Debug, Default)]
+pub struct UpdateUserRequest {
+    pub email: Option<String>,
+    pub name: Option<String>,
+    // Replaces all of the user's metadata
+    pub metadata: Option<domain::RawMetadata>,
 }
 
+// Full replacement (PUT): every field is required, except metadata, which
+// is cleared if left out
+#[derive(serde::Deserialize, Debug)]
+pub struct ReplaceUserRequest {
+    pub email: String,
+    pub name: String,
+    #[serde(default)]
+    pub metadata: domain::RawMetadata,
+}
+
+impl From<ReplaceUserRequest> for UpdateUserRequest {
+    fn from(value: ReplaceUserRequest) -> Self {
+        UpdateUserRequest {
+            email: Some(value.email),
+            name: Some(value.name),
+            metadata: Some(value.metadata),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct VerifyUserRequest {
+    pub token: String,
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+pub struct GetUserOptions {
+    #[serde(default)]
+    pub include_deleted: bool,
+}
+
+#[derive(serde::Deserialize, Debug, Default, Clone)]
 pub struct Query {
-    // todo
+    #[serde(default)]
+    pub include_deleted: bool,
+    pub page_size: Option<u32>,
+    pub page_token: Option<String>,
+    pub filter: Option<String>,
+    // e.g. "name,-email" (leading '-' for descending)
+    pub sort: Option<String>,
+}
+
+// Response projection, e.g. "?fields=id,email".
+// Applied by the transports, `Logic` always returns whole users.
+#[derive(serde::Deserialize, Debug, Default)]
+pub struct FieldsQuery {
+    pub fields: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct UserList {
+    pub items: Vec<domain::User>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_page_token: Option<String>,
+}
+
+impl From<UserList> for proto::UserList {
+    fn from(val: UserList) -> Self {
+        let converted: Vec<proto::User> = val
+            .items
+            .into_iter()
+            .map(|element| element.into())
+            .collect();
+
+        proto::UserList {
+            items: converted,
+            next_page_token: val.next_page_token.unwrap_or_default(),
+        }
+    }
+}
+
+// Oldest entry first
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct UserHistory {
+    pub items: Vec<domain::AuditEntry>,
+}
+
+impl From<UserHistory> for proto::UserHistory {
+    fn from(val: UserHistory) -> Self {
+        proto::UserHistory {
+            items: val
+                .items
+                .into_iter()
+                .map(|element| element.into())
+                .collect(),
+        }
+    }
+}
+
+// Opaque cursor handed out as `next_page_token`.
+// Holds the sort key values of the last returned row (keyset pagination)
+// and the sort order it was issued for.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub struct PageToken {
+    pub sort: String,
+    pub after: Vec<String>,
+}
+
+impl PageToken {
+    pub fn encode(&self) -> String {
+        let js = serde_json::to_vec(self).unwrap_or_default();
+        URL_SAFE_NO_PAD.encode(js)
+    }
+
+    pub fn decode(value: &str) -> Result<Self, String> {
+        let js = URL_SAFE_NO_PAD
+            .decode(value)
+            .map_err(|e| format!("invalid page token: {e}"))?;
+        serde_json::from_slice(&js).map_err(|e| format!("invalid page token: {e}"))
+    }
+}
+
+// -----------------------
+// SORT ------------------
+// -----------------------
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortField {
+    Id,
+    Email,
+    Name,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SortKey {
+    pub field: SortField,
+    pub descending: bool,
+}
+
+impl SortField {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "id" => Ok(SortField::Id),
+            "email" => Ok(SortField::Email),
+            "name" => Ok(SortField::Name),
+            _ => Err(format!("invalid sort: unknown field '{value}'")),
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            SortField::Id => "id",
+            SortField::Email => "email",
+            SortField::Name => "name",
+        }
+    }
+
+    // Value used for ordering and stored in page tokens
+    pub fn value_of(&self, usr: &domain::User) -> String {
+        match self {
+            SortField::Id => usr.id().to_string(),
+            SortField::Email => usr.email().to_string(),
+            SortField::Name => usr.name().to_string(),
+        }
+    }
+}
+
+impl SortKey {
+    pub fn id() -> Self {
+        SortKey {
+            field: SortField::Id,
+            descending: false,
+        }
+    }
+
+    // "name,-email" => [name ASC, email DESC, id ASC].
+    // The id is always appended as a tie-breaker so the order is total.
+    pub fn parse_list(value: &str) -> Result<Vec<Self>, String> {
+        let mut keys: Vec<SortKey> = Vec::new();
+
+        for part in value.split(',').map(str::trim) {
+            if part.is_empty() {
+                continue;
+            }
+
+            let (descending, name) = match part.strip_prefix('-') {
+                Some(rest) => (true, rest),
+                None => (false, part),
+            };
+
+            let field = SortField::parse(name)?;
+            if keys.iter().any(|k| k.field == field) {
+                return Err(format!("invalid sort: duplicate field '{name}'"));
+            }
+
+            keys.push(SortKey {
+                field,
+                descending,
+            });
+        }
+
+        if !keys
+            .iter()
+            .any(|k| k.field == SortField::Id)
+        {
+            keys.push(SortKey::id());
+        }
+
+        Ok(keys)
+    }
+
+    // Normalized form, e.g. "name,-email,id"
+    pub fn list_to_string(keys: &[SortKey]) -> String {
+        keys.iter()
+            .map(|k| match k.descending {
+                true => format!("-{}", k.field.as_str()),
+                false => k.field.as_str().to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+// -----------------------
+// FIELD MASK ------------
+// -----------------------
+
+pub const USER_FIELDS: &[&str] = &[
+    "id",
+    "email",
+    "name",
+    "created_at",
+    "updated_at",
+    "deleted_at",
+    "version",
+    "status",
+    "metadata",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldMask {
+    fields: Vec<String>,
+}
+
+impl FieldMask {
+    // "id,email" over HTTP, FieldMask.paths over gRPC
+    pub fn parse<S: AsRef<str>>(paths: impl IntoIterator<Item = S>) -> Result<Self, String> {
+        let mut fields: Vec<String> = Vec::new();
+
+        for path in paths {
+            let path = path.as_ref().trim();
+            if path.is_empty() {
+                continue;
+            }
+            if !USER_FIELDS.contains(&path) {
+                return Err(format!("invalid fields: unknown field '{path}'"));
+            }
+            if !fields.iter().any(|f| f == path) {
+                fields.push(path.to_string());
+            }
+        }
+
+        if fields.is_empty() {
+            return Err("invalid fields: no field selected".to_string());
+        }
+
+        Ok(FieldMask {
+            fields,
+        })
+    }
+
+    pub fn contains(&self, field: &str) -> bool {
+        self.fields.iter().any(|f| f == field)
+    }
+
+    // Keeps only the selected keys of a serialized user
+    pub fn apply_json(&self, value: serde_json::Value) -> serde_json::Value {
+        match value {
+            serde_json::Value::Object(mut map) => {
+                map.retain(|k, _| self.contains(k));
+                serde_json::Value::Object(map)
+            },
+            other => other,
+        }
+    }
+
+    // Resets the fields that weren't selected to their proto defaults
+    pub fn apply_proto(&self, value: proto::User) -> proto::User {
+        let default = proto::User::default();
+        proto::User {
+            id: if self.contains("id") {
+                value.id
+            } else {
+                default.id
+            },
+            email: if self.contains("email") {
+                value.email
+            } else {
+                default.email
+            },
+            name: if self.contains("name") {
+                value.name
+            } else {
+                default.name
+            },
+            created_at: if self.contains("created_at") {
+                value.created_at
+            } else {
+                default.created_at
+            },
+            updated_at: if self.contains("updated_at") {
+                value.updated_at
+            } else {
+                default.updated_at
+            },
+            deleted_at: if self.contains("deleted_at") {
+                value.deleted_at
+            } else {
+                default.deleted_at
+            },
+            version: if self.contains("version") {
+                value.version
+            } else {
+                default.version
+            },
+            status: if self.contains("status") {
+                value.status
+            } else {
+                default.status
+            },
+            metadata: if self.contains("metadata") {
+                value.metadata
+            } else {
+                default.metadata
+            },
+        }
+    }
+}
+
+// -----------------------
+// FILTER ----------------
+// -----------------------
+//
+// Small expression language for `list_users`, e.g.
+//   email.domain == "acme.com" and (name ^= "Jo" or name *= "son")
+//   id in ("094c6c65-fa4c-4324-bb7e-c0dda9595e54", "...")
+//   status in ("suspended", "deactivated")
+//   metadata.department == "sales" and metadata.remote == "true"
+//
+// Operators: == (equal), != (not equal), ^= (prefix), $= (suffix), *= (substring).
+// Comparisons are exact and case-sensitive in every datastore. Metadata values
+// compare in their text form ("4711", "true"), a missing key as "".
+// `email.domain` is the canonical domain (lowercase, punycode) and ids their
+// lowercase text, values are brought into the same form when parsed.
+
+const FILTER_MAX_LEN: usize = 2048;
+const FILTER_MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    Compare {
+        field: FilterField,
+        op: FilterOp,
+        value: String,
+    },
+    In {
+        field: FilterField,
+        values: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterField {
+    Id,
+    Email,
+    EmailDomain,
+    Name,
+    Status,
+    // The key, checked with domain::is_metadata_key
+    Metadata(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterOp {
+    Eq,
+    Ne,
+    Prefix,
+    Suffix,
+    Contains,
+}
+
+impl Filter {
+    pub fn parse(input: &str) -> Result<Self, String> {
+        if input.len() > FILTER_MAX_LEN {
+            return Err(format!(
+                "invalid filter: longer than {FILTER_MAX_LEN} bytes"
+            ));
+        }
+
+        let tokens = tokenize(input)?;
+        let mut parser = FilterParser {
+            tokens,
+            pos: 0,
+            depth: 0,
+        };
+
+        let filter = parser.parse_or()?;
+        match parser.peek() {
+            None => Ok(filter),
+            Some(tok) => Err(format!("invalid filter: unexpected {tok}")),
+        }
+    }
+}
+
+impl FilterField {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "id" => Ok(FilterField::Id),
+            "email" => Ok(FilterField::Email),
+            "email.domain" => Ok(FilterField::EmailDomain),
+            "name" => Ok(FilterField::Name),
+            "status" => Ok(FilterField::Status),
+            _ => match value.strip_prefix("metadata.") {
+                Some(key) if domain::is_metadata_key(key) => {
+                    Ok(FilterField::Metadata(key.to_string()))
+                },
+                _ => Err(format!("invalid filter: unknown field '{value}'")),
+            },
+        }
+    }
+
+    // Into the form the field is compared in, rejecting values that can't
+    // match. `op` is None for `in`, which compares like ==.
+    fn normalize(&self, op: Option<FilterOp>, value: String) -> Result<String, String> {
+        match (self, op) {
+            (FilterField::Id, None | Some(FilterOp::Eq | FilterOp::Ne)) => {
+                domain::ID::try_from(value.as_str())
+                    .map(|id| id.to_string())
+                    .map_err(|e| format!("invalid filter: {e}"))
+            },
+            // Any part of the text form
+            (FilterField::Id, _) => match value
+                .bytes()
+                .all(|b| b.is_ascii_hexdigit() || b == b'-')
+            {
+                true => Ok(value.to_ascii_lowercase()),
+                false => Err(format!(
+                    "invalid filter: '{value}' is not part of an id"
+                )),
+            },
+            (FilterField::EmailDomain, _) => {
+                Ok(idna::domain_to_ascii(&value).unwrap_or_else(|_| value.to_lowercase()))
+            },
+            (FilterField::Status, _) => domain::UserStatus::try_from(value.as_str())
+                .map(|_| value)
+                .map_err(|e| format!("invalid filter: {e}")),
+            _ => Ok(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum FilterToken {
+    Ident(String),
+    Str(String),
+    Op(FilterOp),
+    And,
+    Or,
+    Not,
+    In,
+    LParen,
+    RParen,
+    Comma,
+}
+
+impl std::fmt::Display for FilterToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FilterToken::Ident(v) => write!(f, "'{v}'"),
+            FilterToken::Str(v) => write!(f, "\"{v}\""),
+            FilterToken::Op(op) => write!(f, "operator {:?}", op),
+            FilterToken::And => f.write_str("'and'"),
+            FilterToken::Or => f.write_str("'or'"),
+            FilterToken::Not => f.write_str("'not'"),
+            FilterToken::In => f.write_str("'in'"),
+            FilterToken::LParen => f.write_str("'('"),
+            FilterToken::RParen => f.write_str("')'"),
+            FilterToken::Comma => f.write_str("','"),
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<FilterToken>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {},
+            '(' => tokens.push(FilterToken::LParen),
+            ')' => tokens.push(FilterToken::RParen),
+            ',' => tokens.push(FilterToken::Comma),
+            '=' | '!' | '^' | '$' | '*' => {
+                if !matches!(chars.next(), Some((_, '='))) {
+                    return Err(format!(
+                        "invalid filter: bad operator at position {pos}"
+                    ));
+                }
+                let op = match c {
+                    '=' => FilterOp::Eq,
+                    '!' => FilterOp::Ne,
+                    '^' => FilterOp::Prefix,
+                    '$' => FilterOp::Suffix,
+                    _ => FilterOp::Contains,
+                };
+                tokens.push(FilterToken::Op(op));
+            },
+            '"' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, escaped @ ('"' | '\\'))) => value.push(escaped),
+                            _ => {
+                                return Err(format!(
+                                    "invalid filter: bad escape in string at position {pos}"
+                                ))
+                            },
+                        },
+                        Some((_, other)) => value.push(other),
+                        None => {
+                            return Err(format!(
+                                "invalid filter: unterminated string at position {pos}"
+                            ))
+                        },
+                    }
+                }
+                tokens.push(FilterToken::Str(value));
+            },
+            c if c.is_ascii_alphabetic() => {
+                let mut ident = String::from(c);
+                while let Some((_, next)) = chars.peek() {
+                    if next.is_ascii_alphanumeric() || *next == '_' || *next == '.' {
+                        ident.push(*next);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                let token = match ident.to_ascii_lowercase().as_str() {
+                    "and" => FilterToken::And,
+                    "or" => FilterToken::Or,
+                    "not" => FilterToken::Not,
+                    "in" => FilterToken::In,
+                    _ => FilterToken::Ident(ident),
+                };
+                tokens.push(token);
+            },
+            other => {
+                return Err(format!(
+                    "invalid filter: unexpected '{other}' at position {pos}"
+                ))
+            },
+        }
+    }
+
+    Ok(tokens)
+}
+
+// Recursive descent, lowest precedence first: or > and > not > comparison
+struct FilterParser {
+    tokens: Vec<FilterToken>,
+    pos: usize,
+    depth: usize,
+}
+
+impl FilterParser {
+    fn peek(&self) -> Option<&FilterToken> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<FilterToken> {
+        let tok = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        tok
+    }
+
+    fn expect(&mut self, expected: FilterToken) -> Result<(), String> {
+        match self.next() {
+            Some(tok) if tok == expected => Ok(()),
+            Some(tok) => Err(format!(
+                "invalid filter: expected {expected}, found {tok}"
+            )),
+            None => Err(format!(
+                "invalid filter: expected {expected}, found end of input"
+            )),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Filter, String> {
+        let mut left = self.parse_and()?;
+        while self.peek() == Some(&FilterToken::Or) {
+            self.next();
+            let right = self.parse_and()?;
+            left = Filter::Or(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<Filter, String> {
+        let mut left = self.parse_unary()?;
+        while self.peek() == Some(&FilterToken::And) {
+            self.next();
+            let right = self.parse_unary()?;
+            left = Filter::And(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Filter, String> {
+        self.depth += 1;
+        if self.depth > FILTER_MAX_DEPTH {
+            return Err("invalid filter: nested too deeply".to_string());
+        }
+
+        let res = match self.peek() {
+            Some(FilterToken::Not) => {
+                self.next();
+                self.parse_unary()
+                    .map(|inner| Filter::Not(Box::new(inner)))
+            },
+            Some(FilterToken::LParen) => {
+                self.next();
+                let inner = self.parse_or()?;
+                self.expect(FilterToken::RParen)?;
+                Ok(inner)
+            },
+            _ => self.parse_comparison(),
+        };
+
+        self.depth -= 1;
+        res
+    }
+
+    fn parse_comparison(&mut self) -> Result<Filter, String> {
+        let field = match self.next() {
+            Some(FilterToken::Ident(name)) => FilterField::parse(&name)?,
+            Some(tok) => {
+                return Err(format!(
+                    "invalid filter: expected field, found {tok}"
+                ))
+            },
+            None => return Err("invalid filter: expected field, found end of input".to_string()),
+        };
+
+        match self.next() {
+            Some(FilterToken::Op(op)) => {
+                let value = field.normalize(Some(op), self.parse_string()?)?;
+                Ok(Filter::Compare {
+                    field,
+                    op,
+                    value,
+                })
+            },
+            Some(FilterToken::In) => {
+                self.expect(FilterToken::LParen)?;
+                let mut values = vec![self.parse_string()?];
+                while self.peek() == Some(&FilterToken::Comma) {
+                    self.next();
+                    values.push(self.parse_string()?);
+                }
+                self.expect(FilterToken::RParen)?;
+
+                let values = values
+                    .into_iter()
+                    .map(|v| field.normalize(None, v))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(Filter::In {
+                    field,
+                    values,
+                })
+            },
+            Some(tok) => Err(format!(
+                "invalid filter: expected operator, found {tok}"
+            )),
+            None => Err("invalid filter: expected operator, found end of input".to_string()),
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(FilterToken::Str(value)) => Ok(value),
+            Some(tok) => Err(format!(
+                "invalid filter: expected quoted string, found {tok}"
+            )),
+            None => Err("invalid filter: expected quoted string, found end of input".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FieldMask, Filter, FilterField, FilterOp, PageToken, SortField, SortKey};
+
+    #[test]
+    fn page_token_roundtrip() {
+        let token = PageToken {
+            sort: "name,id".to_string(),
+            after: vec![
+                "Jeff".to_string(),
+                "094c6c65-fa4c-4324-bb7e-c0dda9595e54".to_string(),
+            ],
+        };
+        let encoded = token.encode();
+        assert_eq!(PageToken::decode(&encoded).unwrap(), token);
+    }
+
+    #[test]
+    fn page_token_invalid() {
+        assert!(PageToken::decode("not a token").is_err());
+        assert!(PageToken::decode("bm90IGpzb24").is_err()); // "not json"
+    }
+
+    #[test]
+    fn filter_parse_precedence() {
+        let res =
+            Filter::parse(r#"email.domain == "acme.com" and name ^= "Jo" or not name *= "x""#)
+                .unwrap();
+
+        let expected = Filter::Or(
+            Box::new(Filter::And(
+                Box::new(Filter::Compare {
+                    field: FilterField::EmailDomain,
+                    op: FilterOp::Eq,
+                    value: "acme.com".to_string(),
+                }),
+                Box::new(Filter::Compare {
+                    field: FilterField::Name,
+                    op: FilterOp::Prefix,
+                    value: "Jo".to_string(),
+                }),
+            )),
+            Box::new(Filter::Not(Box::new(Filter::Compare {
+                field: FilterField::Name,
+                op: FilterOp::Contains,
+                value: "x".to_string(),
+            }))),
+        );
+
+        assert_eq!(res, expected);
+    }
+
+    #[test]
+    fn filter_parse_in_and_escapes() {
+        let id = "094c6c65-fa4c-4324-bb7e-c0dda9595e54";
+        let res = Filter::parse(&format!(
+            r#"(id in ("{id}")) and name == "say \"hi\"""#
+        ))
+        .unwrap();
+
+        let expected = Filter::And(
+            Box::new(Filter::In {
+                field: FilterField::Id,
+                values: vec![id.to_string()],
+            }),
+            Box::new(Filter::Compare {
+                field: FilterField::Name,
+                op: FilterOp::Eq,
+                value: "say \"hi\"".to_string(),
+            }),
+        );
+
+        assert_eq!(res, expected);
+
+        let res = Filter::parse(r#"metadata.cost_center in ("4711", "4712")"#).unwrap();
+        assert_eq!(
+            res,
+            Filter::In {
+                field: FilterField::Metadata("cost_center".to_string()),
+                values: vec!["4711".to_string(), "4712".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn filter_parse_normalizes_values() {
+        let cases = [
+            (
+                r#"email.domain == "Bücher.EXAMPLE""#,
+                FilterField::EmailDomain,
+                "xn--bcher-kva.example",
+            ),
+            (
+                r#"email.domain $= ".ACME.com""#,
+                FilterField::EmailDomain,
+                ".acme.com",
+            ),
+            (
+                r#"id == "094C6C65-FA4C-4324-BB7E-C0DDA9595E54""#,
+                FilterField::Id,
+                "094c6c65-fa4c-4324-bb7e-c0dda9595e54",
+            ),
+            (r#"id ^= "094C""#, FilterField::Id, "094c"),
+            (r#"name == "Jo""#, FilterField::Name, "Jo"),
+        ];
+
+        for (case, field, value) in cases {
+            match Filter::parse(case).unwrap() {
+                Filter::Compare {
+                    field: f,
+                    value: v,
+                    ..
+                } => {
+                    assert_eq!(f, field, "{case}");
+                    assert_eq!(v, value, "{case}");
+                },
+                other => panic!("{case}: {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn filter_parse_errors() {
+        let cases = [
+            "",
+            "name",
+            "name ==",
+            "name == Jo",
+            r#"name = "Jo""#,
+            r#"age == "3""#,
+            r#"id == "not-an-id""#,
+            r#"id ^= "not-an-id""#,
+            r#"name == "unterminated"#,
+            r#"(name == "Jo""#,
+            r#"name == "Jo" name == "Jo""#,
+            r#"name in ()"#,
+            r#"metadata == "x""#,
+            r#"metadata.Dept == "x""#,
+            r#"metadata.a.b == "x""#,
+        ];
+
+        for case in cases {
+            let res = Filter::parse(case);
+            assert!(res.is_err(), "should fail: {case}");
+            assert!(res
+                .unwrap_err()
+                .starts_with("invalid filter"));
+        }
+    }
+
+    #[test]
+    fn filter_parse_too_deep() {
+        let expr = format!("{}name == \"x\"", "not ".repeat(100));
+        assert!(Filter::parse(&expr).is_err());
+    }
+
+    #[test]
+    fn sort_parse_list() {
+        let keys = SortKey::parse_list("name, -email").unwrap();
+        assert_eq!(
+            keys,
+            vec![
+                SortKey {
+                    field: SortField::Name,
+                    descending: false,
+                },
+                SortKey {
+                    field: SortField::Email,
+                    descending: true,
+                },
+                SortKey::id(),
+            ]
+        );
+        assert_eq!(SortKey::list_to_string(&keys), "name,-email,id");
+
+        assert_eq!(
+            SortKey::parse_list("").unwrap(),
+            vec![SortKey::id()]
+        );
+        assert_eq!(
+            SortKey::list_to_string(&SortKey::parse_list("-id,name").unwrap()),
+            "-id,name"
+        );
+    }
+
+    #[test]
+    fn sort_parse_list_errors() {
+        assert!(SortKey::parse_list("age").is_err());
+        assert!(SortKey::parse_list("name,-name").is_err());
+        assert!(SortKey::parse_list("--name").is_err());
+    }
+
+    #[test]
+    fn field_mask_apply() {
+        let mask = FieldMask::parse("id, email".split(',')).unwrap();
+
+        let js = serde_json::json!({"id": "1", "email": "a@b.c", "name": "Jeff"});
+        assert_eq!(
+            mask.apply_json(js),
+            serde_json::json!({"id": "1", "email": "a@b.c"})
+        );
+
+        let usr = crate::proto::User {
+            id: "1".to_string(),
+            email: "a@b.c".to_string(),
+            name: "Jeff".to_string(),
+            ..Default::default()
+        };
+        let projected = mask.apply_proto(usr);
+        assert_eq!(projected.email, "a@b.c");
+        assert_eq!(projected.name, "");
+    }
+
+    #[test]
+    fn field_mask_errors() {
+        assert!(FieldMask::parse(["password"]).is_err());
+        assert!(FieldMask::parse([""]).is_err());
+    }
 }
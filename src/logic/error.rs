@@ -7,16 +7,38 @@ use std::{
     fmt::{Debug, Display},
 };
 use tonic::{Code, Status};
+use tonic_types::{ErrorDetails, StatusExt};
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct LogicError {
     code: LogicErrorCode,
 
+    // Every invalid input field, exposed to clients
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    violations: Vec<FieldViolation>,
+
     #[serde(skip)]
     internal_msg: Option<String>,
 
     #[serde(skip)]
-    wrapped: Option<Box<dyn Error>>, // wrapped error
+    wrapped: Option<Box<dyn Error + Send + Sync>>, // wrapped error
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct FieldViolation {
+    pub field: String,
+    pub rule: String,
+    pub message: String,
+}
+
+impl FieldViolation {
+    pub fn new(field: &str, rule: &str, message: String) -> Self {
+        FieldViolation {
+            field: field.to_string(),
+            rule: rule.to_string(),
+            message,
+        }
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug)]
@@ -26,18 +48,43 @@ pub enum LogicErrorCode {
     DuplicateEmail,
     UserNotFound,
     UserInvalidData,
+    UserRestoreExpired,
+    UserVersionMismatch,
+    InvalidQuery,
+    // Batch item not stored because another item of an all-or-nothing batch failed
+    BatchAborted,
+    BatchTooLarge,
+    InvalidIdempotencyKey,
+    // Key reused with a different request
+    IdempotencyKeyMismatch,
+    // The request that holds the key hasn't finished yet
+    IdempotencyKeyInUse,
+    // The user's status doesn't allow the requested change
+    InvalidStatusTransition,
+    // The user is already in the requested status
+    UserStatusUnchanged,
+    // Forged, malformed, already used or replaced by a newer one
+    InvalidVerificationToken,
+    VerificationTokenExpired,
+    // Missing, malformed or rejected credentials
+    Unauthenticated,
+    // Authenticated, but the principal's roles don't allow it
+    Forbidden,
+    // No tenant, a malformed one, or one the credentials aren't for
+    InvalidTenant,
 }
 
 impl LogicError {
     pub fn new(code: LogicErrorCode) -> Self {
         LogicError {
             code,
+            violations: Vec::new(),
             internal_msg: None,
             wrapped: None,
         }
     }
 
-    pub fn wrap(mut self, prev: impl Error + 'static) -> Self {
+    pub fn wrap(mut self, prev: impl Error + Send + Sync + 'static) -> Self {
         self.wrapped = Some(Box::new(prev));
         self
     }
@@ -47,9 +94,18 @@ impl LogicError {
         self
     }
 
+    pub fn with_violations(mut self, violations: Vec<FieldViolation>) -> Self {
+        self.violations = violations;
+        self
+    }
+
     pub fn code(&self) -> LogicErrorCode {
         self.code
     }
+
+    pub fn violations(&self) -> &[FieldViolation] {
+        &self.violations
+    }
 }
 
 // (HTTP) Convert ServiceError to actix_web response
@@ -61,13 +117,32 @@ impl ResponseError for LogicError {
             LogicErrorCode::DuplicateEmail => http::StatusCode::CONFLICT,
             LogicErrorCode::UserNotFound => http::StatusCode::NOT_FOUND,
             LogicErrorCode::UserInvalidData => http::StatusCode::BAD_REQUEST,
+            LogicErrorCode::UserRestoreExpired => http::StatusCode::GONE,
+            LogicErrorCode::UserVersionMismatch => http::StatusCode::PRECONDITION_FAILED,
+            LogicErrorCode::InvalidQuery => http::StatusCode::BAD_REQUEST,
+            LogicErrorCode::BatchAborted => http::StatusCode::CONFLICT,
+            LogicErrorCode::BatchTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
+            LogicErrorCode::InvalidIdempotencyKey => http::StatusCode::BAD_REQUEST,
+            LogicErrorCode::IdempotencyKeyMismatch => http::StatusCode::CONFLICT,
+            LogicErrorCode::IdempotencyKeyInUse => http::StatusCode::CONFLICT,
+            LogicErrorCode::InvalidStatusTransition => http::StatusCode::CONFLICT,
+            LogicErrorCode::UserStatusUnchanged => http::StatusCode::CONFLICT,
+            LogicErrorCode::InvalidVerificationToken => http::StatusCode::BAD_REQUEST,
+            LogicErrorCode::VerificationTokenExpired => http::StatusCode::GONE,
+            LogicErrorCode::Unauthenticated => http::StatusCode::UNAUTHORIZED,
+            LogicErrorCode::Forbidden => http::StatusCode::FORBIDDEN,
+            LogicErrorCode::InvalidTenant => http::StatusCode::BAD_REQUEST,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code())
-            .insert_header(ContentType::json())
-            .json(self)
+        let mut resp = HttpResponse::build(self.status_code());
+        resp.insert_header(ContentType::json());
+        // RFC 9110: a 401 names the scheme to authenticate with
+        if let LogicErrorCode::Unauthenticated = self.code {
+            resp.insert_header((http::header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        resp.json(self)
     }
 }
 
@@ -80,9 +155,44 @@ impl From<LogicError> for Status {
             LogicErrorCode::DuplicateEmail => Code::AlreadyExists,
             LogicErrorCode::UserNotFound => Code::NotFound,
             LogicErrorCode::UserInvalidData => Code::InvalidArgument,
+            LogicErrorCode::UserRestoreExpired => Code::FailedPrecondition,
+            LogicErrorCode::UserVersionMismatch => Code::FailedPrecondition,
+            LogicErrorCode::InvalidQuery => Code::InvalidArgument,
+            LogicErrorCode::BatchAborted => Code::Aborted,
+            LogicErrorCode::BatchTooLarge => Code::InvalidArgument,
+            LogicErrorCode::InvalidIdempotencyKey => Code::InvalidArgument,
+            LogicErrorCode::IdempotencyKeyMismatch => Code::AlreadyExists,
+            LogicErrorCode::IdempotencyKeyInUse => Code::Aborted,
+            LogicErrorCode::InvalidStatusTransition => Code::FailedPrecondition,
+            LogicErrorCode::UserStatusUnchanged => Code::FailedPrecondition,
+            LogicErrorCode::InvalidVerificationToken => Code::InvalidArgument,
+            LogicErrorCode::VerificationTokenExpired => Code::FailedPrecondition,
+            LogicErrorCode::Unauthenticated => Code::Unauthenticated,
+            LogicErrorCode::Forbidden => Code::PermissionDenied,
+            LogicErrorCode::InvalidTenant => Code::InvalidArgument,
         };
 
-        Status::new(grpc_code, val.code)
+        if val.violations.is_empty() {
+            return Status::new(grpc_code, val.code);
+        }
+
+        // Field violations travel as google.rpc.BadRequest details
+        let violations: Vec<tonic_types::FieldViolation> = val
+            .violations
+            .into_iter()
+            .map(|v| tonic_types::FieldViolation {
+                field: v.field,
+                description: v.message,
+                reason: v.rule,
+                localized_message: None,
+            })
+            .collect();
+
+        Status::with_error_details(
+            grpc_code,
+            val.code,
+            ErrorDetails::with_bad_request(violations),
+        )
     }
 }
 
@@ -94,7 +204,9 @@ impl Display for LogicError {
 
 impl Error for LogicError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
-        self.wrapped.as_deref()
+        self.wrapped
+            .as_deref()
+            .map(|e| e as &(dyn Error + 'static))
     }
 }
 
@@ -109,3 +221,54 @@ impl From<LogicErrorCode> for String {
         format!("{:?}", value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{FieldViolation, LogicError, LogicErrorCode};
+    use tonic::{Code, Status};
+    use tonic_types::StatusExt;
+
+    #[test]
+    fn violations_in_json_body() {
+        let err = LogicError::new(LogicErrorCode::UserInvalidData).with_violations(vec![
+            FieldViolation::new("email", "format", "invalid email".to_string()),
+        ]);
+
+        let js = serde_json::to_value(&err).unwrap();
+        assert_eq!(
+            js,
+            serde_json::json!({
+                "code": "UserInvalidData",
+                "violations": [{"field": "email", "rule": "format", "message": "invalid email"}]
+            })
+        );
+    }
+
+    #[test]
+    fn no_violations_in_json_body() {
+        let err = LogicError::new(LogicErrorCode::UserNotFound);
+        let js = serde_json::to_value(&err).unwrap();
+        assert_eq!(js, serde_json::json!({"code": "UserNotFound"}));
+    }
+
+    #[test]
+    fn violations_in_grpc_bad_request() {
+        let err = LogicError::new(LogicErrorCode::UserInvalidData).with_violations(vec![
+            FieldViolation::new("email", "format", "invalid email".to_string()),
+            FieldViolation::new("name", "too_long", "name too long".to_string()),
+        ]);
+
+        let status: Status = err.into();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let bad_request = status
+            .get_details_bad_request()
+            .expect("should have BadRequest details");
+        assert_eq!(bad_request.field_violations.len(), 2);
+        assert_eq!(bad_request.field_violations[0].field, "email");
+        assert_eq!(
+            bad_request.field_violations[1].description,
+            "name too long"
+        );
+    }
+}
@@ -1,41 +1,309 @@
+pub mod authz;
 pub mod domain;
 pub mod dto;
 pub mod error;
+pub mod transfer;
+pub mod verification;
 
 use self::{domain::ID, error::*};
 use crate::{
-    datastore::{Datastore, DatastoreErrorType},
-    toolbox::{context::Context, logger},
+    datastore::{Datastore, DatastoreErrorType, UserListParams},
+    mailer::{self, Mailer},
+    toolbox::{clock::Clock, context::Context, idgen::IdGenerator, logger},
 };
-use std::result;
+use futures::{stream, Stream, StreamExt};
+use sha2::{Digest, Sha256};
+use std::{collections::HashSet, result, sync::Arc};
+use time::{format_description::well_known::Rfc3339, Duration};
 
 type LogicResult<T> = result::Result<T, LogicError>;
 
+// How long a soft-deleted user can still be restored
+const RESTORE_GRACE_PERIOD: Duration = Duration::days(30);
+
+// Applied when the caller doesn't ask for a page size / asks for too much
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 1000;
+
+// Items per batch create
+const MAX_BATCH_SIZE: usize = 1000;
+
+// Recorded as the actor of audit entries when the Context carries no
+// principal and no actor
+const ANONYMOUS_ACTOR: &str = "anonymous";
+
+// How long a key stays locked by a request that never completes it
+const IDEMPOTENCY_LOCK_TIMEOUT: Duration = Duration::minutes(1);
+
+// Idempotency keys are opaque to us, but bounded
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct IdempotencyConfig {
+    // How long a completed create is replayed for its key
+    pub ttl_secs: u64,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        IdempotencyConfig {
+            ttl_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+impl IdempotencyConfig {
+    fn ttl(&self) -> Duration {
+        Duration::seconds(self.ttl_secs.min(i64::MAX as u64) as i64)
+    }
+}
+
 pub struct Logic {
-    datastore: Box<dyn Datastore + Send + Sync>,
+    datastore: Arc<dyn Datastore + Send + Sync>,
+    clock: Arc<dyn Clock>,
+    ids: Arc<dyn IdGenerator>,
+    email_policy: domain::EmailPolicy,
+    name_policy: domain::UserNamePolicy,
+    idempotency: IdempotencyConfig,
+    mailer: Arc<dyn Mailer>,
+    verification: verification::VerificationConfig,
+    signer: verification::TokenSigner,
+    authz: authz::AuthzConfig,
 }
 
 impl Logic {
-    pub fn new(datastore: Box<dyn Datastore + Send + Sync>) -> Self {
+    // Use SystemClock / RandomIdGenerator outside of tests
+    pub fn new(
+        datastore: Arc<dyn Datastore + Send + Sync>, clock: Arc<dyn Clock>,
+        ids: Arc<dyn IdGenerator>,
+    ) -> Self {
         Self {
             datastore,
+            clock,
+            ids,
+            email_policy: domain::EmailPolicy::default(),
+            name_policy: domain::UserNamePolicy::default(),
+            idempotency: IdempotencyConfig::default(),
+            mailer: Arc::new(mailer::StdoutMailer),
+            verification: verification::VerificationConfig::default(),
+            signer: verification::TokenSigner::ephemeral(),
+            authz: authz::AuthzConfig::default(),
         }
     }
 
+    pub fn with_email_policy(mut self, email_policy: domain::EmailPolicy) -> Self {
+        self.email_policy = email_policy;
+        self
+    }
+
+    pub fn with_name_policy(mut self, name_policy: domain::UserNamePolicy) -> Self {
+        self.name_policy = name_policy;
+        self
+    }
+
+    pub fn with_idempotency(mut self, idempotency: IdempotencyConfig) -> Self {
+        self.idempotency = idempotency;
+        self
+    }
+
+    pub fn with_mailer(mut self, mailer: Arc<dyn Mailer>) -> Self {
+        self.mailer = mailer;
+        self
+    }
+
+    pub fn with_verification(
+        mut self, verification: verification::VerificationConfig, signer: verification::TokenSigner,
+    ) -> Self {
+        self.signer = signer;
+        self.verification = verification;
+        self
+    }
+
+    pub fn with_authz(mut self, authz: authz::AuthzConfig) -> Self {
+        self.authz = authz;
+        self
+    }
+
     // -----------------------
     // USE CASES -------------
     // -----------------------
 
+    // With an "idempotency_key" in the Context, a retry of the same request
+    // gets the user the first one created for as long as the key lives
     pub async fn create_user(
         &self, ctx: &Context, data: dto::CreateUserRequest,
     ) -> LogicResult<domain::User> {
         logger::ctx_info!(ctx, "hello");
+        self.authz
+            .require(ctx, authz::Permission::CreateUsers)?;
+        let tenant = tenant(ctx)?;
+
+        match ctx.get_clone::<String>("idempotency_key") {
+            Some(key) => {
+                self.create_user_once(ctx, &tenant, &key, data)
+                    .await
+            },
+            None => {
+                self.insert_user(ctx, &tenant, data)
+                    .await
+            },
+        }
+    }
+
+    // The key is reserved before the user is stored, so a concurrent retry
+    // is told to back off instead of creating the user a second time
+    async fn create_user_once(
+        &self, ctx: &Context, tenant: &domain::TenantId, key: &str, data: dto::CreateUserRequest,
+    ) -> LogicResult<domain::User> {
+        let key = parse_idempotency_key(key)?;
+        let now = self.clock.now();
+        let record = domain::IdempotencyRecord {
+            key: key.to_string(),
+            fingerprint: fingerprint(
+                "create_user",
+                &[
+                    &data.email,
+                    &data.name,
+                    &serde_json::to_string(&data.metadata).unwrap_or_default(),
+                ],
+            ),
+            response: None,
+            created_at: now,
+            expires_at: now + IDEMPOTENCY_LOCK_TIMEOUT,
+        };
+
+        match self
+            .datastore
+            .reserve_idempotency_key(tenant, &record, now)
+            .await
+        {
+            Ok(_) => {},
+            Err(db_err) => match db_err.error_type {
+                DatastoreErrorType::Conflict => return self.replay(tenant, &record, now).await,
+                _ => return Err(LogicError::new(LogicErrorCode::UnexpectedError).wrap(db_err)),
+            },
+        }
+
+        let usr = match self
+            .insert_user(ctx, tenant, data)
+            .await
+        {
+            Ok(usr) => usr,
+            Err(e) => {
+                // Nothing was created, the key is free for the next attempt
+                if let Err(db_err) = self
+                    .datastore
+                    .release_idempotency_key(tenant, key)
+                    .await
+                {
+                    logger::ctx_warning!(
+                        ctx,
+                        "idempotency key {} not released: {}",
+                        key,
+                        db_err
+                    );
+                }
+                return Err(e);
+            },
+        };
+
+        // The user exists either way: a lost response only means a retry
+        // runs into the lock until it times out
+        let stored = match serde_json::to_string(&usr) {
+            Ok(response) => self
+                .datastore
+                .complete_idempotency_key(
+                    tenant,
+                    key,
+                    &response,
+                    now + self.idempotency.ttl(),
+                )
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = stored {
+            logger::ctx_error!(
+                ctx,
+                "idempotency key {} not completed: {}",
+                key,
+                e
+            );
+        }
+
+        Ok(usr)
+    }
+
+    async fn replay(
+        &self, tenant: &domain::TenantId, record: &domain::IdempotencyRecord,
+        now: time::OffsetDateTime,
+    ) -> LogicResult<domain::User> {
+        let stored = match self
+            .datastore
+            .get_idempotency_record(tenant, &record.key, now)
+            .await
+        {
+            Ok(stored) => stored,
+            // Released or expired since, just as racy as a request in flight
+            Err(db_err) if matches!(db_err.error_type, DatastoreErrorType::NotFound) => {
+                return Err(LogicError::new(LogicErrorCode::IdempotencyKeyInUse).wrap(db_err))
+            },
+            Err(db_err) => {
+                return Err(LogicError::new(LogicErrorCode::UnexpectedError).wrap(db_err))
+            },
+        };
+
+        if stored.fingerprint != record.fingerprint {
+            return Err(
+                LogicError::new(LogicErrorCode::IdempotencyKeyMismatch).with_internal_msg(format!(
+                    "idempotency key: {} (used for another request)",
+                    record.key
+                )),
+            );
+        }
 
-        let new_id = ID::new().to_string();
-        let obj = domain::User::try_new(&new_id, &data.email, &data.name)?;
+        match stored.response {
+            Some(ref response) => serde_json::from_str::<domain::User>(response).map_err(|e| {
+                LogicError::new(LogicErrorCode::UnexpectedError).with_internal_msg(format!(
+                    "idempotency key: {} (invalid response: {})",
+                    record.key, e
+                ))
+            }),
+            None => Err(
+                LogicError::new(LogicErrorCode::IdempotencyKeyInUse).with_internal_msg(format!(
+                    "idempotency key: {} (request in progress)",
+                    record.key
+                )),
+            ),
+        }
+    }
+
+    async fn insert_user(
+        &self, ctx: &Context, tenant: &domain::TenantId, data: dto::CreateUserRequest,
+    ) -> LogicResult<domain::User> {
+        let new_id = ID::from(self.ids.generate()).to_string();
+        let obj = domain::User::try_new(
+            &new_id,
+            &data.email,
+            &data.name,
+            &data.metadata,
+            self.clock.now(),
+            &self.email_policy,
+            &self.name_policy,
+        )?;
 
-        match self.datastore.store_user(&obj).await {
-            Ok(_) => Ok(obj),
+        let audit = self.audit_entry(ctx, None, &obj, domain::AuditOperation::Create);
+        match self
+            .datastore
+            .store_user(tenant, &obj, &audit)
+            .await
+        {
+            Ok(_) => {
+                self.send_verification(ctx, tenant, &obj)
+                    .await;
+                Ok(obj)
+            },
             Err(db_err) => match db_err.error_type {
                 DatastoreErrorType::Conflict => {
                     Err(LogicError::new(LogicErrorCode::DuplicateEmail).wrap(db_err))
@@ -45,10 +313,141 @@ impl Logic {
         }
     }
 
-    pub async fn get_user(&self, _: &Context, id: &str) -> LogicResult<domain::User> {
+    // Results line up with the request items. In all-or-nothing mode a single
+    // failure turns every other item into BatchAborted.
+    pub async fn batch_create_users(
+        &self, ctx: &Context, data: dto::BatchCreateUsersRequest,
+    ) -> LogicResult<dto::BatchCreateUsersResponse> {
+        self.authz
+            .require(ctx, authz::Permission::CreateUsers)?;
+        let tenant = tenant(ctx)?;
+
+        if data.items.len() > MAX_BATCH_SIZE {
+            return Err(
+                LogicError::new(LogicErrorCode::BatchTooLarge).with_internal_msg(format!(
+                    "{} items (max {})",
+                    data.items.len(),
+                    MAX_BATCH_SIZE
+                )),
+            );
+        }
+
+        let atomic = data.mode == dto::BatchMode::AllOrNothing;
+        let now = self.clock.now();
+
+        // The datastore would only report these as a conflict of the whole batch
+        let mut emails: HashSet<String> = HashSet::new();
+        let mut parsed: Vec<LogicResult<domain::User>> = Vec::with_capacity(data.items.len());
+        for item in data.items.into_iter() {
+            let new_id = ID::from(self.ids.generate()).to_string();
+            let result = domain::User::try_new(
+                &new_id,
+                &item.email,
+                &item.name,
+                &item.metadata,
+                now,
+                &self.email_policy,
+                &self.name_policy,
+            )
+            .and_then(
+                |usr| match emails.insert(usr.email().canonical().to_string()) {
+                    true => Ok(usr),
+                    false => Err(LogicError::new(LogicErrorCode::DuplicateEmail)
+                        .with_internal_msg(format!(
+                            "email: {} (repeated in batch)",
+                            usr.email()
+                        ))),
+                },
+            );
+            parsed.push(result);
+        }
+
+        if atomic && parsed.iter().any(|r| r.is_err()) {
+            return Ok(abort_batch(parsed));
+        }
+
+        let users: Vec<domain::User> = parsed
+            .iter()
+            .filter_map(|r| r.as_ref().ok().cloned())
+            .collect();
+        let audit: Vec<domain::AuditEntry> = users
+            .iter()
+            .map(|usr| self.audit_entry(ctx, None, usr, domain::AuditOperation::Create))
+            .collect();
+        let mut stored = match self
+            .datastore
+            .store_users(&tenant, &users, &audit, atomic)
+            .await
+        {
+            Ok(results) => results.into_iter(),
+            Err(db_err) => {
+                return Err(LogicError::new(LogicErrorCode::UnexpectedError).wrap(db_err))
+            },
+        };
+
+        let results: Vec<LogicResult<domain::User>> = parsed
+            .into_iter()
+            .map(|item| {
+                let usr = item?;
+                match stored.next() {
+                    Some(Ok(_)) => Ok(usr),
+                    Some(Err(db_err)) => match db_err.error_type {
+                        DatastoreErrorType::Conflict => {
+                            Err(LogicError::new(LogicErrorCode::DuplicateEmail).wrap(db_err))
+                        },
+                        _ => Err(LogicError::new(LogicErrorCode::UnexpectedError).wrap(db_err)),
+                    },
+                    None => Err(LogicError::new(LogicErrorCode::UnexpectedError)
+                        .with_internal_msg("datastore returned too few results".to_string())),
+                }
+            })
+            .collect();
+
+        if atomic && results.iter().any(|r| r.is_err()) {
+            return Ok(abort_batch(results));
+        }
+
+        let mut items = Vec::with_capacity(results.len());
+        let mut created = 0;
+        for result in results.into_iter() {
+            items.push(match result {
+                Ok(usr) => {
+                    created += 1;
+                    self.send_verification(ctx, &tenant, &usr)
+                        .await;
+                    dto::BatchCreateUserResult::User(usr)
+                },
+                Err(e) => dto::BatchCreateUserResult::Error(e),
+            });
+        }
+        logger::ctx_info!(
+            ctx,
+            "batch created {} of {} users",
+            created,
+            items.len()
+        );
+
+        Ok(dto::BatchCreateUsersResponse {
+            items,
+        })
+    }
+
+    pub async fn get_user(
+        &self, ctx: &Context, id: &str, opts: dto::GetUserOptions,
+    ) -> LogicResult<domain::User> {
         let id = parse_id(id)?;
+        self.authz.require_read(ctx, &id)?;
+        let tenant = tenant(ctx)?;
 
-        match self.datastore.get_user(&id).await {
+        match self
+            .datastore
+            .get_user(&tenant, &id)
+            .await
+        {
+            Ok(obj) if obj.is_deleted() && !opts.include_deleted => {
+                Err(LogicError::new(LogicErrorCode::UserNotFound)
+                    .with_internal_msg(format!("id: {} (deleted)", id)))
+            },
             Ok(obj) => Ok(obj),
             Err(db_err) => match db_err.error_type {
                 DatastoreErrorType::NotFound => {
@@ -59,12 +458,728 @@ impl Logic {
         }
     }
 
-    pub async fn list_users(&self, _: &Context, _: dto::Query) -> LogicResult<Vec<domain::User>> {
-        match self.datastore.list_users().await {
-            Ok(res) => Ok(res),
+    // `expected_version` makes the write conditional (If-Match)
+    pub async fn update_user(
+        &self, ctx: &Context, id: &str, data: dto::UpdateUserRequest, expected_version: Option<u64>,
+    ) -> LogicResult<domain::User> {
+        self.authz
+            .require(ctx, authz::Permission::UpdateUsers)?;
+        let id = parse_id(id)?;
+        let tenant = tenant(ctx)?;
+
+        let mut obj = match self
+            .datastore
+            .get_user(&tenant, &id)
+            .await
+        {
+            Ok(obj) if obj.is_deleted() => {
+                return Err(LogicError::new(LogicErrorCode::UserNotFound)
+                    .with_internal_msg(format!("id: {} (deleted)", id)))
+            },
+            Ok(obj) => obj,
+            Err(db_err) => match db_err.error_type {
+                DatastoreErrorType::NotFound => {
+                    return Err(LogicError::new(LogicErrorCode::UserNotFound).wrap(db_err))
+                },
+                _ => return Err(LogicError::new(LogicErrorCode::UnexpectedError).wrap(db_err)),
+            },
+        };
+
+        check_version(&obj, expected_version)?;
+        let before = obj.clone();
+
+        let mut violations = Vec::new();
+
+        if let Some(email) = data.email {
+            match domain::Email::parse(&email, &self.email_policy) {
+                Ok(v) => obj.change_email(v),
+                Err(e) => violations.push(e.into_violation("email")),
+            }
+        }
+
+        if let Some(name) = data.name {
+            match domain::UserName::parse(&name, &self.name_policy) {
+                Ok(v) => obj.set_name(v),
+                Err(e) => violations.push(e.into_violation("name")),
+            }
+        }
+
+        if let Some(metadata) = data.metadata {
+            match domain::Metadata::parse(&metadata) {
+                Ok(v) => obj.set_metadata(v),
+                Err(e) => violations.push(e.into_violation("metadata")),
+            }
+        }
+
+        if !violations.is_empty() {
+            return Err(
+                LogicError::new(LogicErrorCode::UserInvalidData).with_violations(violations)
+            );
+        }
+
+        // Nothing to store, the version and timestamps stay as they are
+        if domain::AuditEntry::diff(Some(&before), Some(&obj)).is_empty() {
+            return Ok(before);
+        }
+
+        // A new address has to be verified again, see User::change_email
+        let email_changed = obj.email().canonical() != before.email().canonical();
+
+        // Stored only if nobody else wrote in between
+        obj.set_updated_at(self.clock.now());
+        obj.bump_version();
+
+        let audit = self.audit_entry(
+            ctx,
+            Some(&before),
+            &obj,
+            domain::AuditOperation::Update,
+        );
+        match self
+            .datastore
+            .update_user(&tenant, &obj, &audit)
+            .await
+        {
+            Ok(_) => {
+                if email_changed {
+                    self.send_verification(ctx, &tenant, &obj)
+                        .await;
+                }
+                Ok(obj)
+            },
+            Err(db_err) => match db_err.error_type {
+                DatastoreErrorType::Conflict => {
+                    Err(LogicError::new(LogicErrorCode::DuplicateEmail).wrap(db_err))
+                },
+                DatastoreErrorType::VersionMismatch => {
+                    Err(LogicError::new(LogicErrorCode::UserVersionMismatch).wrap(db_err))
+                },
+                DatastoreErrorType::NotFound => {
+                    Err(LogicError::new(LogicErrorCode::UserNotFound).wrap(db_err))
+                },
+                _ => Err(LogicError::new(LogicErrorCode::UnexpectedError).wrap(db_err)),
+            },
+        }
+    }
+
+    pub async fn suspend_user(
+        &self, ctx: &Context, id: &str, expected_version: Option<u64>,
+    ) -> LogicResult<domain::User> {
+        self.change_status(
+            ctx,
+            id,
+            domain::StatusChange::Suspend,
+            expected_version,
+        )
+        .await
+    }
+
+    pub async fn reactivate_user(
+        &self, ctx: &Context, id: &str, expected_version: Option<u64>,
+    ) -> LogicResult<domain::User> {
+        self.change_status(
+            ctx,
+            id,
+            domain::StatusChange::Reactivate,
+            expected_version,
+        )
+        .await
+    }
+
+    pub async fn deactivate_user(
+        &self, ctx: &Context, id: &str, expected_version: Option<u64>,
+    ) -> LogicResult<domain::User> {
+        self.change_status(
+            ctx,
+            id,
+            domain::StatusChange::Deactivate,
+            expected_version,
+        )
+        .await
+    }
+
+    // Consumes a token sent by `send_verification`. It must be the latest
+    // token of the user and its address must still be the user's.
+    pub async fn verify_user(
+        &self, ctx: &Context, id: &str, token: &str,
+    ) -> LogicResult<domain::User> {
+        let id = parse_id(id)?;
+        self.authz.require_on(
+            ctx,
+            &id,
+            authz::Permission::ManageUsers,
+            authz::Permission::VerifySelf,
+        )?;
+        let tenant = tenant(ctx)?;
+
+        let claims = match self.signer.verify(token) {
+            Ok(claims) if claims.user_id == id => claims,
+            Ok(claims) => {
+                return Err(
+                    LogicError::new(LogicErrorCode::InvalidVerificationToken).with_internal_msg(
+                        format!("id: {} (token of user {})", id, claims.user_id),
+                    ),
+                )
+            },
+            Err(e) => {
+                return Err(
+                    LogicError::new(LogicErrorCode::InvalidVerificationToken)
+                        .with_internal_msg(format!("id: {} ({})", id, e)),
+                )
+            },
+        };
+
+        if claims.expires_at <= self.clock.now() {
+            return Err(
+                LogicError::new(LogicErrorCode::VerificationTokenExpired).with_internal_msg(
+                    format!("id: {} (expired at {})", id, claims.expires_at),
+                ),
+            );
+        }
+
+        let stored = match self
+            .datastore
+            .get_verification_token(&tenant, &id)
+            .await
+        {
+            Ok(stored) if stored.id == claims.token_id => stored,
+            Ok(_) => {
+                return Err(
+                    LogicError::new(LogicErrorCode::InvalidVerificationToken)
+                        .with_internal_msg(format!("id: {} (token replaced)", id)),
+                )
+            },
+            Err(db_err) => match db_err.error_type {
+                DatastoreErrorType::NotFound => {
+                    return Err(
+                        LogicError::new(LogicErrorCode::InvalidVerificationToken)
+                            .with_internal_msg(format!("id: {} (token already used)", id)),
+                    )
+                },
+                _ => return Err(LogicError::new(LogicErrorCode::UnexpectedError).wrap(db_err)),
+            },
+        };
+
+        let before = self.get_live_user(&tenant, &id).await?;
+        if stored.email_canonical != before.email().canonical() {
+            return Err(
+                LogicError::new(LogicErrorCode::InvalidVerificationToken)
+                    .with_internal_msg(format!("id: {} (email changed)", id)),
+            );
+        }
+
+        let usr = self
+            .transition(ctx, &tenant, before, domain::StatusChange::Verify)
+            .await?;
+
+        // Verified either way, a leftover token only fails the status check
+        if let Err(db_err) = self
+            .datastore
+            .delete_verification_token(&tenant, &id, &stored.id)
+            .await
+        {
+            logger::ctx_warning!(
+                ctx,
+                "verification token of user {} not deleted: {}",
+                id,
+                db_err
+            );
+        }
+
+        Ok(usr)
+    }
+
+    // Deleted users keep their status until they are restored
+    async fn change_status(
+        &self, ctx: &Context, id: &str, change: domain::StatusChange, expected_version: Option<u64>,
+    ) -> LogicResult<domain::User> {
+        self.authz
+            .require(ctx, authz::Permission::ManageUsers)?;
+        let id = parse_id(id)?;
+        let tenant = tenant(ctx)?;
+        let before = self.get_live_user(&tenant, &id).await?;
+        check_version(&before, expected_version)?;
+
+        self.transition(ctx, &tenant, before, change)
+            .await
+    }
+
+    async fn transition(
+        &self, ctx: &Context, tenant: &domain::TenantId, before: domain::User,
+        change: domain::StatusChange,
+    ) -> LogicResult<domain::User> {
+        let id = *before.id();
+        let from = before.status();
+        let to = match before.status_after(change) {
+            Some(to) => to,
+            None => {
+                // Only a change that would end where the user is already counts
+                // as unchanged, reactivating a pending user is the wrong change
+                let code = match change.target(true) == from {
+                    true => LogicErrorCode::UserStatusUnchanged,
+                    false => LogicErrorCode::InvalidStatusTransition,
+                };
+                return Err(LogicError::new(code).with_internal_msg(format!(
+                    "id: {} ({} can't {:?})",
+                    id, from, change
+                )));
+            },
+        };
+
+        let now = self.clock.now();
+        let mut obj = before.clone();
+        obj.set_status(to);
+        if change == domain::StatusChange::Verify {
+            obj.set_email_verified_at(Some(now));
+        }
+        obj.set_updated_at(now);
+        obj.bump_version();
+
+        let audit = self.audit_entry(ctx, Some(&before), &obj, change.into());
+        match self
+            .datastore
+            .update_user(tenant, &obj, &audit)
+            .await
+        {
+            Ok(_) => {
+                logger::ctx_info!(ctx, "user {} status {} -> {}", id, from, to);
+                Ok(obj)
+            },
+            Err(db_err) => match db_err.error_type {
+                DatastoreErrorType::VersionMismatch => {
+                    Err(LogicError::new(LogicErrorCode::UserVersionMismatch).wrap(db_err))
+                },
+                DatastoreErrorType::NotFound => {
+                    Err(LogicError::new(LogicErrorCode::UserNotFound).wrap(db_err))
+                },
+                _ => Err(LogicError::new(LogicErrorCode::UnexpectedError).wrap(db_err)),
+            },
+        }
+    }
+
+    pub async fn delete_user(
+        &self, ctx: &Context, id: &str, expected_version: Option<u64>,
+    ) -> LogicResult<()> {
+        self.authz
+            .require(ctx, authz::Permission::DeleteUsers)?;
+        let id = parse_id(id)?;
+        let tenant = tenant(ctx)?;
+
+        // Read first for the version check and the audit diff
+        let mut obj = match self
+            .datastore
+            .get_user(&tenant, &id)
+            .await
+        {
+            Ok(obj) => obj,
+            Err(db_err) => match db_err.error_type {
+                DatastoreErrorType::NotFound => {
+                    return Err(LogicError::new(LogicErrorCode::UserNotFound).wrap(db_err))
+                },
+                _ => return Err(LogicError::new(LogicErrorCode::UnexpectedError).wrap(db_err)),
+            },
+        };
+        check_version(&obj, expected_version)?;
+        let before = obj.clone();
+
+        let now = self.clock.now();
+        obj.set_deleted_at(Some(now));
+        obj.set_updated_at(now);
+        obj.bump_version();
+        let audit = self.audit_entry(
+            ctx,
+            Some(&before),
+            &obj,
+            domain::AuditOperation::Delete,
+        );
+
+        // Fails if anyone wrote since the read above
+        match self
+            .datastore
+            .delete_user(&tenant, &id, before.version(), now, &audit)
+            .await
+        {
+            Ok(_) => {
+                logger::ctx_info!(ctx, "user deleted: {}", id);
+                Ok(())
+            },
+            Err(db_err) => match db_err.error_type {
+                DatastoreErrorType::VersionMismatch => {
+                    Err(LogicError::new(LogicErrorCode::UserVersionMismatch).wrap(db_err))
+                },
+                DatastoreErrorType::NotFound => {
+                    Err(LogicError::new(LogicErrorCode::UserNotFound).wrap(db_err))
+                },
+                _ => Err(LogicError::new(LogicErrorCode::UnexpectedError).wrap(db_err)),
+            },
+        }
+    }
+
+    pub async fn restore_user(
+        &self, ctx: &Context, id: &str, expected_version: Option<u64>,
+    ) -> LogicResult<domain::User> {
+        self.authz
+            .require(ctx, authz::Permission::DeleteUsers)?;
+        let id = parse_id(id)?;
+        let tenant = tenant(ctx)?;
+
+        let mut obj = match self
+            .datastore
+            .get_user(&tenant, &id)
+            .await
+        {
+            Ok(obj) => obj,
+            Err(db_err) => match db_err.error_type {
+                DatastoreErrorType::NotFound => {
+                    return Err(LogicError::new(LogicErrorCode::UserNotFound).wrap(db_err))
+                },
+                _ => return Err(LogicError::new(LogicErrorCode::UnexpectedError).wrap(db_err)),
+            },
+        };
+        check_version(&obj, expected_version)?;
+
+        // Restoring an active user is a no-op
+        let deleted_at = match obj.deleted_at() {
+            Some(v) => v,
+            None => return Ok(obj),
+        };
+
+        let now = self.clock.now();
+        let before = obj.clone();
+        if now - deleted_at > RESTORE_GRACE_PERIOD {
+            return Err(
+                LogicError::new(LogicErrorCode::UserRestoreExpired)
+                    .with_internal_msg(format!("id: {} (deleted at {})", id, deleted_at)),
+            );
+        }
+
+        obj.set_deleted_at(None);
+        obj.set_updated_at(now);
+        obj.bump_version();
+        let audit = self.audit_entry(
+            ctx,
+            Some(&before),
+            &obj,
+            domain::AuditOperation::Restore,
+        );
+
+        match self
+            .datastore
+            .restore_user(&tenant, &id, before.version(), now, &audit)
+            .await
+        {
+            Ok(_) => {
+                logger::ctx_info!(ctx, "user restored: {}", id);
+                Ok(obj)
+            },
+            Err(db_err) => match db_err.error_type {
+                DatastoreErrorType::VersionMismatch => {
+                    Err(LogicError::new(LogicErrorCode::UserVersionMismatch).wrap(db_err))
+                },
+                DatastoreErrorType::NotFound => {
+                    Err(LogicError::new(LogicErrorCode::UserNotFound).wrap(db_err))
+                },
+                _ => Err(LogicError::new(LogicErrorCode::UnexpectedError).wrap(db_err)),
+            },
+        }
+    }
+
+    pub async fn list_users(&self, ctx: &Context, query: dto::Query) -> LogicResult<dto::UserList> {
+        self.authz
+            .require(ctx, authz::Permission::ListUsers)?;
+        let tenant = tenant(ctx)?;
+
+        let page_size = match query.page_size {
+            None | Some(0) => DEFAULT_PAGE_SIZE,
+            Some(v) => (v as usize).min(MAX_PAGE_SIZE),
+        };
+
+        let sort = match query.sort {
+            Some(ref spec) => parse_sort(spec)?,
+            None => vec![dto::SortKey::id()],
+        };
+
+        let after = match query.page_token {
+            Some(ref token) if !token.is_empty() => Some(parse_page_token(token, &sort)?),
+            _ => None,
+        };
+
+        let filter = match query.filter {
+            Some(ref expr) if !expr.trim().is_empty() => Some(parse_filter(expr)?),
+            _ => None,
+        };
+
+        // Fetch one extra row to find out whether there is a next page
+        let params = UserListParams {
+            include_deleted: query.include_deleted,
+            sort,
+            after,
+            limit: page_size + 1,
+            filter,
+        };
+
+        let mut items = match self
+            .datastore
+            .list_users(&tenant, &params)
+            .await
+        {
+            Ok(res) => res,
+            Err(db_err) => {
+                return Err(LogicError::new(LogicErrorCode::UnexpectedError).wrap(db_err))
+            },
+        };
+
+        let mut next_page_token = None;
+        if items.len() > page_size {
+            items.truncate(page_size);
+            next_page_token = items.last().map(|last| {
+                dto::PageToken {
+                    sort: dto::SortKey::list_to_string(&params.sort),
+                    after: params
+                        .sort
+                        .iter()
+                        .map(|k| k.field.value_of(last))
+                        .collect(),
+                }
+                .encode()
+            });
+        }
+
+        Ok(dto::UserList {
+            items,
+            next_page_token,
+        })
+    }
+
+    // Every page of `query`, from its page token on, fetched one at a time
+    // as the stream is polled. The page size is always MAX_PAGE_SIZE.
+    pub fn export_users(
+        self: Arc<Self>, ctx: Arc<Context>, query: dto::Query,
+    ) -> impl Stream<Item = LogicResult<Vec<domain::User>>> {
+        let first = dto::Query {
+            page_size: Some(MAX_PAGE_SIZE as u32),
+            ..query
+        };
+
+        stream::try_unfold(Some(first), move |query| {
+            let logic = Arc::clone(&self);
+            let ctx = Arc::clone(&ctx);
+            async move {
+                let query = match query {
+                    Some(query) => query,
+                    None => return Ok(None),
+                };
+                let page = logic
+                    .list_users(&ctx, query.clone())
+                    .await?;
+                let next = page
+                    .next_page_token
+                    .map(|token| dto::Query {
+                        page_token: Some(token),
+                        ..query
+                    });
+                Ok(Some((page.items, next)))
+            }
+        })
+    }
+
+    // Creates the users of parsed import rows, MAX_BATCH_SIZE rows at a time.
+    // Rejected rows (unparsable, invalid or conflicting) don't stop the import.
+    pub async fn import_users(
+        &self, ctx: &Context, rows: impl Stream<Item = transfer::ImportRow>,
+    ) -> LogicResult<dto::ImportReport> {
+        let mut report = dto::ImportReport::default();
+        let mut chunks = std::pin::pin!(rows.chunks(MAX_BATCH_SIZE));
+
+        while let Some(chunk) = chunks.next().await {
+            let mut lines: Vec<u64> = Vec::with_capacity(MAX_BATCH_SIZE);
+            let mut items: Vec<dto::CreateUserRequest> = Vec::with_capacity(MAX_BATCH_SIZE);
+
+            for (line, row) in chunk {
+                match row {
+                    Ok(item) => {
+                        lines.push(line);
+                        items.push(item);
+                    },
+                    Err(e) => report.rejected.push(dto::RejectedRow {
+                        line,
+                        error: LogicError::new(LogicErrorCode::UserInvalidData)
+                            .with_violations(vec![FieldViolation::new("row", "format", e)]),
+                    }),
+                }
+            }
+
+            if items.is_empty() {
+                continue;
+            }
+
+            let batch = dto::BatchCreateUsersRequest {
+                items,
+                mode: dto::BatchMode::BestEffort,
+            };
+            let results = self
+                .batch_create_users(ctx, batch)
+                .await?;
+
+            for (line, result) in lines.into_iter().zip(results.items) {
+                match result {
+                    dto::BatchCreateUserResult::User(_) => report.imported += 1,
+                    dto::BatchCreateUserResult::Error(error) => {
+                        report.rejected.push(dto::RejectedRow {
+                            line,
+                            error,
+                        })
+                    },
+                }
+            }
+        }
+
+        report.rejected.sort_by_key(|r| r.line);
+        logger::ctx_info!(
+            ctx,
+            "imported {} users, rejected {} rows",
+            report.imported,
+            report.rejected.len()
+        );
+
+        Ok(report)
+    }
+
+    // Deleted users keep their history
+    // Diffs show emails and names, so this is a read like get_user
+    pub async fn get_user_history(&self, ctx: &Context, id: &str) -> LogicResult<dto::UserHistory> {
+        let id = parse_id(id)?;
+        self.authz.require_read(ctx, &id)?;
+        let tenant = tenant(ctx)?;
+
+        if let Err(db_err) = self
+            .datastore
+            .get_user(&tenant, &id)
+            .await
+        {
+            return match db_err.error_type {
+                DatastoreErrorType::NotFound => {
+                    Err(LogicError::new(LogicErrorCode::UserNotFound).wrap(db_err))
+                },
+                _ => Err(LogicError::new(LogicErrorCode::UnexpectedError).wrap(db_err)),
+            };
+        }
+
+        match self
+            .datastore
+            .list_audit_entries(&tenant, &id)
+            .await
+        {
+            Ok(items) => Ok(dto::UserHistory {
+                items,
+            }),
             Err(db_err) => Err(LogicError::new(LogicErrorCode::UnexpectedError).wrap(db_err)),
         }
     }
+
+    // Deleted users count as missing
+    async fn get_live_user(&self, tenant: &domain::TenantId, id: &ID) -> LogicResult<domain::User> {
+        match self
+            .datastore
+            .get_user(tenant, id)
+            .await
+        {
+            Ok(obj) if obj.is_deleted() => Err(LogicError::new(LogicErrorCode::UserNotFound)
+                .with_internal_msg(format!("id: {} (deleted)", id))),
+            Ok(obj) => Ok(obj),
+            Err(db_err) => match db_err.error_type {
+                DatastoreErrorType::NotFound => {
+                    Err(LogicError::new(LogicErrorCode::UserNotFound).wrap(db_err))
+                },
+                _ => Err(LogicError::new(LogicErrorCode::UnexpectedError).wrap(db_err)),
+            },
+        }
+    }
+
+    // Issues a new token for the user's current address, replacing the one
+    // sent before. The user is stored already, so failures are only logged.
+    // Token ids come from ID::new: they must not be predictable.
+    async fn send_verification(
+        &self, ctx: &Context, tenant: &domain::TenantId, usr: &domain::User,
+    ) {
+        let now = self.clock.now();
+        let token = domain::VerificationToken {
+            id: ID::new(),
+            user_id: *usr.id(),
+            email: usr.email().to_string(),
+            email_canonical: usr.email().canonical().to_string(),
+            created_at: now,
+            expires_at: now + self.verification.ttl(),
+        };
+
+        if let Err(db_err) = self
+            .datastore
+            .store_verification_token(tenant, &token)
+            .await
+        {
+            logger::ctx_error!(
+                ctx,
+                "verification token of user {} not stored: {}",
+                usr.id(),
+                db_err
+            );
+            return;
+        }
+
+        let signed = self
+            .signer
+            .sign(&verification::TokenClaims {
+                user_id: token.user_id,
+                token_id: token.id,
+                expires_at: token.expires_at,
+            });
+        let msg = mailer::Message {
+            to: token.email,
+            subject: "Verify your email address".to_string(),
+            body: format!(
+                "Hi {},\n\nyour verification token is:\n\n{}\n\nIt expires at {}.\n",
+                usr.name(),
+                signed,
+                token
+                    .expires_at
+                    .format(&Rfc3339)
+                    .unwrap_or_default()
+            ),
+        };
+
+        if let Err(e) = self.mailer.send(&msg).await {
+            logger::ctx_error!(
+                ctx,
+                "verification mail to user {} not sent: {}",
+                usr.id(),
+                e
+            );
+        }
+    }
+
+    // The mutation is already stored at this point, so a failed audit write
+    // is logged instead of failing the request
+    // Stored by the datastore along with the write of `usr`
+    fn audit_entry(
+        &self, ctx: &Context, before: Option<&domain::User>, usr: &domain::User,
+        operation: domain::AuditOperation,
+    ) -> domain::AuditEntry {
+        domain::AuditEntry {
+            id: ID::from(self.ids.generate()),
+            user_id: *usr.id(),
+            actor: ctx
+                .get_clone::<domain::Principal>("principal")
+                .map(|p| p.to_string())
+                .or_else(|| ctx.get_clone::<String>("actor"))
+                .unwrap_or_else(|| ANONYMOUS_ACTOR.to_string()),
+            trace_id: ctx
+                .get_clone::<String>("trace_id")
+                .unwrap_or_default(),
+            at: usr.updated_at(),
+            operation,
+            changes: domain::AuditEntry::diff(before, Some(usr)),
+        }
+    }
 }
 
 impl core::fmt::Debug for Logic {
@@ -77,9 +1192,112 @@ impl core::fmt::Debug for Logic {
 // HELPERS ---------------
 // -----------------------
 
+// Set by the transports for every request, see server::tenancy
+fn tenant(ctx: &Context) -> LogicResult<domain::TenantId> {
+    ctx.get_clone::<domain::TenantId>("tenant_id")
+        .ok_or_else(|| {
+            LogicError::new(LogicErrorCode::InvalidTenant)
+                .with_internal_msg("no tenant in context".to_string())
+        })
+}
+
 fn parse_id(value: &str) -> LogicResult<domain::ID> {
     match domain::ID::try_from(value) {
         Ok(id) => Ok(id),
         Err(e) => Err(LogicError::new(LogicErrorCode::InvalidID).with_internal_msg(e)),
     }
 }
+
+fn parse_idempotency_key(value: &str) -> LogicResult<&str> {
+    let valid = !value.is_empty()
+        && value.len() <= MAX_IDEMPOTENCY_KEY_LEN
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_graphic());
+
+    match valid {
+        true => Ok(value),
+        false => Err(
+            LogicError::new(LogicErrorCode::InvalidIdempotencyKey)
+                .with_internal_msg(format!("idempotency key: {:?}", value)),
+        ),
+    }
+}
+
+// Hex SHA-256 over the operation and its request fields, NUL separated
+fn fingerprint(operation: &str, fields: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(operation.as_bytes());
+    for field in fields.iter() {
+        hasher.update([0]);
+        hasher.update(field.as_bytes());
+    }
+
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Nothing was stored: items that were fine report why they weren't created
+fn abort_batch(results: Vec<LogicResult<domain::User>>) -> dto::BatchCreateUsersResponse {
+    let items = results
+        .into_iter()
+        .map(|result| match result {
+            Ok(usr) => dto::BatchCreateUserResult::Error(
+                LogicError::new(LogicErrorCode::BatchAborted)
+                    .with_internal_msg(format!("id: {} (batch aborted)", usr.id())),
+            ),
+            Err(e) => dto::BatchCreateUserResult::Error(e),
+        })
+        .collect();
+
+    dto::BatchCreateUsersResponse {
+        items,
+    }
+}
+
+fn check_version(obj: &domain::User, expected_version: Option<u64>) -> LogicResult<()> {
+    match expected_version {
+        Some(expected) if expected != obj.version() => Err(LogicError::new(
+            LogicErrorCode::UserVersionMismatch,
+        )
+        .with_internal_msg(format!(
+            "id: {} (version {} is stored, expected {})",
+            obj.id(),
+            obj.version(),
+            expected
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn parse_page_token(value: &str, sort: &[dto::SortKey]) -> LogicResult<Vec<String>> {
+    let token = match dto::PageToken::decode(value) {
+        Ok(token) => token,
+        Err(e) => return Err(LogicError::new(LogicErrorCode::InvalidQuery).with_internal_msg(e)),
+    };
+
+    // A token is only valid for the sort order it was issued for
+    if token.sort != dto::SortKey::list_to_string(sort) || token.after.len() != sort.len() {
+        return Err(LogicError::new(LogicErrorCode::InvalidQuery)
+            .with_internal_msg("page token does not match sort order".to_string()));
+    }
+
+    Ok(token.after)
+}
+
+fn parse_sort(value: &str) -> LogicResult<Vec<dto::SortKey>> {
+    match dto::SortKey::parse_list(value) {
+        Ok(keys) => Ok(keys),
+        Err(e) => Err(LogicError::new(LogicErrorCode::InvalidQuery).with_internal_msg(e)),
+    }
+}
+
+fn parse_filter(value: &str) -> LogicResult<dto::Filter> {
+    match dto::Filter::parse(value) {
+        Ok(filter) => Ok(filter),
+        Err(e) => Err(LogicError::new(LogicErrorCode::InvalidQuery).with_internal_msg(e)),
+    }
+}
@@ -0,0 +1,567 @@
+// Bulk formats for moving users in and out: CSV (with a header row) and
+// NDJSON (one JSON object per line).
+
+use super::{domain, dto};
+use futures::{channel::mpsc, executor, SinkExt, Stream, StreamExt};
+use std::io::{self, BufRead, BufReader, Read};
+use time::format_description::well_known::Rfc3339;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferFormat {
+    Csv,
+    Ndjson,
+}
+
+impl TransferFormat {
+    pub fn from_mime(value: &str) -> Option<Self> {
+        match value {
+            "text/csv" => Some(TransferFormat::Csv),
+            "application/x-ndjson" => Some(TransferFormat::Ndjson),
+            _ => None,
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            TransferFormat::Csv => "text/csv",
+            TransferFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    // As given on the command line
+    pub fn from_name(value: &str) -> Option<Self> {
+        match value {
+            "csv" => Some(TransferFormat::Csv),
+            "ndjson" => Some(TransferFormat::Ndjson),
+            _ => None,
+        }
+    }
+}
+
+// Serializes users one page at a time, so exports never hold more than a page
+pub struct Encoder {
+    format: TransferFormat,
+    fields: Vec<&'static str>,
+    mask: Option<dto::FieldMask>,
+    header_written: bool,
+}
+
+impl Encoder {
+    pub fn new(format: TransferFormat, mask: Option<dto::FieldMask>) -> Self {
+        let fields = dto::USER_FIELDS
+            .iter()
+            .copied()
+            .filter(|f| match mask {
+                Some(ref mask) => mask.contains(f),
+                None => true,
+            })
+            .collect();
+
+        Encoder {
+            format,
+            fields,
+            mask,
+            header_written: false,
+        }
+    }
+
+    // The first call also emits the CSV header, even for an empty page
+    pub fn encode(&mut self, users: &[domain::User]) -> Result<Vec<u8>, String> {
+        match self.format {
+            TransferFormat::Csv => self.encode_csv(users),
+            TransferFormat::Ndjson => self.encode_ndjson(users),
+        }
+    }
+
+    fn encode_csv(&mut self, users: &[domain::User]) -> Result<Vec<u8>, String> {
+        let mut wr = csv::Writer::from_writer(Vec::new());
+
+        if !self.header_written {
+            wr.write_record(&self.fields)
+                .map_err(|e| format!("csv: {e}"))?;
+            self.header_written = true;
+        }
+
+        for usr in users.iter() {
+            let record = self
+                .fields
+                .iter()
+                .map(|f| escape_formula(field_value(usr, f)));
+            wr.write_record(record)
+                .map_err(|e| format!("csv: {e}"))?;
+        }
+
+        wr.into_inner()
+            .map_err(|e| format!("csv: {e}"))
+    }
+
+    fn encode_ndjson(&mut self, users: &[domain::User]) -> Result<Vec<u8>, String> {
+        let mut out: Vec<u8> = Vec::new();
+
+        for usr in users.iter() {
+            let mut js = serde_json::to_value(usr).map_err(|e| format!("ndjson: {e}"))?;
+            if let Some(ref mask) = self.mask {
+                js = mask.apply_json(js);
+            }
+            serde_json::to_writer(&mut out, &js).map_err(|e| format!("ndjson: {e}"))?;
+            out.push(b'\n');
+        }
+
+        Ok(out)
+    }
+}
+
+fn field_value(usr: &domain::User, field: &str) -> String {
+    let timestamp = |v: time::OffsetDateTime| v.format(&Rfc3339).unwrap_or_default();
+
+    match field {
+        "id" => usr.id().to_string(),
+        "email" => usr.email().to_string(),
+        "name" => usr.name().to_string(),
+        "created_at" => timestamp(usr.created_at()),
+        "updated_at" => timestamp(usr.updated_at()),
+        "deleted_at" => usr
+            .deleted_at()
+            .map(timestamp)
+            .unwrap_or_default(),
+        "version" => usr.version().to_string(),
+        "status" => usr.status().to_string(),
+        "metadata" => usr.metadata().to_json(),
+        _ => String::new(),
+    }
+}
+
+// Spreadsheets run cells starting with one of these as formulas
+const FORMULA_PREFIXES: &[char] = &['=', '+', '-', '@', '\t', '\r'];
+
+fn escape_formula(value: String) -> String {
+    match value.starts_with(FORMULA_PREFIXES) {
+        true => format!("'{value}"),
+        false => value,
+    }
+}
+
+// Longer rows are rejected instead of being buffered
+const MAX_ROW_SIZE: usize = 64 * 1024;
+
+// (1-based line number, parsed row or why it couldn't be parsed).
+// Only `email`, `name` and `metadata` are read, other columns/keys are ignored.
+pub type ImportRow = (u64, Result<dto::CreateUserRequest, String>);
+
+// CSV cells are flat, metadata is a JSON object in one (or empty)
+#[derive(serde::Deserialize)]
+struct CsvRow {
+    email: String,
+    name: String,
+    #[serde(default)]
+    metadata: String,
+}
+
+impl TryFrom<CsvRow> for dto::CreateUserRequest {
+    type Error = String;
+
+    fn try_from(value: CsvRow) -> Result<Self, Self::Error> {
+        let metadata = match value.metadata.trim() {
+            "" => domain::RawMetadata::new(),
+            v => serde_json::from_str(v).map_err(|e| format!("invalid csv row: metadata: {e}"))?,
+        };
+
+        Ok(dto::CreateUserRequest {
+            email: value.email,
+            name: value.name,
+            metadata,
+        })
+    }
+}
+
+// Lazily parses rows off `reader`
+pub fn decode<'a, R: Read + Send + 'a>(
+    format: TransferFormat, reader: R,
+) -> Box<dyn Iterator<Item = ImportRow> + Send + 'a> {
+    match format {
+        TransferFormat::Csv => Box::new(decode_csv(reader)),
+        TransferFormat::Ndjson => Box::new(decode_ndjson(reader)),
+    }
+}
+
+// `decode` for a body that arrives in chunks, e.g. a request body. The
+// decoders read synchronously, so they run on a blocking thread that is
+// handed one chunk and returns one row at a time: only a few chunks are ever
+// held. Dropping the returned stream stops it.
+pub fn decode_chunks<S, C>(format: TransferFormat, chunks: S) -> impl Stream<Item = ImportRow>
+where
+    S: Stream<Item = Result<C, String>> + Send + Unpin + 'static,
+    C: AsRef<[u8]> + Send + 'static,
+{
+    let (mut tx, rx) = mpsc::channel::<ImportRow>(1);
+    tokio::task::spawn_blocking(move || {
+        let reader = ChunkReader {
+            chunks,
+            current: None,
+            pos: 0,
+        };
+        for row in decode(format, reader) {
+            // Nobody is listening anymore
+            if executor::block_on(tx.send(row)).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+struct ChunkReader<S, C> {
+    chunks: S,
+    current: Option<C>,
+    pos: usize,
+}
+
+impl<S, C> Read for ChunkReader<S, C>
+where
+    S: Stream<Item = Result<C, String>> + Unpin,
+    C: AsRef<[u8]>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(ref chunk) = self.current {
+                let rest = &chunk.as_ref()[self.pos..];
+                if !rest.is_empty() {
+                    let n = rest.len().min(buf.len());
+                    buf[..n].copy_from_slice(&rest[..n]);
+                    self.pos += n;
+                    return Ok(n);
+                }
+            }
+
+            match executor::block_on(self.chunks.next()) {
+                Some(Ok(chunk)) => {
+                    self.current = Some(chunk);
+                    self.pos = 0;
+                },
+                Some(Err(e)) => return Err(io::Error::other(e)),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+// Fails reads once more than MAX_ROW_SIZE bytes went into the current row.
+// The CSV reader reads ahead, so a row may exceed the limit by its buffer.
+struct RowLimit<R> {
+    inner: R,
+    read: usize,
+}
+
+impl<R: Read> Read for RowLimit<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read >= MAX_ROW_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("row longer than {MAX_ROW_SIZE} bytes"),
+            ));
+        }
+        let max = buf.len().min(MAX_ROW_SIZE - self.read);
+        let n = self.inner.read(&mut buf[..max])?;
+        self.read += n;
+        Ok(n)
+    }
+}
+
+// A quoted field can span lines, so the CSV can't be picked up again after a
+// row that is too long: decoding stops there.
+fn decode_csv<R: Read>(reader: R) -> impl Iterator<Item = ImportRow> {
+    let mut rd = csv::ReaderBuilder::new()
+        .trim(csv::Trim::Headers)
+        .from_reader(RowLimit {
+            inner: reader,
+            read: 0,
+        });
+    let mut header: Option<Result<csv::StringRecord, String>> = None;
+    let mut done = false;
+
+    std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        let header = header.get_or_insert_with(|| {
+            rd.headers()
+                .cloned()
+                .map_err(|e| format!("invalid csv header: {e}"))
+        });
+        let header = match header {
+            Ok(header) => header.clone(),
+            // Reported once, as line 1
+            Err(e) if !e.is_empty() => return Some((1, Err(std::mem::take(e)))),
+            Err(_) => return None,
+        };
+
+        let mut record = csv::StringRecord::new();
+        rd.get_mut().read = 0;
+        match rd.read_record(&mut record) {
+            Ok(false) => None,
+            Ok(true) => {
+                let line = record
+                    .position()
+                    .map(|p| p.line())
+                    .unwrap_or_default();
+                let row = record
+                    .deserialize::<CsvRow>(Some(&header))
+                    .map_err(|e| format!("invalid csv row: {e}"))
+                    .and_then(dto::CreateUserRequest::try_from);
+                Some((line, row))
+            },
+            Err(e) => {
+                // Read errors don't know their row, it's the one being read
+                let line = e
+                    .position()
+                    .unwrap_or(rd.position())
+                    .line();
+                done = matches!(e.kind(), csv::ErrorKind::Io(_));
+                Some((line, Err(format!("invalid csv row: {e}"))))
+            },
+        }
+    })
+}
+
+// A line that is too long is skipped without being held, decoding goes on
+// with the next one
+fn decode_ndjson<R: Read>(reader: R) -> impl Iterator<Item = ImportRow> {
+    let mut rd = BufReader::new(reader);
+    let mut line: Vec<u8> = Vec::new();
+    let mut line_no: u64 = 0;
+    let mut done = false;
+
+    std::iter::from_fn(move || loop {
+        if done {
+            return None;
+        }
+        line.clear();
+        line_no += 1;
+
+        let read = rd
+            .by_ref()
+            .take(MAX_ROW_SIZE as u64 + 1)
+            .read_until(b'\n', &mut line);
+        let too_long = line.len() > MAX_ROW_SIZE && line.last() != Some(&b'\n');
+        let read = match too_long {
+            // The rest of the line is dropped unread
+            true => read.and_then(|_| rd.skip_until(b'\n')),
+            false => read,
+        };
+
+        match read {
+            Ok(_) if too_long => {
+                return Some((
+                    line_no,
+                    Err(format!("row longer than {MAX_ROW_SIZE} bytes")),
+                ))
+            },
+            Ok(0) => return None,
+            Ok(_) if line.trim_ascii().is_empty() => continue,
+            Ok(_) => {
+                return Some((
+                    line_no,
+                    serde_json::from_slice::<dto::CreateUserRequest>(&line)
+                        .map_err(|e| format!("invalid json: {e}")),
+                ))
+            },
+            Err(e) => {
+                done = true;
+                return Some((line_no, Err(format!("invalid input: {e}"))));
+            },
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, decode_chunks, Encoder, TransferFormat, MAX_ROW_SIZE};
+    use crate::logic::{
+        domain::{Email, User, UserName, ID},
+        dto::FieldMask,
+    };
+    use futures::{stream, StreamExt};
+
+    fn user(email: &str, name: &str) -> User {
+        User::new(
+            ID::try_from("094c6c65-fa4c-4324-bb7e-c0dda9595e54").unwrap(),
+            Email::try_from(email).unwrap(),
+            UserName::try_from(name.to_string()).unwrap(),
+            time::OffsetDateTime::UNIX_EPOCH,
+        )
+    }
+
+    #[test]
+    fn encode_csv() {
+        let mut enc = Encoder::new(TransferFormat::Csv, None);
+        let first = enc
+            .encode(&[user("jo@acme.com", "Jo, \"Jr\"")])
+            .unwrap();
+        let second = enc.encode(&[]).unwrap();
+
+        assert_eq!(
+            String::from_utf8(first).unwrap(),
+            "id,email,name,created_at,updated_at,deleted_at,version,status,metadata\n\
+             094c6c65-fa4c-4324-bb7e-c0dda9595e54,jo@acme.com,\"Jo, \"\"Jr\"\"\",\
+             1970-01-01T00:00:00Z,1970-01-01T00:00:00Z,,1,pending_verification,{}\n"
+        );
+        assert!(second.is_empty());
+    }
+
+    #[test]
+    fn encode_csv_formulas() {
+        let mask = FieldMask::parse(["email", "name"]).unwrap();
+        let mut enc = Encoder::new(TransferFormat::Csv, Some(mask));
+        let out = enc
+            .encode(&[
+                user("jo@acme.com", "=HYPERLINK(\"x\")"),
+                user("al@acme.com", "@Al"),
+                user("bo@acme.com", "Bo -Jr"),
+            ])
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "email,name\n\
+             jo@acme.com,\"'=HYPERLINK(\"\"x\"\")\"\n\
+             al@acme.com,'@Al\n\
+             bo@acme.com,Bo -Jr\n"
+        );
+    }
+
+    #[test]
+    fn encode_masked() {
+        let mask = FieldMask::parse(["name", "email"]).unwrap();
+        let mut enc = Encoder::new(TransferFormat::Csv, Some(mask));
+        let out = enc
+            .encode(&[user("jo@acme.com", "Jo")])
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "email,name\njo@acme.com,Jo\n"
+        );
+
+        let mask = FieldMask::parse(["name"]).unwrap();
+        let mut enc = Encoder::new(TransferFormat::Ndjson, Some(mask));
+        let out = enc
+            .encode(&[user("jo@acme.com", "Jo"), user("al@acme.com", "Al")])
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"name\":\"Jo\"}\n{\"name\":\"Al\"}\n"
+        );
+    }
+
+    #[test]
+    fn decode_csv_lines() {
+        let input = "id,name,email\n\
+                     x,Jo,jo@acme.com\n\
+                     x,\"Multi\nLine\",ml@acme.com\n\
+                     x,Missing\n\
+                     x,Al,al@acme.com\n";
+        let rows: Vec<_> = decode(TransferFormat::Csv, input.as_bytes()).collect();
+
+        let lines: Vec<_> = rows
+            .iter()
+            .map(|(line, _)| *line)
+            .collect();
+        assert_eq!(lines, [2, 3, 5, 6]);
+        assert_eq!(rows[0].1.as_ref().unwrap().email, "jo@acme.com");
+        assert_eq!(rows[1].1.as_ref().unwrap().name, "Multi\nLine");
+        assert!(rows[2].1.is_err());
+        assert_eq!(rows[3].1.as_ref().unwrap().name, "Al");
+    }
+
+    #[test]
+    fn decode_ndjson_lines() {
+        let input = "{\"email\":\"jo@acme.com\",\"name\":\"Jo\",\"id\":\"ignored\"}\n\
+                     \n\
+                     not json\n\
+                     {\"email\":\"al@acme.com\",\"name\":\"Al\"}";
+        let rows: Vec<_> = decode(TransferFormat::Ndjson, input.as_bytes()).collect();
+
+        let lines: Vec<_> = rows
+            .iter()
+            .map(|(line, _)| *line)
+            .collect();
+        assert_eq!(lines, [1, 3, 4]);
+        assert_eq!(rows[0].1.as_ref().unwrap().name, "Jo");
+        assert!(rows[1].1.is_err());
+        assert_eq!(rows[2].1.as_ref().unwrap().email, "al@acme.com");
+    }
+
+    #[tokio::test]
+    async fn decode_split_chunks() {
+        // Rows and even a quoted field are split across chunks
+        let chunks = [
+            "id,name,em",
+            "ail\nx,Jo,jo@ac",
+            "me.com\nx,\"Multi\n",
+            "Line\",ml@acme.com\n",
+        ]
+        .map(|c| Ok(c.as_bytes()));
+        let rows: Vec<_> = decode_chunks(TransferFormat::Csv, stream::iter(chunks))
+            .collect()
+            .await;
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].1.as_ref().unwrap().email, "jo@acme.com");
+        assert_eq!(rows[1].0, 3);
+        assert_eq!(rows[1].1.as_ref().unwrap().name, "Multi\nLine");
+    }
+
+    #[tokio::test]
+    async fn decode_chunk_error() {
+        let chunks = [
+            Ok("{\"email\":\"jo@acme.com\",\"name\":\"Jo\"}\n".as_bytes()),
+            Err("reset".to_string()),
+        ];
+        let rows: Vec<_> = decode_chunks(TransferFormat::Ndjson, stream::iter(chunks))
+            .collect()
+            .await;
+
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].1.is_ok());
+        assert!(rows[1].1.is_err());
+    }
+
+    #[test]
+    fn decode_long_rows() {
+        let long = "x".repeat(2 * MAX_ROW_SIZE);
+
+        // The long line is skipped, the next one still counts
+        let input = format!(
+            "{{\"email\":\"jo@acme.com\",\"name\":\"Jo\"}}\n\
+             {{\"name\":\"{long}\"}}\n\
+             {{\"email\":\"al@acme.com\",\"name\":\"Al\"}}\n"
+        );
+        let rows: Vec<_> = decode(TransferFormat::Ndjson, input.as_bytes()).collect();
+        let lines: Vec<_> = rows
+            .iter()
+            .map(|(line, _)| *line)
+            .collect();
+        assert_eq!(lines, [1, 2, 3]);
+        assert!(rows[1]
+            .1
+            .as_ref()
+            .unwrap_err()
+            .contains("row longer than"));
+        assert_eq!(rows[2].1.as_ref().unwrap().name, "Al");
+
+        // CSV stops at the long row
+        let input = format!(
+            "email,name\n\
+             jo@acme.com,Jo\n\
+             al@acme.com,\"{long}\"\n\
+             bo@acme.com,Bo\n"
+        );
+        let rows: Vec<_> = decode(TransferFormat::Csv, input.as_bytes()).collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1].0, 3);
+        assert!(rows[1]
+            .1
+            .as_ref()
+            .unwrap_err()
+            .contains("row longer than"));
+    }
+}
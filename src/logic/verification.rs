@@ -0,0 +1,230 @@
+// Email verification tokens: "<payload>.<signature>", both base64url, where the
+// payload is "<user id>.<token id>.<expiry as unix seconds>" and the signature
+// is its HMAC-SHA256. The signature keeps token ids from being guessed, the
+// stored token (one per user) makes them single use.
+
+use super::domain::ID;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use time::{Duration, OffsetDateTime};
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(serde::Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct VerificationConfig {
+    // Signing key, shared by all replicas. Required unless `ephemeral_secret`.
+    pub secret: String,
+    // Signs with a random key instead, for local runs only: tokens then die
+    // with the process and aren't valid on any other replica
+    pub ephemeral_secret: bool,
+    pub token_ttl_secs: u64,
+}
+
+impl Default for VerificationConfig {
+    fn default() -> Self {
+        VerificationConfig {
+            secret: String::new(),
+            ephemeral_secret: false,
+            token_ttl_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+// Keeps the secret out of the startup log
+impl std::fmt::Debug for VerificationConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VerificationConfig")
+            .field("secret", &"***")
+            .field("ephemeral_secret", &self.ephemeral_secret)
+            .field("token_ttl_secs", &self.token_ttl_secs)
+            .finish()
+    }
+}
+
+impl VerificationConfig {
+    pub(super) fn ttl(&self) -> Duration {
+        Duration::seconds(self.token_ttl_secs.min(i64::MAX as u64) as i64)
+    }
+}
+
+// What a token vouches for, once its signature checks out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenClaims {
+    pub user_id: ID,
+    pub token_id: ID,
+    pub expires_at: OffsetDateTime,
+}
+
+pub struct TokenSigner {
+    key: Vec<u8>,
+}
+
+impl TokenSigner {
+    pub fn from_config(config: &VerificationConfig) -> Result<Self, String> {
+        match (config.secret.is_empty(), config.ephemeral_secret) {
+            (false, false) => Ok(Self::new(&config.secret)),
+            (true, true) => Ok(Self::ephemeral()),
+            (true, false) => {
+                Err("verification.secret is empty, set it or ephemeral_secret".to_string())
+            },
+            (false, true) => {
+                Err("verification.secret and ephemeral_secret are exclusive".to_string())
+            },
+        }
+    }
+
+    pub fn new(secret: &str) -> Self {
+        TokenSigner {
+            key: secret.as_bytes().to_vec(),
+        }
+    }
+
+    // A random key of this process only
+    pub fn ephemeral() -> Self {
+        let mut key = vec![0u8; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+
+        TokenSigner {
+            key,
+        }
+    }
+
+    pub fn sign(&self, claims: &TokenClaims) -> String {
+        let payload = format!(
+            "{}.{}.{}",
+            claims.user_id,
+            claims.token_id,
+            claims.expires_at.unix_timestamp()
+        );
+        let signature = self
+            .mac(payload.as_bytes())
+            .finalize()
+            .into_bytes();
+
+        format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(payload),
+            URL_SAFE_NO_PAD.encode(signature)
+        )
+    }
+
+    // Checks the signature only, expiry is up to the caller
+    pub fn verify(&self, token: &str) -> Result<TokenClaims, String> {
+        let (payload, signature) = token
+            .split_once('.')
+            .ok_or("malformed token")?;
+        let payload = URL_SAFE_NO_PAD
+            .decode(payload)
+            .map_err(|_| "malformed token")?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| "malformed token")?;
+
+        self.mac(&payload)
+            .verify_slice(&signature)
+            .map_err(|_| "bad signature")?;
+
+        let payload = String::from_utf8(payload).map_err(|_| "malformed token")?;
+        let mut parts = payload.split('.');
+        let (user_id, token_id, expires_at) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(u), Some(t), Some(e)) if parts.next().is_none() => (u, t, e),
+            _ => return Err("malformed token".to_string()),
+        };
+
+        let expires_at = expires_at
+            .parse::<i64>()
+            .ok()
+            .and_then(|v| OffsetDateTime::from_unix_timestamp(v).ok())
+            .ok_or("malformed token")?;
+
+        Ok(TokenClaims {
+            user_id: ID::try_from(user_id)?,
+            token_id: ID::try_from(token_id)?,
+            expires_at,
+        })
+    }
+
+    fn mac(&self, data: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        mac.update(data);
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TokenClaims, TokenSigner, VerificationConfig};
+    use crate::logic::domain::ID;
+    use time::OffsetDateTime;
+
+    fn claims() -> TokenClaims {
+        TokenClaims {
+            user_id: ID::new(),
+            token_id: ID::new(),
+            expires_at: OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap(),
+        }
+    }
+
+    #[test]
+    fn sign_verify() {
+        let signer = TokenSigner::new("secret");
+        let claims = claims();
+        let token = signer.sign(&claims);
+
+        assert_eq!(signer.verify(&token), Ok(claims));
+        assert!(token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)));
+    }
+
+    #[test]
+    fn verify_rejects_tampering() {
+        let signer = TokenSigner::new("secret");
+        let token = signer.sign(&claims());
+
+        assert!(TokenSigner::new("other")
+            .verify(&token)
+            .is_err());
+
+        let (payload, signature) = token.split_once('.').unwrap();
+        let forged = format!("{}.{}", &payload[1..], signature);
+        assert!(signer.verify(&forged).is_err());
+
+        for garbage in ["", ".", "abc", "abc.def", "a.b.c"] {
+            assert!(signer.verify(garbage).is_err(), "{garbage}");
+        }
+    }
+
+    #[test]
+    fn secret_required() {
+        let config = |secret: &str, ephemeral_secret: bool| VerificationConfig {
+            secret: secret.to_string(),
+            ephemeral_secret,
+            ..Default::default()
+        };
+
+        assert!(TokenSigner::from_config(&config("", false)).is_err());
+        assert!(TokenSigner::from_config(&config("secret", true)).is_err());
+
+        let token = TokenSigner::from_config(&config("secret", false))
+            .unwrap()
+            .sign(&claims());
+        assert!(TokenSigner::new("secret")
+            .verify(&token)
+            .is_ok());
+
+        // Nothing else can check tokens of an ephemeral key
+        let token = TokenSigner::from_config(&config("", true))
+            .unwrap()
+            .sign(&claims());
+        assert!(TokenSigner::ephemeral()
+            .verify(&token)
+            .is_err());
+        assert!(TokenSigner::new("")
+            .verify(&token)
+            .is_err());
+    }
+}
@@ -0,0 +1,130 @@
+use crate::toolbox::logger;
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Message {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+// Delivers outgoing mail. Callers treat failures as non-fatal: the action
+// that triggered the mail has already happened.
+#[tonic::async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, msg: &Message) -> Result<(), String>;
+}
+
+#[derive(serde::Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum MailerConfig {
+    // Prints every message as a log line
+    #[default]
+    #[serde(rename = "stdout")]
+    Stdout,
+    // Appends every message to `path`, one JSON object per line
+    #[serde(rename = "file")]
+    File {
+        path: String,
+    },
+}
+
+pub fn from_config(config: &MailerConfig) -> Arc<dyn Mailer> {
+    match config {
+        MailerConfig::Stdout => Arc::new(StdoutMailer),
+        MailerConfig::File {
+            path,
+        } => Arc::new(FileMailer::new(path)),
+    }
+}
+
+pub struct StdoutMailer;
+
+#[tonic::async_trait]
+impl Mailer for StdoutMailer {
+    async fn send(&self, msg: &Message) -> Result<(), String> {
+        let js = serde_json::to_string(msg).map_err(|e| format!("StdoutMailer: {e}"))?;
+        logger::logger()
+            .log_entry(logger::Level::Info, format!("mail: {js}"))
+            .publish();
+        Ok(())
+    }
+}
+
+pub struct FileMailer {
+    path: String,
+    // Keeps concurrent sends from interleaving their lines
+    lock: Mutex<()>,
+}
+
+impl FileMailer {
+    pub fn new(path: &str) -> Self {
+        FileMailer {
+            path: path.to_string(),
+            lock: Mutex::new(()),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, msg: &Message) -> Result<(), String> {
+        let mut line = serde_json::to_vec(msg).map_err(|e| format!("FileMailer: {e}"))?;
+        line.push(b'\n');
+
+        let _guard = self.lock.lock().unwrap();
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(&line))
+            .map_err(|e| format!("FileMailer: {}: {}", self.path, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileMailer, Mailer, MailerConfig, Message};
+
+    #[tokio::test]
+    async fn file_mailer_appends_lines() {
+        let path = std::env::temp_dir().join(format!("mailer-{}.ndjson", uuid::Uuid::new_v4()));
+        let mailer = FileMailer::new(path.to_str().unwrap());
+
+        for to in ["a@acme.com", "b@acme.com"] {
+            let msg = Message {
+                to: to.to_string(),
+                subject: "Hi".to_string(),
+                body: "Hello\nthere".to_string(),
+            };
+            mailer.send(&msg).await.unwrap();
+        }
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["to"], "b@acme.com");
+        assert_eq!(lines[1]["body"], "Hello\nthere");
+    }
+
+    #[test]
+    fn config() {
+        let file: MailerConfig =
+            serde_json::from_str(r#"{"type": "file", "path": "/tmp/mail"}"#).unwrap();
+        assert_eq!(
+            file,
+            MailerConfig::File {
+                path: "/tmp/mail".to_string()
+            }
+        );
+    }
+}
@@ -1,44 +1,116 @@
 use blueprint::{
     datastore::{inmem::InMemDatastore, sql::SqlDatastore, Datastore},
-    logic::Logic,
-    server::{grpc, http},
-    toolbox::logger,
-    Config, ConfigDbType,
+    logic::{
+        domain::TenantId,
+        dto,
+        transfer::{self, Encoder, TransferFormat},
+        verification::TokenSigner,
+        Logic,
+    },
+    mailer,
+    outbox::{EventSink, LogSink, OutboxSinkType, Relay},
+    server::{auth::Authenticator, grpc, http, tenancy::TenantResolver},
+    toolbox::{
+        clock::SystemClock,
+        context::Context,
+        idgen::{IdGenerator, RandomIdGenerator, TimeOrderedIdGenerator},
+        logger,
+    },
+    worker::Worker,
+    Config, ConfigDbType, ConfigIdFormat,
+};
+use futures::StreamExt;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    sync::Arc,
+    time::Duration,
 };
-use std::{sync::Arc, time::Duration};
 use tokio::runtime::Runtime;
 
+const USAGE: &str = "usage:
+    blueprint
+    blueprint export --format csv|ndjson [--tenant ID] [--include-deleted] [--filter EXPR] [--sort SPEC] FILE
+    blueprint import --format csv|ndjson [--tenant ID] FILE";
+
 fn main() {
     // CONFIG
     let config = Config::new_from_file("config.yaml")
         .unwrap_or_else(|err| panic!("failed to load config: {}", err));
-    logger::logger()
-        .log_entry(logger::Level::Debug, format!("{:?}", config))
-        .publish();
-    run(config)
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = parse_args(&args).unwrap_or_else(|err| {
+        eprintln!("{}\n{}", err, USAGE);
+        std::process::exit(2);
+    });
+
+    match command {
+        Command::Serve => {
+            logger::logger()
+                .log_entry(logger::Level::Debug, format!("{:?}", config))
+                .publish();
+            run(config)
+        },
+        command => {
+            if let Err(err) = run_transfer(config, command) {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        },
+    }
 }
 
 fn run(config: Config) {
     // RUNTIME
-    let runtime = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .unwrap_or_else(|err| panic!("failed to build tokio runtime: {}", err));
+    let runtime = init_runtime();
 
     // DB
-    let datastore = init_db(config.datastore, &runtime);
+    let datastore = init_db(&config.datastore, &runtime);
 
     // LOGIC CONTROLLER
-    let logic = Arc::new(Logic::new(datastore));
+    if config.verification.ephemeral_secret {
+        logger::logger()
+            .log_entry(
+                logger::Level::Warn,
+                "ephemeral verification secret, tokens won't survive a restart or work on other \
+                 replicas"
+                    .to_string(),
+            )
+            .publish();
+    }
+    let logic = init_logic(&config, Arc::clone(&datastore));
+
+    // AUTHENTICATION
+    let authenticator = Authenticator::from_config(&config.auth)
+        .unwrap_or_else(|err| panic!("failed to init authentication: {}", err))
+        .map(Arc::new);
+    if authenticator.is_none() {
+        logger::logger()
+            .log_entry(
+                logger::Level::Warn,
+                "authentication disabled, every request is anonymous".to_string(),
+            )
+            .publish();
+    }
+
+    // TENANCY
+    let tenants = TenantResolver::from_config(&config.tenancy)
+        .map(Arc::new)
+        .unwrap_or_else(|err| panic!("failed to init tenancy: {}", err));
 
     // HTTP SERVER
     let http_listener = http::create_listener(config.http_port)
         .unwrap_or_else(|err| panic!("failed to init http listener: {}", err));
-    let http_server = http::init(http_listener, Arc::clone(&logic))
-        .unwrap_or_else(|err| panic!("failed to init http server: {}", err));
+    let http_server = http::init(
+        http_listener,
+        Arc::clone(&logic),
+        authenticator.clone(),
+        Arc::clone(&tenants),
+    )
+    .unwrap_or_else(|err| panic!("failed to init http server: {}", err));
 
     // GRPC SERVER
-    let grpc_server = grpc::init(config.grpc_port, logic)
+    let grpc_server = grpc::init(config.grpc_port, logic, authenticator, tenants)
         .unwrap_or_else(|err| panic!("failed to init grpc server: {}", err));
 
     let http_task = runtime.spawn(async {
@@ -47,6 +119,18 @@ fn run(config: Config) {
         }
     });
 
+    // OUTBOX RELAY
+    let relay = Relay::new(
+        Arc::clone(&datastore),
+        init_event_sink(config.outbox.sink),
+    )
+    .with_config(config.outbox);
+    runtime.spawn(relay.run());
+
+    // BACKGROUND WORKER
+    let worker = Worker::new(datastore, Arc::new(SystemClock)).with_config(config.worker);
+    runtime.spawn(worker.run());
+
     let grpc_task = runtime.spawn(async {
         if let Err(e) = grpc_server.await {
             eprintln!("grpc server error: {}", e);
@@ -65,9 +149,199 @@ fn run(config: Config) {
     runtime.shutdown_timeout(Duration::from_secs(30));
 }
 
-fn init_db(config: ConfigDbType, runtime: &Runtime) -> Box<dyn Datastore + Send + Sync> {
+// -----------------------
+// CLI -------------------
+// -----------------------
+
+enum Command {
+    Serve,
+    Export {
+        format: TransferFormat,
+        tenant: Option<String>,
+        query: dto::Query,
+        path: String,
+    },
+    Import {
+        format: TransferFormat,
+        tenant: Option<String>,
+        path: String,
+    },
+}
+
+fn parse_args(args: &[String]) -> Result<Command, String> {
+    let (name, rest) = match args.split_first() {
+        None => return Ok(Command::Serve),
+        Some((name, rest)) => (name.as_str(), rest),
+    };
+    if name != "export" && name != "import" {
+        return Err(format!("unknown command: {}", name));
+    }
+
+    let mut format = None;
+    let mut tenant = None;
+    let mut query = dto::Query::default();
+    let mut path = None;
+
+    let mut rest = rest.iter();
+    while let Some(arg) = rest.next() {
+        let mut value = || {
+            rest.next()
+                .cloned()
+                .ok_or_else(|| format!("missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--format" => {
+                let name = value()?;
+                format = Some(
+                    TransferFormat::from_name(&name)
+                        .ok_or_else(|| format!("unknown format: {}", name))?,
+                );
+            },
+            "--tenant" => tenant = Some(value()?),
+            "--include-deleted" if name == "export" => query.include_deleted = true,
+            "--filter" if name == "export" => query.filter = Some(value()?),
+            "--sort" if name == "export" => query.sort = Some(value()?),
+            flag if flag.starts_with("--") => return Err(format!("unknown flag: {}", flag)),
+            _ if path.is_none() => path = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument: {}", arg)),
+        }
+    }
+
+    let format = format.ok_or("missing --format")?;
+    let path = path.ok_or("missing FILE")?;
+
+    Ok(match name {
+        "export" => Command::Export {
+            format,
+            tenant,
+            query,
+            path,
+        },
+        _ => Command::Import {
+            format,
+            tenant,
+            path,
+        },
+    })
+}
+
+// Exports to a file rather than stdout, which is where the logs go. Without
+// --tenant the configured default tenant is used.
+fn run_transfer(config: Config, command: Command) -> Result<(), String> {
+    let tenant = match command {
+        Command::Export {
+            ref tenant,
+            ..
+        }
+        | Command::Import {
+            ref tenant,
+            ..
+        } => tenant.clone(),
+        Command::Serve => None,
+    }
+    .or(config.tenancy.default_tenant.clone())
+    .ok_or("missing --tenant")?;
+    let tenant = TenantId::try_from(tenant)?;
+
+    let runtime = init_runtime();
+    let datastore = init_db(&config.datastore, &runtime);
+    let logic = init_logic(&config, datastore);
+
+    let ctx = Arc::new(Context::new());
+    ctx.store("trace_id", uuid::Uuid::new_v4().to_string());
+    ctx.store("actor", "cli".to_string());
+    ctx.store("tenant_id", tenant);
+
+    runtime.block_on(async move {
+        match command {
+            Command::Serve => Ok(()),
+            Command::Export {
+                format,
+                query,
+                path,
+                ..
+            } => {
+                let file = File::create(&path).map_err(|e| format!("{}: {}", path, e))?;
+                let mut out = BufWriter::new(file);
+                let mut encoder = Encoder::new(format, None);
+
+                let mut pages = Box::pin(logic.export_users(ctx, query));
+                while let Some(page) = pages.next().await {
+                    let page = page.map_err(|e| format!("export failed: {:?}", e))?;
+                    let bytes = encoder.encode(&page)?;
+                    out.write_all(&bytes)
+                        .map_err(|e| format!("{}: {}", path, e))?;
+                }
+                out.flush()
+                    .map_err(|e| format!("{}: {}", path, e))
+            },
+            Command::Import {
+                format,
+                path,
+                ..
+            } => {
+                let file = File::open(&path).map_err(|e| format!("{}: {}", path, e))?;
+                let report = logic
+                    .import_users(
+                        &ctx,
+                        futures::stream::iter(transfer::decode(format, file)),
+                    )
+                    .await
+                    .map_err(|e| format!("import failed: {:?}", e))?;
+                let js = serde_json::to_string_pretty(&report).map_err(|e| e.to_string())?;
+                println!("{}", js);
+                Ok(())
+            },
+        }
+    })
+}
+
+// -----------------------
+// INIT ------------------
+// -----------------------
+
+fn init_runtime() -> Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap_or_else(|err| panic!("failed to build tokio runtime: {}", err))
+}
+
+fn init_logic(config: &Config, datastore: Arc<dyn Datastore + Send + Sync>) -> Arc<Logic> {
+    let signer = TokenSigner::from_config(&config.verification)
+        .unwrap_or_else(|err| panic!("failed to init verification: {}", err));
+
+    Arc::new(
+        Logic::new(
+            datastore,
+            Arc::new(SystemClock),
+            init_id_generator(config.id_format),
+        )
+        .with_email_policy(config.email.clone())
+        .with_name_policy(config.user_name)
+        .with_idempotency(config.idempotency)
+        .with_mailer(mailer::from_config(&config.mailer))
+        .with_verification(config.verification.clone(), signer)
+        .with_authz(config.authz.clone()),
+    )
+}
+
+fn init_id_generator(format: ConfigIdFormat) -> Arc<dyn IdGenerator> {
+    match format {
+        ConfigIdFormat::UuidV4 => Arc::new(RandomIdGenerator),
+        ConfigIdFormat::UuidV7 => Arc::new(TimeOrderedIdGenerator),
+    }
+}
+
+fn init_event_sink(sink: OutboxSinkType) -> Arc<dyn EventSink> {
+    match sink {
+        OutboxSinkType::Log => Arc::new(LogSink),
+    }
+}
+
+fn init_db(config: &ConfigDbType, runtime: &Runtime) -> Arc<dyn Datastore + Send + Sync> {
     match config {
-        blueprint::ConfigDbType::InMem => Box::new(InMemDatastore::new()),
+        blueprint::ConfigDbType::InMem => Arc::new(InMemDatastore::new()),
         blueprint::ConfigDbType::MySql {
             addr,
             port,
@@ -75,7 +349,7 @@ fn init_db(config: ConfigDbType, runtime: &Runtime) -> Box<dyn Datastore + Send
             password,
         } => {
             let res =
-                runtime.block_on(async { SqlDatastore::new(&addr, port, &user, &password).await });
+                runtime.block_on(async { SqlDatastore::new(addr, *port, user, password).await });
 
             if let Err(e) = res {
                 panic!("failed to connect to db: {}", e);
@@ -84,7 +358,7 @@ fn init_db(config: ConfigDbType, runtime: &Runtime) -> Box<dyn Datastore + Send
             logger::logger()
                 .log_entry(logger::Level::Info, "MYSQL_CONNECTED".to_string())
                 .publish();
-            Box::new(res.unwrap())
+            Arc::new(res.unwrap())
         },
     }
 }
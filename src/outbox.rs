@@ -0,0 +1,235 @@
+use crate::{
+    datastore::{DataResult, Datastore},
+    logic::domain,
+    toolbox::logger,
+};
+use std::{sync::Arc, time::Duration};
+
+// Where relayed events end up. `publish` must only return Ok once the sink
+// has taken over the events: they are removed from the outbox right after.
+#[tonic::async_trait]
+pub trait EventSink: Send + Sync {
+    async fn publish(&self, events: &[domain::UserEvent]) -> Result<(), String>;
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct OutboxConfig {
+    pub sink: OutboxSinkType,
+    pub batch_size: usize,
+    pub poll_interval_ms: u64,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            sink: OutboxSinkType::Log,
+            batch_size: 100,
+            poll_interval_ms: 1000,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum OutboxSinkType {
+    #[default]
+    #[serde(rename = "log")]
+    Log,
+}
+
+// Writes every event to the log as one JSON line
+pub struct LogSink;
+
+#[tonic::async_trait]
+impl EventSink for LogSink {
+    async fn publish(&self, events: &[domain::UserEvent]) -> Result<(), String> {
+        for event in events.iter() {
+            let js = serde_json::to_string(event).map_err(|e| format!("LogSink: {e}"))?;
+            logger::logger()
+                .log_entry(logger::Level::Info, format!("event: {js}"))
+                .publish();
+        }
+        Ok(())
+    }
+}
+
+// Moves events from the outbox to the sink, at least once: a batch is acked
+// only after the sink accepted it, so a crash in between sends it again.
+// Consumers dedupe on (user_id, version).
+pub struct Relay {
+    datastore: Arc<dyn Datastore + Send + Sync>,
+    sink: Arc<dyn EventSink>,
+    batch_size: usize,
+    poll_interval: Duration,
+}
+
+impl Relay {
+    pub fn new(datastore: Arc<dyn Datastore + Send + Sync>, sink: Arc<dyn EventSink>) -> Self {
+        let config = OutboxConfig::default();
+        Relay {
+            datastore,
+            sink,
+            batch_size: config.batch_size,
+            poll_interval: Duration::from_millis(config.poll_interval_ms),
+        }
+    }
+
+    pub fn with_config(mut self, config: OutboxConfig) -> Self {
+        self.batch_size = config.batch_size.max(1);
+        self.poll_interval = Duration::from_millis(config.poll_interval_ms);
+        self
+    }
+
+    // Relays a single batch and returns its size
+    pub async fn relay_once(&self) -> DataResult<usize> {
+        let events = self
+            .datastore
+            .list_outbox(self.batch_size)
+            .await?;
+        if events.is_empty() {
+            return Ok(0);
+        }
+
+        if let Err(e) = self.sink.publish(&events).await {
+            logger::logger()
+                .log_entry(
+                    logger::Level::Warn,
+                    format!(
+                        "outbox: sink rejected {} events: {}",
+                        events.len(),
+                        e
+                    ),
+                )
+                .publish();
+            return Ok(0);
+        }
+
+        let seqs: Vec<u64> = events.iter().map(|e| e.seq).collect();
+        self.datastore.ack_outbox(&seqs).await?;
+
+        Ok(events.len())
+    }
+
+    // Drains full batches back to back, then waits for the next poll
+    pub async fn run(self) {
+        loop {
+            match self.relay_once().await {
+                Ok(n) if n == self.batch_size => continue,
+                Ok(_) => {},
+                Err(e) => logger::logger()
+                    .log_entry(
+                        logger::Level::Error,
+                        format!("outbox: relay failed: {}", e),
+                    )
+                    .publish(),
+            }
+
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EventSink, OutboxConfig, Relay};
+    use crate::{
+        datastore::{conformance::audit, inmem::InMemDatastore, Datastore},
+        logic::domain::{
+            AuditOperation, Email, TenantId, User, UserEvent, UserEventType, UserName, ID,
+        },
+    };
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    };
+
+    #[derive(Default)]
+    struct RecordingSink {
+        events: Mutex<Vec<UserEvent>>,
+        failing: AtomicBool,
+    }
+
+    #[tonic::async_trait]
+    impl EventSink for RecordingSink {
+        async fn publish(&self, events: &[UserEvent]) -> Result<(), String> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err("unavailable".to_string());
+            }
+            self.events
+                .lock()
+                .unwrap()
+                .extend_from_slice(events);
+            Ok(())
+        }
+    }
+
+    // Spread over two tenants, the outbox is shared
+    async fn store_users(ds: &InMemDatastore, count: usize) {
+        for i in 0..count {
+            let tenant = TenantId::try_from(["acme", "globex"][i % 2]).unwrap();
+            let usr = User::new(
+                ID::new(),
+                Email::try_from(format!("user{i}@acme.com")).unwrap(),
+                UserName::try_from(format!("User {i}")).unwrap(),
+                time::OffsetDateTime::now_utc(),
+            );
+            ds.store_user(
+                &tenant,
+                &usr,
+                &audit(&usr, AuditOperation::Create),
+            )
+            .await
+            .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn relay_in_batches() {
+        let ds = Arc::new(InMemDatastore::new());
+        let sink = Arc::new(RecordingSink::default());
+        let relay = Relay::new(ds.clone(), sink.clone()).with_config(OutboxConfig {
+            batch_size: 2,
+            ..Default::default()
+        });
+        store_users(&ds, 3).await;
+
+        assert_eq!(relay.relay_once().await.unwrap(), 2);
+        assert_eq!(relay.relay_once().await.unwrap(), 1);
+        assert_eq!(relay.relay_once().await.unwrap(), 0);
+
+        let events = sink.events.lock().unwrap();
+        let seqs: Vec<_> = events.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, [1, 2, 3]);
+        assert!(events
+            .iter()
+            .all(|e| e.event_type == UserEventType::UserCreated));
+        let tenants: Vec<_> = events
+            .iter()
+            .map(|e| e.tenant_id.as_str())
+            .collect();
+        assert_eq!(tenants, ["acme", "globex", "acme"]);
+    }
+
+    #[tokio::test]
+    async fn relay_keeps_events_the_sink_rejected() {
+        let ds = Arc::new(InMemDatastore::new());
+        let sink = Arc::new(RecordingSink::default());
+        let relay = Relay::new(ds.clone(), sink.clone());
+        store_users(&ds, 2).await;
+
+        sink.failing
+            .store(true, Ordering::SeqCst);
+        assert_eq!(relay.relay_once().await.unwrap(), 0);
+        assert_eq!(ds.list_outbox(10).await.unwrap().len(), 2);
+
+        sink.failing
+            .store(false, Ordering::SeqCst);
+        assert_eq!(relay.relay_once().await.unwrap(), 2);
+        assert!(ds
+            .list_outbox(10)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(sink.events.lock().unwrap().len(), 2);
+    }
+}
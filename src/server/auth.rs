@@ -0,0 +1,357 @@
+// Shared by the HTTP and gRPC servers: both take the same `Authorization`
+// value, "Bearer <jwt>" or "ApiKey <key>", and fail the same way.
+
+use crate::logic::{
+    domain::{Principal, PrincipalKind, TenantId},
+    error::{LogicError, LogicErrorCode},
+};
+use jsonwebtoken::{
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, str::FromStr};
+
+#[derive(serde::Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct AuthConfig {
+    // Off, every request is anonymous
+    pub enabled: bool,
+    // JWKS (RFC 7517) with the keys bearer tokens may be signed with
+    pub jwks_path: Option<String>,
+    // Required `iss` / `aud` claims, unchecked if unset
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    pub api_keys: Vec<ApiKeyConfig>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct ApiKeyConfig {
+    pub name: String,
+    // Hex SHA-256 of the key: the key itself never goes into config
+    pub sha256: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    // Pins the key to one tenant. Unpinned keys act for the tenant the
+    // request names.
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    roles: Vec<String>,
+    #[serde(default)]
+    tenant: Option<String>,
+}
+
+pub struct Authenticator {
+    keys: Vec<Jwk>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    api_keys: HashMap<String, Principal>, // <hex sha256, principal>
+}
+
+impl Authenticator {
+    // None if authentication is disabled
+    pub fn from_config(config: &AuthConfig) -> Result<Option<Self>, String> {
+        if !config.enabled {
+            return Ok(None);
+        }
+
+        let jwks = match config.jwks_path {
+            Some(ref path) => {
+                let content = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+                serde_json::from_str::<JwkSet>(&content).map_err(|e| format!("{path}: {e}"))?
+            },
+            None => JwkSet {
+                keys: Vec::new(),
+            },
+        };
+
+        Self::new(jwks, config).map(Some)
+    }
+
+    pub fn new(jwks: JwkSet, config: &AuthConfig) -> Result<Self, String> {
+        let mut api_keys = HashMap::new();
+        for key in config.api_keys.iter() {
+            let hash = key.sha256.to_ascii_lowercase();
+            if hash.len() != 64
+                || !hash
+                    .bytes()
+                    .all(|b| b.is_ascii_hexdigit())
+            {
+                return Err(format!(
+                    "api key {}: sha256 must be 64 hex digits",
+                    key.name
+                ));
+            }
+            let tenant = match key.tenant {
+                Some(ref t) => Some(
+                    TenantId::try_from(t.as_str())
+                        .map_err(|e| format!("api key {}: {e}", key.name))?,
+                ),
+                None => None,
+            };
+            api_keys.insert(
+                hash,
+                Principal {
+                    kind: PrincipalKind::ApiKey,
+                    subject: key.name.clone(),
+                    roles: key.roles.clone(),
+                    tenant,
+                },
+            );
+        }
+
+        Ok(Authenticator {
+            keys: jwks.keys,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            api_keys,
+        })
+    }
+
+    // `authorization` is the raw header/metadata value, if any
+    pub fn authenticate(&self, authorization: Option<&str>) -> Result<Principal, LogicError> {
+        let authorization = authorization.ok_or_else(|| unauthenticated("no credentials"))?;
+
+        let (scheme, credentials) = authorization
+            .trim()
+            .split_once(' ')
+            .ok_or_else(|| unauthenticated("malformed authorization"))?;
+        let credentials = credentials.trim();
+
+        match scheme {
+            s if s.eq_ignore_ascii_case("bearer") => self.check_jwt(credentials),
+            s if s.eq_ignore_ascii_case("apikey") => self.check_api_key(credentials),
+            s => Err(unauthenticated(&format!(
+                "unsupported scheme: {s}"
+            ))),
+        }
+    }
+
+    fn check_jwt(&self, token: &str) -> Result<Principal, LogicError> {
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|e| unauthenticated(&format!("jwt: {e}")))?;
+
+        // Without a kid, only an unambiguous key set will do
+        let jwk = match header.kid {
+            Some(ref kid) => self
+                .keys
+                .iter()
+                .find(|k| k.common.key_id.as_deref() == Some(kid)),
+            None if self.keys.len() == 1 => self.keys.first(),
+            None => None,
+        }
+        .ok_or_else(|| unauthenticated("jwt: unknown signing key"))?;
+
+        // A key pinned to an algorithm can't be used with another one. The
+        // key type itself is checked against the algorithm by `decode`.
+        if let Some(alg) = jwk.common.key_algorithm {
+            if Algorithm::from_str(&alg.to_string()).ok() != Some(header.alg) {
+                return Err(unauthenticated(
+                    "jwt: algorithm not allowed for key",
+                ));
+            }
+        }
+
+        let key = DecodingKey::from_jwk(jwk).map_err(|e| unauthenticated(&format!("jwt: {e}")))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        match self.audience {
+            Some(ref aud) => validation.set_audience(&[aud]),
+            None => validation.validate_aud = false,
+        }
+        if let Some(ref iss) = self.issuer {
+            validation.set_issuer(&[iss]);
+        }
+
+        let data = jsonwebtoken::decode::<Claims>(token, &key, &validation)
+            .map_err(|e| unauthenticated(&format!("jwt: {e}")))?;
+
+        let tenant = match data.claims.tenant {
+            Some(t) => {
+                Some(TenantId::try_from(t).map_err(|e| unauthenticated(&format!("jwt: {e}")))?)
+            },
+            None => None,
+        };
+
+        Ok(Principal {
+            kind: PrincipalKind::User,
+            subject: data.claims.sub,
+            roles: data.claims.roles,
+            tenant,
+        })
+    }
+
+    fn check_api_key(&self, key: &str) -> Result<Principal, LogicError> {
+        self.api_keys
+            .get(&sha256_hex(key))
+            .cloned()
+            .ok_or_else(|| unauthenticated("unknown api key"))
+    }
+}
+
+fn sha256_hex(value: &str) -> String {
+    Sha256::digest(value.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn unauthenticated(msg: &str) -> LogicError {
+    LogicError::new(LogicErrorCode::Unauthenticated).with_internal_msg(msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sha256_hex, ApiKeyConfig, AuthConfig, Authenticator};
+    use crate::logic::{
+        domain::{Principal, PrincipalKind, TenantId},
+        error::LogicErrorCode,
+    };
+    use jsonwebtoken::{jwk::JwkSet, Algorithm, EncodingKey, Header};
+
+    // base64url("secret-key-for-tests")
+    const JWKS: &str = r#"{"keys": [
+        {"kty": "oct", "kid": "k1", "alg": "HS256", "k": "c2VjcmV0LWtleS1mb3ItdGVzdHM"}
+    ]}"#;
+
+    fn authenticator() -> Authenticator {
+        let config = AuthConfig {
+            enabled: true,
+            issuer: Some("https://issuer.test".to_string()),
+            api_keys: vec![ApiKeyConfig {
+                name: "ci".to_string(),
+                sha256: sha256_hex("key-1").to_uppercase(),
+                roles: vec!["admin".to_string()],
+                tenant: None,
+            }],
+            ..Default::default()
+        };
+        Authenticator::new(
+            serde_json::from_str::<JwkSet>(JWKS).unwrap(),
+            &config,
+        )
+        .unwrap()
+    }
+
+    fn jwt(kid: &str, alg: Algorithm, claims: serde_json::Value) -> String {
+        let mut header = Header::new(alg);
+        header.kid = Some(kid.to_string());
+        jsonwebtoken::encode(
+            &header,
+            &claims,
+            &EncodingKey::from_secret(b"secret-key-for-tests"),
+        )
+        .unwrap()
+    }
+
+    fn in_an_hour() -> i64 {
+        time::OffsetDateTime::now_utc().unix_timestamp() + 3600
+    }
+
+    #[test]
+    fn bearer_jwt() {
+        let auth = authenticator();
+        let token = jwt(
+            "k1",
+            Algorithm::HS256,
+            serde_json::json!({"sub": "alice", "iss": "https://issuer.test", "exp": in_an_hour(), "roles": ["user"], "tenant": "acme"}),
+        );
+
+        assert_eq!(
+            auth.authenticate(Some(&format!("Bearer {token}")))
+                .unwrap(),
+            Principal {
+                kind: PrincipalKind::User,
+                subject: "alice".to_string(),
+                roles: vec!["user".to_string()],
+                tenant: Some(TenantId::try_from("acme").unwrap()),
+            }
+        );
+    }
+
+    #[test]
+    fn bearer_jwt_rejected() {
+        let auth = authenticator();
+        let cases = [
+            // expired
+            jwt(
+                "k1",
+                Algorithm::HS256,
+                serde_json::json!({"sub": "alice", "iss": "https://issuer.test", "exp": 1}),
+            ),
+            // wrong issuer
+            jwt(
+                "k1",
+                Algorithm::HS256,
+                serde_json::json!({"sub": "alice", "iss": "https://evil.test", "exp": in_an_hour()}),
+            ),
+            // unknown key
+            jwt(
+                "k2",
+                Algorithm::HS256,
+                serde_json::json!({"sub": "alice", "iss": "https://issuer.test", "exp": in_an_hour()}),
+            ),
+            // algorithm the key isn't for
+            jwt(
+                "k1",
+                Algorithm::HS512,
+                serde_json::json!({"sub": "alice", "iss": "https://issuer.test", "exp": in_an_hour()}),
+            ),
+            // no subject
+            jwt(
+                "k1",
+                Algorithm::HS256,
+                serde_json::json!({"iss": "https://issuer.test", "exp": in_an_hour()}),
+            ),
+            // malformed tenant
+            jwt(
+                "k1",
+                Algorithm::HS256,
+                serde_json::json!({"sub": "alice", "iss": "https://issuer.test", "exp": in_an_hour(), "tenant": "a/b"}),
+            ),
+            "not.a.jwt".to_string(),
+        ];
+
+        for token in cases {
+            let err = auth
+                .authenticate(Some(&format!("Bearer {token}")))
+                .unwrap_err();
+            assert!(
+                matches!(err.code(), LogicErrorCode::Unauthenticated),
+                "{token}"
+            );
+        }
+    }
+
+    #[test]
+    fn api_key() {
+        let auth = authenticator();
+        assert_eq!(
+            auth.authenticate(Some("ApiKey key-1"))
+                .unwrap(),
+            Principal {
+                kind: PrincipalKind::ApiKey,
+                subject: "ci".to_string(),
+                roles: vec!["admin".to_string()],
+                tenant: None,
+            }
+        );
+
+        for value in [
+            None,
+            Some(""),
+            Some("ApiKey key-2"),
+            Some("Basic a2V5LTE="),
+            Some("key-1"),
+        ] {
+            assert!(auth.authenticate(value).is_err(), "{value:?}");
+        }
+    }
+}
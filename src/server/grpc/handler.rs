@@ -1,10 +1,15 @@
 use crate::{
-    logic,
+    logic::{
+        self,
+        domain::{self, Principal, TenantId},
+        dto,
+        error::{LogicError, LogicErrorCode},
+    },
     proto::{self, blueprint_server},
     toolbox::context,
 };
 use std::sync::Arc;
-use tonic::{Request, Response, Status};
+use tonic::{metadata::MetadataMap, Request, Response, Status};
 
 pub struct BlueprintServerImpl {
     logic: Arc<logic::Logic>,
@@ -26,14 +31,17 @@ impl BlueprintServerImpl {}
 #[tonic::async_trait]
 impl blueprint_server::Blueprint for BlueprintServerImpl {
     async fn create_user(&self, request: Request<proto::CreateUserRequest>) -> Result<Response<proto::User>, Status> {
+        let key = idempotency_key(request.metadata())?;
+        let ctx = new_ctx(&request);
         let request = request.into_inner();
-        let tid = uuid::Uuid::new_v4().to_string(); // todo
-        let ctx = context::Context::new();
-        ctx.store("trace_id", tid);
-        
+        if let Some(key) = key {
+            ctx.store("idempotency_key", key);
+        }
+
         let req = logic::dto::CreateUserRequest {
             email: request.email,
             name: request.name,
+            metadata: request.metadata.map(domain::metadata_from_proto).unwrap_or_default(),
         };
 
         match self.logic.create_user(&ctx, req).await {
@@ -42,29 +50,200 @@ impl blueprint_server::Blueprint for BlueprintServerImpl {
         }
     }
 
-    async fn get_user(&self, request: Request<String>) -> Result<Response<proto::User>, Status> {
+    async fn batch_create_users(&self, request: Request<proto::BatchCreateUsersRequest>) -> Result<Response<proto::BatchCreateUsersResponse>, Status> {
+        let ctx = new_ctx(&request);
+        let request = request.into_inner();
+
+        let req = logic::dto::BatchCreateUsersRequest {
+            items: request.items.into_iter().map(|item| logic::dto::CreateUserRequest {
+                email: item.email,
+                name: item.name,
+                metadata: item.metadata.map(domain::metadata_from_proto).unwrap_or_default(),
+            }).collect(),
+            mode: match request.best_effort {
+                true => logic::dto::BatchMode::BestEffort,
+                false => logic::dto::BatchMode::AllOrNothing,
+            },
+        };
+
+        match self.logic.batch_create_users(&ctx, req).await {
+            Ok(results) => Ok(Response::new(results.into())),
+            Err(service_error) => Err(service_error.into()),
+        }
+    }
+
+    async fn get_user(&self, request: Request<proto::GetUserRequest>) -> Result<Response<proto::User>, Status> {
+        let ctx = new_ctx(&request);
+        let request = request.into_inner();
+
+        let opts = logic::dto::GetUserOptions {
+            include_deleted: request.include_deleted,
+        };
+        let mask = parse_field_mask(request.field_mask)?;
+
+        match self.logic.get_user(&ctx, &request.id, opts).await {
+            Ok(obj) => Ok(Response::new(project_user(obj.into(), &mask))),
+            Err(service_error) => Err(service_error.into()),
+        }
+    }
+
+    async fn update_user(&self, request: Request<proto::UpdateUserRequest>) -> Result<Response<proto::User>, Status> {
+        let ctx = new_ctx(&request);
+        let request = request.into_inner();
+
+        let req = logic::dto::UpdateUserRequest {
+            email: request.email,
+            name: request.name,
+            metadata: request.metadata.map(domain::metadata_from_proto),
+        };
+
+        match self.logic.update_user(&ctx, &request.id, req, request.expected_version).await {
+            Ok(obj) => Ok(Response::new(obj.into())),
+            Err(service_error) => Err(service_error.into()),
+        }
+    }
+
+    async fn delete_user(&self, request: Request<proto::DeleteUserRequest>) -> Result<Response<()>, Status> {
+        let ctx = new_ctx(&request);
+        let request = request.into_inner();
+
+        match self.logic.delete_user(&ctx, &request.id, request.expected_version).await {
+            Ok(_) => Ok(Response::new(())),
+            Err(service_error) => Err(service_error.into()),
+        }
+    }
+
+    async fn restore_user(&self, request: Request<proto::RestoreUserRequest>) -> Result<Response<proto::User>, Status> {
+        let ctx = new_ctx(&request);
+        let request = request.into_inner();
+
+        match self.logic.restore_user(&ctx, &request.id, request.expected_version).await {
+            Ok(obj) => Ok(Response::new(obj.into())),
+            Err(service_error) => Err(service_error.into()),
+        }
+    }
+
+    async fn suspend_user(&self, request: Request<proto::ChangeUserStatusRequest>) -> Result<Response<proto::User>, Status> {
+        let ctx = new_ctx(&request);
+        let request = request.into_inner();
+
+        match self.logic.suspend_user(&ctx, &request.id, request.expected_version).await {
+            Ok(obj) => Ok(Response::new(obj.into())),
+            Err(service_error) => Err(service_error.into()),
+        }
+    }
+
+    async fn reactivate_user(&self, request: Request<proto::ChangeUserStatusRequest>) -> Result<Response<proto::User>, Status> {
+        let ctx = new_ctx(&request);
+        let request = request.into_inner();
+
+        match self.logic.reactivate_user(&ctx, &request.id, request.expected_version).await {
+            Ok(obj) => Ok(Response::new(obj.into())),
+            Err(service_error) => Err(service_error.into()),
+        }
+    }
+
+    async fn deactivate_user(&self, request: Request<proto::ChangeUserStatusRequest>) -> Result<Response<proto::User>, Status> {
+        let ctx = new_ctx(&request);
+        let request = request.into_inner();
+
+        match self.logic.deactivate_user(&ctx, &request.id, request.expected_version).await {
+            Ok(obj) => Ok(Response::new(obj.into())),
+            Err(service_error) => Err(service_error.into()),
+        }
+    }
+
+    async fn verify_user(&self, request: Request<proto::VerifyUserRequest>) -> Result<Response<proto::User>, Status> {
+        let ctx = new_ctx(&request);
         let request = request.into_inner();
-        let tid = uuid::Uuid::new_v4().to_string(); // todo
-        let ctx = context::Context::new();
-        ctx.store("trace_id", tid);
 
-        match self.logic.get_user(&ctx, &request).await {
+        match self.logic.verify_user(&ctx, &request.id, &request.token).await {
             Ok(obj) => Ok(Response::new(obj.into())),
             Err(service_error) => Err(service_error.into()),
         }
     }
 
-    async fn list_users(&self, _: Request<proto::Query>) -> Result<Response<proto::UserList>, Status> {
-        // let request = request.into_inner();
-        let tid = uuid::Uuid::new_v4().to_string(); // todo
-        let ctx = context::Context::new();
-        ctx.store("trace_id", tid);
+    async fn list_users(&self, request: Request<proto::Query>) -> Result<Response<proto::UserList>, Status> {
+        let ctx = new_ctx(&request);
+        let request = request.into_inner();
         
-        let req = logic::dto::Query {};
+        let req = logic::dto::Query {
+            include_deleted: request.include_deleted,
+            page_size: Some(request.page_size),
+            page_token: Some(request.page_token),
+            filter: Some(request.filter),
+            sort: Some(request.sort),
+        };
+        let mask = parse_field_mask(request.field_mask)?;
 
         match self.logic.list_users(&ctx, req).await {
+            Ok(results) => {
+                let mut results: proto::UserList = results.into();
+                results.items = results.items.into_iter().map(|u| project_user(u, &mask)).collect();
+                Ok(Response::new(results))
+            },
+            Err(service_error) => Err(service_error.into()),
+        }
+    }
+
+    async fn get_user_history(&self, request: Request<proto::GetUserHistoryRequest>) -> Result<Response<proto::UserHistory>, Status> {
+        let ctx = new_ctx(&request);
+        let request = request.into_inner();
+
+        match self.logic.get_user_history(&ctx, &request.id).await {
             Ok(results) => Ok(Response::new(results.into())),
             Err(service_error) => Err(service_error.into()),
         }
     }
 }
+
+// With the principal the interceptor authenticated, if any
+fn new_ctx<T>(request: &Request<T>) -> context::Context {
+    let tid = uuid::Uuid::new_v4().to_string(); // todo
+    let ctx = context::Context::new();
+    ctx.store("trace_id", tid);
+    if let Some(principal) = request.extensions().get::<Principal>() {
+        ctx.store("principal", principal.clone());
+    }
+    if let Some(tenant) = request.extensions().get::<TenantId>() {
+        ctx.store("tenant_id", tenant.clone());
+    }
+    ctx
+}
+
+// "idempotency-key" metadata, the counterpart of the HTTP header
+fn idempotency_key(metadata: &MetadataMap) -> Result<Option<String>, Status> {
+    match metadata.get("idempotency-key") {
+        Some(value) => match value.to_str() {
+            Ok(v) => Ok(Some(v.to_string())),
+            Err(e) => Err(
+                LogicError::new(LogicErrorCode::InvalidIdempotencyKey)
+                    .wrap(e)
+                    .into(),
+            ),
+        },
+        None => Ok(None),
+    }
+}
+
+// An empty mask means "all fields"
+fn parse_field_mask(
+    mask: Option<prost_types::FieldMask>,
+) -> Result<Option<dto::FieldMask>, Status> {
+    match mask {
+        Some(mask) if !mask.paths.is_empty() => match dto::FieldMask::parse(mask.paths) {
+            Ok(v) => Ok(Some(v)),
+            Err(e) => Err(LogicError::new(LogicErrorCode::InvalidQuery)
+                .with_internal_msg(e)
+                .into()),
+        },
+        _ => Ok(None),
+    }
+}
+
+fn project_user(usr: proto::User, mask: &Option<dto::FieldMask>) -> proto::User {
+    match mask {
+        Some(mask) => mask.apply_proto(usr),
+        None => usr,
+    }
+}
@@ -1,16 +1,27 @@
 mod handler;
 
+use super::{auth::Authenticator, tenancy};
 use crate::{
-    logic::{self},
+    logic::{self, domain::Principal},
     proto::blueprint_server::BlueprintServer,
+    toolbox::logger,
 };
 use futures::Future;
 use std::{error::Error, net::SocketAddr, sync::Arc};
 use tokio::signal::unix::SignalKind;
-use tonic::{transport::Server, Request, Status};
+use tonic::{
+    metadata::{MetadataMap, MetadataValue},
+    transport::Server,
+    Request, Status,
+};
+
+// Metadata that never goes into the log
+const SECRET_METADATA: &[&str] = &["authorization"];
 
+// Without an authenticator every request is anonymous
 pub fn init(
-    port: u16, logic: Arc<logic::Logic>,
+    port: u16, logic: Arc<logic::Logic>, authenticator: Option<Arc<Authenticator>>,
+    tenants: Arc<tenancy::TenantResolver>,
 ) -> Result<impl Future<Output = Result<(), tonic::transport::Error>>, Box<dyn Error>> {
     let addr = format!("127.0.0.1:{}", port);
     let addr: SocketAddr = addr.parse()?;
@@ -18,7 +29,11 @@ pub fn init(
     // blueprint_server implementation
     let handler = handler::BlueprintServerImpl::new(logic);
 
-    let svr = BlueprintServer::with_interceptor(handler, intercept_logger);
+    let svr = BlueprintServer::with_interceptor(handler, move |req| {
+        let req = intercept_logger(req)?;
+        let req = authenticate(authenticator.as_deref(), req)?;
+        resolve_tenant(&tenants, req)
+    });
 
     let server = Server::builder()
         .add_service(svr)
@@ -37,8 +52,77 @@ async fn shutdown_watcher() {
     }
 }
 
+// The principal travels to the handlers as a request extension
+fn authenticate(
+    authenticator: Option<&Authenticator>, mut req: Request<()>,
+) -> Result<Request<()>, Status> {
+    let authenticator = match authenticator {
+        Some(v) => v,
+        None => return Ok(req),
+    };
+
+    let authorization = req
+        .metadata()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok());
+    let principal = authenticator.authenticate(authorization)?;
+    req.extensions_mut().insert(principal);
+
+    Ok(req)
+}
+
+// So does the tenant, picked once the principal is known
+fn resolve_tenant(
+    tenants: &tenancy::TenantResolver, mut req: Request<()>,
+) -> Result<Request<()>, Status> {
+    let requested = req
+        .metadata()
+        .get(tenancy::TENANT_HEADER)
+        .map(|v| v.to_str().unwrap_or_default());
+    let tenant = tenants.resolve(req.extensions().get::<Principal>(), requested)?;
+    req.extensions_mut().insert(tenant);
+
+    Ok(req)
+}
+
 fn intercept_logger(req: Request<()>) -> Result<Request<()>, Status> {
-    // todo: use "tower" instead of interceptor
-    println!("Intercepting request: {:?}", req);
+    // todo: use "tower" instead of interceptor, it would see the method too
+    logger::logger()
+        .log_entry(
+            logger::Level::Info,
+            format!("grpc.request({:?})", redacted(req.metadata())),
+        )
+        .publish();
     Ok(req)
 }
+
+fn redacted(metadata: &MetadataMap) -> MetadataMap {
+    let mut metadata = metadata.clone();
+    for key in SECRET_METADATA {
+        if metadata.contains_key(*key) {
+            metadata.insert(*key, MetadataValue::from_static("[redacted]"));
+        }
+    }
+    metadata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::redacted;
+    use tonic::metadata::MetadataMap;
+
+    #[test]
+    fn credentials_redacted() {
+        let mut metadata = MetadataMap::new();
+        metadata.insert(
+            "authorization",
+            "Bearer secret-jwt".parse().unwrap(),
+        );
+        metadata.insert("x-tenant-id", "acme".parse().unwrap());
+
+        let logged = format!("{:?}", redacted(&metadata));
+        assert!(!logged.contains("secret-jwt"), "{logged}");
+        assert!(logged.contains("[redacted]"), "{logged}");
+        assert!(logged.contains("acme"), "{logged}");
+    }
+}
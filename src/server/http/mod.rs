@@ -1,13 +1,15 @@
 mod routes;
 
+use super::{auth::Authenticator, tenancy};
 use crate::{
     logic::{self},
     toolbox::{context, logger},
 };
 use actix_web::{
-    body::MessageBody,
+    body::{EitherBody, MessageBody},
     dev::{ServiceRequest, ServiceResponse},
-    web, HttpMessage,
+    http::header,
+    web, HttpMessage, HttpResponse,
 };
 use actix_web_lab::middleware::{self, CatchPanic};
 use std::{error::Error, net::TcpListener, sync::Arc};
@@ -18,17 +20,27 @@ pub fn create_listener(port: u16) -> Result<TcpListener, Box<dyn Error>> {
     Ok(listener)
 }
 
+// Without an authenticator every request is anonymous
 pub fn init(
-    listener: TcpListener, logic: Arc<logic::Logic>,
+    listener: TcpListener, logic: Arc<logic::Logic>, authenticator: Option<Arc<Authenticator>>,
+    tenants: Arc<tenancy::TenantResolver>,
 ) -> Result<actix_web::dev::Server, Box<dyn Error>> {
     let app_init = move || {
         let logic = web::Data::from(Arc::clone(&logic));
+        let authenticator = web::Data::new(authenticator.clone());
+        let tenants = web::Data::from(Arc::clone(&tenants));
 
         actix_web::App::new()
             // Attach logic controller
             .app_data(logic)
+            .app_data(authenticator)
+            .app_data(tenants)
             // Turn panic into 500
             .wrap(CatchPanic::default())
+            // Pick the tenant, runs after authenticate
+            .wrap(middleware::from_fn(resolve_tenant))
+            // Reject requests without valid credentials
+            .wrap(middleware::from_fn(authenticate))
             // Custom request/response logging middleware
             .wrap(middleware::from_fn(custom_logger_mw))
             // Inject Context into request
@@ -57,6 +69,68 @@ async fn inject_context(
     next.call(req).await
 }
 
+// Puts the principal into the Context. Health checks stay open.
+async fn authenticate(
+    req: ServiceRequest, next: middleware::Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let authenticator = req
+        .app_data::<web::Data<Option<Arc<Authenticator>>>>()
+        .and_then(|data| data.as_ref().clone());
+
+    if let Some(authenticator) = authenticator.filter(|_| req.path() != "/healthz") {
+        let authorization = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok());
+
+        match authenticator.authenticate(authorization) {
+            Ok(principal) => ctx_from_req(req.request()).store("principal", principal),
+            Err(e) => {
+                let resp = HttpResponse::from_error(e);
+                return Ok(req
+                    .into_response(resp)
+                    .map_into_right_body());
+            },
+        }
+    }
+
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+// Puts the tenant into the Context, once the principal is known. A header
+// that isn't valid UTF-8 is rejected like any other malformed tenant.
+async fn resolve_tenant(
+    req: ServiceRequest, next: middleware::Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let tenants = req
+        .app_data::<web::Data<tenancy::TenantResolver>>()
+        .cloned();
+
+    if let Some(tenants) = tenants.filter(|_| req.path() != "/healthz") {
+        let ctx = ctx_from_req(req.request());
+        let requested = req
+            .headers()
+            .get(tenancy::TENANT_HEADER)
+            .map(|v| v.to_str().unwrap_or_default());
+
+        match tenants.resolve(ctx.get_clone("principal").as_ref(), requested) {
+            Ok(tenant) => ctx.store("tenant_id", tenant),
+            Err(e) => {
+                let resp = HttpResponse::from_error(e);
+                return Ok(req
+                    .into_response(resp)
+                    .map_into_right_body());
+            },
+        }
+    }
+
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
 async fn custom_logger_mw(
     req: ServiceRequest, next: middleware::Next<impl MessageBody>,
 ) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
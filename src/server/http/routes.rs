@@ -1,16 +1,22 @@
 use crate::{
     logic::{
-        dto,
+        domain, dto,
         error::{LogicError, LogicErrorCode},
+        transfer::{self, Encoder, TransferFormat},
         Logic,
     },
-    toolbox::logger,
+    toolbox::{context::Context, logger},
 };
 use actix_web::{
-    http::Method,
+    http::{
+        header::{self, EntityTag, Header},
+        Method,
+    },
     web::{self, ServiceConfig},
-    HttpRequest, HttpResponse, Resource, Responder, Route, Scope,
+    HttpMessage, HttpRequest, HttpResponse, Resource, Responder, Route, Scope,
 };
+use futures::{channel::mpsc, stream, SinkExt, StreamExt};
+use std::sync::Arc;
 
 pub type HttpResult = std::result::Result<HttpResponse, LogicError>;
 
@@ -39,11 +45,84 @@ pub(super) fn endpoints(cfg: &mut ServiceConfig) {
                     ),
             )
             .service(
-                Resource::new("/users/{id}").route(
+                Resource::new("/users/{id}/history").route(
                     Route::new()
                         .method(Method::GET)
-                        .to(get_user),
+                        .to(get_user_history),
+                ),
+            )
+            // Custom methods must be registered before "/users/{id}"
+            .service(
+                Resource::new("/users:import").route(
+                    Route::new()
+                        .method(Method::POST)
+                        .to(import_users),
+                ),
+            )
+            .service(
+                Resource::new("/users:batchCreate").route(
+                    Route::new()
+                        .method(Method::POST)
+                        .to(batch_create_users),
+                ),
+            )
+            .service(
+                Resource::new("/users/{id}:restore").route(
+                    Route::new()
+                        .method(Method::POST)
+                        .to(restore_user),
+                ),
+            )
+            .service(
+                Resource::new("/users/{id}:suspend").route(
+                    Route::new()
+                        .method(Method::POST)
+                        .to(suspend_user),
+                ),
+            )
+            .service(
+                Resource::new("/users/{id}:reactivate").route(
+                    Route::new()
+                        .method(Method::POST)
+                        .to(reactivate_user),
                 ),
+            )
+            .service(
+                Resource::new("/users/{id}:deactivate").route(
+                    Route::new()
+                        .method(Method::POST)
+                        .to(deactivate_user),
+                ),
+            )
+            .service(
+                Resource::new("/users/{id}:verify").route(
+                    Route::new()
+                        .method(Method::POST)
+                        .to(verify_user),
+                ),
+            )
+            .service(
+                Resource::new("/users/{id}")
+                    .route(
+                        Route::new()
+                            .method(Method::GET)
+                            .to(get_user),
+                    )
+                    .route(
+                        Route::new()
+                            .method(Method::PUT)
+                            .to(put_user),
+                    )
+                    .route(
+                        Route::new()
+                            .method(Method::PATCH)
+                            .to(patch_user),
+                    )
+                    .route(
+                        Route::new()
+                            .method(Method::DELETE)
+                            .to(delete_user),
+                    ),
             ),
     );
 }
@@ -56,6 +135,9 @@ pub(super) async fn post_user(
     logic: web::Data<Logic>, req: HttpRequest, body: web::Bytes,
 ) -> HttpResult {
     let ctx = super::ctx_from_req(&req);
+    if let Some(key) = parse_idempotency_key(&req)? {
+        ctx.store("idempotency_key", key);
+    }
     let data = serde_json::from_slice::<dto::CreateUserRequest>(&body);
 
     if let Err(json_err) = data {
@@ -67,21 +149,362 @@ pub(super) async fn post_user(
     let data = data.unwrap();
     let result = logic.create_user(&ctx, data).await?;
 
-    Ok(HttpResponse::Created().json(result))
+    Ok(HttpResponse::Created()
+        .insert_header(etag(&result))
+        .json(result))
+}
+
+// 200 with one result per item, failed items don't fail the request
+pub(super) async fn batch_create_users(
+    logic: web::Data<Logic>, req: HttpRequest, body: web::Bytes,
+) -> HttpResult {
+    let ctx = super::ctx_from_req(&req);
+    let data = match serde_json::from_slice::<dto::BatchCreateUsersRequest>(&body) {
+        Ok(data) => data,
+        Err(json_err) => {
+            return Err(LogicError::new(LogicErrorCode::UserInvalidData).wrap(json_err))
+        },
+    };
+
+    let result = logic
+        .batch_create_users(&ctx, data)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(result))
 }
 
 pub(super) async fn get_user(logic: web::Data<Logic>, req: HttpRequest) -> HttpResult {
     let ctx = super::ctx_from_req(&req);
     let id = req.match_info().get("id").unwrap();
-    let result = logic.get_user(&ctx, id).await?;
+    let opts = parse_query::<dto::GetUserOptions>(&req)?;
+    let mask = parse_fields(&req)?;
+    let result = logic.get_user(&ctx, id, opts).await?;
+
+    let mut resp = HttpResponse::Ok();
+    resp.insert_header(etag(&result));
+
+    match mask {
+        Some(mask) => Ok(resp.json(mask.apply_json(to_json(&result)))),
+        None => Ok(resp.json(result)),
+    }
+}
+
+pub(super) async fn put_user(
+    logic: web::Data<Logic>, req: HttpRequest, body: web::Bytes,
+) -> HttpResult {
+    let ctx = super::ctx_from_req(&req);
+    let id = req.match_info().get("id").unwrap();
+    let data = serde_json::from_slice::<dto::ReplaceUserRequest>(&body);
+
+    if let Err(json_err) = data {
+        return Err(LogicError::new(LogicErrorCode::UserInvalidData).wrap(json_err));
+    }
+
+    let data = data.unwrap();
+    let expected_version = parse_if_match(&req)?;
+    let result = logic
+        .update_user(&ctx, id, data.into(), expected_version)
+        .await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(etag(&result))
+        .json(result))
+}
+
+pub(super) async fn patch_user(
+    logic: web::Data<Logic>, req: HttpRequest, body: web::Bytes,
+) -> HttpResult {
+    let ctx = super::ctx_from_req(&req);
+    let id = req.match_info().get("id").unwrap();
+    let data = serde_json::from_slice::<dto::UpdateUserRequest>(&body);
+
+    if let Err(json_err) = data {
+        return Err(LogicError::new(LogicErrorCode::UserInvalidData).wrap(json_err));
+    }
+
+    let data = data.unwrap();
+    let expected_version = parse_if_match(&req)?;
+    let result = logic
+        .update_user(&ctx, id, data, expected_version)
+        .await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(etag(&result))
+        .json(result))
+}
+
+pub(super) async fn delete_user(logic: web::Data<Logic>, req: HttpRequest) -> HttpResult {
+    let ctx = super::ctx_from_req(&req);
+    let id = req.match_info().get("id").unwrap();
+    let expected_version = parse_if_match(&req)?;
+    logic
+        .delete_user(&ctx, id, expected_version)
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub(super) async fn restore_user(logic: web::Data<Logic>, req: HttpRequest) -> HttpResult {
+    let ctx = super::ctx_from_req(&req);
+    let id = req.match_info().get("id").unwrap();
+    let expected_version = parse_if_match(&req)?;
+    let result = logic
+        .restore_user(&ctx, id, expected_version)
+        .await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(etag(&result))
+        .json(result))
+}
+
+pub(super) async fn suspend_user(logic: web::Data<Logic>, req: HttpRequest) -> HttpResult {
+    let ctx = super::ctx_from_req(&req);
+    let id = req.match_info().get("id").unwrap();
+    let expected_version = parse_if_match(&req)?;
+    let result = logic
+        .suspend_user(&ctx, id, expected_version)
+        .await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(etag(&result))
+        .json(result))
+}
+
+pub(super) async fn reactivate_user(logic: web::Data<Logic>, req: HttpRequest) -> HttpResult {
+    let ctx = super::ctx_from_req(&req);
+    let id = req.match_info().get("id").unwrap();
+    let expected_version = parse_if_match(&req)?;
+    let result = logic
+        .reactivate_user(&ctx, id, expected_version)
+        .await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(etag(&result))
+        .json(result))
+}
+
+pub(super) async fn deactivate_user(logic: web::Data<Logic>, req: HttpRequest) -> HttpResult {
+    let ctx = super::ctx_from_req(&req);
+    let id = req.match_info().get("id").unwrap();
+    let expected_version = parse_if_match(&req)?;
+    let result = logic
+        .deactivate_user(&ctx, id, expected_version)
+        .await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(etag(&result))
+        .json(result))
+}
+
+pub(super) async fn verify_user(
+    logic: web::Data<Logic>, req: HttpRequest, body: web::Bytes,
+) -> HttpResult {
+    let ctx = super::ctx_from_req(&req);
+    let id = req.match_info().get("id").unwrap();
+    let data = serde_json::from_slice::<dto::VerifyUserRequest>(&body);
+
+    if let Err(json_err) = data {
+        return Err(LogicError::new(LogicErrorCode::UserInvalidData).wrap(json_err));
+    }
+
+    let data = data.unwrap();
+    let result = logic
+        .verify_user(&ctx, id, &data.token)
+        .await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(etag(&result))
+        .json(result))
+}
+
+pub(super) async fn get_user_history(logic: web::Data<Logic>, req: HttpRequest) -> HttpResult {
+    let ctx = super::ctx_from_req(&req);
+    let id = req.match_info().get("id").unwrap();
+    let result = logic.get_user_history(&ctx, id).await?;
 
     Ok(HttpResponse::Ok().json(result))
 }
 
 pub(super) async fn list_users(logic: web::Data<Logic>, req: HttpRequest) -> HttpResult {
     let ctx = super::ctx_from_req(&req);
-    let query = dto::Query {};
+    let query = parse_query::<dto::Query>(&req)?;
+    let mask = parse_fields(&req)?;
+
+    if let Some(format) = export_format(&req) {
+        return export_users(logic, ctx, query, mask, format).await;
+    }
+
     let result = logic.list_users(&ctx, query).await?;
 
-    Ok(HttpResponse::Ok().json(result))
+    let mask = match mask {
+        Some(mask) => mask,
+        None => return Ok(HttpResponse::Ok().json(result)),
+    };
+
+    let mut js = to_json(&result);
+    if let Some(items) = js
+        .get_mut("items")
+        .and_then(|v| v.as_array_mut())
+    {
+        for item in items.iter_mut() {
+            *item = mask.apply_json(item.take());
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(js))
+}
+
+// "Accept: text/csv" or "application/x-ndjson" exports every page of the
+// query as one streamed response
+async fn export_users(
+    logic: web::Data<Logic>, ctx: Arc<Context>, query: dto::Query, mask: Option<dto::FieldMask>,
+    format: TransferFormat,
+) -> HttpResult {
+    let mut pages = Box::pin(
+        logic
+            .into_inner()
+            .export_users(ctx, query),
+    );
+
+    // Errors of the first page (bad filter, page token...) still get a
+    // proper error response, later ones cut the stream short
+    let first = pages
+        .next()
+        .await
+        .unwrap_or_else(|| Ok(Vec::new()))?;
+    let mut encoder = Encoder::new(format, mask);
+    let body = stream::once(async { Ok(first) })
+        .chain(pages)
+        .map(move |page| {
+            let page = page?;
+            match encoder.encode(&page) {
+                Ok(bytes) => Ok(web::Bytes::from(bytes)),
+                Err(e) => {
+                    Err(LogicError::new(LogicErrorCode::UnexpectedError).with_internal_msg(e))
+                },
+            }
+        });
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.mime())
+        .streaming(body))
+}
+
+// Content-Type picks the format, the report lists rejected rows by line.
+// The body is decoded while it arrives, it's never held whole.
+pub(super) async fn import_users(
+    logic: web::Data<Logic>, req: HttpRequest, mut body: web::Payload,
+) -> HttpResult {
+    let ctx = super::ctx_from_req(&req);
+    let format = req
+        .mime_type()
+        .ok()
+        .flatten()
+        .and_then(|mime| TransferFormat::from_mime(mime.essence_str()));
+    let format = match format {
+        Some(format) => format,
+        None => {
+            return Err(
+                LogicError::new(LogicErrorCode::UserInvalidData).with_internal_msg(
+                    "Content-Type: expected text/csv or application/x-ndjson".to_string(),
+                ),
+            )
+        },
+    };
+
+    // The payload can't leave this task, so its chunks are forwarded to the
+    // decoder. Either side stops once the other is gone.
+    let (mut tx, rx) = mpsc::channel(1);
+    let forward = async move {
+        while let Some(chunk) = body.next().await {
+            let failed = chunk.is_err();
+            let chunk = chunk.map_err(|e| format!("invalid body: {e}"));
+            if tx.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    };
+    let rows = transfer::decode_chunks(format, rx);
+    let (_, result) = futures::join!(forward, logic.import_users(&ctx, rows));
+
+    Ok(HttpResponse::Ok().json(result?))
+}
+
+// -----------------------
+// HELPERS ---------------
+// -----------------------
+
+// "?fields=id,email", absent means "all fields"
+fn parse_fields(req: &HttpRequest) -> Result<Option<dto::FieldMask>, LogicError> {
+    let fields = match parse_query::<dto::FieldsQuery>(req)?.fields {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    match dto::FieldMask::parse(fields.split(',')) {
+        Ok(mask) => Ok(Some(mask)),
+        Err(e) => Err(LogicError::new(LogicErrorCode::InvalidQuery).with_internal_msg(e)),
+    }
+}
+
+// The export type must be the most preferred one, "*/*" and friends get JSON
+fn export_format(req: &HttpRequest) -> Option<TransferFormat> {
+    let accept = header::Accept::parse(req).ok()?;
+    let preferred = accept.ranked().into_iter().next()?;
+    TransferFormat::from_mime(preferred.essence_str())
+}
+
+// "Idempotency-Key: <opaque string>", validated by the Logic
+fn parse_idempotency_key(req: &HttpRequest) -> Result<Option<String>, LogicError> {
+    match req.headers().get("Idempotency-Key") {
+        Some(value) => match value.to_str() {
+            Ok(v) => Ok(Some(v.to_string())),
+            Err(e) => Err(LogicError::new(LogicErrorCode::InvalidIdempotencyKey).wrap(e)),
+        },
+        None => Ok(None),
+    }
+}
+
+// Strong validator from the user's version, e.g. ETag: "3"
+fn etag(usr: &domain::User) -> header::ETag {
+    header::ETag(EntityTag::new_strong(usr.version().to_string()))
+}
+
+// "If-Match: *" or a missing header means unconditional. Anything that
+// isn't one of our own ETags can never match.
+fn parse_if_match(req: &HttpRequest) -> Result<Option<u64>, LogicError> {
+    if !req
+        .headers()
+        .contains_key(header::IF_MATCH)
+    {
+        return Ok(None);
+    }
+
+    let mismatch = |msg: &str| {
+        LogicError::new(LogicErrorCode::UserVersionMismatch)
+            .with_internal_msg(format!("If-Match: {msg}"))
+    };
+
+    match header::IfMatch::parse(req) {
+        Ok(header::IfMatch::Any) => Ok(None),
+        Ok(header::IfMatch::Items(tags)) => match tags.as_slice() {
+            [tag] if !tag.weak => match tag.tag().parse::<u64>() {
+                Ok(version) => Ok(Some(version)),
+                Err(_) => Err(mismatch("unknown entity tag")),
+            },
+            _ => Err(mismatch("expected a single strong entity tag")),
+        },
+        Err(e) => Err(mismatch(&e.to_string())),
+    }
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> serde_json::Value {
+    serde_json::to_value(value).unwrap_or_default()
+}
+
+fn parse_query<T: serde::de::DeserializeOwned>(req: &HttpRequest) -> Result<T, LogicError> {
+    match web::Query::<T>::from_query(req.query_string()) {
+        Ok(query) => Ok(query.into_inner()),
+        Err(e) => Err(LogicError::new(LogicErrorCode::InvalidQuery).wrap(e)),
+    }
 }
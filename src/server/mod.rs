@@ -1,2 +1,4 @@
+pub mod auth;
 pub mod grpc;
 pub mod http;
+pub mod tenancy;
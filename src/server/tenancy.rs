@@ -0,0 +1,168 @@
+// Shared by the HTTP and gRPC servers: which tenant a request acts for.
+// Credentials bound to a tenant decide it. Users without a tenant claim are
+// confined to the default tenant, while unpinned API keys and anonymous
+// requests may name one in `x-tenant-id`. Without any of these the default
+// tenant is used.
+
+use crate::logic::{
+    domain::{Principal, PrincipalKind, TenantId},
+    error::{LogicError, LogicErrorCode},
+};
+
+// HTTP header and gRPC metadata key
+pub const TENANT_HEADER: &str = "x-tenant-id";
+
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct TenancyConfig {
+    // For requests that don't name a tenant. Unset, they are rejected.
+    pub default_tenant: Option<String>,
+}
+
+impl Default for TenancyConfig {
+    fn default() -> Self {
+        TenancyConfig {
+            default_tenant: Some("default".to_string()),
+        }
+    }
+}
+
+pub struct TenantResolver {
+    default_tenant: Option<TenantId>,
+}
+
+impl TenantResolver {
+    pub fn from_config(config: &TenancyConfig) -> Result<Self, String> {
+        let default_tenant = match config.default_tenant {
+            Some(ref t) => Some(TenantId::try_from(t.as_str())?),
+            None => None,
+        };
+
+        Ok(TenantResolver {
+            default_tenant,
+        })
+    }
+
+    // `requested` is the raw header/metadata value, if any
+    pub fn resolve(
+        &self, principal: Option<&Principal>, requested: Option<&str>,
+    ) -> Result<TenantId, LogicError> {
+        let requested = match requested {
+            Some(v) => Some(TenantId::try_from(v).map_err(|e| invalid_tenant(&e))?),
+            None => None,
+        };
+
+        let bound = match principal {
+            Some(p) if p.tenant.is_some() => p.tenant.clone(),
+            Some(p) if p.kind == PrincipalKind::User => Some(self.default_tenant()?),
+            _ => None,
+        };
+
+        match (bound, requested) {
+            (Some(bound), Some(requested)) if bound != requested => Err(LogicError::new(
+                LogicErrorCode::Forbidden,
+            )
+            .with_internal_msg(format!(
+                "tenant {requested} requested, credentials are for {bound}"
+            ))),
+            (Some(tenant), _) | (None, Some(tenant)) => Ok(tenant),
+            (None, None) => self.default_tenant(),
+        }
+    }
+
+    fn default_tenant(&self) -> Result<TenantId, LogicError> {
+        self.default_tenant
+            .clone()
+            .ok_or_else(|| invalid_tenant("no tenant"))
+    }
+}
+
+fn invalid_tenant(msg: &str) -> LogicError {
+    LogicError::new(LogicErrorCode::InvalidTenant).with_internal_msg(msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TenancyConfig, TenantResolver};
+    use crate::logic::domain::{Principal, PrincipalKind, TenantId};
+
+    fn principal(kind: PrincipalKind, tenant: Option<&str>) -> Principal {
+        Principal {
+            kind,
+            subject: "alice".to_string(),
+            roles: vec![],
+            tenant: tenant.map(|t| TenantId::try_from(t).unwrap()),
+        }
+    }
+
+    // The tenant, or the error code
+    fn resolve(
+        resolver: &TenantResolver, principal: Option<&Principal>, requested: Option<&str>,
+    ) -> String {
+        match resolver.resolve(principal, requested) {
+            Ok(t) => t.to_string(),
+            Err(e) => format!("{:?}", e.code()),
+        }
+    }
+
+    #[test]
+    fn resolve_tenant() {
+        let resolver = TenantResolver::from_config(&TenancyConfig::default()).unwrap();
+        let bound_user = principal(PrincipalKind::User, Some("acme"));
+        let user = principal(PrincipalKind::User, None);
+        let pinned_key = principal(PrincipalKind::ApiKey, Some("acme"));
+        let key = principal(PrincipalKind::ApiKey, None);
+
+        assert_eq!(resolve(&resolver, None, None), "default");
+        assert_eq!(resolve(&resolver, None, Some("acme")), "acme");
+        assert_eq!(
+            resolve(&resolver, Some(&bound_user), None),
+            "acme"
+        );
+        assert_eq!(
+            resolve(&resolver, Some(&bound_user), Some("acme")),
+            "acme"
+        );
+        assert_eq!(resolve(&resolver, Some(&user), None), "default");
+        assert_eq!(
+            resolve(&resolver, Some(&key), Some("globex")),
+            "globex"
+        );
+        assert_eq!(resolve(&resolver, Some(&key), None), "default");
+
+        for (p, requested) in [
+            (&bound_user, "globex"),
+            (&pinned_key, "globex"),
+            (&user, "globex"),
+        ] {
+            assert_eq!(
+                resolve(&resolver, Some(p), Some(requested)),
+                "Forbidden"
+            );
+        }
+
+        assert_eq!(
+            resolve(&resolver, None, Some("a b")),
+            "InvalidTenant"
+        );
+    }
+
+    #[test]
+    fn no_default_tenant() {
+        let resolver = TenantResolver::from_config(&TenancyConfig {
+            default_tenant: None,
+        })
+        .unwrap();
+
+        assert_eq!(resolve(&resolver, None, Some("acme")), "acme");
+        assert_eq!(resolve(&resolver, None, None), "InvalidTenant");
+        assert_eq!(
+            resolve(
+                &resolver,
+                Some(&principal(PrincipalKind::User, None)),
+                Some("acme")
+            ),
+            "InvalidTenant"
+        );
+    }
+}
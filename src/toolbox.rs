@@ -101,7 +101,7 @@ pub mod context {
                 let res = ctx.modify("test", |v: &mut String| {
                     v.push_str("67890");
                 });
-                assert!(res == true);
+                assert!(res);
             }
 
             {
@@ -113,12 +113,170 @@ pub mod context {
                 let res = ctx.modify("test", |v: &mut String| {
                     v.push_str("67890");
                 });
-                assert!(res == false);
+                assert!(!res);
             }
         }
     }
 }
 
+pub mod clock {
+    use std::sync::Mutex;
+    use time::{Duration, OffsetDateTime};
+
+    pub trait Clock: Send + Sync {
+        fn now(&self) -> OffsetDateTime;
+    }
+
+    // Wall clock, truncated to microseconds (the precision of MySQL DATETIME(6))
+    // so values read back from any datastore compare equal
+    pub struct SystemClock;
+
+    impl Clock for SystemClock {
+        fn now(&self) -> OffsetDateTime {
+            let now = OffsetDateTime::now_utc();
+            now.replace_microsecond(now.microsecond())
+                .unwrap_or(now)
+        }
+    }
+
+    // Deterministic clock for tests, only moves when told to
+    pub struct FakeClock {
+        now: Mutex<OffsetDateTime>,
+    }
+
+    impl FakeClock {
+        pub fn new(start: OffsetDateTime) -> Self {
+            FakeClock {
+                now: Mutex::new(start),
+            }
+        }
+
+        pub fn set(&self, now: OffsetDateTime) {
+            *self.now.lock().unwrap() = now;
+        }
+
+        pub fn advance(&self, by: Duration) {
+            *self.now.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> OffsetDateTime {
+            *self.now.lock().unwrap()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn system_clock_microseconds() {
+            let now = SystemClock.now();
+            assert_eq!(now.nanosecond() % 1_000, 0);
+        }
+
+        #[test]
+        fn fake_clock() {
+            let start = OffsetDateTime::UNIX_EPOCH;
+            let clock = FakeClock::new(start);
+            assert_eq!(clock.now(), start);
+            assert_eq!(clock.now(), start);
+
+            clock.advance(Duration::seconds(90));
+            assert_eq!(clock.now(), start + Duration::seconds(90));
+
+            clock.set(start);
+            assert_eq!(clock.now(), start);
+        }
+    }
+}
+
+pub mod idgen {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use uuid::Uuid;
+
+    pub trait IdGenerator: Send + Sync {
+        fn generate(&self) -> Uuid;
+    }
+
+    // Random UUIDv4
+    pub struct RandomIdGenerator;
+
+    impl IdGenerator for RandomIdGenerator {
+        fn generate(&self) -> Uuid {
+            Uuid::new_v4()
+        }
+    }
+
+    // UUIDv7: time-ordered, so new rows land at the end of the primary key index
+    pub struct TimeOrderedIdGenerator;
+
+    impl IdGenerator for TimeOrderedIdGenerator {
+        fn generate(&self) -> Uuid {
+            Uuid::now_v7()
+        }
+    }
+
+    // Deterministic ids for tests: 00000000-0000-4000-8000-000000000001, ...2, ...
+    pub struct FakeIdGenerator {
+        next: AtomicU64,
+    }
+
+    impl FakeIdGenerator {
+        pub fn new() -> Self {
+            FakeIdGenerator {
+                next: AtomicU64::new(1),
+            }
+        }
+
+        // The id the n-th call to `generate` returns, starting at 1
+        pub fn nth(n: u64) -> Uuid {
+            Uuid::from_u128(0x4000_8000_0000_0000_0000 | n as u128)
+        }
+    }
+
+    impl Default for FakeIdGenerator {
+        fn default() -> Self {
+            FakeIdGenerator::new()
+        }
+    }
+
+    impl IdGenerator for FakeIdGenerator {
+        fn generate(&self) -> Uuid {
+            FakeIdGenerator::nth(
+                self.next
+                    .fetch_add(1, Ordering::Relaxed),
+            )
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn fake_id_generator() {
+            let ids = FakeIdGenerator::new();
+            assert_eq!(
+                ids.generate().to_string(),
+                "00000000-0000-4000-8000-000000000001"
+            );
+            assert_eq!(ids.generate(), FakeIdGenerator::nth(2));
+        }
+
+        #[test]
+        fn time_ordered_ids() {
+            let ids: Vec<Uuid> = (0..100)
+                .map(|_| TimeOrderedIdGenerator.generate())
+                .collect();
+
+            assert!(ids.windows(2).all(|w| w[0] < w[1]));
+            assert_eq!(ids[0].get_version_num(), 7);
+        }
+    }
+}
+
 pub mod logger {
     static LOGGER: &Logger = &Logger {
         format: LogFormat::Json,
@@ -0,0 +1,433 @@
+use crate::{
+    datastore::{DataResult, Datastore, DatastoreErrorType},
+    logic::domain::ID,
+    toolbox::{clock::Clock, context::Context, logger},
+};
+use std::{collections::HashMap, fmt::Display, sync::Arc};
+use time::{Duration, OffsetDateTime};
+
+// Runs a single kind of job. An Err schedules a retry (or dead-letters the job
+// once it ran out of attempts), so handlers must be safe to run more than once.
+#[tonic::async_trait]
+pub trait JobHandler: Send + Sync {
+    async fn handle(&self, ctx: &Context, job: &Job) -> Result<(), String>;
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Pending,
+    // Leased by a worker until `run_at`, claimable again afterwards
+    Running,
+    Done,
+    // Gave up after `max_attempts`
+    Dead,
+}
+
+impl JobState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Pending => "pending",
+            JobState::Running => "running",
+            JobState::Done => "done",
+            JobState::Dead => "dead",
+        }
+    }
+}
+
+impl TryFrom<&str> for JobState {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "pending" => Ok(JobState::Pending),
+            "running" => Ok(JobState::Running),
+            "done" => Ok(JobState::Done),
+            "dead" => Ok(JobState::Dead),
+            _ => Err(format!("invalid job state: {value}")),
+        }
+    }
+}
+
+impl Display for JobState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Job {
+    pub id: ID,
+    // Selects the handler
+    pub kind: String,
+    // JSON, opaque to the queue
+    pub payload: String,
+    pub state: JobState,
+    // Claims so far, also guards writes from workers whose lease ran out
+    pub attempts: u32,
+    pub max_attempts: u32,
+
+    // Not claimable before this; for running jobs the end of the lease
+    #[serde(with = "time::serde::rfc3339")]
+    pub run_at: OffsetDateTime,
+
+    #[serde(default)]
+    pub last_error: Option<String>,
+
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+impl Job {
+    pub fn new(id: ID, kind: &str, payload: String, now: OffsetDateTime) -> Self {
+        Job {
+            id,
+            kind: kind.to_string(),
+            payload,
+            state: JobState::Pending,
+            attempts: 0,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            run_at: now,
+            last_error: None,
+            created_at: now,
+        }
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+}
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct WorkerConfig {
+    pub batch_size: usize,
+    pub poll_interval_ms: u64,
+    pub visibility_timeout_secs: u64,
+    // Retry n waits backoff_base_ms * 2^(n-1), capped at backoff_max_ms
+    pub backoff_base_ms: u64,
+    pub backoff_max_ms: u64,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        WorkerConfig {
+            batch_size: 10,
+            poll_interval_ms: 1000,
+            visibility_timeout_secs: 300,
+            backoff_base_ms: 1000,
+            backoff_max_ms: 3_600_000,
+        }
+    }
+}
+
+impl WorkerConfig {
+    fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 2u64.saturating_pow(attempts.saturating_sub(1));
+        let ms = self
+            .backoff_base_ms
+            .saturating_mul(factor)
+            .min(self.backoff_max_ms);
+        Duration::milliseconds(ms as i64)
+    }
+}
+
+pub struct Worker {
+    datastore: Arc<dyn Datastore + Send + Sync>,
+    clock: Arc<dyn Clock>,
+    handlers: HashMap<String, Arc<dyn JobHandler>>,
+    config: WorkerConfig,
+}
+
+impl Worker {
+    pub fn new(datastore: Arc<dyn Datastore + Send + Sync>, clock: Arc<dyn Clock>) -> Self {
+        Worker {
+            datastore,
+            clock,
+            handlers: HashMap::new(),
+            config: WorkerConfig::default(),
+        }
+    }
+
+    pub fn with_config(mut self, config: WorkerConfig) -> Self {
+        self.config = config;
+        self.config.batch_size = config.batch_size.max(1);
+        self
+    }
+
+    pub fn with_handler(mut self, kind: &str, handler: Arc<dyn JobHandler>) -> Self {
+        self.handlers
+            .insert(kind.to_string(), handler);
+        self
+    }
+
+    // Claims and runs one batch, returns how many jobs it ran
+    pub async fn run_once(&self) -> DataResult<usize> {
+        let lease = Duration::seconds(self.config.visibility_timeout_secs as i64);
+        let jobs = self
+            .datastore
+            .claim_jobs(self.clock.now(), self.config.batch_size, lease)
+            .await?;
+
+        let count = jobs.len();
+        for job in jobs.into_iter() {
+            self.process(job).await;
+        }
+
+        Ok(count)
+    }
+
+    // Runs full batches back to back, then waits for the next poll
+    pub async fn run(self) {
+        let poll_interval = std::time::Duration::from_millis(self.config.poll_interval_ms);
+
+        loop {
+            match self.run_once().await {
+                Ok(n) if n == self.config.batch_size => continue,
+                Ok(_) => {},
+                Err(e) => logger::logger()
+                    .log_entry(
+                        logger::Level::Error,
+                        format!("worker: claim failed: {}", e),
+                    )
+                    .publish(),
+            }
+
+            tokio::time::sleep(poll_interval).await;
+        }
+    }
+
+    async fn process(&self, mut job: Job) {
+        // Every run gets its own trace, like a request does
+        let ctx = Context::new();
+        ctx.store("trace_id", uuid::Uuid::new_v4().to_string());
+        ctx.store("job_id", job.id.to_string());
+
+        logger::ctx_info!(
+            &ctx,
+            "job started: {} {} (attempt {}/{})",
+            job.kind,
+            job.id,
+            job.attempts,
+            job.max_attempts
+        );
+
+        let result = match self.handlers.get(&job.kind) {
+            Some(handler) => handler.handle(&ctx, &job).await,
+            None => Err(format!("no handler for job kind: {}", job.kind)),
+        };
+
+        let now = self.clock.now();
+        match result {
+            Ok(_) => {
+                logger::ctx_info!(&ctx, "job done: {} {}", job.kind, job.id);
+                job.state = JobState::Done;
+                job.run_at = now;
+                job.last_error = None;
+            },
+            Err(e) if job.attempts >= job.max_attempts => {
+                logger::ctx_error!(&ctx, "job dead: {} {}: {}", job.kind, job.id, e);
+                job.state = JobState::Dead;
+                job.run_at = now;
+                job.last_error = Some(e);
+            },
+            Err(e) => {
+                let retry_at = now + self.config.backoff(job.attempts);
+                logger::ctx_warning!(
+                    &ctx,
+                    "job failed: {} {}: {} (retry at {})",
+                    job.kind,
+                    job.id,
+                    e,
+                    retry_at
+                );
+                job.state = JobState::Pending;
+                job.run_at = retry_at;
+                job.last_error = Some(e);
+            },
+        }
+
+        if let Err(db_err) = self.datastore.update_job(&job).await {
+            match db_err.error_type {
+                // Took longer than the lease and another worker claimed it
+                DatastoreErrorType::VersionMismatch => {
+                    logger::ctx_warning!(&ctx, "job lease lost: {} {}", job.kind, job.id);
+                },
+                _ => {
+                    logger::ctx_error!(
+                        &ctx,
+                        "job not saved: {} {}: {}",
+                        job.kind,
+                        job.id,
+                        db_err
+                    );
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Job, JobHandler, JobState, Worker, WorkerConfig};
+    use crate::{
+        datastore::{inmem::InMemDatastore, Datastore},
+        logic::domain::ID,
+        toolbox::{
+            clock::{Clock, FakeClock},
+            context::Context,
+        },
+    };
+    use std::sync::{Arc, Mutex};
+    use time::{Duration, OffsetDateTime};
+
+    // Fails the first `failures` runs, records the trace id of every run
+    struct FlakyHandler {
+        failures: u32,
+        traces: Mutex<Vec<String>>,
+    }
+
+    impl FlakyHandler {
+        fn new(failures: u32) -> Self {
+            FlakyHandler {
+                failures,
+                traces: Mutex::new(vec![]),
+            }
+        }
+    }
+
+    #[tonic::async_trait]
+    impl JobHandler for FlakyHandler {
+        async fn handle(&self, ctx: &Context, job: &Job) -> Result<(), String> {
+            let tid = ctx
+                .get_clone::<String>("trace_id")
+                .unwrap_or_default();
+            self.traces.lock().unwrap().push(tid);
+
+            match job.attempts <= self.failures {
+                true => Err(format!("failure {}", job.attempts)),
+                false => Ok(()),
+            }
+        }
+    }
+
+    fn setup(handler: Arc<FlakyHandler>) -> (Arc<InMemDatastore>, Arc<FakeClock>, Worker) {
+        let ds = Arc::new(InMemDatastore::new());
+        let clock = Arc::new(FakeClock::new(OffsetDateTime::UNIX_EPOCH));
+        let worker = Worker::new(ds.clone(), clock.clone())
+            .with_config(WorkerConfig {
+                backoff_base_ms: 1000,
+                backoff_max_ms: 3000,
+                ..Default::default()
+            })
+            .with_handler("flaky", handler);
+        (ds, clock, worker)
+    }
+
+    async fn enqueue(ds: &InMemDatastore, clock: &FakeClock, kind: &str, max_attempts: u32) -> ID {
+        let job = Job::new(ID::new(), kind, "{}".to_string(), clock.now())
+            .with_max_attempts(max_attempts);
+        ds.enqueue_job(&job).await.unwrap();
+        job.id
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let config = WorkerConfig {
+            backoff_base_ms: 1000,
+            backoff_max_ms: 5000,
+            ..Default::default()
+        };
+        let waits: Vec<_> = (1..=5)
+            .map(|n| config.backoff(n).whole_milliseconds())
+            .collect();
+        assert_eq!(waits, [1000, 2000, 4000, 5000, 5000]);
+        assert_eq!(config.backoff(u32::MAX), Duration::seconds(5));
+    }
+
+    #[tokio::test]
+    async fn job_done() {
+        let handler = Arc::new(FlakyHandler::new(0));
+        let (ds, clock, worker) = setup(handler.clone());
+        let id = enqueue(&ds, &clock, "flaky", 3).await;
+
+        assert_eq!(worker.run_once().await.unwrap(), 1);
+        assert_eq!(worker.run_once().await.unwrap(), 0);
+
+        let job = ds.get_job(&id).await.unwrap();
+        assert_eq!(job.state, JobState::Done);
+        assert_eq!(job.attempts, 1);
+        assert!(!handler.traces.lock().unwrap()[0].is_empty());
+    }
+
+    #[tokio::test]
+    async fn job_retried_with_backoff() {
+        let handler = Arc::new(FlakyHandler::new(2));
+        let (ds, clock, worker) = setup(handler.clone());
+        let id = enqueue(&ds, &clock, "flaky", 5).await;
+
+        worker.run_once().await.unwrap();
+        let job = ds.get_job(&id).await.unwrap();
+        assert_eq!(job.state, JobState::Pending);
+        assert_eq!(job.last_error.as_deref(), Some("failure 1"));
+        assert_eq!(job.run_at, clock.now() + Duration::seconds(1));
+
+        // Not due yet
+        assert_eq!(worker.run_once().await.unwrap(), 0);
+
+        clock.advance(Duration::seconds(1));
+        worker.run_once().await.unwrap();
+        let job = ds.get_job(&id).await.unwrap();
+        assert_eq!(job.run_at, clock.now() + Duration::seconds(2));
+
+        clock.advance(Duration::seconds(2));
+        worker.run_once().await.unwrap();
+        let job = ds.get_job(&id).await.unwrap();
+        assert_eq!(job.state, JobState::Done);
+        assert_eq!(job.attempts, 3);
+        assert_eq!(job.last_error, None);
+
+        // A fresh trace per run
+        let traces = handler.traces.lock().unwrap();
+        assert_eq!(traces.len(), 3);
+        assert_ne!(traces[0], traces[1]);
+    }
+
+    #[tokio::test]
+    async fn job_dead_lettered() {
+        let handler = Arc::new(FlakyHandler::new(u32::MAX));
+        let (ds, clock, worker) = setup(handler);
+        let id = enqueue(&ds, &clock, "flaky", 2).await;
+
+        worker.run_once().await.unwrap();
+        clock.advance(Duration::hours(1));
+        worker.run_once().await.unwrap();
+
+        let job = ds.get_job(&id).await.unwrap();
+        assert_eq!(job.state, JobState::Dead);
+        assert_eq!(job.attempts, 2);
+        assert_eq!(job.last_error.as_deref(), Some("failure 2"));
+
+        clock.advance(Duration::days(1));
+        assert_eq!(worker.run_once().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn job_unknown_kind() {
+        let handler = Arc::new(FlakyHandler::new(0));
+        let (ds, clock, worker) = setup(handler);
+        let id = enqueue(&ds, &clock, "unknown", 1).await;
+
+        worker.run_once().await.unwrap();
+
+        let job = ds.get_job(&id).await.unwrap();
+        assert_eq!(job.state, JobState::Dead);
+        assert_eq!(
+            job.last_error.as_deref(),
+            Some("no handler for job kind: unknown")
+        );
+    }
+}
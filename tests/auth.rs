@@ -0,0 +1,332 @@
+#[rustfmt::skip]
+mod helpers;
+
+use actix_web::http;
+use blueprint::{
+    logic::{
+        domain::User,
+        error::{LogicError, LogicErrorCode},
+    },
+    server::auth::{ApiKeyConfig, AuthConfig, Authenticator},
+};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, EncodingKey, Header};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+const SECRET: &[u8] = b"secret-key-for-tests";
+
+// `k` is base64url(SECRET)
+const JWKS: &str = r#"{"keys": [
+    {"kty": "oct", "kid": "k1", "alg": "HS256", "k": "c2VjcmV0LWtleS1mb3ItdGVzdHM"}
+]}"#;
+
+fn spawn_app() -> helpers::TestServer {
+    let config = AuthConfig {
+        enabled: true,
+        issuer: Some("https://issuer.test".to_string()),
+        api_keys: vec![ApiKeyConfig {
+            name: "ci".to_string(),
+            sha256: Sha256::digest(b"key-1")
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+            roles: vec!["admin".to_string()],
+            tenant: None,
+        }],
+        ..Default::default()
+    };
+    let jwks: JwkSet = serde_json::from_str(JWKS).unwrap();
+    helpers::spawn_app_with_auth(Authenticator::new(jwks, &config).unwrap())
+}
+
+fn jwt(sub: &str, exp: i64) -> String {
+    jwt_with_roles(sub, exp, &["user"])
+}
+
+fn jwt_with_roles(sub: &str, exp: i64, roles: &[&str]) -> String {
+    let mut header = Header::new(Algorithm::HS256);
+    header.kid = Some("k1".to_string());
+    let claims =
+        serde_json::json!({"sub": sub, "iss": "https://issuer.test", "exp": exp, "roles": roles});
+    jsonwebtoken::encode(
+        &header,
+        &claims,
+        &EncodingKey::from_secret(SECRET),
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+async fn unauthenticated_401() {
+    let srv = spawn_app();
+    let client = reqwest::Client::new();
+    let endpoint = format!("{}/api/v1/users", srv.basepath);
+    let expired = jwt("alice", 1);
+
+    for authorization in [
+        None,
+        Some("ApiKey key-2"),
+        Some("Bearer not-a-jwt"),
+        Some(format!("Bearer {expired}").as_str()),
+    ] {
+        let mut req = client.get(&endpoint);
+        if let Some(value) = authorization {
+            req = req.header("Authorization", value);
+        }
+        let resp = req
+            .send()
+            .await
+            .expect("failed to execute request");
+
+        assert_eq!(
+            http::StatusCode::UNAUTHORIZED,
+            resp.status(),
+            "{authorization:?}"
+        );
+        assert_eq!(resp.headers()["www-authenticate"], "Bearer");
+        let err: LogicError = resp.json().await.unwrap();
+        assert!(matches!(
+            err.code(),
+            LogicErrorCode::Unauthenticated
+        ));
+    }
+
+    // Health checks need no credentials
+    let resp = client
+        .get(format!("{}/healthz", srv.basepath))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert!(resp.status().is_success());
+}
+
+#[tokio::test]
+async fn authenticated_principal_is_the_actor() {
+    let srv = spawn_app();
+    let client = reqwest::Client::new();
+
+    let mut req = HashMap::new();
+    req.insert("email", "test@foo.com");
+    req.insert("name", "Jeff Jefferson");
+    let resp = client
+        .post(format!("{}/api/v1/users", srv.basepath))
+        .header("Authorization", "ApiKey key-1")
+        .json(&req)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::CREATED, resp.status());
+    let created_usr: User = resp.json().await.unwrap();
+
+    let exp = time::OffsetDateTime::now_utc().unix_timestamp() + 3600;
+    let mut req = HashMap::new();
+    req.insert("name", "Geoff Jefferson");
+    let resp = client
+        .patch(format!(
+            "{}/api/v1/users/{}",
+            srv.basepath,
+            created_usr.id()
+        ))
+        .bearer_auth(jwt_with_roles("alice", exp, &["admin"]))
+        .json(&req)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+
+    let resp = client
+        .get(format!(
+            "{}/api/v1/users/{}/history",
+            srv.basepath,
+            created_usr.id()
+        ))
+        .header("Authorization", "ApiKey key-1")
+        .send()
+        .await
+        .expect("failed to execute request");
+    let js: serde_json::Value = resp.json().await.unwrap();
+    let actors: Vec<&str> = js["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["actor"].as_str().unwrap())
+        .collect();
+    assert_eq!(actors, ["api_key:ci", "user:alice"]);
+}
+
+#[tokio::test]
+async fn forbidden_403() {
+    let srv = spawn_app();
+    let client = reqwest::Client::new();
+
+    let mut ids = Vec::new();
+    for email in ["alice@foo.com", "bob@foo.com"] {
+        let mut req = HashMap::new();
+        req.insert("email", email);
+        req.insert("name", "Jeff Jefferson");
+        let resp = client
+            .post(format!("{}/api/v1/users", srv.basepath))
+            .header("Authorization", "ApiKey key-1")
+            .json(&req)
+            .send()
+            .await
+            .expect("failed to execute request");
+        assert_eq!(http::StatusCode::CREATED, resp.status());
+        let usr: User = resp.json().await.unwrap();
+        ids.push(usr.id().to_string());
+    }
+
+    // Signed in as alice, with the "user" role
+    let exp = time::OffsetDateTime::now_utc().unix_timestamp() + 3600;
+    let token = jwt(&ids[0], exp);
+
+    let resp = client
+        .get(format!(
+            "{}/api/v1/users/{}",
+            srv.basepath, ids[0]
+        ))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+
+    let mut req = HashMap::new();
+    req.insert("email", "carol@foo.com");
+    req.insert("name", "Jeff Jefferson");
+    for req in [
+        client.get(format!(
+            "{}/api/v1/users/{}",
+            srv.basepath, ids[1]
+        )),
+        client.get(format!("{}/api/v1/users", srv.basepath)),
+        client
+            .post(format!("{}/api/v1/users", srv.basepath))
+            .json(&req),
+    ] {
+        let resp = req
+            .bearer_auth(&token)
+            .send()
+            .await
+            .expect("failed to execute request");
+
+        assert_eq!(http::StatusCode::FORBIDDEN, resp.status());
+        let err: LogicError = resp.json().await.unwrap();
+        assert!(matches!(err.code(), LogicErrorCode::Forbidden));
+    }
+
+    let resp = client
+        .get(format!("{}/api/v1/users", srv.basepath))
+        .header("Authorization", "ApiKey key-1")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+}
+
+#[tokio::test]
+async fn users_confined_to_their_tenant() {
+    let srv = spawn_app();
+    let client = reqwest::Client::new();
+    let endpoint = format!("{}/api/v1/users", srv.basepath);
+    let exp = time::OffsetDateTime::now_utc().unix_timestamp() + 3600;
+
+    // The key isn't pinned, so it may act for any tenant
+    let resp = client
+        .get(&endpoint)
+        .header("Authorization", "ApiKey key-1")
+        .header("x-tenant-id", "acme")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+
+    // Without a tenant claim a user only has the default tenant
+    let resp = client
+        .get(format!("{}/{}", endpoint, uuid::Uuid::new_v4()))
+        .bearer_auth(jwt("alice", exp))
+        .header("x-tenant-id", "acme")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::FORBIDDEN, resp.status());
+    let err: LogicError = resp.json().await.unwrap();
+    assert!(matches!(err.code(), LogicErrorCode::Forbidden));
+}
+
+#[tokio::test]
+async fn writes_and_history_need_permissions() {
+    let srv = spawn_app();
+    let client = reqwest::Client::new();
+    let endpoint = format!("{}/api/v1/users", srv.basepath);
+
+    let mut ids = Vec::new();
+    for email in ["alice@foo.com", "bob@foo.com"] {
+        let mut req = HashMap::new();
+        req.insert("email", email);
+        req.insert("name", "Jeff Jefferson");
+        let resp = client
+            .post(&endpoint)
+            .header("Authorization", "ApiKey key-1")
+            .json(&req)
+            .send()
+            .await
+            .expect("failed to execute request");
+        assert_eq!(http::StatusCode::CREATED, resp.status());
+        let usr: User = resp.json().await.unwrap();
+        ids.push(usr.id().to_string());
+    }
+
+    // Signed in as alice, with the "user" role
+    let exp = time::OffsetDateTime::now_utc().unix_timestamp() + 3600;
+    let token = jwt(&ids[0], exp);
+
+    let mut req = HashMap::new();
+    req.insert("name", "Geoff Jefferson");
+    for id in [&ids[0], &ids[1]] {
+        for req in [
+            client
+                .patch(format!("{endpoint}/{id}"))
+                .json(&req),
+            client.delete(format!("{endpoint}/{id}")),
+            client.post(format!("{endpoint}/{id}:restore")),
+            client.post(format!("{endpoint}/{id}:suspend")),
+            client.post(format!("{endpoint}/{id}:deactivate")),
+        ] {
+            let resp = req
+                .bearer_auth(&token)
+                .send()
+                .await
+                .expect("failed to execute request");
+
+            assert_eq!(http::StatusCode::FORBIDDEN, resp.status());
+            let err: LogicError = resp.json().await.unwrap();
+            assert!(matches!(err.code(), LogicErrorCode::Forbidden));
+        }
+    }
+
+    // History holds emails and names, so it's readable like the user
+    let resp = client
+        .get(format!("{endpoint}/{}/history", ids[1]))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::FORBIDDEN, resp.status());
+
+    let resp = client
+        .get(format!("{endpoint}/{}/history", ids[0]))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+
+    let resp = client
+        .post(format!("{endpoint}/{}:suspend", ids[1]))
+        .header("Authorization", "ApiKey key-1")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+}
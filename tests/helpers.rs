@@ -1,21 +1,100 @@
 #[rustfmt::skip]
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use blueprint::{datastore::inmem::InMemDatastore, logic::Logic, server::http};
+use blueprint::{
+    datastore::inmem::InMemDatastore,
+    logic::Logic,
+    mailer::{Mailer, Message},
+    server::{
+        auth::Authenticator,
+        http,
+        tenancy::{TenancyConfig, TenantResolver},
+    },
+    toolbox::{
+        clock::{Clock, SystemClock},
+        idgen::{IdGenerator, RandomIdGenerator},
+    },
+};
 
 pub struct TestServer {
     pub basepath: String,
+    // Not every test binary looks at sent mail
+    #[allow(dead_code)]
+    pub mailer: Arc<RecordingMailer>,
 }
 
 impl TestServer {
-    fn new(basepath: String) -> Self {
+    fn new(basepath: String, mailer: Arc<RecordingMailer>) -> Self {
         TestServer {
             basepath,
+            mailer,
         }
     }
 }
 
+// Keeps every sent message for the test to inspect
+#[derive(Default)]
+pub struct RecordingMailer {
+    messages: Mutex<Vec<Message>>,
+}
+
+#[allow(dead_code)]
+impl RecordingMailer {
+    pub fn messages(&self) -> Vec<Message> {
+        self.messages.lock().unwrap().clone()
+    }
+
+    // The token of the last verification mail sent to `to`
+    pub fn last_token(&self, to: &str) -> Option<String> {
+        self.messages
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|m| m.to == to)
+            .and_then(|m| {
+                m.body
+                    .lines()
+                    .nth(4)
+                    .map(|l| l.to_string())
+            })
+    }
+}
+
+#[tonic::async_trait]
+impl Mailer for RecordingMailer {
+    async fn send(&self, msg: &Message) -> Result<(), String> {
+        self.messages
+            .lock()
+            .unwrap()
+            .push(msg.clone());
+        Ok(())
+    }
+}
+
+#[allow(dead_code)]
 pub fn spawn_app() -> TestServer {
+    spawn_app_with(Arc::new(SystemClock), Arc::new(RandomIdGenerator))
+}
+
+// For tests that assert exact ids and timestamps
+#[allow(dead_code)]
+pub fn spawn_app_with(clock: Arc<dyn Clock>, ids: Arc<dyn IdGenerator>) -> TestServer {
+    spawn(clock, ids, None)
+}
+
+#[allow(dead_code)]
+pub fn spawn_app_with_auth(authenticator: Authenticator) -> TestServer {
+    spawn(
+        Arc::new(SystemClock),
+        Arc::new(RandomIdGenerator),
+        Some(Arc::new(authenticator)),
+    )
+}
+
+fn spawn(
+    clock: Arc<dyn Clock>, ids: Arc<dyn IdGenerator>, authenticator: Option<Arc<Authenticator>>,
+) -> TestServer {
     // random port
     let listener = http::create_listener(0).unwrap_or_else(|err| {
         panic!("unable to bind http listener: {}", err);
@@ -23,10 +102,12 @@ pub fn spawn_app() -> TestServer {
 
     let actual_http_port = listener.local_addr().unwrap().port();
 
-    let ds = Box::new(InMemDatastore::new());
-    let svc = Arc::new(Logic::new(ds));
+    let ds = Arc::new(InMemDatastore::new());
+    let mailer = Arc::new(RecordingMailer::default());
+    let svc = Arc::new(Logic::new(ds, clock, ids).with_mailer(mailer.clone()));
 
-    let http_server = http::init(listener, svc).unwrap_or_else(|err| {
+    let tenants = Arc::new(TenantResolver::from_config(&TenancyConfig::default()).unwrap());
+    let http_server = http::init(listener, svc, authenticator, tenants).unwrap_or_else(|err| {
         panic!("failed to start http server: {}", err);
     });
 
@@ -34,5 +115,5 @@ pub fn spawn_app() -> TestServer {
 
     tokio::spawn(http_server);
 
-    TestServer::new(basepath)
+    TestServer::new(basepath, mailer)
 }
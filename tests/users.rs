@@ -4,9 +4,12 @@ use std::collections::HashMap;
 use actix_web::http;
 use blueprint::logic::{
     domain::{User, ID},
+    dto::UserList,
     error::{LogicError, LogicErrorCode},
 };
-use serde_json;
+use blueprint::toolbox::{clock::FakeClock, idgen::FakeIdGenerator};
+use std::sync::Arc;
+use time::{format_description::well_known::Rfc3339, Duration, OffsetDateTime};
 
 mod helpers;
 
@@ -102,12 +105,1683 @@ async fn get_user_404() {
     assert!(matches!(err.code(), LogicErrorCode::UserNotFound));
 }
 
+#[tokio::test]
+async fn patch_user_200() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+
+    let created_usr = create_user(&srv, &client, "foo@bar.com", "Jeff Jefferson").await;
+
+    let endpoint = format!(
+        "{}/api/v1/users/{}",
+        srv.basepath,
+        created_usr.id()
+    );
+
+    let mut req = HashMap::new();
+    req.insert("name", "Geoff Jefferson");
+
+    let resp = client
+        .patch(endpoint)
+        .json(&req)
+        .send()
+        .await
+        .expect("failed to execute request");
+
+    assert_eq!(http::StatusCode::OK, resp.status());
+
+    let updated_usr: User = resp
+        .json()
+        .await
+        .expect("failed to get payload");
+
+    assert_eq!(created_usr.id(), updated_usr.id());
+    assert_eq!("foo@bar.com", updated_usr.email().to_string());
+    assert_eq!("Geoff Jefferson", updated_usr.name().to_string());
+}
+
+#[tokio::test]
+async fn patch_user_invalid_email_400() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+
+    let created_usr = create_user(&srv, &client, "foo@bar.com", "Jeff Jefferson").await;
+
+    let endpoint = format!(
+        "{}/api/v1/users/{}",
+        srv.basepath,
+        created_usr.id()
+    );
+
+    let mut req = HashMap::new();
+    req.insert("email", "not_an_email");
+
+    let resp = client
+        .patch(endpoint)
+        .json(&req)
+        .send()
+        .await
+        .expect("failed to execute request");
+
+    assert_eq!(http::StatusCode::BAD_REQUEST, resp.status());
+
+    let err = resp
+        .json::<LogicError>()
+        .await
+        .expect("failed to get payload");
+
+    assert!(matches!(
+        err.code(),
+        LogicErrorCode::UserInvalidData
+    ));
+}
+
+#[tokio::test]
+async fn put_user_404() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+
+    let id = ID::new().to_string();
+    let endpoint = format!("{}/api/v1/users/{}", srv.basepath, id);
+
+    let mut req = HashMap::new();
+    req.insert("email", "foo@bar.com");
+    req.insert("name", "Jeff Jefferson");
+
+    let resp = client
+        .put(endpoint)
+        .json(&req)
+        .send()
+        .await
+        .expect("failed to execute request");
+
+    assert_eq!(http::StatusCode::NOT_FOUND, resp.status());
+
+    let err = resp
+        .json::<LogicError>()
+        .await
+        .expect("failed to get payload");
+
+    assert!(matches!(err.code(), LogicErrorCode::UserNotFound));
+}
+
+#[tokio::test]
+async fn delete_user_204_restore_user_200() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+
+    let created_usr = create_user(&srv, &client, "foo@bar.com", "Jeff Jefferson").await;
+    let endpoint = format!(
+        "{}/api/v1/users/{}",
+        srv.basepath,
+        created_usr.id()
+    );
+
+    let resp = client
+        .delete(&endpoint)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::NO_CONTENT, resp.status());
+
+    // Hidden by default
+    let resp = client
+        .get(&endpoint)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::NOT_FOUND, resp.status());
+
+    // Visible when asked for
+    let resp = client
+        .get(format!("{}?include_deleted=true", endpoint))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+
+    let deleted_usr: User = resp
+        .json()
+        .await
+        .expect("failed to get payload");
+    assert!(deleted_usr.is_deleted());
+
+    let resp = client
+        .post(format!("{}:restore", endpoint))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+
+    let restored_usr: User = resp
+        .json()
+        .await
+        .expect("failed to get payload");
+    assert!(!restored_usr.is_deleted());
+    assert_eq!(created_usr.id(), restored_usr.id());
+
+    let resp = client
+        .get(&endpoint)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+}
+
+#[tokio::test]
+async fn delete_user_404() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+
+    let id = ID::new().to_string();
+    let endpoint = format!("{}/api/v1/users/{}", srv.basepath, id);
+
+    let resp = client
+        .delete(endpoint)
+        .send()
+        .await
+        .expect("failed to execute request");
+
+    assert_eq!(http::StatusCode::NOT_FOUND, resp.status());
+}
+
+#[tokio::test]
+async fn list_users_paginated_200() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+
+    let mut created: Vec<String> = Vec::new();
+    for i in 0..5 {
+        let email = format!("user{i}@bar.com");
+        let usr = create_user(&srv, &client, &email, "Jeff Jefferson").await;
+        created.push(usr.id().to_string());
+    }
+    created.sort();
+
+    let mut listed: Vec<String> = Vec::new();
+    let mut page_token = String::new();
+    let mut pages = 0;
+
+    loop {
+        let endpoint = format!(
+            "{}/api/v1/users?page_size=2&page_token={}",
+            srv.basepath, page_token
+        );
+
+        let resp = client
+            .get(endpoint)
+            .send()
+            .await
+            .expect("failed to execute request");
+        assert_eq!(http::StatusCode::OK, resp.status());
+
+        let page: UserList = resp
+            .json()
+            .await
+            .expect("failed to get payload");
+        assert!(page.items.len() <= 2);
+
+        listed.extend(
+            page.items
+                .iter()
+                .map(|u| u.id().to_string()),
+        );
+        pages += 1;
+
+        match page.next_page_token {
+            Some(token) => page_token = token,
+            None => break,
+        }
+    }
+
+    assert_eq!(3, pages);
+    assert_eq!(created, listed);
+}
+
+#[tokio::test]
+async fn list_users_invalid_page_token_400() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+
+    let endpoint = format!("{}/api/v1/users?page_token=garbage", srv.basepath);
+
+    let resp = client
+        .get(endpoint)
+        .send()
+        .await
+        .expect("failed to execute request");
+
+    assert_eq!(http::StatusCode::BAD_REQUEST, resp.status());
+
+    let err = resp
+        .json::<LogicError>()
+        .await
+        .expect("failed to get payload");
+
+    assert!(matches!(err.code(), LogicErrorCode::InvalidQuery));
+}
+
+#[tokio::test]
+async fn list_users_filtered_200() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+
+    let jo = create_user(&srv, &client, "jo@acme.com", "Joanna Smith").await;
+    create_user(&srv, &client, "jane@acme.com", "Jane Doe").await;
+    create_user(&srv, &client, "john@example.org", "John Doe").await;
+
+    let resp = client
+        .get(format!("{}/api/v1/users", srv.basepath))
+        .query(&[(
+            "filter",
+            r#"email.domain == "acme.com" and name ^= "Jo""#,
+        )])
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+
+    let page: UserList = resp
+        .json()
+        .await
+        .expect("failed to get payload");
+    assert_eq!(1, page.items.len());
+    assert_eq!(jo.id(), page.items[0].id());
+}
+
+#[tokio::test]
+async fn list_users_invalid_filter_400() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+
+    let resp = client
+        .get(format!("{}/api/v1/users", srv.basepath))
+        .query(&[("filter", r#"age == "3""#)])
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::BAD_REQUEST, resp.status());
+
+    let err = resp
+        .json::<LogicError>()
+        .await
+        .expect("failed to get payload");
+
+    assert!(matches!(err.code(), LogicErrorCode::InvalidQuery));
+}
+
+#[tokio::test]
+async fn list_users_sorted_projected_200() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+
+    create_user(&srv, &client, "b@bar.com", "Bravo").await;
+    create_user(&srv, &client, "a@bar.com", "Alpha").await;
+    create_user(&srv, &client, "c@bar.com", "Bravo").await;
+
+    let resp = client
+        .get(format!("{}/api/v1/users", srv.basepath))
+        .query(&[("sort", "-name,email"), ("fields", "email")])
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+
+    let page: serde_json::Value = resp
+        .json()
+        .await
+        .expect("failed to get payload");
+
+    assert_eq!(
+        page["items"],
+        serde_json::json!([
+            {"email": "b@bar.com"},
+            {"email": "c@bar.com"},
+            {"email": "a@bar.com"},
+        ])
+    );
+}
+
+#[tokio::test]
+async fn list_users_page_token_other_sort_400() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+
+    create_user(&srv, &client, "a@bar.com", "Alpha").await;
+    create_user(&srv, &client, "b@bar.com", "Bravo").await;
+
+    let page: UserList = client
+        .get(format!("{}/api/v1/users", srv.basepath))
+        .query(&[("sort", "name"), ("page_size", "1")])
+        .send()
+        .await
+        .expect("failed to execute request")
+        .json()
+        .await
+        .expect("failed to get payload");
+
+    let resp = client
+        .get(format!("{}/api/v1/users", srv.basepath))
+        .query(&[
+            ("sort", "-name"),
+            ("page_size", "1"),
+            ("page_token", &page.next_page_token.unwrap()),
+        ])
+        .send()
+        .await
+        .expect("failed to execute request");
+
+    assert_eq!(http::StatusCode::BAD_REQUEST, resp.status());
+}
+
+#[tokio::test]
+async fn get_user_projected_200() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+
+    let created_usr = create_user(&srv, &client, "foo@bar.com", "Jeff Jefferson").await;
+
+    let resp = client
+        .get(format!(
+            "{}/api/v1/users/{}?fields=id,name",
+            srv.basepath,
+            created_usr.id()
+        ))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+
+    let js: serde_json::Value = resp
+        .json()
+        .await
+        .expect("failed to get payload");
+
+    assert_eq!(
+        js,
+        serde_json::json!({"id": created_usr.id().to_string(), "name": "Jeff Jefferson"})
+    );
+}
+
+#[tokio::test]
+async fn create_user_junk_name() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+    let endpoint = format!("{}/api/v1/users", srv.basepath);
+
+    let mut req = HashMap::new();
+    req.insert("email", "test@foo.com");
+    req.insert("name", "Jeff\u{202E}nosreffeJ");
+
+    let resp = client
+        .post(&endpoint)
+        .json(&req)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::BAD_REQUEST, resp.status());
+
+    let js: serde_json::Value = resp
+        .json()
+        .await
+        .expect("failed to get payload");
+
+    assert_eq!(js["violations"][0]["field"], "name");
+    assert_eq!(js["violations"][0]["rule"], "bidi_control");
+
+    // Whitespace is cleaned up rather than rejected
+    let created_usr = create_user(
+        &srv,
+        &client,
+        "test@foo.com",
+        "  Jeff   Jefferson ",
+    )
+    .await;
+    assert_eq!("Jeff Jefferson", created_usr.name().to_string());
+}
+
+#[tokio::test]
+async fn update_user_if_match() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+    let created_usr = create_user(&srv, &client, "test@foo.com", "Jeff Jefferson").await;
+    let endpoint = format!(
+        "{}/api/v1/users/{}",
+        srv.basepath,
+        created_usr.id()
+    );
+
+    let resp = client
+        .get(&endpoint)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+    assert_eq!(resp.headers()["etag"], "\"1\"");
+
+    let mut req = HashMap::new();
+    req.insert("name", "Geoff Jefferson");
+
+    let resp = client
+        .patch(&endpoint)
+        .header("If-Match", "\"1\"")
+        .json(&req)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+    assert_eq!(resp.headers()["etag"], "\"2\"");
+
+    // A second writer still holding version 1 loses
+    let resp = client
+        .patch(&endpoint)
+        .header("If-Match", "\"1\"")
+        .json(&req)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(
+        http::StatusCode::PRECONDITION_FAILED,
+        resp.status()
+    );
+
+    let js: serde_json::Value = resp
+        .json()
+        .await
+        .expect("failed to get payload");
+    assert_eq!(js["code"], "UserVersionMismatch");
+
+    let resp = client
+        .delete(&endpoint)
+        .header("If-Match", "\"1\"")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(
+        http::StatusCode::PRECONDITION_FAILED,
+        resp.status()
+    );
+
+    let resp = client
+        .delete(&endpoint)
+        .header("If-Match", "\"2\"")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::NO_CONTENT, resp.status());
+}
+
+#[tokio::test]
+async fn restore_user_if_match() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+    let created_usr = create_user(&srv, &client, "test@foo.com", "Jeff Jefferson").await;
+    let endpoint = format!(
+        "{}/api/v1/users/{}",
+        srv.basepath,
+        created_usr.id()
+    );
+
+    let resp = client
+        .delete(&endpoint)
+        .header("If-Match", "\"1\"")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::NO_CONTENT, resp.status());
+
+    // The delete moved the user on to version 2
+    let resp = client
+        .post(format!("{endpoint}:restore"))
+        .header("If-Match", "\"1\"")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(
+        http::StatusCode::PRECONDITION_FAILED,
+        resp.status()
+    );
+
+    let js: serde_json::Value = resp
+        .json()
+        .await
+        .expect("failed to get payload");
+    assert_eq!(js["code"], "UserVersionMismatch");
+
+    let resp = client
+        .post(format!("{endpoint}:restore"))
+        .header("If-Match", "\"2\"")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+    assert_eq!(resp.headers()["etag"], "\"3\"");
+}
+
+#[tokio::test]
+async fn update_user_without_changes() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+    let created_usr = create_user(&srv, &client, "test@foo.com", "Jeff Jefferson").await;
+    let endpoint = format!(
+        "{}/api/v1/users/{}",
+        srv.basepath,
+        created_usr.id()
+    );
+
+    // Neither an empty patch nor one repeating the stored values is a write
+    let empty: HashMap<&str, &str> = HashMap::new();
+    let mut same = HashMap::new();
+    same.insert("name", "Jeff Jefferson");
+    same.insert("email", "test@foo.com");
+
+    for req in [empty, same] {
+        let resp = client
+            .patch(&endpoint)
+            .header("If-Match", "\"1\"")
+            .json(&req)
+            .send()
+            .await
+            .expect("failed to execute request");
+        assert_eq!(http::StatusCode::OK, resp.status());
+        assert_eq!(resp.headers()["etag"], "\"1\"");
+
+        let js: serde_json::Value = resp
+            .json()
+            .await
+            .expect("failed to get payload");
+        assert_eq!(js["updated_at"], js["created_at"]);
+    }
+
+    let resp = client
+        .get(format!("{endpoint}/history"))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+
+    let js: serde_json::Value = resp
+        .json()
+        .await
+        .expect("failed to get payload");
+    assert_eq!(js["items"].as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn user_timestamps() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+    let created_usr = create_user(&srv, &client, "test@foo.com", "Jeff Jefferson").await;
+    assert_eq!(created_usr.created_at(), created_usr.updated_at());
+
+    let mut req = HashMap::new();
+    req.insert("name", "Geoff Jefferson");
+
+    let resp = client
+        .patch(format!(
+            "{}/api/v1/users/{}",
+            srv.basepath,
+            created_usr.id()
+        ))
+        .json(&req)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+
+    let js: serde_json::Value = resp
+        .json()
+        .await
+        .expect("failed to get payload");
+
+    // RFC 3339 on the wire
+    let created_at = js["created_at"].as_str().unwrap();
+    let updated_at = js["updated_at"].as_str().unwrap();
+    let parse = |v| OffsetDateTime::parse(v, &Rfc3339).unwrap();
+
+    assert_eq!(parse(created_at), created_usr.created_at());
+    assert!(parse(updated_at) > created_usr.updated_at());
+}
+
+#[tokio::test]
+async fn deterministic_ids_and_timestamps() {
+    let start = OffsetDateTime::parse("2024-01-02T03:04:05Z", &Rfc3339).unwrap();
+    let clock = Arc::new(FakeClock::new(start));
+    let srv = helpers::spawn_app_with(clock.clone(), Arc::new(FakeIdGenerator::new()));
+    let client = reqwest::Client::new();
+
+    let first = create_user(&srv, &client, "first@foo.com", "Jeff Jefferson").await;
+    assert_eq!(
+        first.id().to_string(),
+        "00000000-0000-4000-8000-000000000001"
+    );
+    assert_eq!(first.created_at(), start);
+
+    clock.advance(Duration::minutes(5));
+    let second = create_user(&srv, &client, "second@foo.com", "Geoff Jefferson").await;
+    // The first create also drew an id for its audit entry
+    assert_eq!(
+        second.id().to_string(),
+        FakeIdGenerator::nth(3).to_string()
+    );
+    assert_eq!(second.created_at(), start + Duration::minutes(5));
+
+    clock.advance(Duration::minutes(5));
+    let resp = client
+        .delete(format!(
+            "{}/api/v1/users/{}",
+            srv.basepath,
+            first.id()
+        ))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::NO_CONTENT, resp.status());
+
+    let resp = client
+        .get(format!(
+            "{}/api/v1/users/{}?include_deleted=true",
+            srv.basepath,
+            first.id()
+        ))
+        .send()
+        .await
+        .expect("failed to execute request");
+
+    let js: serde_json::Value = resp
+        .json()
+        .await
+        .expect("failed to get payload");
+    assert_eq!(
+        js,
+        serde_json::json!({
+            "id": "00000000-0000-4000-8000-000000000001",
+            "email": "first@foo.com",
+            "name": "Jeff Jefferson",
+            "created_at": "2024-01-02T03:04:05Z",
+            "updated_at": "2024-01-02T03:14:05Z",
+            "deleted_at": "2024-01-02T03:14:05Z",
+            "version": 2,
+            "status": "pending_verification",
+            "metadata": {}
+        })
+    );
+}
+
+#[tokio::test]
+async fn restore_user_expired() {
+    let clock = Arc::new(FakeClock::new(OffsetDateTime::now_utc()));
+    let srv = helpers::spawn_app_with(clock.clone(), Arc::new(FakeIdGenerator::new()));
+    let client = reqwest::Client::new();
+    let created_usr = create_user(&srv, &client, "test@foo.com", "Jeff Jefferson").await;
+    let endpoint = format!(
+        "{}/api/v1/users/{}",
+        srv.basepath,
+        created_usr.id()
+    );
+
+    let resp = client
+        .delete(&endpoint)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::NO_CONTENT, resp.status());
+
+    clock.advance(Duration::days(31));
+
+    let resp = client
+        .post(format!("{endpoint}:restore"))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::GONE, resp.status());
+}
+
 #[tokio::test]
 async fn create_user_duplicate() {
-    // todo
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+    let endpoint = format!("{}/api/v1/users", srv.basepath);
+
+    create_user(&srv, &client, "test@foo.com", "Jeff Jefferson").await;
+
+    let mut req = HashMap::new();
+    req.insert("email", "Test@Foo.com");
+    req.insert("name", "Geoff Jefferson");
+
+    let resp = client
+        .post(&endpoint)
+        .json(&req)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::CONFLICT, resp.status());
+
+    let js: serde_json::Value = resp
+        .json()
+        .await
+        .expect("failed to get payload");
+    assert_eq!(js["code"], "DuplicateEmail");
+}
+
+#[tokio::test]
+async fn create_user_bad_data() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+    let endpoint = format!("{}/api/v1/users", srv.basepath);
+
+    let mut req = HashMap::new();
+    req.insert("email", "not_an_email.com");
+    req.insert("name", "Jeff");
+
+    let resp = client
+        .post(&endpoint)
+        .json(&req)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::BAD_REQUEST, resp.status());
+
+    let js: serde_json::Value = resp
+        .json()
+        .await
+        .expect("failed to get payload");
+
+    assert_eq!(js["code"], "UserInvalidData");
+    assert_eq!(js["violations"][0]["field"], "email");
+    assert_eq!(js["violations"][0]["rule"], "format");
+    assert!(js["violations"][0]["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("invalid email"));
+}
+
+#[tokio::test]
+async fn user_history() {
+    let start = OffsetDateTime::parse("2024-01-02T03:04:05Z", &Rfc3339).unwrap();
+    let clock = Arc::new(FakeClock::new(start));
+    let srv = helpers::spawn_app_with(clock.clone(), Arc::new(FakeIdGenerator::new()));
+    let client = reqwest::Client::new();
+    let created_usr = create_user(&srv, &client, "test@foo.com", "Jeff Jefferson").await;
+    let endpoint = format!(
+        "{}/api/v1/users/{}",
+        srv.basepath,
+        created_usr.id()
+    );
+
+    clock.advance(Duration::minutes(1));
+    let mut req = HashMap::new();
+    req.insert("name", "Geoff Jefferson");
+    let resp = client
+        .patch(&endpoint)
+        .json(&req)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+
+    clock.advance(Duration::minutes(1));
+    let resp = client
+        .delete(&endpoint)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::NO_CONTENT, resp.status());
+
+    // Still available once the user is deleted
+    let resp = client
+        .get(format!("{endpoint}/history"))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+
+    let js: serde_json::Value = resp
+        .json()
+        .await
+        .expect("failed to get payload");
+    let items = js["items"].as_array().unwrap();
+    assert_eq!(items.len(), 3);
+
+    assert_eq!(items[0]["operation"], "create");
+    assert_eq!(items[0]["user_id"], created_usr.id().to_string());
+    assert_eq!(items[0]["actor"], "anonymous");
+    assert!(!items[0]["trace_id"]
+        .as_str()
+        .unwrap()
+        .is_empty());
+    assert_eq!(items[0]["at"], "2024-01-02T03:04:05Z");
+    assert_eq!(
+        items[0]["changes"],
+        serde_json::json!([
+            {"field": "email", "before": null, "after": "test@foo.com"},
+            {"field": "name", "before": null, "after": "Jeff Jefferson"},
+            {"field": "status", "before": null, "after": "pending_verification"},
+        ])
+    );
+
+    assert_eq!(items[1]["operation"], "update");
+    assert_eq!(items[1]["at"], "2024-01-02T03:05:05Z");
+    assert_eq!(
+        items[1]["changes"],
+        serde_json::json!([
+            {"field": "name", "before": "Jeff Jefferson", "after": "Geoff Jefferson"},
+        ])
+    );
+
+    assert_eq!(items[2]["operation"], "delete");
+    assert_eq!(
+        items[2]["changes"],
+        serde_json::json!([
+            {"field": "deleted_at", "before": null, "after": "2024-01-02T03:06:05Z"},
+        ])
+    );
+}
+
+#[tokio::test]
+async fn user_history_not_found() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+
+    let resp = client
+        .get(format!(
+            "{}/api/v1/users/{}/history",
+            srv.basepath,
+            ID::new()
+        ))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::NOT_FOUND, resp.status());
+}
+
+#[tokio::test]
+async fn batch_create_users_best_effort() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+    create_user(&srv, &client, "taken@foo.com", "Jeff Jefferson").await;
+
+    let req = serde_json::json!({
+        "mode": "best_effort",
+        "items": [
+            {"email": "one@foo.com", "name": "One"},
+            {"email": "not_an_email", "name": "Two"},
+            {"email": "Taken@foo.com", "name": "Three"},
+            {"email": "four@foo.com", "name": "Four"},
+            {"email": "ONE@foo.com", "name": "Five"},
+        ]
+    });
+    let resp = client
+        .post(format!(
+            "{}/api/v1/users:batchCreate",
+            srv.basepath
+        ))
+        .json(&req)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+
+    let js: serde_json::Value = resp
+        .json()
+        .await
+        .expect("failed to get payload");
+    let items = js["items"].as_array().unwrap();
+    assert_eq!(items.len(), 5);
+    assert_eq!(items[0]["user"]["email"], "one@foo.com");
+    assert_eq!(items[1]["error"]["code"], "UserInvalidData");
+    assert_eq!(
+        items[1]["error"]["violations"][0]["field"],
+        "email"
+    );
+    assert_eq!(items[2]["error"]["code"], "DuplicateEmail");
+    assert_eq!(items[3]["user"]["email"], "four@foo.com");
+    assert_eq!(items[4]["error"]["code"], "DuplicateEmail");
+
+    let resp = client
+        .get(format!(
+            "{}/api/v1/users/{}",
+            srv.basepath,
+            items[3]["user"]["id"].as_str().unwrap()
+        ))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+}
+
+#[tokio::test]
+async fn batch_create_users_all_or_nothing() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+    let endpoint = format!("{}/api/v1/users:batchCreate", srv.basepath);
+
+    let req = serde_json::json!({
+        "items": [
+            {"email": "one@foo.com", "name": "One"},
+            {"email": "two@foo.com", "name": ""},
+        ]
+    });
+    let resp = client
+        .post(&endpoint)
+        .json(&req)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+
+    let js: serde_json::Value = resp
+        .json()
+        .await
+        .expect("failed to get payload");
+    assert_eq!(js["items"][0]["error"]["code"], "BatchAborted");
+    assert_eq!(js["items"][1]["error"]["code"], "UserInvalidData");
+
+    // Nothing was stored
+    let list: UserList = client
+        .get(format!("{}/api/v1/users", srv.basepath))
+        .send()
+        .await
+        .expect("failed to execute request")
+        .json()
+        .await
+        .expect("failed to get payload");
+    assert!(list.items.is_empty());
+
+    let req = serde_json::json!({
+        "mode": "all_or_nothing",
+        "items": [
+            {"email": "one@foo.com", "name": "One"},
+            {"email": "two@foo.com", "name": "Two"},
+        ]
+    });
+    let js: serde_json::Value = client
+        .post(&endpoint)
+        .json(&req)
+        .send()
+        .await
+        .expect("failed to execute request")
+        .json()
+        .await
+        .expect("failed to get payload");
+    assert_eq!(js["items"][0]["user"]["name"], "One");
+    assert_eq!(js["items"][1]["user"]["name"], "Two");
+}
+
+#[tokio::test]
+async fn batch_create_users_too_large() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+
+    let items: Vec<_> = (0..1001)
+        .map(|i| serde_json::json!({"email": format!("u{i}@foo.com"), "name": "U"}))
+        .collect();
+    let resp = client
+        .post(format!(
+            "{}/api/v1/users:batchCreate",
+            srv.basepath
+        ))
+        .json(&serde_json::json!({"items": items}))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::PAYLOAD_TOO_LARGE, resp.status());
+
+    let js: serde_json::Value = resp
+        .json()
+        .await
+        .expect("failed to get payload");
+    assert_eq!(js["code"], "BatchTooLarge");
+}
+
+async fn create_user(
+    srv: &helpers::TestServer, client: &reqwest::Client, email: &str, name: &str,
+) -> User {
+    let endpoint = format!("{}/api/v1/users", srv.basepath);
+
+    let mut req = HashMap::new();
+    req.insert("email", email);
+    req.insert("name", name);
+
+    let resp = client
+        .post(endpoint)
+        .json(&req)
+        .send()
+        .await
+        .expect("failed to execute request");
+
+    assert_eq!(http::StatusCode::CREATED, resp.status());
+    resp.json()
+        .await
+        .expect("failed to get payload")
+}
+
+#[tokio::test]
+async fn export_users() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+    create_user(&srv, &client, "one@foo.com", "One").await;
+    create_user(&srv, &client, "two@foo.com", "Two, Jr").await;
+
+    let resp = client
+        .get(format!(
+            "{}/api/v1/users?sort=email&fields=email,name",
+            srv.basepath
+        ))
+        .header("Accept", "text/csv")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+    assert_eq!(resp.headers()["content-type"], "text/csv");
+    assert_eq!(
+        resp.text().await.unwrap(),
+        "email,name\none@foo.com,One\ntwo@foo.com,\"Two, Jr\"\n"
+    );
+
+    let resp = client
+        .get(format!("{}/api/v1/users", srv.basepath))
+        .query(&[("filter", r#"name == "One""#)])
+        .header("Accept", "application/x-ndjson")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+    let body = resp.text().await.unwrap();
+    let lines: Vec<&str> = body.lines().collect();
+    assert_eq!(lines.len(), 1);
+    let js: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+    assert_eq!(js["email"], "one@foo.com");
+
+    // Errors are still reported before streaming starts
+    let resp = client
+        .get(format!("{}/api/v1/users?sort=nope", srv.basepath))
+        .header("Accept", "text/csv")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::BAD_REQUEST, resp.status());
 }
 
 #[tokio::test]
-async fn create_user_bad_data() {
-    // todo
+async fn import_users() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+    create_user(&srv, &client, "taken@foo.com", "Jeff Jefferson").await;
+
+    let csv = "email,name\n\
+               one@foo.com,One\n\
+               not_an_email,Two\n\
+               taken@foo.com,Three\n\
+               \"four@foo.com\",Four\n\
+               broken\n";
+    let resp = client
+        .post(format!("{}/api/v1/users:import", srv.basepath))
+        .header("Content-Type", "text/csv")
+        .body(csv)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+
+    let js: serde_json::Value = resp
+        .json()
+        .await
+        .expect("failed to get payload");
+    assert_eq!(js["imported"], 2);
+    let rejected = js["rejected"].as_array().unwrap();
+    assert_eq!(rejected.len(), 3);
+    assert_eq!(rejected[0]["line"], 3);
+    assert_eq!(rejected[0]["error"]["code"], "UserInvalidData");
+    assert_eq!(rejected[1]["line"], 4);
+    assert_eq!(rejected[1]["error"]["code"], "DuplicateEmail");
+    assert_eq!(rejected[2]["line"], 6);
+    assert_eq!(
+        rejected[2]["error"]["violations"][0]["field"],
+        "row"
+    );
+
+    let ndjson = "{\"email\":\"five@foo.com\",\"name\":\"Five\"}\n\
+                  {\"email\":\"six@foo.com\"}\n";
+    let resp = client
+        .post(format!("{}/api/v1/users:import", srv.basepath))
+        .header("Content-Type", "application/x-ndjson")
+        .body(ndjson)
+        .send()
+        .await
+        .expect("failed to execute request");
+    let js: serde_json::Value = resp
+        .json()
+        .await
+        .expect("failed to get payload");
+    assert_eq!(js["imported"], 1);
+    assert_eq!(js["rejected"][0]["line"], 2);
+
+    let resp = client
+        .post(format!("{}/api/v1/users:import", srv.basepath))
+        .header("Content-Type", "application/json")
+        .body("[]")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::BAD_REQUEST, resp.status());
+}
+
+#[tokio::test]
+async fn idempotent_create_user() {
+    let start = OffsetDateTime::parse("2024-01-02T03:04:05Z", &Rfc3339).unwrap();
+    let clock = Arc::new(FakeClock::new(start));
+    let srv = helpers::spawn_app_with(clock.clone(), Arc::new(FakeIdGenerator::new()));
+    let client = reqwest::Client::new();
+    let endpoint = format!("{}/api/v1/users", srv.basepath);
+
+    let post = |key: &'static str, email: &'static str| {
+        client
+            .post(&endpoint)
+            .header("Idempotency-Key", key)
+            .json(&serde_json::json!({"email": email, "name": "Jeff Jefferson"}))
+            .send()
+    };
+
+    let resp = post("key-1", "jeff@foo.com")
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::CREATED, resp.status());
+    let first: User = resp.json().await.unwrap();
+
+    // Replayed, not a DuplicateEmail
+    let resp = post("key-1", "jeff@foo.com")
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::CREATED, resp.status());
+    let replayed: User = resp.json().await.unwrap();
+    assert_eq!(replayed.id(), first.id());
+
+    let resp = post("key-1", "other@foo.com")
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::CONFLICT, resp.status());
+    let err: LogicError = resp.json().await.unwrap();
+    assert!(matches!(
+        err.code(),
+        LogicErrorCode::IdempotencyKeyMismatch
+    ));
+
+    // A failed create doesn't hold on to its key
+    let resp = post("key-2", "not_an_email")
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::BAD_REQUEST, resp.status());
+    let resp = post("key-2", "other@foo.com")
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::CREATED, resp.status());
+
+    let resp = post("not a key", "third@foo.com")
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::BAD_REQUEST, resp.status());
+    let err: LogicError = resp.json().await.unwrap();
+    assert!(matches!(
+        err.code(),
+        LogicErrorCode::InvalidIdempotencyKey
+    ));
+
+    // Past the TTL the key starts over
+    clock.advance(Duration::days(1));
+    let resp = post("key-1", "jeff@foo.com")
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::CONFLICT, resp.status());
+    let err: LogicError = resp.json().await.unwrap();
+    assert!(matches!(
+        err.code(),
+        LogicErrorCode::DuplicateEmail
+    ));
+}
+
+#[tokio::test]
+async fn user_status_lifecycle() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+    let created_usr = create_user(&srv, &client, "test@foo.com", "Jeff Jefferson").await;
+    let endpoint = |op: &str| {
+        format!(
+            "{}/api/v1/users/{}:{}",
+            srv.basepath,
+            created_usr.id(),
+            op
+        )
+    };
+
+    // Unverified users can't skip verification by being reactivated
+    let resp = client
+        .post(endpoint("reactivate"))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::CONFLICT, resp.status());
+    let err: LogicError = resp.json().await.unwrap();
+    assert!(matches!(
+        err.code(),
+        LogicErrorCode::InvalidStatusTransition
+    ));
+
+    let resp = client
+        .post(endpoint("suspend"))
+        .header("If-Match", "\"1\"")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+    assert_eq!(resp.headers()["etag"], "\"2\"");
+    let js: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(js["status"], "suspended");
+
+    let resp = client
+        .post(endpoint("suspend"))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::CONFLICT, resp.status());
+    let err: LogicError = resp.json().await.unwrap();
+    assert!(matches!(
+        err.code(),
+        LogicErrorCode::UserStatusUnchanged
+    ));
+
+    let resp = client
+        .get(format!("{}/api/v1/users", srv.basepath))
+        .query(&[("filter", r#"status == "suspended""#)])
+        .send()
+        .await
+        .expect("failed to execute request");
+    let list: UserList = resp.json().await.unwrap();
+    assert_eq!(list.items.len(), 1);
+    assert_eq!(list.items[0].id(), created_usr.id());
+
+    let resp = client
+        .post(endpoint("deactivate"))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+
+    let resp = client
+        .post(endpoint("suspend"))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::CONFLICT, resp.status());
+    let err: LogicError = resp.json().await.unwrap();
+    assert!(matches!(
+        err.code(),
+        LogicErrorCode::InvalidStatusTransition
+    ));
+
+    let resp = client
+        .post(endpoint("reactivate"))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+    let js: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(js["status"], "pending_verification");
+    assert_eq!(js["version"], 4);
+
+    let resp = client
+        .get(format!(
+            "{}/api/v1/users/{}/history",
+            srv.basepath,
+            created_usr.id()
+        ))
+        .send()
+        .await
+        .expect("failed to execute request");
+    let js: serde_json::Value = resp.json().await.unwrap();
+    let ops: Vec<&str> = js["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["operation"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        ops,
+        ["create", "suspend", "deactivate", "reactivate"]
+    );
+
+    let resp = client
+        .get(format!("{}/api/v1/users", srv.basepath))
+        .query(&[("filter", r#"status == "banned""#)])
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::BAD_REQUEST, resp.status());
+}
+
+#[tokio::test]
+async fn verify_user() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+    let created_usr = create_user(&srv, &client, "test@foo.com", "Jeff Jefferson").await;
+    assert_eq!(
+        created_usr.status().as_str(),
+        "pending_verification"
+    );
+    let endpoint = format!(
+        "{}/api/v1/users/{}:verify",
+        srv.basepath,
+        created_usr.id()
+    );
+    let verify = |token: String| {
+        let mut req = HashMap::new();
+        req.insert("token", token);
+        client.post(&endpoint).json(&req).send()
+    };
+
+    let messages = srv.mailer.messages();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].to, "test@foo.com");
+    let token = srv
+        .mailer
+        .last_token("test@foo.com")
+        .unwrap();
+
+    // Signed, so it can't be altered
+    let resp = verify(format!("{}x", token))
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::BAD_REQUEST, resp.status());
+    let err: LogicError = resp.json().await.unwrap();
+    assert!(matches!(
+        err.code(),
+        LogicErrorCode::InvalidVerificationToken
+    ));
+
+    let resp = verify(token.clone())
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+    assert_eq!(resp.headers()["etag"], "\"2\"");
+    let js: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(js["status"], "active");
+
+    // Single use
+    let resp = verify(token)
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::BAD_REQUEST, resp.status());
+
+    // A new address resets verification and gets a token of its own
+    let mut req = HashMap::new();
+    req.insert("email", "new@foo.com");
+    let resp = client
+        .patch(format!(
+            "{}/api/v1/users/{}",
+            srv.basepath,
+            created_usr.id()
+        ))
+        .json(&req)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+    let js: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(js["status"], "pending_verification");
+
+    let token = srv
+        .mailer
+        .last_token("new@foo.com")
+        .unwrap();
+    let resp = verify(token)
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+
+    let resp = client
+        .get(format!(
+            "{}/api/v1/users/{}/history",
+            srv.basepath,
+            created_usr.id()
+        ))
+        .send()
+        .await
+        .expect("failed to execute request");
+    let js: serde_json::Value = resp.json().await.unwrap();
+    let ops: Vec<&str> = js["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["operation"].as_str().unwrap())
+        .collect();
+    assert_eq!(ops, ["create", "verify", "update", "verify"]);
+}
+
+#[tokio::test]
+async fn verify_user_stale_token() {
+    let clock = Arc::new(FakeClock::new(OffsetDateTime::now_utc()));
+    let srv = helpers::spawn_app_with(clock.clone(), Arc::new(FakeIdGenerator::new()));
+    let client = reqwest::Client::new();
+    let first = create_user(&srv, &client, "first@foo.com", "Jeff Jefferson").await;
+    let second = create_user(&srv, &client, "second@foo.com", "Geoff Jefferson").await;
+    let verify = |usr: &User, token: String| {
+        let mut req = HashMap::new();
+        req.insert("token", token);
+        client
+            .post(format!(
+                "{}/api/v1/users/{}:verify",
+                srv.basepath,
+                usr.id()
+            ))
+            .json(&req)
+            .send()
+    };
+
+    // Tokens are bound to their user
+    let token = srv
+        .mailer
+        .last_token("first@foo.com")
+        .unwrap();
+    let resp = verify(&second, token.clone())
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::BAD_REQUEST, resp.status());
+
+    // Replaced by the token for the new address
+    let mut req = HashMap::new();
+    req.insert("email", "changed@foo.com");
+    let resp = client
+        .patch(format!(
+            "{}/api/v1/users/{}",
+            srv.basepath,
+            first.id()
+        ))
+        .json(&req)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+    let resp = verify(&first, token)
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::BAD_REQUEST, resp.status());
+    let err: LogicError = resp.json().await.unwrap();
+    assert!(matches!(
+        err.code(),
+        LogicErrorCode::InvalidVerificationToken
+    ));
+
+    clock.advance(Duration::days(1));
+    let token = srv
+        .mailer
+        .last_token("second@foo.com")
+        .unwrap();
+    let resp = verify(&second, token)
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::GONE, resp.status());
+    let err: LogicError = resp.json().await.unwrap();
+    assert!(matches!(
+        err.code(),
+        LogicErrorCode::VerificationTokenExpired
+    ));
+}
+
+#[tokio::test]
+async fn verify_user_after_case_change() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+    let usr = create_user(&srv, &client, "test@foo.com", "Jeff Jefferson").await;
+    let token = srv
+        .mailer
+        .last_token("test@foo.com")
+        .unwrap();
+
+    // Same mailbox, so no new token: the one sent stays good
+    let mut req = HashMap::new();
+    req.insert("email", "Test@FOO.com");
+    let resp = client
+        .patch(format!(
+            "{}/api/v1/users/{}",
+            srv.basepath,
+            usr.id()
+        ))
+        .json(&req)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+    assert_eq!(srv.mailer.messages().len(), 1);
+
+    let mut req = HashMap::new();
+    req.insert("token", token);
+    let resp = client
+        .post(format!(
+            "{}/api/v1/users/{}:verify",
+            srv.basepath,
+            usr.id()
+        ))
+        .json(&req)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+    let js: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(js["status"], "active");
+    assert_eq!(js["email"], "Test@FOO.com");
+}
+
+#[tokio::test]
+async fn tenants_are_isolated() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+    let endpoint = format!("{}/api/v1/users", srv.basepath);
+
+    // In the default tenant
+    let usr = create_user(&srv, &client, "test@foo.com", "Jeff Jefferson").await;
+
+    let resp = client
+        .get(format!("{}/{}", endpoint, usr.id()))
+        .header("x-tenant-id", "acme")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::NOT_FOUND, resp.status());
+
+    let resp = client
+        .get(&endpoint)
+        .header("x-tenant-id", "acme")
+        .send()
+        .await
+        .expect("failed to execute request");
+    let list: UserList = resp.json().await.unwrap();
+    assert!(list.items.is_empty());
+
+    let mut req = HashMap::new();
+    req.insert("email", "test@foo.com");
+    req.insert("name", "Jeff Jefferson");
+    let resp = client
+        .post(&endpoint)
+        .header("x-tenant-id", "acme")
+        .json(&req)
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::CREATED, resp.status());
+
+    let resp = client
+        .get(&endpoint)
+        .header("x-tenant-id", "a b")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::BAD_REQUEST, resp.status());
+    let err: LogicError = resp.json().await.unwrap();
+    assert!(matches!(
+        err.code(),
+        LogicErrorCode::InvalidTenant
+    ));
+}
+
+#[tokio::test]
+async fn user_metadata() {
+    let srv = helpers::spawn_app();
+    let client = reqwest::Client::new();
+    let endpoint = format!("{}/api/v1/users", srv.basepath);
+
+    let resp = client
+        .post(&endpoint)
+        .json(&serde_json::json!({
+            "email": "test@foo.com",
+            "name": "Jeff Jefferson",
+            "metadata": {"department": "sales", "cost_center": 4711}
+        }))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::CREATED, resp.status());
+    let js: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(
+        js["metadata"],
+        serde_json::json!({"department": "sales", "cost_center": 4711})
+    );
+    let id = js["id"].as_str().unwrap().to_string();
+    create_user(&srv, &client, "other@foo.com", "Jeff Jefferson").await;
+
+    let resp = client
+        .get(&endpoint)
+        .query(&[("filter", r#"metadata.cost_center == "4711""#)])
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+    let page: UserList = resp.json().await.unwrap();
+    assert_eq!(1, page.items.len());
+    assert_eq!(id, page.items[0].id().to_string());
+
+    // PATCH replaces the whole map
+    let resp = client
+        .patch(format!("{endpoint}/{id}"))
+        .json(&serde_json::json!({"metadata": {"remote": true}}))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::OK, resp.status());
+    let js: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(
+        js["metadata"],
+        serde_json::json!({"remote": true})
+    );
+
+    let resp = client
+        .patch(format!("{endpoint}/{id}"))
+        .json(&serde_json::json!({"metadata": {"Department": "sales"}}))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(http::StatusCode::BAD_REQUEST, resp.status());
+    let js: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(js["code"], "UserInvalidData");
+    assert_eq!(js["violations"][0]["field"], "metadata");
+    assert_eq!(js["violations"][0]["rule"], "key_format");
 }